@@ -1,4 +1,5 @@
-use magnus::{exception, Ruby};
+use magnus::prelude::*;
+use magnus::{exception, Exception, ExceptionClass, Ruby};
 use std::any;
 use std::fmt::Display;
 use std::io;
@@ -6,11 +7,17 @@ use std::path::Path;
 use thiserror::Error;
 
 use crate::util;
-use crate::PROM_EPARSING_ERROR;
+use crate::{PROM_EALLOCATION_ERROR, PROM_EPARSING_ERROR};
+
+/// Allocations larger than this are not expected from any sane `.db` file or
+/// scrape output. Failing to reserve more than this indicates corrupt or
+/// implausibly large input rather than genuine memory pressure.
+pub const PLAUSIBLE_ALLOC_LIMIT: usize = 1 << 30; // 1GiB
 
 /// A lightweight representation of Ruby ExceptionClasses.
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
 pub enum RubyError {
+    Alloc,
     Arg,
     Encoding,
     Frozen,
@@ -25,6 +32,11 @@ pub enum RubyError {
 impl From<RubyError> for magnus::ExceptionClass {
     fn from(err: RubyError) -> magnus::ExceptionClass {
         match err {
+            RubyError::Alloc => {
+                // UNWRAP: this will panic if called outside of a Ruby thread.
+                let ruby = Ruby::get().unwrap();
+                ruby.get_inner(&PROM_EALLOCATION_ERROR)
+            }
             RubyError::Arg => exception::arg_error(),
             RubyError::Encoding => exception::encoding_error(),
             RubyError::Frozen => exception::frozen_error(),
@@ -50,6 +62,10 @@ pub enum MmapError {
     /// A read or write was made while another thread had mutable access to the mmap.
     #[error("read/write operation attempted while mmap was being written to")]
     ConcurrentAccess,
+    /// A thread panicked while holding the lock on the mmap, which may have
+    /// been left in an inconsistent state.
+    #[error("mmap lock poisoned by a panic while it was held")]
+    Poisoned,
     /// An error message used to exactly match the messages returned by the C
     /// implementation.
     #[error("{0}")]
@@ -77,9 +93,39 @@ pub enum MmapError {
     },
     #[error("string length gt {}", i32::MAX)]
     KeyLength,
-    /// Failed to allocate memory.
+    /// A key was longer than the `max_key_length` of a file or scrape.
+    #[error("key length {len} exceeds limit of {limit}")]
+    KeyTooLong { len: usize, limit: usize },
+    /// A label value was longer than the `max_label_value_length` of a file.
+    #[error("value of label {label} length {len} exceeds limit of {limit}")]
+    LabelValueTooLong {
+        label: String,
+        len: usize,
+        limit: usize,
+    },
+    /// Two metric families were rendered under the same name, e.g. once
+    /// sanitized, which would write duplicate `# TYPE` lines.
+    #[error("metric families '{first}' and '{second}' both render as '{name}'")]
+    NameCollision {
+        first: String,
+        second: String,
+        name: String,
+    },
+    /// A file was larger than `MAX_FILE_SIZE`.
+    #[error("file '{path}' size {len} exceeds limit of {limit}")]
+    FileTooLarge {
+        path: String,
+        len: usize,
+        limit: usize,
+    },
+    /// Failed to allocate memory. Raised as Ruby's `NoMemoryError`.
     #[error("Couldn't allocate for {0} memory")]
     OutOfMemory(usize),
+    /// Failed to allocate an implausibly large amount of memory, typically
+    /// sized from a corrupt file. Unlike `OutOfMemory` this is raised as
+    /// `PrometheusAllocationError`, which callers can safely rescue.
+    #[error("Couldn't allocate for {0} memory, size is implausibly large")]
+    TooLarge(usize),
     /// A memory operation fell outside of the containers bounds.
     #[error("offset {index} out of bounds of len {len}")]
     OutOfBounds { index: String, len: String },
@@ -91,18 +137,31 @@ pub enum MmapError {
         op: String,
         ty: &'static str,
     },
+    /// A value was `NaN` or infinite when only finite values were permitted.
+    #[error("non-finite value {value} for series {series}")]
+    NonFinite { series: String, value: String },
+    /// A gauge file had a multiprocess mode the Ruby client doesn't write,
+    /// which would otherwise be merged as `all`.
+    #[error("unknown multiprocess mode '{mode}' for gauge file '{path}'")]
+    UnknownMode { mode: String, path: String },
+    /// An exemplar that downstream parsers would reject.
+    #[error("invalid exemplar: {0}")]
+    InvalidExemplar(String),
     /// A miscellaneous error.
     #[error("{0}")]
     Other(String),
     /// A failure when parsing a `.db` file containing Prometheus metrics.
     #[error("{0}")]
     PromParsing(String),
+    /// A write was attempted to a file opened read-only.
+    #[error("file '{0}' not opened for writing")]
+    ReadOnly(String),
     /// No mmap open.
     #[error("unmapped file")]
     UnmappedFile,
-    /// A custom error message with `strerror(3)` appended.
+    /// A custom error message with `strerror(3)` appended, and the raw errno.
     #[error("{0}")]
-    WithErrno(String),
+    WithErrno(String, i32),
 }
 
 impl MmapError {
@@ -142,33 +201,122 @@ impl MmapError {
         }
     }
 
+    /// Construct the error for a failed allocation of `size` bytes, using
+    /// `TooLarge` for sizes beyond `PLAUSIBLE_ALLOC_LIMIT`.
+    pub fn alloc_failed(size: usize) -> Self {
+        if size > PLAUSIBLE_ALLOC_LIMIT {
+            MmapError::TooLarge(size)
+        } else {
+            MmapError::OutOfMemory(size)
+        }
+    }
+
     pub fn with_errno<T: Into<String>>(msg: T) -> Self {
-        let strerror = util::strerror(util::errno());
-        MmapError::WithErrno(format!("{}: ({strerror})", msg.into()))
+        let errno = util::errno();
+        let strerror = util::strerror(errno);
+        MmapError::WithErrno(format!("{}: ({strerror})", msg.into()), errno)
     }
 
     pub fn ruby_err(&self) -> RubyError {
         match self {
             MmapError::ConcurrentAccess => RubyError::Arg,
+            MmapError::Poisoned => RubyError::Runtime,
             MmapError::Legacy(_, e) => *e,
             MmapError::Encoding(_) => RubyError::Encoding,
             MmapError::Io { .. } => RubyError::Io,
             MmapError::FailedCast { .. } => RubyError::Arg,
             MmapError::Frozen => RubyError::Frozen,
             MmapError::KeyLength => RubyError::Arg,
+            MmapError::KeyTooLong { .. } => RubyError::Arg,
+            MmapError::LabelValueTooLong { .. } => RubyError::Arg,
+            MmapError::NameCollision { .. } => RubyError::PromParsing,
+            MmapError::FileTooLarge { .. } => RubyError::PromParsing,
             MmapError::Overflow { .. } => RubyError::Arg,
             MmapError::OutOfBounds { .. } => RubyError::Index,
             MmapError::OutOfMemory { .. } => RubyError::NoMem,
+            MmapError::TooLarge(_) => RubyError::Alloc,
+            MmapError::NonFinite { .. } => RubyError::Arg,
+            MmapError::UnknownMode { .. } => RubyError::PromParsing,
+            MmapError::InvalidExemplar(_) => RubyError::Arg,
             MmapError::Other(_) => RubyError::Arg,
             MmapError::PromParsing(_) => RubyError::PromParsing,
+            MmapError::ReadOnly(_) => RubyError::Io,
             MmapError::UnmappedFile => RubyError::Io,
-            MmapError::WithErrno(_) => RubyError::Io,
+            MmapError::WithErrno(..) => RubyError::Io,
         }
     }
 }
 
 impl From<MmapError> for magnus::error::Error {
     fn from(err: MmapError) -> magnus::error::Error {
-        magnus::error::Error::new(err.ruby_err().into(), err.to_string())
+        let class: ExceptionClass = err.ruby_err().into();
+
+        match err {
+            MmapError::WithErrno(msg, errno) => {
+                errno_error(class, msg, errno).unwrap_or_else(|e| e)
+            }
+            _ => magnus::error::Error::new(class, err.to_string()),
+        }
+    }
+}
+
+/// Build an exception with the raw errno set as its `@errno` instance
+/// variable, allowing callers to distinguish e.g. `ENOSPC` from `EACCES`
+/// without parsing the message.
+fn errno_error(
+    class: ExceptionClass,
+    msg: String,
+    errno: i32,
+) -> magnus::error::Result<magnus::error::Error> {
+    let exception: Exception = class.new_instance((msg,))?;
+    exception.ivar_set("@errno", errno)?;
+
+    Ok(exception.into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_alloc_failed() {
+        assert_eq!(MmapError::OutOfMemory(1024), MmapError::alloc_failed(1024));
+        assert_eq!(
+            MmapError::OutOfMemory(PLAUSIBLE_ALLOC_LIMIT),
+            MmapError::alloc_failed(PLAUSIBLE_ALLOC_LIMIT)
+        );
+        assert_eq!(
+            MmapError::TooLarge(PLAUSIBLE_ALLOC_LIMIT + 1),
+            MmapError::alloc_failed(PLAUSIBLE_ALLOC_LIMIT + 1)
+        );
+
+        assert_eq!(RubyError::NoMem, MmapError::OutOfMemory(1).ruby_err());
+        assert_eq!(RubyError::Alloc, MmapError::TooLarge(1).ruby_err());
+    }
+
+    #[test]
+    fn test_with_errno_ivar() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        assert!(std::fs::File::open(dir.path().join("missing.db")).is_err());
+
+        let err = MmapError::with_errno("Can't open missing.db");
+        assert_eq!(
+            MmapError::WithErrno(
+                "Can't open missing.db: (No such file or directory)".to_string(),
+                libc::ENOENT
+            ),
+            err
+        );
+
+        let err: magnus::error::Error = err.into();
+        let exception = Exception::from_value(err.value().unwrap()).unwrap();
+        assert!(exception.is_kind_of(exception::io_error()));
+
+        let errno: i32 = exception.ivar_get("@errno").unwrap();
+        assert_eq!(libc::ENOENT, errno);
     }
 }
@@ -17,9 +17,83 @@ pub struct Exemplar {
 
 use serde::{Deserialize, Serialize};
 
+use crate::error::MmapError;
+use crate::raw_entry::HeaderFields;
 use crate::size_of;
+use crate::util;
+use crate::Result;
+
+impl Exemplar {
+    /// The maximum combined length of the label names and values, in code
+    /// points, permitted by OpenMetrics.
+    pub const MAX_LABEL_CHARS: usize = 128;
+
+    /// Reject exemplars that OpenMetrics parsers would refuse: the label name
+    /// must be a valid identifier, and the labels must fit `MAX_LABEL_CHARS`.
+    pub fn validate(&self) -> Result<()> {
+        if !util::is_valid_name(&self.label_name, true) {
+            return Err(MmapError::InvalidExemplar(format!(
+                "label name {:?} is not a valid identifier",
+                self.label_name
+            )));
+        }
+
+        let len = self.label_name.chars().count() + self.label_value.chars().count();
+        if len > Self::MAX_LABEL_CHARS {
+            return Err(MmapError::InvalidExemplar(format!(
+                "labels are {len} characters, limit is {}",
+                Self::MAX_LABEL_CHARS
+            )));
+        }
+
+        Ok(())
+    }
+}
 
 pub const EXEMPLAR_ENTRY_MAX_SIZE_BYTES:usize = 512 + size_of::<f64>() + size_of::<u64>();
 
+/// How exemplars are stored in their slots, recorded in the `version` field
+/// of the `HeaderFields` of exemplar files.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub enum ExemplarFormat {
+    /// The JSON followed by NUL padding, with the whole slot available for
+    /// the JSON. Files written before the version was recorded have a zeroed
+    /// version and are read as `Legacy`.
+    #[default]
+    Legacy,
+    /// A `u32` JSON length, the JSON, then NUL padding. The prefix leaves
+    /// four bytes less of the slot for the JSON than `Legacy`.
+    LengthPrefixed,
+}
+
+impl ExemplarFormat {
+    /// Read the format from the header of an exemplar file.
+    pub fn from_header(buf: &[u8]) -> Result<Self> {
+        match HeaderFields::from_header(buf)?.version {
+            0 => Ok(ExemplarFormat::Legacy),
+            1 => Ok(ExemplarFormat::LengthPrefixed),
+            version => Err(MmapError::PromParsing(format!(
+                "unknown exemplar format version {version}"
+            ))),
+        }
+    }
+
+    /// The value of the header version for this format.
+    pub fn version(self) -> u8 {
+        match self {
+            ExemplarFormat::Legacy => 0,
+            ExemplarFormat::LengthPrefixed => 1,
+        }
+    }
+
+    /// The space in a slot available for the JSON of an exemplar.
+    pub fn max_json_len(self) -> usize {
+        match self {
+            ExemplarFormat::Legacy => EXEMPLAR_ENTRY_MAX_SIZE_BYTES,
+            ExemplarFormat::LengthPrefixed => EXEMPLAR_ENTRY_MAX_SIZE_BYTES - size_of::<u32>(),
+        }
+    }
+}
+
 // Key -> use the old one.
 // Value -> allocate EXEMPLAR_ENTRY_MAX_SIZE_BYTES. If it exceeds this, we need to return an error. Use JSON.
\ No newline at end of file
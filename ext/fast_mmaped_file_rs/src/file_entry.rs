@@ -3,15 +3,23 @@ use magnus::Symbol;
 use serde::Deserialize;
 use serde_json::value::RawValue;
 use smallvec::SmallVec;
+use std::borrow::Cow;
 use std::fmt::Write;
+use std::mem::size_of;
+use std::ops::Range;
+use std::rc::Rc;
 use std::str;
 
 use crate::error::{MmapError, RubyError};
 use crate::exemplars::Exemplar;
 use crate::file_info::FileInfo;
+use crate::interner::Interner;
 use crate::raw_entry::RawEntry;
+use crate::util::{self, Fnv64};
 use crate::Result;
-use crate::{SYM_GAUGE, SYM_LIVESUM, SYM_MAX, SYM_MIN};
+use crate::{
+    SYM_ALL, SYM_COUNTER, SYM_EXEMPLAR, SYM_GAUGE, SYM_LIVEALL, SYM_LIVESUM, SYM_MAX, SYM_MIN,
+};
 use std::io::Cursor;
 use varint_rs::VarintWriter;
 
@@ -30,14 +38,56 @@ pub struct FileEntry {
     pub meta: EntryMetadata,
 }
 
+/// The number of labels a `MetricText` holds inline, series with more spill
+/// to the heap each time they are parsed. Of series with 2 to 12 labels,
+/// parsing allocates for 8 of 11 with 4 inline, 4 of 11 with 8, and none
+/// with 16, as `test_label_inline_size` asserts. Each extra inline label
+/// grows every `MetricText` by two pointer pairs, so we keep it small.
+pub const INLINE_LABELS: usize = 4;
+
 /// String slices pointing to the fields of a borrowed `Entry`'s JSON data.
 #[derive(Deserialize, Debug, Clone)]
 pub struct MetricText<'a> {
     pub family_name: &'a str,
     pub metric_name: &'a str,
-    pub labels: SmallVec<[&'a str; 4]>,
+    pub labels: SmallVec<[&'a str; INLINE_LABELS]>,
     #[serde(borrow)]
-    pub values: SmallVec<[&'a RawValue; 4]>,
+    pub values: SmallVec<[&'a RawValue; INLINE_LABELS]>,
+}
+
+/// The names written by a single text render, to count the distinct names
+/// sanitized and to catch two families rendered under the same name.
+#[derive(Default, Debug)]
+pub(crate) struct RenderedNames {
+    /// The stored names that were changed by sanitizing.
+    sanitized: HashSet<String>,
+    /// The stored name of each family header written, by rendered name.
+    families: HashMap<String, String>,
+}
+
+impl RenderedNames {
+    /// The number of distinct names changed by sanitizing.
+    pub(crate) fn sanitized_count(&self) -> usize {
+        self.sanitized.len()
+    }
+
+    /// Record a family header for `family_name` rendered as `rendered`,
+    /// failing if another family was already rendered under that name.
+    fn add_family(&mut self, family_name: &str, rendered: &str) -> Result<()> {
+        match self.families.get(rendered) {
+            Some(first) if first != family_name => Err(MmapError::NameCollision {
+                first: first.clone(),
+                second: family_name.to_owned(),
+                name: rendered.to_owned(),
+            }),
+            Some(_) => Ok(()),
+            None => {
+                self.families
+                    .insert(rendered.to_owned(), family_name.to_owned());
+                Ok(())
+            }
+        }
+    }
 }
 
 /// The primary data payload for a `FileEntry`, the JSON string and the
@@ -60,7 +110,7 @@ impl<'a> TryFrom<BorrowedData<'a>> for EntryData {
     fn try_from(borrowed: BorrowedData) -> Result<Self> {
         let mut json = String::new();
         if json.try_reserve_exact(borrowed.json.len()).is_err() {
-            return Err(MmapError::OutOfMemory(borrowed.json.len()));
+            return Err(MmapError::alloc_failed(borrowed.json.len()));
         }
         json.push_str(borrowed.json);
 
@@ -100,6 +150,90 @@ impl<'a> BorrowedData<'a> {
     }
 }
 
+/// The line terminating output in the OpenMetrics text format.
+pub const OPENMETRICS_EOF: &str = "# EOF\n";
+
+/// Options controlling how entries are rendered.
+#[derive(Clone, Debug, Default)]
+pub struct RenderOptions {
+    /// Return an error naming the series rather than emitting a `NaN` or
+    /// infinite value. Text format only.
+    pub strict_finite: bool,
+    /// Emit the time of a series' most recent exemplar as the sample
+    /// timestamp, in milliseconds, or in seconds in the OpenMetrics format.
+    /// Prometheus discourages explicit timestamps in most cases, so this is
+    /// off by default.
+    pub timestamps: bool,
+    /// Replace characters that are invalid in metric and label names with
+    /// `_`, so a bad name written by the application can't break the
+    /// exposition format. Text format only.
+    pub sanitize_names: bool,
+    /// Render in the OpenMetrics text format rather than the Prometheus one:
+    /// counter names take the `_total` suffix and the output is terminated by
+    /// `# EOF`. Text format only.
+    pub openmetrics: bool,
+    /// Rewrite metric and family names starting with the first string of a
+    /// pair to start with the second instead, e.g. to namespace third-party
+    /// metrics. The longest matching prefix is used, so `("", "vendor_")`
+    /// prefixes every name.
+    pub name_prefixes: Vec<(String, String)>,
+    /// A single timestamp in milliseconds, e.g. the time of the scrape, to
+    /// emit on every sample. It is written in seconds in the OpenMetrics
+    /// format. Takes precedence over `timestamps`. Prometheus assigns its own
+    /// scrape timestamp, so most setups should leave this unset.
+    pub scrape_timestamp_ms: Option<i64>,
+    /// Return an error counting the histogram buckets and summary quantiles
+    /// without a valid `le` or `quantile` label, rather than skipping them
+    /// and rendering the rest. Protobuf format only.
+    pub reject_malformed: bool,
+    /// Skip a UTF-8 byte order mark and any whitespace before the JSON key of
+    /// each entry, so a key prefixed by a foreign writer can still be parsed.
+    /// By default such an entry fails to parse.
+    pub tolerant_keys: bool,
+    /// Labels describing the target as a whole, e.g. its environment, emitted
+    /// as a `target_info` sample as OpenMetrics recommends rather than being
+    /// repeated on every series. OpenMetrics format only, nothing is emitted
+    /// when empty.
+    pub target_info: Vec<(String, String)>,
+}
+
+impl RenderOptions {
+    /// Apply the longest matching rewrite in `name_prefixes` to `name`.
+    pub fn rewrite_name<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        let rewrite = self
+            .name_prefixes
+            .iter()
+            .filter(|(from, _)| name.starts_with(from.as_str()))
+            .max_by_key(|(from, _)| from.len());
+
+        match rewrite {
+            Some((from, to)) => Cow::Owned(format!("{to}{}", &name[from.len()..])),
+            None => Cow::Borrowed(name),
+        }
+    }
+
+    /// Parse the JSON key of an entry, trimming a leading byte order mark and
+    /// whitespace first if `tolerant_keys` is set.
+    fn parse_key<'a>(&self, json: &'a str) -> serde_json::Result<MetricText<'a>> {
+        let json = if self.tolerant_keys {
+            util::trim_key(json)
+        } else {
+            json
+        };
+
+        serde_json::from_str::<MetricText>(json)
+    }
+
+    /// The timestamp to emit on the sample of `entry`, if any.
+    fn sample_timestamp_ms(&self, entry: &FileEntry) -> Option<i64> {
+        match self.scrape_timestamp_ms {
+            Some(ts) => Some(ts),
+            None if self.timestamps => entry.meta.timestamp_ms(),
+            None => None,
+        }
+    }
+}
+
 /// The metadata associated with a `FileEntry`. The value in `EntryMap`.
 #[derive(Clone, Debug)]
 pub struct EntryMetadata {
@@ -111,12 +245,19 @@ pub struct EntryMetadata {
 
 impl EntryMetadata {
     /// Construct a new `FileEntry`, copying the JSON string from the `RawEntry`
-    /// into an internal buffer.
+    /// into an internal buffer. Gauges with a multiprocess mode the Ruby
+    /// client doesn't write are rejected, so a typo in a mode name surfaces
+    /// rather than being merged as `all`.
     pub fn new(mmap_entry: &RawEntry, file: &FileInfo) -> Result<Self> {
-        if file.type_.to_string() == "exemplar" {
-            let ex = mmap_entry.exemplar();
+        if file.type_ == SYM_GAUGE && !Self::is_known_mode(file.multiprocess_mode) {
+            return Err(MmapError::UnknownMode {
+                mode: file.multiprocess_mode.to_string(),
+                path: file.path.display().to_string(),
+            });
+        }
 
-            
+        if file.type_.to_string() == "exemplar" {
+            let ex = mmap_entry.exemplar()?;
 
             return Ok(EntryMetadata {
                 multiprocess_mode: file.multiprocess_mode,
@@ -136,21 +277,28 @@ impl EntryMetadata {
         })
     }
 
-    /// Combine values with another `EntryMetadata`.
+    /// Combine values with another `EntryMetadata`. When both carry an
+    /// exemplar the most recent one is kept, with ties broken by the larger
+    /// value so the result doesn't depend on the order workers are merged.
+    ///
+    /// Gauges in `all` mode are keyed by pid and keep the value merged last,
+    /// so a pid writing the same series to several files renders one line.
     pub fn merge(&mut self, other: &Self) {
-        if other.ex.is_some() {
-            let otherex = other.ex.clone().unwrap();
-            
-            if self.ex.is_some() {
-                let selfex = self.ex.clone().unwrap();
-
-                if selfex.timestamp < otherex.timestamp || (selfex.timestamp == otherex.timestamp && selfex.value < otherex.value) {
-                    self.ex = other.ex.clone();
+        self.adopt_type(other);
+
+        if let Some(other_ex) = other.ex.as_ref() {
+            let newer = match self.ex.as_ref() {
+                Some(self_ex) => {
+                    (self_ex.timestamp, self_ex.value) < (other_ex.timestamp, other_ex.value)
                 }
-            } else {
-                self.ex = other.ex.clone();
+                None => true,
+            };
+
+            if newer {
+                self.ex = Some(other_ex.clone());
             }
         }
+
         if other.value.is_some() {
             if self.value.is_none() {
                 self.value = other.value;
@@ -170,7 +318,47 @@ impl EntryMetadata {
                 }
             }
         }
-        
+    }
+
+    /// Combine values with another counter's `EntryMetadata`. Counters are
+    /// always summed and counter files never carry exemplars, so this skips
+    /// the type and `multiprocess_mode` checks made by `merge` and keeps any
+    /// exemplar already merged.
+    pub fn merge_counter(&mut self, other: &Self) {
+        self.adopt_type(other);
+
+        self.value = match (self.value, other.value) {
+            (Some(self_value), Some(other_value)) => Some(self_value + other_value),
+            (self_value, other_value) => self_value.or(other_value),
+        };
+    }
+
+    /// Entries read from an exemplar file have the `exemplar` type and no
+    /// value. Take the type of the series they are merged with, so the series
+    /// is rendered the same regardless of the order the files are read in.
+    fn adopt_type(&mut self, other: &Self) {
+        if self.type_ == SYM_EXEMPLAR && other.type_ != SYM_EXEMPLAR {
+            self.type_ = other.type_;
+            self.multiprocess_mode = other.multiprocess_mode;
+        }
+    }
+
+    /// The timestamp of the entry's exemplar in milliseconds, if it has one.
+    pub fn timestamp_ms(&self) -> Option<i64> {
+        // CAST: nanoseconds since the epoch in milliseconds fits in an i64
+        // for the next ~290 million years.
+        self.ex.as_ref().map(|ex| (ex.timestamp / 1_000_000) as i64)
+    }
+
+    /// Whether `mode` is one of the gauge multiprocess modes accepted by
+    /// `Prometheus::Client::Gauge`. Modes not reduced by `merge` keep a
+    /// series per pid.
+    fn is_known_mode(mode: Symbol) -> bool {
+        mode == SYM_ALL
+            || mode == SYM_LIVEALL
+            || mode == SYM_LIVESUM
+            || mode == SYM_MAX
+            || mode == SYM_MIN
     }
 
     /// Validate if pid is significant for metric.
@@ -185,12 +373,87 @@ use crate::io::prometheus::client::MetricType::{Counter, Gauge, Histogram, Summa
 use itertools::Itertools;
 use prost::Message;
 use std::collections::hash_map::DefaultHasher;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::hash::Hash;
 use std::hash::Hasher;
 
 use std::io::Write as OtherWrite;
 
+/// Append `mf` to `out` as a length-delimited message, returning an error
+/// rather than panicking if the write fails.
+pub(crate) fn write_length_delimited<W: OtherWrite>(
+    out: &mut W,
+    mf: &io::prometheus::client::MetricFamily,
+) -> Result<()> {
+    let encoded = mf.encode_to_vec();
+    let len = frame_len(mf.name(), encoded.len())?;
+
+    let write_err = |e: std::io::Error| MmapError::Other(format!("failed to write output: {e}"));
+    out.write_u32_varint(len).map_err(write_err)?;
+    out.write_all(&encoded).map_err(write_err)?;
+
+    Ok(())
+}
+
+/// The length prefix of an encoded `MetricFamily` named `name`. Fails if the
+/// encoded length doesn't fit the `u32` prefix.
+fn frame_len(name: &str, len: usize) -> Result<u32> {
+    u32::try_from(len).map_err(|_| {
+        MmapError::Other(format!(
+            "metric family {name} encoded length {len} exceeds limit of {}",
+            u32::MAX
+        ))
+    })
+}
+
+/// A `fmt::Write` sink that only counts the bytes written to it.
+#[derive(Default)]
+struct LenCounter(usize);
+
+impl std::fmt::Write for LenCounter {
+    fn write_str(&mut self, s: &str) -> std::fmt::Result {
+        self.0 += s.len();
+        Ok(())
+    }
+}
+
+/// The labels of a series, each name and value shared with every other series
+/// using the same string.
+type SharedLabels = Box<[(Rc<str>, Rc<str>)]>;
+
+/// A `MetricFamily` whose series hold their labels as shared strings rather
+/// than owned `LabelPair`s, see `FileEntry::metric_families`.
+pub(crate) struct SharedFamily {
+    /// The family, with the labels of each metric left empty.
+    family: io::prometheus::client::MetricFamily,
+    /// The labels of each metric in `family.metric`, in the same order.
+    labels: Vec<SharedLabels>,
+}
+
+impl SharedFamily {
+    pub(crate) fn name(&self) -> &str {
+        self.family.name()
+    }
+
+    /// Convert into a `MetricFamily` with owned labels, just before it is
+    /// encoded.
+    pub(crate) fn into_proto(self) -> io::prometheus::client::MetricFamily {
+        let mut family = self.family;
+
+        for (metric, labels) in family.metric.iter_mut().zip(self.labels) {
+            metric.label = labels
+                .iter()
+                .map(|(name, value)| io::prometheus::client::LabelPair {
+                    name: Some(name.to_string()),
+                    value: Some(value.to_string()),
+                })
+                .collect();
+        }
+
+        family
+    }
+}
+
 fn exemplar_to_proto(e: &Exemplar) -> io::prometheus::client::Exemplar {
     let seconds = e.timestamp / (1000 * 1000 * 1000);
     let nanos = e.timestamp % (1000 * 1000 * 1000);
@@ -208,8 +471,103 @@ fn exemplar_to_proto(e: &Exemplar) -> io::prometheus::client::Exemplar {
     }
 }
 
+/// The role of a series within a histogram family, derived from the suffix
+/// of its metric name.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum HistogramSeries {
+    Bucket,
+    Sum,
+    Count,
+}
+
+impl HistogramSeries {
+    /// Split a histogram metric name into its base name and series role.
+    /// Names without a recognized suffix are treated as buckets.
+    fn parse(metric_name: &str) -> (&str, Self) {
+        if let Some(base) = metric_name.strip_suffix("_bucket") {
+            (base, HistogramSeries::Bucket)
+        } else if let Some(base) = metric_name.strip_suffix("_sum") {
+            (base, HistogramSeries::Sum)
+        } else if let Some(base) = metric_name.strip_suffix("_count") {
+            (base, HistogramSeries::Count)
+        } else {
+            (metric_name, HistogramSeries::Bucket)
+        }
+    }
+}
+
+/// Estimate the `q` quantile from `buckets` of `(upper_bound, cumulative_count)`
+/// sorted by bound, and the total number of observations. Mirrors
+/// `bucketQuantile` in Prometheus: the quantile is interpolated linearly
+/// within the bucket it falls in, and one falling in the implicit `+Inf`
+/// bucket is reported as the largest finite bound.
+fn bucket_quantile(q: f64, buckets: &[(f64, f64)], total: f64) -> f64 {
+    if q.is_nan() {
+        return f64::NAN;
+    }
+    if q < 0.0 {
+        return f64::NEG_INFINITY;
+    }
+    if q > 1.0 {
+        return f64::INFINITY;
+    }
+    if buckets.is_empty() || total == 0.0 {
+        return f64::NAN;
+    }
+
+    let rank = q * total;
+    let Some(b) = buckets.iter().position(|&(_, count)| count >= rank) else {
+        return buckets[buckets.len() - 1].0;
+    };
+
+    let (upper, count) = buckets[b];
+    if b == 0 {
+        if upper <= 0.0 {
+            return upper;
+        }
+        return upper * (rank / count);
+    }
+
+    let (lower, below) = buckets[b - 1];
+    lower + (upper - lower) * ((rank - below) / (count - below))
+}
+
+/// Use the integer `cumulative_count` and `sample_count` fields when every
+/// count in the histogram is a whole number, as some consumers reject float
+/// counts for integer-valued histograms. Otherwise the float fields are kept.
+fn prefer_integer_counts(hs: &mut io::prometheus::client::Histogram) {
+    // CAST: `u64::MAX as f64` rounds up to 2^64, hence the strict comparison.
+    let is_whole = |v: f64| v.is_finite() && v >= 0.0 && v.fract() == 0.0 && v < u64::MAX as f64;
+
+    let all_whole = hs
+        .bucket
+        .iter()
+        .filter_map(|b| b.cumulative_count_float)
+        .chain(hs.sample_count_float)
+        .all(is_whole);
+
+    if !all_whole {
+        return;
+    }
+
+    // CAST: confirmed above that each count is a whole number in range.
+    for bucket in &mut hs.bucket {
+        if let Some(count) = bucket.cumulative_count_float.take() {
+            bucket.cumulative_count = Some(count as u64);
+        }
+    }
+    if let Some(count) = hs.sample_count_float.take() {
+        hs.sample_count = Some(count as u64);
+    }
+}
+
 impl FileEntry {
     pub fn trim_quotes(s: &str) -> String {
+        Self::strip_quotes(s).to_string()
+    }
+
+    /// Borrowing equivalent of `trim_quotes`.
+    fn strip_quotes(s: &str) -> &str {
         let mut chars = s.chars();
 
         if s.starts_with('"') {
@@ -219,372 +577,214 @@ impl FileEntry {
             chars.next_back();
         }
 
-        chars.as_str().to_string()
+        chars.as_str()
     }
 
-    pub fn entries_to_protobuf(entries: Vec<FileEntry>) -> Result<String> {
-        let mut buffer: Cursor<Vec<u8>> = Cursor::new(Vec::new());
-        let mut mtrcs: HashMap<u64, io::prometheus::client::Metric> = HashMap::new();
-        let mut metric_types = HashMap::new();
-        let mut metric_names = HashMap::new();
+    /// Re-serialize an entry's JSON with its labels sorted by name, so entries
+    /// containing the same labels in a different order compare equal. Returns
+    /// `None` if the JSON is not a valid entry.
+    pub fn canonical_json(json: &str) -> Option<String> {
+        let metric = serde_json::from_str::<MetricText>(json).ok()?;
+        if metric.labels.len() != metric.values.len() {
+            return None;
+        }
 
-        entries
+        let mut pairs: SmallVec<[(&str, &RawValue); INLINE_LABELS]> = metric
+            .labels
             .iter()
-            // TODO: Don't just unwrap. Handle the error gracefully.
-            .map(|v| {
-                (
-                    v,
-                    serde_json::from_str::<MetricText>(&v.data.json)
-                        .expect("cannot parse json entry"),
-                    v.meta.type_.name().expect("getting name").into_owned(),
-                )
-            })
-            .filter(|v| v.1.labels.len() == v.1.values.len())
-            .group_by(|v| v.1.family_name)
-            .into_iter()
-            .for_each(|(_, group)| {
-                // NOTE(GiedriusS): different dynamic labels fall under the same
-                // metric group.
+            .copied()
+            .zip(metric.values.iter().copied())
+            .collect();
+        pairs.sort_by(|a, b| a.0.cmp(b.0));
 
-                'outer: for gr in group {
-                    let metric_type = gr.2;
+        let (labels, values): (
+            SmallVec<[&str; INLINE_LABELS]>,
+            SmallVec<[&RawValue; INLINE_LABELS]>,
+        ) = pairs.into_iter().unzip();
 
-                    let lbls =
-                        gr.1.labels
-                            .iter()
-                            .map(|l| Self::trim_quotes(l))
-                            .zip(gr.1.values.iter().map(|v| Self::trim_quotes(v.get())));
-
-                    let mut m = io::prometheus::client::Metric {
-                        label: lbls
-                            .clone()
-                            .map(|l| io::prometheus::client::LabelPair {
-                                name: Some(l.0),
-                                value: Some(l.1.to_string()),
-                            })
-                            .collect::<Vec<io::prometheus::client::LabelPair>>(),
-                        gauge: None,
-                        counter: None,
-                        summary: None,
-                        untyped: None,
-                        histogram: None,
-                        timestamp_ms: None,
-                    };
+        serde_json::to_string(&(metric.family_name, metric.metric_name, labels, values)).ok()
+    }
 
-                    match metric_type.as_str() {
-                        "counter" => {
-                            let mut hasher = DefaultHasher::new();
+    /// Compute a digest over the key and value of each entry. Entries must be
+    /// sorted, as returned by `EntryMap::into_sorted`, so the same metric state
+    /// yields the same digest regardless of the order files were read in.
+    ///
+    /// Only explicit little-endian bytes are hashed, never through `Hash`
+    /// impls whose output may vary by platform or release. Strings are length
+    /// prefixed and optional fields flagged so adjacent fields can't run
+    /// together.
+    pub fn digest(entries: &[FileEntry]) -> u64 {
+        fn write_str(hasher: &mut Fnv64, s: &str) {
+            // CAST: widening on 32 and 64-bit platforms.
+            hasher.write(&(s.len() as u64).to_le_bytes());
+            hasher.write(s.as_bytes());
+        }
 
-                            // Iterate over the tuples and hash their elements
-                            for (a, b) in lbls {
-                                a.hash(&mut hasher);
-                                b.hash(&mut hasher);
-                            }
-                            "counter".hash(&mut hasher);
+        let mut hasher = Fnv64::default();
 
-                            // Get the final u64 hash value
-                            let hash_value = hasher.finish();
+        for entry in entries {
+            write_str(&mut hasher, &entry.data.json);
 
-                            m.counter = Some(io::prometheus::client::Counter {
-                                value: gr.0.meta.value,
-                                created_timestamp: None,
-                                exemplar: None,
-                            });
+            match &entry.data.pid {
+                Some(pid) => {
+                    hasher.write(&[1]);
+                    write_str(&mut hasher, pid);
+                }
+                None => hasher.write(&[0]),
+            }
 
-                            if gr.0.meta.ex.is_some() {
-                                m.counter.as_mut().unwrap().exemplar =
-                                    Some(exemplar_to_proto(gr.0.meta.ex.as_ref().unwrap()));
-                            }
+            match entry.meta.value {
+                Some(value) => {
+                    hasher.write(&[1]);
+                    hasher.write(&value.to_bits().to_le_bytes());
+                }
+                None => hasher.write(&[0]),
+            }
+        }
 
-                            mtrcs.insert(hash_value, m);
-                            metric_types.insert(hash_value, "counter");
-                            metric_names.insert(hash_value, gr.1.metric_name);
-                        }
-                        "gauge" => {
-                            let mut hasher = DefaultHasher::new();
+        hasher.finish()
+    }
 
-                            // Iterate over the tuples and hash their elements
-                            for (a, b) in lbls {
-                                a.hash(&mut hasher);
-                                b.hash(&mut hasher);
-                            }
-                            "gauge".hash(&mut hasher);
+    /// Split the sorted entries into a run per family, in order, e.g. to
+    /// `digest` or render each family separately. An entry whose key can't be
+    /// parsed stays in the run before it, so rendering that run reports it.
+    pub fn split_families(entries: Vec<FileEntry>) -> Vec<(String, Vec<FileEntry>)> {
+        let mut out: Vec<(String, Vec<FileEntry>)> = Vec::new();
 
-                            let hash_value = hasher.finish();
+        for entry in entries {
+            let family = serde_json::from_str::<MetricText>(&entry.data.json)
+                .ok()
+                .map(|m| m.family_name.to_owned());
+
+            match (out.last_mut(), family) {
+                (Some((prev, run)), Some(family)) if *prev == family => run.push(entry),
+                (Some((_, run)), None) => run.push(entry),
+                (_, family) => out.push((family.unwrap_or_default(), vec![entry])),
+            }
+        }
 
-                            m.gauge = Some(io::prometheus::client::Gauge {
-                                value: gr.0.meta.value,
-                            });
-                            mtrcs.insert(hash_value, m);
-                            metric_types.insert(hash_value, "gauge");
-                            metric_names.insert(hash_value, gr.1.metric_name);
-                        }
-                        "histogram" => {
-                            let mut hasher = DefaultHasher::new();
+        out
+    }
 
-                            let mut le: Option<f64> = None;
+    /// Parse the entry's JSON key, rejecting entries whose label names and
+    /// values don't pair up.
+    pub fn metric_text(&self) -> Result<MetricText<'_>> {
+        match serde_json::from_str::<MetricText>(&self.data.json) {
+            Ok(m) if m.labels.len() == m.values.len() => Ok(m),
+            _ => Err(MmapError::PromParsing(format!(
+                "invalid entry {}",
+                self.data.json
+            ))),
+        }
+    }
 
-                            // Iterate over the tuples and hash their elements
-                            for (a, b) in lbls {
-                                if a != "le" {
-                                    a.hash(&mut hasher);
-                                    b.hash(&mut hasher);
-                                }
+    /// Decode a label value from the entry's JSON. Strings are unescaped,
+    /// `null` is the empty string, and numbers keep their JSON text.
+    pub fn label_value(value: &RawValue) -> Result<String> {
+        match value.get() {
+            "null" => Ok(String::new()),
+            s if s.starts_with('"') => serde_json::from_str::<String>(s)
+                .map_err(|e| MmapError::PromParsing(format!("invalid label value {s}: {e}"))),
+            s => Ok(s.to_string()),
+        }
+    }
 
-                                // Safe to ignore +Inf bound.
-                                if a == "le" {
-                                    if b == "+Inf" {
-                                        continue 'outer;
-                                    }
-                                    let leparsed = b.parse::<f64>();
-                                    match leparsed {
-                                        Ok(p) => le = Some(p),
-                                        Err(e) => panic!("failed to parse {} due to {}", b, e),
-                                    }
-                                }
-                            }
-                            "histogram".hash(&mut hasher);
+    /// Check the decoded length of each label value in the entry JSON `key`
+    /// against `limit`. With no limit the key isn't parsed, otherwise a key
+    /// that isn't a valid entry is rejected.
+    pub fn check_label_values(key: &[u8], limit: Option<usize>) -> Result<()> {
+        let Some(limit) = limit else {
+            return Ok(());
+        };
 
-                            let hash_value = hasher.finish();
+        let metric = match serde_json::from_slice::<MetricText>(key) {
+            Ok(m) if m.labels.len() == m.values.len() => m,
+            _ => {
+                return Err(MmapError::PromParsing(format!(
+                    "invalid entry {}",
+                    String::from_utf8_lossy(key)
+                )))
+            }
+        };
 
-                            match mtrcs.get_mut(&hash_value) {
-                                Some(v) => {
-                                    let hs =
-                                        v.histogram.as_mut().expect("getting mutable histogram");
+        for (label, value) in metric.labels.iter().zip(metric.values) {
+            let len = Self::label_value(value)?.len();
+            if len > limit {
+                return Err(MmapError::LabelValueTooLong {
+                    label: label.to_string(),
+                    len,
+                    limit,
+                });
+            }
+        }
 
-                                    for bucket in &mut hs.bucket {
-                                        if bucket.upper_bound != le {
-                                            continue;
-                                        }
+        Ok(())
+    }
 
-                                        let mut curf: f64 =
-                                            bucket.cumulative_count_float.unwrap_or_default();
-                                        curf += gr.0.meta.value.unwrap();
+    /// Encode the sorted entries as a remote-write `WriteRequest`, with one
+    /// `TimeSeries` holding a single sample at `timestamp_ms` per entry. The
+    /// output is uncompressed, callers must snappy-compress it before sending.
+    pub fn entries_to_remote_write(entries: Vec<FileEntry>, timestamp_ms: i64) -> Result<Vec<u8>> {
+        use crate::prometheus::{Label, Sample, TimeSeries, WriteRequest};
 
-                                        bucket.cumulative_count_float = Some(curf);
+        let mut timeseries = Vec::new();
+        timeseries
+            .try_reserve_exact(entries.len())
+            .map_err(|_| MmapError::alloc_failed(entries.len() * size_of::<TimeSeries>()))?;
 
-                                        if gr.0.meta.ex.is_some() {
-                                            bucket.exemplar =
-                                                Some(exemplar_to_proto(gr.0.meta.ex.as_ref().unwrap()));
-                                        }
-                                    }
-                                }
-                                None => {
-                                    let mut final_metric_name = gr.1.metric_name;
+        for entry in &entries {
+            let metric = entry.metric_text()?;
 
-                                    if let Some(stripped) =
-                                        final_metric_name.strip_suffix("_bucket")
-                                    {
-                                        final_metric_name = stripped;
-                                    }
-                                    if let Some(stripped) = final_metric_name.strip_suffix("_sum") {
-                                        final_metric_name = stripped;
-                                    }
-                                    if let Some(stripped) = final_metric_name.strip_suffix("_count")
-                                    {
-                                        final_metric_name = stripped;
-                                    }
+            let mut labels = Vec::with_capacity(metric.labels.len() + 2);
+            labels.push(Label {
+                name: "__name__".to_string(),
+                value: metric.metric_name.to_string(),
+            });
 
-                                    let mut buckets = vec![io::prometheus::client::Bucket {
-                                        cumulative_count: None,
-                                        cumulative_count_float: gr.0.meta.value,
-                                        upper_bound: Some(
-                                            le.expect(
-                                                &format!("got no LE for {}", gr.1.metric_name)
-                                                    .to_string(),
-                                            ),
-                                        ),
-                                        exemplar: None,
-                                    }];
-
-                                    if gr.0.meta.ex.is_some() {
-                                        buckets[0].exemplar =
-                                            Some(exemplar_to_proto(gr.0.meta.ex.as_ref().unwrap()));
-                                    }
-                                    m.label = m
-                                        .label
-                                        .into_iter()
-                                        .filter(|l| l.name != Some("le".to_string()))
-                                        .collect_vec();
-                                    // Create a new metric.
-                                    m.histogram = Some(io::prometheus::client::Histogram {
-                                        // All native histogram fields.
-                                        sample_count: None,
-                                        sample_count_float: None,
-                                        sample_sum: None,
-                                        created_timestamp: None,
-                                        schema: None,
-                                        zero_count: None,
-                                        zero_count_float: None,
-                                        zero_threshold: None,
-                                        negative_count: vec![],
-                                        negative_delta: vec![],
-                                        negative_span: vec![],
-                                        positive_count: vec![],
-                                        positive_delta: vec![],
-                                        positive_span: vec![],
-                                        // All classic histogram fields.
-                                        bucket: buckets,
-                                    });
-                                    mtrcs.insert(hash_value, m);
-                                    metric_types.insert(hash_value, "histogram");
-                                    metric_names.insert(hash_value, final_metric_name);
-                                }
-                            }
-                        }
-                        "summary" => {
-                            let mut hasher = DefaultHasher::new();
+            for (&name, value) in metric.labels.iter().zip(metric.values.iter()) {
+                labels.push(Label {
+                    name: name.to_string(),
+                    value: Self::label_value(value)?,
+                });
+            }
 
-                            let mut quantile: Option<f64> = None;
+            if let Some(pid) = entry.data.pid.as_ref() {
+                labels.push(Label {
+                    name: "pid".to_string(),
+                    value: pid.clone(),
+                });
+            }
 
-                            // Iterate over the tuples and hash their elements
-                            for (a, b) in lbls {
-                                if a != "quantile" {
-                                    a.hash(&mut hasher);
-                                    b.hash(&mut hasher);
-                                }
-                                if a == "quantile" {
-                                    let quantileparsed = b.parse::<f64>();
-                                    match quantileparsed {
-                                        Ok(p) => quantile = Some(p),
-                                        Err(e) => {
-                                            panic!("failed to parse quantile {} due to {}", b, e)
-                                        }
-                                    }
-                                }
-                            }
-                            "summary".hash(&mut hasher);
-                            let hash_value = hasher.finish();
-
-                            match mtrcs.get_mut(&hash_value) {
-                                Some(v) => {
-                                    // Go through and edit buckets.
-                                    let smry = v.summary.as_mut().expect(
-                                        &format!(
-                                            "getting mutable summary for {}",
-                                            gr.1.metric_name
-                                        )
-                                        .to_string(),
-                                    );
-
-                                    if gr.1.metric_name.ends_with("_count") {
-                                        let samplecount = smry.sample_count.unwrap_or_default();
-                                        smry.sample_count =
-                                            Some((gr.0.meta.value.unwrap() as u64) + samplecount);
-                                    } else if gr.1.metric_name.ends_with("_sum") {
-                                        let samplesum: f64 = smry.sample_sum.unwrap_or_default();
-                                        smry.sample_sum = Some(gr.0.meta.value.unwrap() + samplesum);
-                                    } else {
-                                        let mut found_quantile = false;
-                                        for qntl in &mut smry.quantile {
-                                            if qntl.quantile != quantile {
-                                                continue;
-                                            }
-
-                                            let mut curq: f64 = qntl.quantile.unwrap_or_default();
-                                            curq += gr.0.meta.value.unwrap();
-
-                                            qntl.quantile = Some(curq);
-                                            found_quantile = true;
-                                        }
-
-                                        if !found_quantile {
-                                            smry.quantile.push(io::prometheus::client::Quantile {
-                                                quantile: quantile,
-                                                value: gr.0.meta.value,
-                                            });
-                                        }
-                                    }
-                                }
-                                None => {
-                                    m.label = m
-                                        .label
-                                        .into_iter()
-                                        .filter(|l| l.name != Some("quantile".to_string()))
-                                        .collect_vec();
-
-                                    let mut final_metric_name = gr.1.metric_name;
-                                    // If quantile then add to quantiles.
-                                    // if ends with _count then add it to count.
-                                    // If ends with _sum then add it to sum.
-                                    if gr.1.metric_name.ends_with("_count") {
-                                        final_metric_name =
-                                            gr.1.metric_name.strip_suffix("_count").unwrap();
-                                        m.summary = Some(io::prometheus::client::Summary {
-                                            quantile: vec![],
-                                            sample_count: Some(gr.0.meta.value.unwrap() as u64),
-                                            sample_sum: None,
-                                            created_timestamp: None,
-                                        });
-                                    } else if gr.1.metric_name.ends_with("_sum") {
-                                        final_metric_name =
-                                            gr.1.metric_name.strip_suffix("_sum").unwrap();
-                                        m.summary = Some(io::prometheus::client::Summary {
-                                            quantile: vec![],
-                                            sample_sum: Some(gr.0.meta.value.unwrap()),
-                                            sample_count: None,
-                                            created_timestamp: None,
-                                        });
-                                    } else {
-                                        let quantiles = vec![io::prometheus::client::Quantile {
-                                            quantile: quantile,
-                                            value: gr.0.meta.value,
-                                        }];
-                                        m.summary = Some(io::prometheus::client::Summary {
-                                            quantile: quantiles,
-                                            sample_count: None,
-                                            sample_sum: None,
-                                            created_timestamp: None,
-                                        });
-                                    }
+            // Remote-write requires labels sorted by name.
+            labels.sort_unstable_by(|a, b| a.name.cmp(&b.name));
 
-                                    mtrcs.insert(hash_value, m);
-                                    metric_types.insert(hash_value, "summary");
-                                    metric_names.insert(hash_value, final_metric_name);
-                                }
-                            }
-                        }
-                        "exemplar" => {
-                            // Exemplars are handled later on.
-                        }
-                        mtype => {
-                            panic!("unhandled metric type {}", mtype)
-                        }
-                    }
-                }
+            timeseries.push(TimeSeries {
+                labels,
+                samples: vec![Sample {
+                    value: entry.meta.value.unwrap_or_default(),
+                    timestamp: timestamp_ms,
+                }],
             });
+        }
 
-        mtrcs.iter().for_each(|mtrc| {
-            let metric_name = metric_names.get(mtrc.0).expect("getting metric name");
-            let metric_type = metric_types.get(mtrc.0).expect("getting metric type");
+        Ok(WriteRequest { timeseries }.encode_to_vec())
+    }
 
-            let protobuf_mf = io::prometheus::client::MetricFamily {
-                name: Some(metric_name.to_string()),
-                help: Some("Multiprocess metric".to_string()),
-                r#type: match metric_type.to_string().as_str() {
-                    "counter" => Some(Counter.into()),
-                    "gauge" => Some(Gauge.into()),
-                    "histogram" => Some(Histogram.into()),
-                    "summary" => Some(Summary.into()),
-                    mtype => panic!("unhandled metric type {}", mtype),
-                },
-                metric: vec![mtrc.1.clone()],
-            };
+    pub fn entries_to_protobuf(entries: Vec<FileEntry>) -> Result<String> {
+        Self::entries_to_protobuf_with_options(entries, &RenderOptions::default())
+    }
 
-            let encoded_mf = protobuf_mf.encode_to_vec();
+    /// Convert the sorted entries into length-delimited protobuf
+    /// `MetricFamily` messages, applying the `RenderOptions` provided.
+    pub fn entries_to_protobuf_with_options(
+        entries: Vec<FileEntry>,
+        options: &RenderOptions,
+    ) -> Result<String> {
+        let mut buffer: Cursor<Vec<u8>> = Cursor::new(Vec::new());
 
-            buffer
-                .write_u32_varint(
-                    encoded_mf
-                        .len()
-                        .try_into()
-                        .expect("failed to encode metricfamily"),
-                )
-                .unwrap();
-            buffer
-                .write_all(&encoded_mf)
-                .expect("failed to write output");
-        });
+        for family in Self::metric_families(entries, options)? {
+            write_length_delimited(&mut buffer, &family.into_proto())?;
+        }
 
         // NOTE: Rust strings are bytes encoded in UTF-8. Ruby doesn't have such
         // invariant. So, let's convert those bytes to a string since everything ends
@@ -592,82 +792,866 @@ impl FileEntry {
         unsafe { Ok(str::from_utf8_unchecked(buffer.get_ref()).to_string()) }
     }
 
-    
+    /// Estimate the `q` quantile of the histogram `family` from its buckets
+    /// summed across all of its series, as PromQL's `histogram_quantile`
+    /// would. Series are expected to share the same bucket bounds. Returns
+    /// `None` if there is no histogram family of that name.
+    pub fn histogram_quantile(
+        entries: Vec<FileEntry>,
+        family: &str,
+        q: f64,
+    ) -> Result<Option<f64>> {
+        let mf = Self::metric_families(entries, &RenderOptions::default())?
+            .into_iter()
+            .find(|mf| mf.name() == family)
+            .map(SharedFamily::into_proto);
 
-    /// Convert the sorted entries into a String in Prometheus metrics format.
-    pub fn entries_to_string(entries: Vec<FileEntry>) -> Result<String> {
-        // We guesstimate that lines are ~100 bytes long, preallocate the string to
-        // roughly that size.
-        let mut out = String::new();
-        out.try_reserve(entries.len() * 128)
-            .map_err(|_| MmapError::OutOfMemory(entries.len() * 128))?;
+        let mf = match mf {
+            Some(mf) if mf.r#type == Some(Histogram.into()) => mf,
+            _ => return Ok(None),
+        };
 
-        let mut prev_name: Option<String> = None;
+        let mut buckets: Vec<(f64, f64)> = Vec::new();
+        let mut total = 0.0;
 
-        let entry_count = entries.len();
-        let mut processed_count = 0;
+        for hs in mf.metric.iter().filter_map(|m| m.histogram.as_ref()) {
+            let mut series_total = 0.0;
 
-        for entry in entries {
-            let metrics_data = match serde_json::from_str::<MetricText>(&entry.data.json) {
-                Ok(m) => {
-                    if m.labels.len() != m.values.len() {
-                        continue;
-                    }
-                    m
-                }
-                // We don't exit the function here so the total number of invalid
-                // entries can be calculated below.
-                Err(_) => continue,
-            };
+            for bucket in &hs.bucket {
+                // CAST: counts are stored as floats, converting back is exact.
+                let count = bucket
+                    .cumulative_count_float
+                    .or(bucket.cumulative_count.map(|c| c as f64))
+                    .unwrap_or_default();
+                let bound = bucket.upper_bound.unwrap_or(f64::INFINITY);
 
-            match prev_name.as_ref() {
-                Some(p) if p == metrics_data.family_name => {}
-                _ => {
-                    entry.append_header(metrics_data.family_name, &mut out);
-                    prev_name = Some(metrics_data.family_name.to_owned());
-                }
+                buckets.push((bound, count));
+                series_total = count;
             }
 
-            entry.append_entry(metrics_data, &mut out)?;
-
-            writeln!(&mut out, " {}", entry.meta.value.unwrap())
-                .map_err(|e| MmapError::Other(format!("Failed to append to output: {e}")))?;
-
-            processed_count += 1;
+            // The `_count` series is the implicit `+Inf` bucket, fall back to
+            // the largest bucket if it's missing.
+            total += hs
+                .sample_count_float
+                .or(hs.sample_count.map(|c| c as f64))
+                .unwrap_or(series_total);
         }
 
-        if processed_count != entry_count {
-            return Err(MmapError::legacy(
-                format!("Processed entries {processed_count} != map entries {entry_count}"),
-                RubyError::Runtime,
-            ));
-        }
+        // Combine the counts of each bound across series.
+        buckets.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let buckets = buckets
+            .into_iter()
+            .coalesce(|a, b| {
+                if a.0 == b.0 {
+                    Ok((a.0, a.1 + b.1))
+                } else {
+                    Err((a, b))
+                }
+            })
+            .collect_vec();
 
-        Ok(out)
+        Ok(Some(bucket_quantile(q, &buckets, total)))
     }
 
-    fn append_header(&self, family_name: &str, out: &mut String) {
-        out.push_str("# HELP ");
-        out.push_str(family_name);
-        out.push_str(" Multiprocess metric\n");
-
-        out.push_str("# TYPE ");
-        out.push_str(family_name);
-        out.push(' ');
+    /// Merge the sorted entries into protobuf `MetricFamily` messages ordered
+    /// by name, applying the `RenderOptions` provided.
+    ///
+    /// Label names and values repeat heavily across series, so each distinct
+    /// string is allocated once for this call and shared by every series
+    /// using it. The families keep the shared labels until `into_proto` is
+    /// called on each, so encoding them one at a time holds owned labels for
+    /// only one family at once. `test_metric_families_shared_labels` asserts
+    /// the families retain less heap than with owned labels for a histogram
+    /// corpus.
+    pub(crate) fn metric_families(
+        entries: Vec<FileEntry>,
+        options: &RenderOptions,
+    ) -> Result<Vec<SharedFamily>> {
+        let mut interner = Interner::new();
+
+        let mut mtrcs: HashMap<u64, (SharedLabels, io::prometheus::client::Metric)> =
+            HashMap::new();
+        let mut metric_types = HashMap::new();
+        let mut metric_names = HashMap::new();
+        // The bounds seen for each histogram series. Distinct entries are
+        // never merged into the same bucket, a repeated bound means a writer
+        // produced the same bucket twice, e.g. as both `le="1"` and `le="1.0"`.
+        let mut bucket_bounds: HashSet<(u64, u64)> = HashSet::new();
+
+        // Series are merged by a hash that includes the family name, so the
+        // order of `entries` doesn't matter: a family whose series are
+        // interleaved with another's still produces a single `MetricFamily`.
+        //
+        // Entries that can't be parsed are skipped rather than exiting here, so
+        // the total number of invalid entries can be reported below as the
+        // text format does.
+        //
+        // Histogram buckets and summary quantiles whose bound can't be read
+        // are skipped as malformed, and count as processed.
+        let entry_count = entries.len();
+        let mut processed_count = 0;
+        let mut malformed_count = 0;
 
-        out.push_str(&self.meta.type_.name().expect("name was invalid UTF-8"));
-        out.push('\n');
-    }
+        let parsed = entries
+            .iter()
+            .filter_map(|v| {
+                let metric = options.parse_key(&v.data.json).ok()?;
+                if metric.labels.len() != metric.values.len() {
+                    return None;
+                }
+                Some((
+                    v,
+                    metric,
+                    v.meta.type_.name().expect("getting name").into_owned(),
+                ))
+            })
+            .inspect(|_| processed_count += 1);
 
-    fn append_entry(&self, json_data: MetricText, out: &mut String) -> Result<()> {
-        out.push_str(json_data.metric_name);
+        'outer: for gr in parsed {
+            let metric_type = gr.2;
 
-        if json_data.labels.is_empty() {
-            if let Some(pid) = self.data.pid.as_ref() {
-                out.push_str("{pid=\"");
-                out.push_str(pid);
-                out.push_str("\"}");
-            }
+            let timestamp_ms = options.sample_timestamp_ms(&gr.0);
+
+            let lbls: Vec<(Rc<str>, Rc<str>)> =
+                gr.1.labels
+                    .iter()
+                    .map(|l| interner.intern(Self::strip_quotes(l)))
+                    .zip(
+                        gr.1.values
+                            .iter()
+                            .map(|v| interner.intern(Self::strip_quotes(v.get()))),
+                    )
+                    .collect();
+
+            let mut m = io::prometheus::client::Metric {
+                label: vec![],
+                gauge: None,
+                counter: None,
+                summary: None,
+                untyped: None,
+                histogram: None,
+                timestamp_ms,
+            };
+
+            match metric_type.as_str() {
+                "counter" => {
+                    let mut hasher = DefaultHasher::new();
+
+                    // Iterate over the tuples and hash their elements
+                    for (a, b) in &lbls {
+                        a.hash(&mut hasher);
+                        b.hash(&mut hasher);
+                    }
+                    gr.1.family_name.hash(&mut hasher);
+                    "counter".hash(&mut hasher);
+
+                    // Get the final u64 hash value
+                    let hash_value = hasher.finish();
+
+                    m.counter = Some(io::prometheus::client::Counter {
+                        value: gr.0.meta.value,
+                        created_timestamp: None,
+                        exemplar: None,
+                    });
+
+                    if gr.0.meta.ex.is_some() {
+                        m.counter.as_mut().unwrap().exemplar =
+                            Some(exemplar_to_proto(gr.0.meta.ex.as_ref().unwrap()));
+                    }
+
+                    mtrcs.insert(hash_value, (lbls.into_boxed_slice(), m));
+                    metric_types.insert(hash_value, "counter");
+                    metric_names.insert(hash_value, gr.1.metric_name);
+                }
+                "gauge" => {
+                    let mut hasher = DefaultHasher::new();
+
+                    // Iterate over the tuples and hash their elements
+                    for (a, b) in &lbls {
+                        a.hash(&mut hasher);
+                        b.hash(&mut hasher);
+                    }
+                    gr.1.family_name.hash(&mut hasher);
+                    "gauge".hash(&mut hasher);
+
+                    let hash_value = hasher.finish();
+
+                    m.gauge = Some(io::prometheus::client::Gauge {
+                        value: gr.0.meta.value,
+                    });
+                    mtrcs.insert(hash_value, (lbls.into_boxed_slice(), m));
+                    metric_types.insert(hash_value, "gauge");
+                    metric_names.insert(hash_value, gr.1.metric_name);
+                }
+                "histogram" => {
+                    // Route each series by its suffix before looking at
+                    // `le`, `_sum` and `_count` series have no bound and
+                    // must never be treated as buckets.
+                    let (base_name, series) = HistogramSeries::parse(gr.1.metric_name);
+
+                    let mut hasher = DefaultHasher::new();
+
+                    let mut le: Option<f64> = None;
+
+                    // Iterate over the tuples and hash their elements
+                    for (a, b) in &lbls {
+                        if &**a != "le" {
+                            a.hash(&mut hasher);
+                            b.hash(&mut hasher);
+                        }
+
+                        // Safe to ignore +Inf bound.
+                        if &**a == "le" && series == HistogramSeries::Bucket {
+                            if &**b == "+Inf" {
+                                continue 'outer;
+                            }
+                            match b.parse::<f64>() {
+                                Ok(p) => le = Some(p),
+                                Err(_) => {
+                                    malformed_count += 1;
+                                    continue 'outer;
+                                }
+                            }
+                        }
+                    }
+                    "histogram".hash(&mut hasher);
+                    base_name.hash(&mut hasher);
+
+                    let hash_value = hasher.finish();
+
+                    // A bucket without a bound can't be placed.
+                    if series == HistogramSeries::Bucket && le.is_none() {
+                        malformed_count += 1;
+                        continue 'outer;
+                    }
+
+                    let (_, v) = mtrcs.entry(hash_value).or_insert_with(|| {
+                        let mut m = m;
+                        let lbls = lbls.into_iter().filter(|l| &*l.0 != "le").collect_vec();
+                        // Create a new metric.
+                        m.histogram = Some(io::prometheus::client::Histogram {
+                            // All native histogram fields.
+                            sample_count: None,
+                            sample_count_float: None,
+                            sample_sum: None,
+                            created_timestamp: None,
+                            schema: None,
+                            zero_count: None,
+                            zero_count_float: None,
+                            zero_threshold: None,
+                            negative_count: vec![],
+                            negative_delta: vec![],
+                            negative_span: vec![],
+                            positive_count: vec![],
+                            positive_delta: vec![],
+                            positive_span: vec![],
+                            // All classic histogram fields.
+                            bucket: vec![],
+                        });
+                        (lbls.into_boxed_slice(), m)
+                    });
+                    metric_types.insert(hash_value, "histogram");
+                    metric_names.insert(hash_value, base_name);
+
+                    v.timestamp_ms = v.timestamp_ms.max(timestamp_ms);
+
+                    let hs = v.histogram.as_mut().expect("getting mutable histogram");
+                    let value = gr.0.meta.value.unwrap_or_default();
+
+                    match series {
+                        HistogramSeries::Sum => {
+                            hs.sample_sum = Some(hs.sample_sum.unwrap_or_default() + value);
+                        }
+                        HistogramSeries::Count => {
+                            hs.sample_count_float =
+                                Some(hs.sample_count_float.unwrap_or_default() + value);
+                        }
+                        HistogramSeries::Bucket => {
+                            // UNWRAP: buckets without a bound were skipped above.
+                            let bound = le.unwrap();
+                            if !bucket_bounds.insert((hash_value, bound.to_bits())) {
+                                return Err(MmapError::PromParsing(format!(
+                                    "duplicate bucket le=\"{bound}\" in histogram {}",
+                                    gr.0.data.json
+                                )));
+                            }
+
+                            hs.bucket.push(io::prometheus::client::Bucket {
+                                cumulative_count: None,
+                                cumulative_count_float: Some(value),
+                                upper_bound: le,
+                                exemplar: gr.0.meta.ex.as_ref().map(exemplar_to_proto),
+                            });
+                        }
+                    }
+                }
+                "summary" => {
+                    let mut hasher = DefaultHasher::new();
+
+                    let mut quantile: Option<f64> = None;
+
+                    // Iterate over the tuples and hash their elements
+                    for (a, b) in &lbls {
+                        if &**a != "quantile" {
+                            a.hash(&mut hasher);
+                            b.hash(&mut hasher);
+                        }
+                        if &**a == "quantile" {
+                            match b.parse::<f64>() {
+                                Ok(p) => quantile = Some(p),
+                                Err(_) => {
+                                    malformed_count += 1;
+                                    continue 'outer;
+                                }
+                            }
+                        }
+                    }
+
+                    // A quantile series without a quantile would be grouped
+                    // with the others under `None`.
+                    let is_quantile = !gr.1.metric_name.ends_with("_count")
+                        && !gr.1.metric_name.ends_with("_sum");
+                    if is_quantile && quantile.is_none() {
+                        malformed_count += 1;
+                        continue 'outer;
+                    }
+                    gr.1.family_name.hash(&mut hasher);
+                    "summary".hash(&mut hasher);
+                    let hash_value = hasher.finish();
+
+                    match mtrcs.get_mut(&hash_value) {
+                        Some((_, v)) => {
+                            v.timestamp_ms = v.timestamp_ms.max(timestamp_ms);
+
+                            // Go through and edit buckets.
+                            let smry = v.summary.as_mut().expect(
+                                &format!("getting mutable summary for {}", gr.1.metric_name)
+                                    .to_string(),
+                            );
+
+                            if gr.1.metric_name.ends_with("_count") {
+                                let samplecount = smry.sample_count.unwrap_or_default();
+                                smry.sample_count =
+                                    Some((gr.0.meta.value.unwrap() as u64) + samplecount);
+                            } else if gr.1.metric_name.ends_with("_sum") {
+                                let samplesum: f64 = smry.sample_sum.unwrap_or_default();
+                                smry.sample_sum = Some(gr.0.meta.value.unwrap() + samplesum);
+                            } else {
+                                let mut found_quantile = false;
+                                for qntl in &mut smry.quantile {
+                                    if qntl.quantile != quantile {
+                                        continue;
+                                    }
+
+                                    let mut curq: f64 = qntl.quantile.unwrap_or_default();
+                                    curq += gr.0.meta.value.unwrap();
+
+                                    qntl.quantile = Some(curq);
+                                    found_quantile = true;
+                                }
+
+                                if !found_quantile {
+                                    smry.quantile.push(io::prometheus::client::Quantile {
+                                        quantile: quantile,
+                                        value: gr.0.meta.value,
+                                    });
+                                }
+                            }
+                        }
+                        None => {
+                            let lbls = lbls
+                                .into_iter()
+                                .filter(|l| &*l.0 != "quantile")
+                                .collect_vec();
+
+                            let mut final_metric_name = gr.1.metric_name;
+                            // If quantile then add to quantiles.
+                            // if ends with _count then add it to count.
+                            // If ends with _sum then add it to sum.
+                            if gr.1.metric_name.ends_with("_count") {
+                                final_metric_name =
+                                    gr.1.metric_name.strip_suffix("_count").unwrap();
+                                m.summary = Some(io::prometheus::client::Summary {
+                                    quantile: vec![],
+                                    sample_count: Some(gr.0.meta.value.unwrap() as u64),
+                                    sample_sum: None,
+                                    created_timestamp: None,
+                                });
+                            } else if gr.1.metric_name.ends_with("_sum") {
+                                final_metric_name = gr.1.metric_name.strip_suffix("_sum").unwrap();
+                                m.summary = Some(io::prometheus::client::Summary {
+                                    quantile: vec![],
+                                    sample_sum: Some(gr.0.meta.value.unwrap()),
+                                    sample_count: None,
+                                    created_timestamp: None,
+                                });
+                            } else {
+                                let quantiles = vec![io::prometheus::client::Quantile {
+                                    quantile: quantile,
+                                    value: gr.0.meta.value,
+                                }];
+                                m.summary = Some(io::prometheus::client::Summary {
+                                    quantile: quantiles,
+                                    sample_count: None,
+                                    sample_sum: None,
+                                    created_timestamp: None,
+                                });
+                            }
+
+                            mtrcs.insert(hash_value, (lbls.into_boxed_slice(), m));
+                            metric_types.insert(hash_value, "summary");
+                            metric_names.insert(hash_value, final_metric_name);
+                        }
+                    }
+                }
+                "exemplar" => {
+                    // Exemplars are handled later on.
+                }
+                mtype => {
+                    panic!("unhandled metric type {}", mtype)
+                }
+            }
+        }
+
+        if processed_count != entry_count {
+            return Err(MmapError::legacy(
+                format!("Processed entries {processed_count} != map entries {entry_count}"),
+                RubyError::Runtime,
+            ));
+        }
+
+        if options.reject_malformed && malformed_count > 0 {
+            return Err(MmapError::PromParsing(format!(
+                "{malformed_count} histogram bucket or summary quantile entries without a valid le or quantile label"
+            )));
+        }
+
+        // Group the merged series into one `MetricFamily` per family, ordered
+        // by name so the output is deterministic.
+        let mut families: BTreeMap<
+            &str,
+            (&str, Vec<(SharedLabels, io::prometheus::client::Metric)>),
+        > = BTreeMap::new();
+
+        for (hash_value, (lbls, mut mtrc)) in mtrcs {
+            if let Some(hs) = mtrc.histogram.as_mut() {
+                // Entries are sorted by their JSON, so `le="10"` precedes
+                // `le="2.5"`. Order the buckets by their bounds.
+                hs.bucket.sort_by(|a, b| {
+                    let a = a.upper_bound.unwrap_or(f64::INFINITY);
+                    let b = b.upper_bound.unwrap_or(f64::INFINITY);
+                    a.total_cmp(&b)
+                });
+                prefer_integer_counts(hs);
+            }
+
+            let metric_name = *metric_names.get(&hash_value).expect("getting metric name");
+            let metric_type = *metric_types.get(&hash_value).expect("getting metric type");
+
+            families
+                .entry(metric_name)
+                .or_insert_with(|| (metric_type, Vec::new()))
+                .1
+                .push((lbls, mtrc));
+        }
+
+        let mut out = Vec::with_capacity(families.len());
+        for (metric_name, (metric_type, mut series)) in families {
+            // Order series within a family by their labels.
+            series.sort_by(|a, b| a.0.cmp(&b.0));
+            let (labels, metric): (Vec<_>, Vec<_>) = series.into_iter().unzip();
+
+            let protobuf_mf = io::prometheus::client::MetricFamily {
+                name: Some(options.rewrite_name(metric_name).into_owned()),
+                help: Some("Multiprocess metric".to_string()),
+                r#type: match metric_type {
+                    "counter" => Some(Counter.into()),
+                    "gauge" => Some(Gauge.into()),
+                    "histogram" => Some(Histogram.into()),
+                    "summary" => Some(Summary.into()),
+                    mtype => panic!("unhandled metric type {}", mtype),
+                },
+                metric,
+            };
+
+            out.push(SharedFamily {
+                family: protobuf_mf,
+                labels,
+            });
+        }
+
+        // Rewritten names may no longer be in the order of the originals.
+        if !options.name_prefixes.is_empty() {
+            out.sort_by(|a, b| a.family.name.cmp(&b.family.name));
+        }
+
+        Ok(out)
+    }
+
+    
+
+    /// Convert the sorted entries into a String in Prometheus metrics format.
+    pub fn entries_to_string(entries: Vec<FileEntry>) -> Result<String> {
+        Self::entries_to_string_with_options(entries, &RenderOptions::default())
+    }
+
+    /// Convert the sorted entries into a String in Prometheus metrics format,
+    /// applying the `RenderOptions` provided.
+    pub fn entries_to_string_with_options(
+        entries: Vec<FileEntry>,
+        options: &RenderOptions,
+    ) -> Result<String> {
+        Self::render_text(entries, options).map(|(out, _)| out)
+    }
+
+    /// Render the sorted entries as text, returning the output along with the
+    /// number of distinct metric and label names that were sanitized. Fails if
+    /// two families would be rendered under the same name, e.g. after
+    /// sanitizing, as the duplicate `# TYPE` lines are rejected by Prometheus.
+    pub fn render_text(
+        entries: Vec<FileEntry>,
+        options: &RenderOptions,
+    ) -> Result<(String, usize)> {
+        Self::render_text_into(entries, options, None)
+    }
+
+    /// Render the sorted entries as text, also returning the byte range each
+    /// family occupies in the output, from its `# HELP` line up to the start
+    /// of the next family. Families are keyed by the name stored in the
+    /// entries, before any `name_prefixes` rewrite or sanitizing, and are
+    /// listed in the order they were written. The OpenMetrics `# EOF`
+    /// trailer is not part of any range.
+    pub fn entries_to_string_with_ranges(
+        entries: Vec<FileEntry>,
+        options: &RenderOptions,
+    ) -> Result<(String, Vec<(String, Range<usize>)>)> {
+        let mut ranges = Vec::new();
+        let (out, _) = Self::render_text_into(entries, options, Some(&mut ranges))?;
+
+        Ok((out, ranges))
+    }
+
+    fn render_text_into(
+        entries: Vec<FileEntry>,
+        options: &RenderOptions,
+        ranges: Option<&mut Vec<(String, Range<usize>)>>,
+    ) -> Result<(String, usize)> {
+        let mut out = String::new();
+        let mut names = RenderedNames::default();
+
+        Self::append_preamble(options, &mut names, &mut out)?;
+        Self::append_text(entries, options, &mut names, &mut out, ranges)?;
+
+        if options.openmetrics {
+            out.push_str(OPENMETRICS_EOF);
+        }
+
+        Ok((out, names.sanitized_count()))
+    }
+
+    /// Append what precedes the families in the text output: the `target`
+    /// info family when rendering OpenMetrics with `target_info` labels.
+    pub(crate) fn append_preamble(
+        options: &RenderOptions,
+        names: &mut RenderedNames,
+        out: &mut String,
+    ) -> Result<()> {
+        if options.openmetrics && !options.target_info.is_empty() {
+            Self::append_target_info(options, names, out)?;
+        }
+
+        Ok(())
+    }
+
+    /// Append the families of the sorted entries to `out` as text, without
+    /// the preamble or the OpenMetrics `# EOF` trailer, so output can be
+    /// built from several batches of families.
+    pub(crate) fn append_text(
+        entries: Vec<FileEntry>,
+        options: &RenderOptions,
+        names: &mut RenderedNames,
+        out: &mut String,
+        mut ranges: Option<&mut Vec<(String, Range<usize>)>>,
+    ) -> Result<()> {
+        let capacity = Self::estimate_output_len(&entries);
+        out.try_reserve(capacity)
+            .map_err(|_| MmapError::alloc_failed(capacity))?;
+
+        let mut prev_name: Option<String> = None;
+
+        let entry_count = entries.len();
+        let mut processed_count = 0;
+
+        for entry in entries {
+            let metrics_data = match options.parse_key(&entry.data.json) {
+                Ok(m) => {
+                    if m.labels.len() != m.values.len() {
+                        continue;
+                    }
+                    m
+                }
+                // We don't exit the function here so the total number of invalid
+                // entries can be calculated below.
+                Err(_) => continue,
+            };
+
+            match prev_name.as_ref() {
+                Some(p) if p == metrics_data.family_name => {}
+                _ => {
+                    if let Some(ranges) = ranges.as_deref_mut() {
+                        if let Some((_, prev)) = ranges.last_mut() {
+                            prev.end = out.len();
+                        }
+                        let start = out.len();
+                        ranges.push((metrics_data.family_name.to_owned(), start..start));
+                    }
+
+                    entry.append_header(metrics_data.family_name, options, names, out)?;
+                    prev_name = Some(metrics_data.family_name.to_owned());
+                }
+            }
+
+            let value = entry.meta.value.unwrap();
+
+            if options.strict_finite && !value.is_finite() {
+                return Err(MmapError::NonFinite {
+                    series: entry.data.json,
+                    value: value.to_string(),
+                });
+            }
+
+            let timestamp_ms = options.sample_timestamp_ms(&entry);
+
+            entry.append_entry(metrics_data, options, names, out)?;
+
+            let written = match timestamp_ms {
+                Some(ts) if options.openmetrics => {
+                    writeln!(out, " {} {}", value, util::format_millis_as_secs(ts))
+                }
+                Some(ts) => writeln!(out, " {} {}", value, ts),
+                None => writeln!(out, " {}", value),
+            };
+            written.map_err(|e| MmapError::Other(format!("Failed to append to output: {e}")))?;
+
+            processed_count += 1;
+        }
+
+        if processed_count != entry_count {
+            return Err(MmapError::legacy(
+                format!("Processed entries {processed_count} != map entries {entry_count}"),
+                RubyError::Runtime,
+            ));
+        }
+
+        if let Some((_, last)) = ranges.and_then(|r| r.last_mut()) {
+            last.end = out.len();
+        }
+
+        Ok(())
+    }
+
+    /// Estimate the length of the text rendered for `entries`. Each line holds
+    /// roughly the entry's JSON, which contains the metric name and labels,
+    /// plus the pid and value. The JSON strings are already owned, so this is
+    /// a cheap pass that lets us allocate the output once.
+    pub fn estimate_output_len(entries: &[FileEntry]) -> usize {
+        // Room for the value, separators, and an occasional family header.
+        const LINE_OVERHEAD: usize = 32;
+
+        entries.iter().fold(0usize, |acc, entry| {
+            let pid_len = entry.data.pid.as_ref().map_or(0, |p| p.len());
+
+            acc.saturating_add(entry.data.json.len() + pid_len + LINE_OVERHEAD)
+        })
+    }
+
+    /// Predict the length of the text rendered for the sorted `entries` with
+    /// the default `RenderOptions`, without rendering them. Each family header
+    /// and `name{labels} value` line is measured as `render_text` would write
+    /// it. Options that rewrite names or append timestamps change the length,
+    /// so treat the result as an estimate. This parses every key, so it is
+    /// not used to size the rendered output, see `estimate_output_len`.
+    pub fn estimate_text_len(entries: &[FileEntry]) -> usize {
+        let mut len = LenCounter::default();
+        let mut prev_name: Option<&str> = None;
+
+        for entry in entries {
+            let Ok(metric) = serde_json::from_str::<MetricText>(&entry.data.json) else {
+                continue;
+            };
+            if metric.labels.len() != metric.values.len() {
+                continue;
+            }
+            let Some(value) = entry.meta.value else {
+                continue;
+            };
+
+            if prev_name != Some(metric.family_name) {
+                let name_len = metric.family_name.len();
+                let type_len = entry.meta.type_.name().map_or(0, |t| t.len());
+                // `# HELP <name> Multiprocess metric\n# TYPE <name> <type>\n`
+                len.0 += 28 + name_len + 9 + name_len + type_len;
+                prev_name = Some(metric.family_name);
+            }
+
+            len.0 += metric.metric_name.len();
+
+            let pid_len = entry.data.pid.as_ref().map(|p| p.len());
+            if metric.labels.is_empty() {
+                // `{pid="<pid>"}`
+                len.0 += pid_len.map_or(0, |p| p + 8);
+            } else {
+                // `{` and `}` plus a `,` between each label.
+                len.0 += 2 + metric.labels.len() - 1;
+
+                for (key, val) in metric.labels.iter().zip(metric.values.iter()) {
+                    // `<key>=` and the value, which is quoted if it isn't a
+                    // string. Bounds are rewritten as `append_entry` does.
+                    len.0 += key.len() + 1;
+                    len.0 += match Self::bound_value(key, val) {
+                        Some(bound) => bound.len() + 2,
+                        None => match val.get() {
+                            "null" => 2,
+                            s if s.starts_with('"') => s.len(),
+                            s => s.len() + 2,
+                        },
+                    };
+                }
+
+                // `,pid="<pid>"`
+                len.0 += pid_len.map_or(0, |p| p + 7);
+            }
+
+            // ` <value>\n`
+            // UNWRAP: writing to a `LenCounter` can't fail.
+            write!(len, " {}", value).unwrap();
+            len.0 += 1;
+        }
+
+        len.0
+    }
+
+    /// Sanitize `name` if requested by `options`, recording each name changed.
+    fn render_name<'a>(
+        name: &'a str,
+        is_label: bool,
+        options: &RenderOptions,
+        names: &mut RenderedNames,
+    ) -> Cow<'a, str> {
+        if !options.sanitize_names {
+            return Cow::Borrowed(name);
+        }
+
+        let out = util::sanitize_name(name, is_label);
+        if let Cow::Owned(_) = out {
+            if !names.sanitized.contains(name) {
+                names.sanitized.insert(name.to_owned());
+            }
+        }
+
+        out
+    }
+
+    /// Render a family or metric name, appending the `_total` suffix to
+    /// counters in OpenMetrics mode unless it is already present.
+    fn render_metric_name<'a>(
+        &self,
+        name: &'a str,
+        options: &RenderOptions,
+        names: &mut RenderedNames,
+    ) -> Cow<'a, str> {
+        let name = match options.rewrite_name(name) {
+            Cow::Borrowed(name) => Self::render_name(name, false, options, names),
+            Cow::Owned(name) => {
+                Cow::Owned(Self::render_name(&name, false, options, names).into_owned())
+            }
+        };
+
+        if !options.openmetrics || self.meta.type_ != SYM_COUNTER || name.ends_with("_total") {
+            return name;
+        }
+
+        Cow::Owned(format!("{name}_total"))
+    }
+
+    /// Append the `target` info family holding the `target_info` labels.
+    fn append_target_info(
+        options: &RenderOptions,
+        names: &mut RenderedNames,
+        out: &mut String,
+    ) -> Result<()> {
+        out.push_str("# HELP target Target metadata\n# TYPE target info\ntarget_info{");
+
+        for (i, (name, value)) in options.target_info.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            out.push_str(&Self::render_name(name, true, options, names));
+            out.push('=');
+
+            let value = serde_json::to_string(value)
+                .map_err(|e| MmapError::Other(format!("invalid target_info value: {e}")))?;
+            out.push_str(&value);
+        }
+
+        out.push_str("} 1\n");
+
+        Ok(())
+    }
+
+    /// The canonical form of a numeric `le` or `quantile` label value, so
+    /// bounds render as the reference client writes them however they were
+    /// stored, e.g. `1.0` as `1`. Other labels are rendered as stored.
+    fn bound_value(label: &str, value: &RawValue) -> Option<String> {
+        if label != "le" && label != "quantile" {
+            return None;
+        }
+
+        let bound = Self::label_value(value).ok()?.parse::<f64>().ok()?;
+
+        Some(util::format_bound(bound))
+    }
+
+    fn append_header(
+        &self,
+        family_name: &str,
+        options: &RenderOptions,
+        names: &mut RenderedNames,
+        out: &mut String,
+    ) -> Result<()> {
+        let rendered = self.render_metric_name(family_name, options, names);
+        names.add_family(family_name, &rendered)?;
+        let family_name = &*rendered;
+
+        out.push_str("# HELP ");
+        out.push_str(family_name);
+        out.push_str(" Multiprocess metric\n");
+
+        out.push_str("# TYPE ");
+        out.push_str(family_name);
+        out.push(' ');
+
+        out.push_str(&self.meta.type_.name().expect("name was invalid UTF-8"));
+        out.push('\n');
+
+        Ok(())
+    }
+
+    fn append_entry(
+        &self,
+        json_data: MetricText,
+        options: &RenderOptions,
+        names: &mut RenderedNames,
+        out: &mut String,
+    ) -> Result<()> {
+        out.push_str(&self.render_metric_name(json_data.metric_name, options, names));
+
+        if json_data.labels.is_empty() {
+            if let Some(pid) = self.data.pid.as_ref() {
+                out.push_str("{pid=\"");
+                out.push_str(pid);
+                out.push_str("\"}");
+            }
 
             return Ok(());
         }
@@ -677,17 +1661,23 @@ impl FileEntry {
         let it = json_data.labels.iter().zip(json_data.values.iter());
 
         for (i, (&key, val)) in it.enumerate() {
-            out.push_str(key);
+            out.push_str(&Self::render_name(key, true, options, names));
             out.push('=');
 
-            match val.get() {
-                "null" => out.push_str("\"\""),
-                s if s.starts_with('"') => out.push_str(s),
-                s => {
-                    // Quote numeric values.
-                    out.push('"');
-                    out.push_str(s);
-                    out.push('"');
+            if let Some(bound) = Self::bound_value(key, val) {
+                out.push('"');
+                out.push_str(&bound);
+                out.push('"');
+            } else {
+                match val.get() {
+                    "null" => out.push_str("\"\""),
+                    s if s.starts_with('"') => out.push_str(s),
+                    s => {
+                        // Quote numeric values.
+                        out.push('"');
+                        out.push_str(s);
+                        out.push('"');
+                    }
                 }
             }
 
@@ -716,14 +1706,80 @@ mod test {
     use super::*;
     use crate::file_info::FileInfo;
     use crate::raw_entry::RawEntry;
-    use crate::testhelper::{TestEntry, TestFile};
+    use crate::testhelper::{self, TestEntry, TestFile};
+
+    /// Build `FileEntry` objects for each JSON string and value, as they would be
+    /// read from a single `.db` file belonging to `pid`.
+    fn build_entries(
+        metric_type: &str,
+        multiprocess_mode: &str,
+        json: &[&'static str],
+        values: &[f64],
+        pid: &str,
+    ) -> Vec<FileEntry> {
+        let TestFile {
+            file,
+            path,
+            dir: _dir,
+        } = TestFile::new(b"foobar");
+
+        let info = FileInfo {
+            file,
+            path,
+            len: json.len(),
+            multiprocess_mode: Symbol::new(multiprocess_mode),
+            type_: Symbol::new(metric_type),
+            pid: pid.to_string(),
+        };
 
-    #[test]
-    fn test_trim_quotes() {
+        json.iter()
+            .zip(values)
+            .map(|(&s, &value)| {
+                let bytes = TestEntry::new(s, value).as_bstring();
+                let entry = RawEntry::from_slice(&bytes).unwrap();
+                let meta = EntryMetadata::new(&entry, &info).unwrap();
+                let borrowed = BorrowedData::new(&entry, &info, meta.is_pid_significant()).unwrap();
+                let data = EntryData::try_from(borrowed).unwrap();
+                FileEntry { data, meta }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_trim_quotes() {
         assert_eq!("foo", FileEntry::trim_quotes("foo"));
         assert_eq!("foo", FileEntry::trim_quotes("\"foo\""));
     }
 
+    #[test]
+    fn test_trim_quotes_utf8() {
+        assert_eq!("", FileEntry::trim_quotes("\""));
+        assert_eq!("", FileEntry::trim_quotes("\"\""));
+
+        // Multi-byte characters next to the quotes are kept whole.
+        assert_eq!("ünïcødé", FileEntry::trim_quotes("\"ünïcødé\""));
+        assert_eq!("日本語", FileEntry::trim_quotes("\"日本語\""));
+        assert_eq!("🎉", FileEntry::trim_quotes("\"🎉\""));
+        assert_eq!("🎉\"", FileEntry::trim_quotes("🎉\"\""));
+    }
+
+    #[test]
+    fn test_canonical_json() {
+        assert_eq!(
+            Some(r#"["family","name",["a","b"],["1",2]]"#.to_string()),
+            FileEntry::canonical_json(r#"["family","name",["b","a"],[2,"1"]]"#)
+        );
+        assert_eq!(
+            Some(r#"["family","name",[],[]]"#.to_string()),
+            FileEntry::canonical_json(r#"["family","name",[],[]]"#)
+        );
+        assert_eq!(
+            None,
+            FileEntry::canonical_json(r#"["family","name",["a"],[]]"#)
+        );
+        assert_eq!(None, FileEntry::canonical_json("[not valid"));
+    }
+
     #[test]
     fn test_entries_to_string() {
         struct TestCase {
@@ -1003,151 +2059,1509 @@ mod test {
                 )),
             },
             TestCase {
-                name: "too many trailing brackets",
-                multiprocess_mode: "min",
-                json: &[r#"["family","name",["label_a","label_b"],["value_a","value_b"]]]"#],
-                values: &[1.5],
-                pids: &["worker-1"],
-                expected_out: None,
-                expected_err: Some(MmapError::legacy(
-                    "Processed entries 0 != map entries 1".to_owned(),
-                    RubyError::Runtime,
-                )),
+                name: "too many trailing brackets",
+                multiprocess_mode: "min",
+                json: &[r#"["family","name",["label_a","label_b"],["value_a","value_b"]]]"#],
+                values: &[1.5],
+                pids: &["worker-1"],
+                expected_out: None,
+                expected_err: Some(MmapError::legacy(
+                    "Processed entries 0 != map entries 1".to_owned(),
+                    RubyError::Runtime,
+                )),
+            },
+            TestCase {
+                name: "too many leading label brackets",
+                multiprocess_mode: "min",
+                json: &[r#"["family","name",[["label_a","label_b"],["value_a","value_b"]]"#],
+                values: &[1.5],
+                pids: &["worker-1"],
+                expected_out: None,
+                expected_err: Some(MmapError::legacy(
+                    "Processed entries 0 != map entries 1".to_owned(),
+                    RubyError::Runtime,
+                )),
+            },
+            TestCase {
+                name: "too many leading label brackets",
+                multiprocess_mode: "min",
+                json: &[r#"["family","name",[["label_a","label_b"],["value_a","value_b"]]"#],
+                values: &[1.5],
+                pids: &["worker-1"],
+                expected_out: None,
+                expected_err: Some(MmapError::legacy(
+                    "Processed entries 0 != map entries 1".to_owned(),
+                    RubyError::Runtime,
+                )),
+            },
+            TestCase {
+                name: "too many leading value brackets",
+                multiprocess_mode: "min",
+                json: &[r#"["family","name",["label_a","label_b"],[["value_a","value_b"]]"#],
+                values: &[1.5],
+                pids: &["worker-1"],
+                expected_out: None,
+                expected_err: Some(MmapError::legacy(
+                    "Processed entries 0 != map entries 1".to_owned(),
+                    RubyError::Runtime,
+                )),
+            },
+            TestCase {
+                name: "misplaced bracket",
+                multiprocess_mode: "min",
+                json: &[r#"["family","name",["label_a","label_b"],]["value_a","value_b"]]"#],
+                values: &[1.5],
+                pids: &["worker-1"],
+                expected_out: None,
+                expected_err: Some(MmapError::legacy(
+                    "Processed entries 0 != map entries 1".to_owned(),
+                    RubyError::Runtime,
+                )),
+            },
+            TestCase {
+                name: "comma in numeric",
+                multiprocess_mode: "min",
+                json: &[r#"["family","name",["label_a","label_b"],["value_a",403,0]]"#],
+                values: &[1.5],
+                pids: &["worker-1"],
+                expected_out: None,
+                expected_err: Some(MmapError::legacy(
+                    "Processed entries 0 != map entries 1".to_owned(),
+                    RubyError::Runtime,
+                )),
+            },
+            TestCase {
+                name: "non-e letter in numeric",
+                multiprocess_mode: "min",
+                json: &[r#"["family","name",["label_a","label_b"],["value_a",-2.0c5]]"#],
+                values: &[1.5],
+                pids: &["worker-1"],
+                expected_out: None,
+                expected_err: Some(MmapError::legacy(
+                    "Processed entries 0 != map entries 1".to_owned(),
+                    RubyError::Runtime,
+                )),
+            },
+        ];
+
+        for case in tc {
+            let name = case.name;
+
+            let input_bytes: Vec<BString> = case
+                .json
+                .iter()
+                .zip(case.values)
+                .map(|(&s, &value)| TestEntry::new(s, value).as_bstring())
+                .collect();
+
+            let mut file_infos = Vec::new();
+            for pid in case.pids {
+                let TestFile {
+                    file,
+                    path,
+                    dir: _dir,
+                } = TestFile::new(b"foobar");
+
+                let info = FileInfo {
+                    file,
+                    path,
+                    len: case.json.len(),
+                    multiprocess_mode: Symbol::new(case.multiprocess_mode),
+                    type_: Symbol::new("gauge"),
+                    pid: pid.to_string(),
+                };
+                file_infos.push(info);
+            }
+
+            let file_entries: Vec<FileEntry> = input_bytes
+                .iter()
+                .map(|s| RawEntry::from_slice(s).unwrap())
+                .zip(file_infos)
+                .map(|(entry, info)| {
+                    let meta = EntryMetadata::new(&entry, &info).unwrap();
+                    let borrowed =
+                        BorrowedData::new(&entry, &info, meta.is_pid_significant()).unwrap();
+                    let data = EntryData::try_from(borrowed).unwrap();
+                    FileEntry { data, meta }
+                })
+                .collect();
+
+            let estimate = FileEntry::estimate_text_len(&file_entries);
+            let output = FileEntry::entries_to_string(file_entries);
+
+            if let Some(expected_out) = case.expected_out {
+                assert_eq!(
+                    expected_out,
+                    output.as_ref().unwrap(),
+                    "test case: {name} - output"
+                );
+                assert_eq!(
+                    expected_out.len(),
+                    estimate,
+                    "test case: {name} - estimated length"
+                );
+            }
+
+            if let Some(expected_err) = case.expected_err {
+                assert_eq!(
+                    expected_err,
+                    output.unwrap_err(),
+                    "test case: {name} - error"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_entries_to_string_strict_finite() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let json = r#"["family","name",["label_a"],["value_a"]]"#;
+
+        let lenient = FileEntry::entries_to_string(build_entries(
+            "gauge",
+            "min",
+            &[json],
+            &[f64::NAN],
+            "worker-1",
+        ))
+        .unwrap();
+        assert!(lenient.ends_with("name{label_a=\"value_a\"} NaN\n"));
+
+        let strict = RenderOptions {
+            strict_finite: true,
+            ..Default::default()
+        };
+
+        let err = FileEntry::entries_to_string_with_options(
+            build_entries("gauge", "min", &[json], &[f64::NAN], "worker-1"),
+            &strict,
+        )
+        .unwrap_err();
+        assert_eq!(
+            MmapError::NonFinite {
+                series: json.to_string(),
+                value: "NaN".to_string(),
+            },
+            err
+        );
+
+        let finite = FileEntry::entries_to_string_with_options(
+            build_entries("gauge", "min", &[json], &[1.0], "worker-1"),
+            &strict,
+        );
+        assert!(finite.is_ok());
+    }
+
+    #[test]
+    fn test_sanitize_names() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let json = &[
+            r#"["5xx","5xx_total",["valid_label"],["value"]]"#,
+            r#"["http.requests","http.requests.total",["status-code"],["200"]]"#,
+            r#"["http.requests","http.requests.total",["status-code"],["500"]]"#,
+        ];
+
+        let entries = || build_entries("counter", "", json, &[2.0, 1.0, 3.0], "worker-1");
+
+        let (out, sanitized) =
+            FileEntry::render_text(entries(), &RenderOptions::default()).unwrap();
+        assert_eq!(0, sanitized);
+        assert!(
+            out.contains("http.requests.total{status-code=\"200\""),
+            "{out}"
+        );
+
+        let options = RenderOptions {
+            sanitize_names: true,
+            ..Default::default()
+        };
+        let (out, sanitized) = FileEntry::render_text(entries(), &options).unwrap();
+
+        // Two family names, two metric names, and one label, each counted
+        // once however many series use it.
+        assert_eq!(5, sanitized);
+        assert!(out.contains("# TYPE http_requests counter\n"), "{out}");
+        assert!(
+            out.contains("http_requests_total{status_code=\"200\",pid=\"worker-1\"} 1\n"),
+            "{out}"
+        );
+        assert!(out.contains("# TYPE _5xx counter\n"), "{out}");
+        assert!(
+            out.contains("_5xx_total{valid_label=\"value\",pid=\"worker-1\"} 2\n"),
+            "{out}"
+        );
+
+        // Families that only differ in invalid characters would be written
+        // with duplicate headers.
+        let json = &[
+            r#"["http.requests","http_requests",[],[]]"#,
+            r#"["http_requests","http_requests",[],[]]"#,
+        ];
+        let entries = build_entries("counter", "", json, &[1.0, 2.0], "worker-1");
+        assert_eq!(
+            Err(MmapError::NameCollision {
+                first: "http.requests".to_string(),
+                second: "http_requests".to_string(),
+                name: "http_requests".to_string(),
+            }),
+            FileEntry::render_text(entries, &options)
+        );
+    }
+
+    #[test]
+    fn test_entries_to_string_with_ranges() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let json = &[
+            r#"["first_family","first_name",["label_a"],["value_a"]]"#,
+            r#"["first_family","first_name",["label_a"],["value_b"]]"#,
+            r#"["second_family","second_name",["label_a"],["value_a"]]"#,
+        ];
+
+        for openmetrics in [false, true] {
+            let options = RenderOptions {
+                openmetrics,
+                ..Default::default()
+            };
+            let entries = build_entries("gauge", "all", json, &[1.0, 2.0, 3.0], "worker-1");
+
+            let (out, ranges) =
+                FileEntry::entries_to_string_with_ranges(entries, &options).unwrap();
+
+            let families: Vec<_> = ranges.iter().map(|(name, _)| name.as_str()).collect();
+            assert_eq!(vec!["first_family", "second_family"], families);
+
+            let first = &out[ranges[0].1.clone()];
+            let second = &out[ranges[1].1.clone()];
+
+            assert!(first.starts_with("# HELP first_family "), "{first}");
+            let first_lines = first.lines().filter(|l| l.starts_with("first_name{"));
+            assert_eq!(2, first_lines.count());
+            assert!(!first.contains("second"), "{first}");
+
+            assert!(second.starts_with("# HELP second_family "), "{second}");
+            assert!(second.ends_with("second_name{label_a=\"value_a\",pid=\"worker-1\"} 3\n"));
+            assert!(!second.contains(OPENMETRICS_EOF), "{second}");
+
+            // The ranges cover the output up to the trailer.
+            assert_eq!(0, ranges[0].1.start);
+            assert_eq!(ranges[0].1.end, ranges[1].1.start);
+            let end = out.len() - usize::from(openmetrics) * OPENMETRICS_EOF.len();
+            assert_eq!(end, ranges[1].1.end);
+        }
+    }
+
+    #[test]
+    fn test_tolerant_keys() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        struct TestCase {
+            name: &'static str,
+            json: &'static str,
+        }
+
+        let tc = vec![
+            TestCase {
+                name: "byte order mark",
+                json: "\u{feff}[\"family\",\"name\",[\"label_a\"],[\"value_a\"]]",
+            },
+            TestCase {
+                name: "unicode whitespace",
+                json: "\u{2003}[\"family\",\"name\",[\"label_a\"],[\"value_a\"]]",
+            },
+            TestCase {
+                name: "whitespace and byte order mark",
+                json: " \t\u{feff}[\"family\",\"name\",[\"label_a\"],[\"value_a\"]]",
+            },
+        ];
+
+        let clean = &[r#"["family","name",["label_a"],["value_a"]]"#];
+        let clean_entries = || build_entries("gauge", "max", clean, &[1.0], "worker-1");
+        let expected_text = FileEntry::entries_to_string(clean_entries()).unwrap();
+        let expected_protobuf = FileEntry::entries_to_protobuf(clean_entries()).unwrap();
+
+        let tolerant = RenderOptions {
+            tolerant_keys: true,
+            ..Default::default()
+        };
+
+        for case in tc {
+            let name = case.name;
+            let entries = || build_entries("gauge", "max", &[case.json], &[1.0], "worker-1");
+
+            // Strict parsing is the default.
+            assert!(FileEntry::entries_to_string(entries()).is_err(), "{name}");
+            assert!(FileEntry::entries_to_protobuf(entries()).is_err(), "{name}");
+
+            let text = FileEntry::entries_to_string_with_options(entries(), &tolerant);
+            assert_eq!(expected_text, text.unwrap(), "{name}");
+
+            let protobuf = FileEntry::entries_to_protobuf_with_options(entries(), &tolerant);
+            assert_eq!(expected_protobuf, protobuf.unwrap(), "{name}");
+        }
+    }
+
+    #[test]
+    fn test_target_info() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let json = &[r#"["family","name",["label_a"],["value_a"]]"#];
+        let entries = || build_entries("gauge", "max", json, &[1.0], "worker-1");
+
+        let target_info = vec![
+            ("env".to_string(), "production".to_string()),
+            ("region".to_string(), "eu \"west\"".to_string()),
+        ];
+        let options = RenderOptions {
+            openmetrics: true,
+            target_info: target_info.clone(),
+            ..Default::default()
+        };
+
+        let out = FileEntry::entries_to_string_with_options(entries(), &options).unwrap();
+        assert_eq!(
+            indoc! {r##"# HELP target Target metadata
+                # TYPE target info
+                target_info{env="production",region="eu \"west\""} 1
+                # HELP family Multiprocess metric
+                # TYPE family gauge
+                name{label_a="value_a"} 1
+                # EOF
+                "##},
+            out
+        );
+
+        // Only in OpenMetrics mode, and only with labels to emit.
+        let text = RenderOptions {
+            target_info,
+            ..Default::default()
+        };
+        let out = FileEntry::entries_to_string_with_options(entries(), &text).unwrap();
+        assert!(!out.contains("target"), "{out}");
+
+        let empty = RenderOptions {
+            openmetrics: true,
+            ..Default::default()
+        };
+        let out = FileEntry::entries_to_string_with_options(entries(), &empty).unwrap();
+        assert!(!out.contains("target"), "{out}");
+    }
+
+    #[test]
+    fn test_openmetrics_counter_suffix() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let json = &[
+            r#"["http_requests","http_requests",["code"],["200"]]"#,
+            r#"["jobs_total","jobs_total",["queue"],["default"]]"#,
+        ];
+
+        let entries = |type_| build_entries(type_, "max", json, &[1.0, 2.0], "worker-1");
+
+        let openmetrics = RenderOptions {
+            openmetrics: true,
+            ..Default::default()
+        };
+
+        // Legacy text format is untouched.
+        let out = FileEntry::entries_to_string(entries("counter")).unwrap();
+        assert!(out.contains("# TYPE http_requests counter\n"), "{out}");
+        assert!(out.contains("http_requests{code=\"200\"} 1\n"), "{out}");
+        assert!(!out.contains("# EOF"), "{out}");
+
+        let out =
+            FileEntry::entries_to_string_with_options(entries("counter"), &openmetrics).unwrap();
+        assert!(
+            out.contains("# TYPE http_requests_total counter\n"),
+            "{out}"
+        );
+        assert!(
+            out.contains("http_requests_total{code=\"200\"} 1\n"),
+            "{out}"
+        );
+        assert!(
+            out.contains("# TYPE jobs_total counter\n"),
+            "not doubled: {out}"
+        );
+        assert!(
+            out.contains("jobs_total{queue=\"default\"} 2\n"),
+            "not doubled: {out}"
+        );
+        assert!(out.ends_with("# EOF\n"), "{out}");
+
+        // Only counters take the suffix.
+        let out =
+            FileEntry::entries_to_string_with_options(entries("gauge"), &openmetrics).unwrap();
+        assert!(out.contains("# TYPE http_requests gauge\n"), "{out}");
+    }
+
+    #[test]
+    fn test_label_inline_size() {
+        // One series of each label count from 2 to 12.
+        let corpus: Vec<String> = (2..=12)
+            .map(|n| {
+                let labels = (0..n).map(|l| format!(r#""label_{l}""#)).join(",");
+                let values = (0..n).map(|l| format!(r#""value_{l}""#)).join(",");
+                format!(r#"["family","name",[{labels}],[{values}]]"#)
+            })
+            .collect();
+
+        // The number of series whose parse allocates with `$n` labels inline.
+        macro_rules! spilled {
+            ($n:literal) => {{
+                #[derive(Deserialize)]
+                #[allow(dead_code)]
+                struct Text<'a> {
+                    family_name: &'a str,
+                    metric_name: &'a str,
+                    labels: SmallVec<[&'a str; $n]>,
+                    #[serde(borrow)]
+                    values: SmallVec<[&'a RawValue; $n]>,
+                }
+
+                corpus
+                    .iter()
+                    .filter(|json| {
+                        let (_, stats) = testhelper::measure_allocs(|| {
+                            let text: Text = serde_json::from_str(json).unwrap();
+                            std::hint::black_box(text);
+                        });
+                        stats.count > 0
+                    })
+                    .count()
+            }};
+        }
+
+        assert_eq!(8, spilled!(4), "4 inline");
+        assert_eq!(4, spilled!(8), "8 inline");
+        assert_eq!(0, spilled!(16), "16 inline");
+
+        // The labels parsed by `MetricText` match the default.
+        let (_, stats) = testhelper::measure_allocs(|| {
+            for json in &corpus[..INLINE_LABELS - 1] {
+                let text: MetricText = serde_json::from_str(json).unwrap();
+                std::hint::black_box(text);
+            }
+        });
+        assert_eq!(0, stats.count, "up to {INLINE_LABELS} labels inline");
+    }
+
+    #[test]
+    fn test_entries_to_string_allocs() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        // Series with many labels render lines longer than the fixed 128
+        // bytes per entry the output was previously sized with, short series
+        // much shorter.
+        let long: Vec<&'static str> = (0..100)
+            .map(|i| {
+                let labels = (0..8)
+                    .map(|l| format!(r#""label_{l}""#))
+                    .collect::<Vec<_>>();
+                let values = (0..8)
+                    .map(|l| format!(r#""value_{l}_of_series_{i:03}""#))
+                    .collect::<Vec<_>>();
+                let json = format!(
+                    r#"["family","family_total",[{}],[{}]]"#,
+                    labels.join(","),
+                    values.join(",")
+                );
+                &*Box::leak(json.into_boxed_str())
+            })
+            .collect();
+        let short: Vec<&'static str> = (0..100)
+            .map(|i| &*Box::leak(format!(r#"["f","f",["l"],["{i}"]]"#).into_boxed_str()))
+            .collect();
+
+        for (name, json) in [("long", long), ("short", short)] {
+            let values = vec![1.0; json.len()];
+            let entries = build_entries("counter", "", &json, &values, "worker-1");
+            let estimate = FileEntry::estimate_output_len(&entries);
+
+            let (out, stats) =
+                testhelper::measure_allocs(|| FileEntry::entries_to_string(entries).unwrap());
+
+            // The output is allocated once at the estimated size and never
+            // grown, a single allocation of that size is the peak.
+            assert!(out.len() <= estimate, "{name}: {} > {estimate}", out.len());
+            assert_eq!(estimate, out.capacity(), "{name}: output reallocated");
+            assert!(stats.peak >= estimate as isize, "{name}");
+
+            match name {
+                "long" => assert!(out.len() > json.len() * 128, "{name}: {}", out.len()),
+                _ => assert!(estimate < json.len() * 128, "{name}: {estimate}"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_digest() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let entry = |json: &str, pid: Option<&str>, value: Option<f64>| FileEntry {
+            data: EntryData {
+                json: json.to_string(),
+                pid: pid.map(str::to_string),
+            },
+            meta: EntryMetadata {
+                multiprocess_mode: Symbol::new("all"),
+                type_: Symbol::new("gauge"),
+                value,
+                ex: None,
+            },
+        };
+
+        let entries = [
+            entry(r#"["family","name",["label"],["a"]]"#, None, Some(1.0)),
+            entry(
+                r#"["family","name",["label"],["b"]]"#,
+                Some("worker-1"),
+                None,
+            ),
+        ];
+
+        // Pinned so any change to the hashed bytes is noticed, as digests are
+        // compared across processes.
+        assert_eq!(0xb1b0_e6cb_7b11_be7b, FileEntry::digest(&entries));
+
+        // Moving bytes between the key and pid changes the digest.
+        let moved = [entry("ab", Some("c"), None)];
+        let shifted = [entry("a", Some("bc"), None)];
+        assert_ne!(FileEntry::digest(&moved), FileEntry::digest(&shifted));
+    }
+
+    #[test]
+    fn test_name_prefixes() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let json = &[
+            r#"["http_requests","http_requests",["code"],["200"]]"#,
+            r#"["vendor_jobs","vendor_jobs",["queue"],["default"]]"#,
+        ];
+        let entries = || build_entries("counter", "", json, &[1.0, 2.0], "worker-1");
+
+        // The longest prefix wins, `vendor_` names are only renamed once.
+        let options = RenderOptions {
+            name_prefixes: vec![
+                (String::new(), "acme_".to_string()),
+                ("vendor_".to_string(), "acme_vendor_".to_string()),
+            ],
+            ..Default::default()
+        };
+
+        let out = FileEntry::entries_to_string_with_options(entries(), &options).unwrap();
+        assert_eq!(
+            indoc! {r##"# HELP acme_http_requests Multiprocess metric
+                # TYPE acme_http_requests counter
+                acme_http_requests{code="200"} 1
+                # HELP acme_vendor_jobs Multiprocess metric
+                # TYPE acme_vendor_jobs counter
+                acme_vendor_jobs{queue="default"} 2
+                "##},
+            out
+        );
+
+        // Rewritten before the OpenMetrics suffix is added.
+        let openmetrics = RenderOptions {
+            openmetrics: true,
+            ..options.clone()
+        };
+        let out = FileEntry::entries_to_string_with_options(entries(), &openmetrics).unwrap();
+        assert!(
+            out.contains("# TYPE acme_http_requests_total counter\n"),
+            "{out}"
+        );
+        assert!(
+            out.contains("acme_http_requests_total{code=\"200\"} 1\n"),
+            "{out}"
+        );
+
+        let out = FileEntry::entries_to_protobuf_with_options(entries(), &options).unwrap();
+        let mut buf = out.as_bytes();
+        let mut names = Vec::new();
+        while !buf.is_empty() {
+            let mf =
+                io::prometheus::client::MetricFamily::decode_length_delimited(&mut buf).unwrap();
+            names.push(mf.name.unwrap());
+        }
+        assert_eq!(vec!["acme_http_requests", "acme_vendor_jobs"], names);
+    }
+
+    #[test]
+    fn test_timestamps() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let json = &[
+            r#"["family","name",["label_a"],["value_a"]]"#,
+            r#"["family","name",["label_a"],["value_b"]]"#,
+        ];
+
+        let entries = || {
+            let mut entries = build_entries("counter", "", json, &[1.0, 2.0], "worker-1");
+            entries[0].meta.ex = Some(Exemplar {
+                label_name: "trace_id".to_string(),
+                label_value: "abc".to_string(),
+                value: 1.0,
+                timestamp: 1_700_000_000_123_456_789,
+            });
+            entries
+        };
+
+        let with_timestamps = RenderOptions {
+            timestamps: true,
+            ..Default::default()
+        };
+
+        // Text format.
+        let out = FileEntry::entries_to_string(entries()).unwrap();
+        assert!(out.contains("name{label_a=\"value_a\"} 1\n"), "{out}");
+
+        let out = FileEntry::entries_to_string_with_options(entries(), &with_timestamps).unwrap();
+        assert!(
+            out.contains("name{label_a=\"value_a\"} 1 1700000000123\n"),
+            "timestamp appended: {out}"
+        );
+        assert!(
+            out.contains("name{label_a=\"value_b\"} 2\n"),
+            "no exemplar, no timestamp: {out}"
+        );
+
+        // Protobuf format.
+        let decode = |out: String| {
+            let mut buf = out.as_bytes();
+            let mut timestamps = Vec::new();
+            while !buf.is_empty() {
+                let mf = io::prometheus::client::MetricFamily::decode_length_delimited(&mut buf)
+                    .unwrap();
+                timestamps.extend(mf.metric.iter().map(|m| m.timestamp_ms));
+            }
+            timestamps.sort();
+            timestamps
+        };
+
+        let out = FileEntry::entries_to_protobuf(entries()).unwrap();
+        assert_eq!(vec![None, None], decode(out));
+
+        let out = FileEntry::entries_to_protobuf_with_options(entries(), &with_timestamps).unwrap();
+        assert_eq!(vec![None, Some(1_700_000_000_123)], decode(out));
+
+        // A scrape timestamp is applied to every sample, replacing exemplar
+        // timestamps.
+        for timestamps in [false, true] {
+            let options = RenderOptions {
+                timestamps,
+                scrape_timestamp_ms: Some(1_700_000_005_000),
+                ..Default::default()
+            };
+
+            let out = FileEntry::entries_to_string_with_options(entries(), &options).unwrap();
+            assert_eq!(
+                indoc! {r##"# HELP family Multiprocess metric
+                    # TYPE family counter
+                    name{label_a="value_a"} 1 1700000005000
+                    name{label_a="value_b"} 2 1700000005000
+                    "##},
+                out
+            );
+
+            let out = FileEntry::entries_to_protobuf_with_options(entries(), &options).unwrap();
+            assert_eq!(
+                vec![Some(1_700_000_005_000), Some(1_700_000_005_000)],
+                decode(out)
+            );
+        }
+
+        // OpenMetrics timestamps are in seconds.
+        let openmetrics = RenderOptions {
+            openmetrics: true,
+            ..with_timestamps.clone()
+        };
+        let out = FileEntry::entries_to_string_with_options(entries(), &openmetrics).unwrap();
+        assert!(
+            out.contains("name_total{label_a=\"value_a\"} 1 1700000000.123\n"),
+            "{out}"
+        );
+
+        let openmetrics = RenderOptions {
+            scrape_timestamp_ms: Some(1_700_000_005_000),
+            ..openmetrics
+        };
+        let out = FileEntry::entries_to_string_with_options(entries(), &openmetrics).unwrap();
+        assert!(
+            out.contains("name_total{label_a=\"value_b\"} 2 1700000005.000\n"),
+            "{out}"
+        );
+    }
+
+    #[test]
+    fn test_histogram_counts() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let json = &[
+            r#"["hist","hist_bucket",["le"],["0.1"]]"#,
+            r#"["hist","hist_bucket",["le"],["1"]]"#,
+        ];
+
+        let decode = |out: String| {
+            let mut buf = out.as_bytes();
+            let mf =
+                io::prometheus::client::MetricFamily::decode_length_delimited(&mut buf).unwrap();
+            assert!(buf.is_empty(), "single family");
+            mf.metric[0].histogram.clone().unwrap()
+        };
+
+        // Whole number counts use the integer field.
+        let out = FileEntry::entries_to_protobuf(build_entries(
+            "histogram",
+            "",
+            json,
+            &[1.0, 3.0],
+            "worker-1",
+        ))
+        .unwrap();
+        let hs = decode(out);
+        assert_eq!(
+            vec![(Some(1), None), (Some(3), None)],
+            hs.bucket
+                .iter()
+                .map(|b| (b.cumulative_count, b.cumulative_count_float))
+                .collect::<Vec<_>>()
+        );
+
+        // Any fractional count keeps all counts as floats.
+        let out = FileEntry::entries_to_protobuf(build_entries(
+            "histogram",
+            "",
+            json,
+            &[1.0, 2.5],
+            "worker-1",
+        ))
+        .unwrap();
+        let hs = decode(out);
+        assert_eq!(
+            vec![(None, Some(1.0)), (None, Some(2.5))],
+            hs.bucket
+                .iter()
+                .map(|b| (b.cumulative_count, b.cumulative_count_float))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_bound_formatting() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let json = &[
+            r#"["hist","hist_bucket",["le"],["0.005"]]"#,
+            r#"["hist","hist_bucket",["le"],[0.1]]"#,
+            r#"["hist","hist_bucket",["le"],[1]]"#,
+            r#"["hist","hist_bucket",["le"],["10.0"]]"#,
+            r#"["hist","hist_bucket",["le"],["1e6"]]"#,
+            r#"["hist","hist_bucket",["le"],["+Inf"]]"#,
+        ];
+
+        let values = [1.0, 2.0, 3.0, 4.0, 5.0, 6.0];
+        let entries = build_entries("histogram", "", json, &values, "worker-1");
+
+        let estimate = FileEntry::estimate_text_len(&entries);
+        let out = FileEntry::entries_to_string(entries).unwrap();
+        assert_eq!(out.len(), estimate, "estimate matches rewritten bounds");
+
+        for bound in ["0.005", "0.1", "1", "10", "1e+06", "+Inf"] {
+            assert!(
+                out.contains(&format!("hist_bucket{{le=\"{bound}\"}}")),
+                "le {bound} in {out}"
+            );
+        }
+
+        // Only bound labels are normalized.
+        let json = &[r#"["summary","summary",["quantile","code"],["0.50","1.0"]]"#];
+        let out =
+            FileEntry::entries_to_string(build_entries("summary", "", json, &[1.0], "worker-1"))
+                .unwrap();
+        assert!(
+            out.contains(r#"summary{quantile="0.5",code="1.0"}"#),
+            "{out}"
+        );
+    }
+
+    #[test]
+    fn test_histogram_duplicate_bucket() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        // The same bound in another series is not a duplicate.
+        let json = &[
+            r#"["hist","hist_bucket",["label_a","le"],["a","1"]]"#,
+            r#"["hist","hist_bucket",["label_a","le"],["b","1"]]"#,
+        ];
+        let out =
+            FileEntry::entries_to_protobuf(build_entries("histogram", "", json, &[1.0, 2.0], "w"));
+        assert!(out.is_ok());
+
+        let json = &[
+            r#"["hist","hist_bucket",["label_a","le"],["a","1"]]"#,
+            r#"["hist","hist_bucket",["label_a","le"],["a","1.0"]]"#,
+        ];
+        let err =
+            FileEntry::entries_to_protobuf(build_entries("histogram", "", json, &[1.0, 2.0], "w"))
+                .unwrap_err();
+        assert_eq!(RubyError::PromParsing, err.ruby_err());
+        assert!(
+            err.to_string().contains(r#"duplicate bucket le="1""#),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_protobuf_malformed_bounds() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        struct TestCase {
+            name: &'static str,
+            metric_type: &'static str,
+            json: &'static [&'static str],
+        }
+
+        let tc = vec![
+            TestCase {
+                name: "bucket without le",
+                metric_type: "histogram",
+                json: &[
+                    r#"["hist","hist_bucket",["label_a"],["a"]]"#,
+                    r#"["hist","hist_bucket",["label_a","le"],["a","1"]]"#,
+                ],
+            },
+            TestCase {
+                name: "bucket with invalid le",
+                metric_type: "histogram",
+                json: &[
+                    r#"["hist","hist_bucket",["label_a","le"],["a","big"]]"#,
+                    r#"["hist","hist_bucket",["label_a","le"],["a","1"]]"#,
+                ],
+            },
+            TestCase {
+                name: "summary without quantile",
+                metric_type: "summary",
+                json: &[
+                    r#"["summ","summ",["label_a"],["a"]]"#,
+                    r#"["summ","summ",["label_a","quantile"],["a","0.5"]]"#,
+                ],
+            },
+            TestCase {
+                name: "summary with invalid quantile",
+                metric_type: "summary",
+                json: &[
+                    r#"["summ","summ",["label_a","quantile"],["a","half"]]"#,
+                    r#"["summ","summ",["label_a","quantile"],["a","0.5"]]"#,
+                ],
+            },
+        ];
+
+        for case in tc {
+            let name = case.name;
+            let entries = || build_entries(case.metric_type, "", case.json, &[1.0, 2.0], "w");
+
+            // The malformed entry is skipped and the valid one rendered.
+            let out = FileEntry::entries_to_protobuf(entries()).unwrap();
+            let mf = io::prometheus::client::MetricFamily::decode_length_delimited(out.as_bytes())
+                .unwrap();
+            assert_eq!(1, mf.metric.len(), "test case: {name}");
+            let bounds = match mf.metric[0].histogram.as_ref() {
+                Some(hs) => hs.bucket.len(),
+                None => mf.metric[0].summary.as_ref().unwrap().quantile.len(),
+            };
+            assert_eq!(1, bounds, "test case: {name}");
+
+            let options = RenderOptions {
+                reject_malformed: true,
+                ..Default::default()
+            };
+            let err = FileEntry::entries_to_protobuf_with_options(entries(), &options).unwrap_err();
+            assert_eq!(RubyError::PromParsing, err.ruby_err(), "test case: {name}");
+            assert!(err.to_string().starts_with("1 histogram"), "{name}: {err}");
+        }
+    }
+
+    #[test]
+    fn test_entries_to_remote_write() {
+        use crate::prometheus::{Label, Sample, WriteRequest};
+
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let json = &[
+            r#"["family","name",["label_b","label_a"],["value \"b\"",1]]"#,
+            r#"["family","name",[],[]]"#,
+        ];
+        let entries = build_entries("gauge", "all", json, &[1.5, 2.0], "worker-1");
+
+        let out = FileEntry::entries_to_remote_write(entries, 1_700_000_000_000).unwrap();
+        let req = WriteRequest::decode(out.as_slice()).unwrap();
+
+        let label = |name: &str, value: &str| Label {
+            name: name.to_string(),
+            value: value.to_string(),
+        };
+
+        assert_eq!(2, req.timeseries.len());
+        assert_eq!(
+            vec![
+                label("__name__", "name"),
+                label("label_a", "1"),
+                label("label_b", "value \"b\""),
+                label("pid", "worker-1"),
+            ],
+            req.timeseries[0].labels
+        );
+        assert_eq!(
+            vec![Sample {
+                value: 1.5,
+                timestamp: 1_700_000_000_000
+            }],
+            req.timeseries[0].samples
+        );
+        assert_eq!(
+            vec![label("__name__", "name"), label("pid", "worker-1")],
+            req.timeseries[1].labels
+        );
+        assert_eq!(2.0, req.timeseries[1].samples[0].value);
+    }
+
+    #[test]
+    fn test_protobuf_interleaved_families() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        // Two workers' series, with the families interleaved.
+        let mut entries = build_entries(
+            "counter",
+            "",
+            &[
+                r#"["family_b","family_b",["worker"],["1"]]"#,
+                r#"["family_a","family_a",["worker"],["1"]]"#,
+            ],
+            &[1.0, 2.0],
+            "worker-1",
+        );
+        entries.extend(build_entries(
+            "counter",
+            "",
+            &[
+                r#"["family_b","family_b",["worker"],["2"]]"#,
+                r#"["family_a","family_a",["worker"],["2"]]"#,
+            ],
+            &[3.0, 4.0],
+            "worker-2",
+        ));
+
+        let out = FileEntry::entries_to_protobuf(entries).unwrap();
+
+        let mut buf = out.as_bytes();
+        let mut families = Vec::new();
+        while !buf.is_empty() {
+            let mf =
+                io::prometheus::client::MetricFamily::decode_length_delimited(&mut buf).unwrap();
+            let values: Vec<_> = mf
+                .metric
+                .iter()
+                .map(|m| m.counter.as_ref().unwrap().value.unwrap())
+                .collect();
+            families.push((mf.name.unwrap(), values));
+        }
+
+        assert_eq!(
+            vec![
+                ("family_a".to_string(), vec![2.0, 4.0]),
+                ("family_b".to_string(), vec![1.0, 3.0]),
+            ],
+            families,
+            "one family each, not merged across families"
+        );
+    }
+
+    #[test]
+    fn test_protobuf_invalid_entry() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let expected = MmapError::legacy(
+            "Processed entries 1 != map entries 2".to_owned(),
+            RubyError::Runtime,
+        );
+
+        let bad = [
+            // Mismatched label names and values.
+            r#"["family","name",["label_a","label_b"],["value_a"]]"#,
+            // Not valid JSON.
+            r#"["family","name",["label_a"],["value_a"]"#,
+        ];
+
+        for json in bad {
+            let entries = || {
+                build_entries(
+                    "gauge",
+                    "all",
+                    &[r#"["family","name",["label_a"],["value_a"]]"#, json],
+                    &[1.0, 2.0],
+                    "worker-1",
+                )
+            };
+
+            // Both formats reject the file in the same way.
+            let text = FileEntry::entries_to_string(entries()).unwrap_err();
+            assert_eq!(expected, text, "text: {json}");
+
+            let protobuf = FileEntry::entries_to_protobuf(entries()).unwrap_err();
+            assert_eq!(expected, protobuf, "protobuf: {json}");
+        }
+    }
+
+    #[test]
+    fn test_protobuf_utf8_labels() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let json = &[
+            r#"["family","family",["city"],["Zürich"]]"#,
+            r#"["family","family",["city"],["東京"]]"#,
+            r#"["family","family",["city"],["🎉"]]"#,
+        ];
+        let entries = build_entries("counter", "", json, &[1.0, 2.0, 3.0], "worker-1");
+
+        // Lengths are counted in bytes, not chars.
+        let text = FileEntry::entries_to_string(entries.clone()).unwrap();
+        assert_eq!(FileEntry::estimate_text_len(&entries), text.len());
+        assert!(text.contains(r#"family{city="東京"} 2"#));
+
+        let out = FileEntry::entries_to_protobuf(entries).unwrap();
+
+        let mut buf = out.as_bytes();
+        let mf = io::prometheus::client::MetricFamily::decode_length_delimited(&mut buf).unwrap();
+        assert!(buf.is_empty());
+
+        let mut values: Vec<_> = mf
+            .metric
+            .iter()
+            .map(|m| {
+                let label = &m.label[0];
+                (
+                    label.name.clone().unwrap(),
+                    label.value.clone().unwrap(),
+                    m.counter.as_ref().unwrap().value.unwrap(),
+                )
+            })
+            .collect();
+        values.sort_by(|a, b| a.2.total_cmp(&b.2));
+
+        assert_eq!(
+            vec![
+                ("city".to_string(), "Zürich".to_string(), 1.0),
+                ("city".to_string(), "東京".to_string(), 2.0),
+                ("city".to_string(), "🎉".to_string(), 3.0),
+            ],
+            values
+        );
+    }
+
+    #[test]
+    fn test_write_length_delimited() {
+        /// A writer that fails once `limit` bytes have been written.
+        struct LimitedWriter {
+            written: Vec<u8>,
+            limit: usize,
+        }
+
+        impl std::io::Write for LimitedWriter {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                if self.written.len() + buf.len() > self.limit {
+                    return Err(std::io::Error::new(std::io::ErrorKind::Other, "full"));
+                }
+                self.written.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mf = io::prometheus::client::MetricFamily {
+            name: Some("family".to_string()),
+            ..Default::default()
+        };
+        let encoded = mf.encode_to_vec();
+
+        let mut out = LimitedWriter {
+            written: Vec::new(),
+            limit: usize::MAX,
+        };
+        write_length_delimited(&mut out, &mf).unwrap();
+        let mut buf = out.written.as_slice();
+        assert_eq!(
+            mf,
+            io::prometheus::client::MetricFamily::decode_length_delimited(&mut buf).unwrap()
+        );
+
+        // Fails writing the length prefix, then the message.
+        for limit in [0, encoded.len()] {
+            let mut out = LimitedWriter {
+                written: Vec::new(),
+                limit,
+            };
+            assert_eq!(
+                Err(MmapError::Other("failed to write output: full".to_string())),
+                write_length_delimited(&mut out, &mf)
+            );
+        }
+
+        assert_eq!(Ok(u32::MAX), frame_len("family", u32::MAX as usize));
+        #[cfg(target_pointer_width = "64")]
+        assert_eq!(
+            Err(MmapError::Other(
+                "metric family family encoded length 4294967296 exceeds limit of 4294967295"
+                    .to_string()
+            )),
+            frame_len("family", u32::MAX as usize + 1)
+        );
+    }
+
+    #[test]
+    fn test_protobuf_deterministic() {
+        use rand::seq::SliceRandom;
+
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let mut entries = build_entries(
+            "counter",
+            "",
+            &[
+                r#"["family_c","family_c",["label"],["a"]]"#,
+                r#"["family_c","family_c",["label"],["b"]]"#,
+                r#"["family_a","family_a",["label"],["a"]]"#,
+            ],
+            &[1.0, 2.0, 3.0],
+            "worker-1",
+        );
+        entries.extend(build_entries(
+            "gauge",
+            "max",
+            &[
+                r#"["family_b","family_b",["label"],["a"]]"#,
+                r#"["family_d","family_d",[],[]]"#,
+            ],
+            &[4.0, 5.0],
+            "worker-1",
+        ));
+
+        let expected = FileEntry::entries_to_protobuf(entries.clone()).unwrap();
+
+        let mut rng = rand::thread_rng();
+        for _ in 0..10 {
+            entries.shuffle(&mut rng);
+            let out = FileEntry::entries_to_protobuf(entries.clone()).unwrap();
+            assert_eq!(expected.as_bytes(), out.as_bytes());
+        }
+
+        let mut buf = expected.as_bytes();
+        let mut names = Vec::new();
+        while !buf.is_empty() {
+            let mf =
+                io::prometheus::client::MetricFamily::decode_length_delimited(&mut buf).unwrap();
+            names.push(mf.name.unwrap());
+        }
+        assert_eq!(vec!["family_a", "family_b", "family_c", "family_d"], names);
+    }
+
+    #[test]
+    fn test_metric_families_shared_labels() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        // 20 histogram families of 50 series, each series with two buckets,
+        // `_count` and `_sum`. Label names and values repeat across families.
+        let json: Vec<&'static str> = (0..20)
+            .flat_map(|family| {
+                (0..50).flat_map(move |series| {
+                    let method = if series % 2 == 0 { "GET" } else { "POST" };
+                    let path = series % 25;
+                    let name = format!("hist_{family}");
+                    let labels = r#""method","path""#;
+                    let values = format!(r#""{method}","/api/v1/items/{path}""#);
+
+                    [
+                        format!(r#"["{name}","{name}_bucket",[{labels},"le"],[{values},"0.5"]]"#),
+                        format!(r#"["{name}","{name}_bucket",[{labels},"le"],[{values},"1"]]"#),
+                        format!(r#"["{name}","{name}_count",[{labels}],[{values}]]"#),
+                        format!(r#"["{name}","{name}_sum",[{labels}],[{values}]]"#),
+                    ]
+                    .map(|json| &*Box::leak(json.into_boxed_str()))
+                })
+            })
+            .collect();
+        let values = vec![1.0; json.len()];
+        let entries = build_entries("histogram", "", &json, &values, "worker-1");
+
+        let options = RenderOptions::default();
+        let (shared, shared_stats) = testhelper::measure_allocs(|| {
+            FileEntry::metric_families(entries.clone(), &options).unwrap()
+        });
+        let (owned, owned_stats) = testhelper::measure_allocs(|| {
+            FileEntry::metric_families(entries.clone(), &options)
+                .unwrap()
+                .into_iter()
+                .map(SharedFamily::into_proto)
+                .collect::<Vec<_>>()
+        });
+
+        let converted: Vec<_> = shared.into_iter().map(SharedFamily::into_proto).collect();
+        assert_eq!(owned, converted);
+        assert_eq!(20, owned.len());
+        assert!(owned.iter().all(|mf| mf.metric.len() == 50));
+
+        // The families hold their labels until they are encoded, sharing them
+        // needs less heap than owning them.
+        assert!(
+            shared_stats.current < owned_stats.current,
+            "shared labels retain {} bytes, owned labels {} bytes",
+            shared_stats.current,
+            owned_stats.current
+        );
+    }
+
+    #[test]
+    fn test_histogram_family() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        // A complete family as written by the Ruby client, in sorted order.
+        let json = &[
+            r#"["hist","hist_bucket",["label_a","le"],["value_a","+Inf"]]"#,
+            r#"["hist","hist_bucket",["label_a","le"],["value_a","0.5"]]"#,
+            r#"["hist","hist_bucket",["label_a","le"],["value_a","10"]]"#,
+            r#"["hist","hist_bucket",["label_a","le"],["value_a","2.5"]]"#,
+            r#"["hist","hist_count",["label_a"],["value_a"]]"#,
+            r#"["hist","hist_sum",["label_a"],["value_a"]]"#,
+        ];
+        let values = &[4.0, 1.0, 4.0, 3.0, 4.0, 12.5];
+
+        let out = FileEntry::entries_to_protobuf(build_entries(
+            "histogram",
+            "",
+            json,
+            values,
+            "worker-1",
+        ))
+        .unwrap();
+
+        let mut buf = out.as_bytes();
+        let mf = io::prometheus::client::MetricFamily::decode_length_delimited(&mut buf).unwrap();
+        assert!(buf.is_empty(), "single family");
+
+        assert_eq!(Some("hist".to_string()), mf.name);
+        assert_eq!(1, mf.metric.len());
+
+        let metric = &mf.metric[0];
+        assert_eq!(
+            vec![io::prometheus::client::LabelPair {
+                name: Some("label_a".to_string()),
+                value: Some("value_a".to_string()),
+            }],
+            metric.label
+        );
+
+        let hs = metric.histogram.as_ref().unwrap();
+        assert_eq!(Some(12.5), hs.sample_sum);
+        assert_eq!(Some(4), hs.sample_count);
+        assert_eq!(
+            vec![
+                (Some(0.5), Some(1)),
+                (Some(2.5), Some(3)),
+                (Some(10.0), Some(4))
+            ],
+            hs.bucket
+                .iter()
+                .map(|b| (b.upper_bound, b.cumulative_count))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_histogram_quantile() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        // 10 observations, 2 up to 1, 8 up to 2 and all 10 up to 4.
+        let json = &[
+            r#"["hist","hist_bucket",["le"],["+Inf"]]"#,
+            r#"["hist","hist_bucket",["le"],["1"]]"#,
+            r#"["hist","hist_bucket",["le"],["2"]]"#,
+            r#"["hist","hist_bucket",["le"],["4"]]"#,
+            r#"["hist","hist_count",[],[]]"#,
+            r#"["hist","hist_sum",[],[]]"#,
+        ];
+        let values = &[10.0, 2.0, 8.0, 10.0, 10.0, 20.0];
+        let entries = || build_entries("histogram", "", json, values, "worker-1");
+
+        struct TestCase {
+            q: f64,
+            expected: f64,
+        }
+
+        let tc = vec![
+            TestCase {
+                q: 0.1,
+                expected: 0.5,
             },
             TestCase {
-                name: "too many leading label brackets",
-                multiprocess_mode: "min",
-                json: &[r#"["family","name",[["label_a","label_b"],["value_a","value_b"]]"#],
-                values: &[1.5],
-                pids: &["worker-1"],
-                expected_out: None,
-                expected_err: Some(MmapError::legacy(
-                    "Processed entries 0 != map entries 1".to_owned(),
-                    RubyError::Runtime,
-                )),
+                q: 0.2,
+                expected: 1.0,
             },
             TestCase {
-                name: "too many leading label brackets",
-                multiprocess_mode: "min",
-                json: &[r#"["family","name",[["label_a","label_b"],["value_a","value_b"]]"#],
-                values: &[1.5],
-                pids: &["worker-1"],
-                expected_out: None,
-                expected_err: Some(MmapError::legacy(
-                    "Processed entries 0 != map entries 1".to_owned(),
-                    RubyError::Runtime,
-                )),
+                q: 0.5,
+                expected: 1.5,
             },
             TestCase {
-                name: "too many leading value brackets",
-                multiprocess_mode: "min",
-                json: &[r#"["family","name",["label_a","label_b"],[["value_a","value_b"]]"#],
-                values: &[1.5],
-                pids: &["worker-1"],
-                expected_out: None,
-                expected_err: Some(MmapError::legacy(
-                    "Processed entries 0 != map entries 1".to_owned(),
-                    RubyError::Runtime,
-                )),
+                q: 0.9,
+                expected: 3.0,
             },
             TestCase {
-                name: "misplaced bracket",
-                multiprocess_mode: "min",
-                json: &[r#"["family","name",["label_a","label_b"],]["value_a","value_b"]]"#],
-                values: &[1.5],
-                pids: &["worker-1"],
-                expected_out: None,
-                expected_err: Some(MmapError::legacy(
-                    "Processed entries 0 != map entries 1".to_owned(),
-                    RubyError::Runtime,
-                )),
+                q: 1.0,
+                expected: 4.0,
             },
             TestCase {
-                name: "comma in numeric",
-                multiprocess_mode: "min",
-                json: &[r#"["family","name",["label_a","label_b"],["value_a",403,0]]"#],
-                values: &[1.5],
-                pids: &["worker-1"],
-                expected_out: None,
-                expected_err: Some(MmapError::legacy(
-                    "Processed entries 0 != map entries 1".to_owned(),
-                    RubyError::Runtime,
-                )),
+                q: -1.0,
+                expected: f64::NEG_INFINITY,
             },
             TestCase {
-                name: "non-e letter in numeric",
-                multiprocess_mode: "min",
-                json: &[r#"["family","name",["label_a","label_b"],["value_a",-2.0c5]]"#],
-                values: &[1.5],
-                pids: &["worker-1"],
-                expected_out: None,
-                expected_err: Some(MmapError::legacy(
-                    "Processed entries 0 != map entries 1".to_owned(),
-                    RubyError::Runtime,
-                )),
+                q: 2.0,
+                expected: f64::INFINITY,
             },
         ];
 
         for case in tc {
-            let name = case.name;
+            assert_eq!(
+                Some(case.expected),
+                FileEntry::histogram_quantile(entries(), "hist", case.q).unwrap(),
+                "q {}",
+                case.q
+            );
+        }
 
-            let input_bytes: Vec<BString> = case
-                .json
-                .iter()
-                .zip(case.values)
-                .map(|(&s, &value)| TestEntry::new(s, value).as_bstring())
-                .collect();
+        // Series are summed. Another series' 10 observations all fall in the
+        // `+Inf` bucket, so the 90th percentile is the largest finite bound.
+        let mut both = entries();
+        both.extend(build_entries(
+            "histogram",
+            "",
+            &[
+                r#"["hist","hist_bucket",["label","le"],["b","+Inf"]]"#,
+                r#"["hist","hist_bucket",["label","le"],["b","1"]]"#,
+                r#"["hist","hist_bucket",["label","le"],["b","2"]]"#,
+                r#"["hist","hist_bucket",["label","le"],["b","4"]]"#,
+                r#"["hist","hist_count",["label"],["b"]]"#,
+                r#"["hist","hist_sum",["label"],["b"]]"#,
+            ],
+            &[10.0, 0.0, 0.0, 0.0, 10.0, 100.0],
+            "worker-1",
+        ));
+        assert_eq!(
+            Some(4.0),
+            FileEntry::histogram_quantile(both, "hist", 0.9).unwrap()
+        );
+
+        // Missing families and other types have no quantile.
+        assert_eq!(
+            None,
+            FileEntry::histogram_quantile(entries(), "missing", 0.5).unwrap()
+        );
+        let counter = build_entries(
+            "counter",
+            "",
+            &[r#"["counter","counter",[],[]]"#],
+            &[1.0],
+            "worker-1",
+        );
+        assert_eq!(
+            None,
+            FileEntry::histogram_quantile(counter, "counter", 0.5).unwrap()
+        );
+    }
 
-            let mut file_infos = Vec::new();
-            for pid in case.pids {
-                let TestFile {
-                    file,
-                    path,
-                    dir: _dir,
-                } = TestFile::new(b"foobar");
+    #[test]
+    fn test_unknown_mode() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
 
-                let info = FileInfo {
-                    file,
-                    path,
-                    len: case.json.len(),
-                    multiprocess_mode: Symbol::new(case.multiprocess_mode),
-                    type_: Symbol::new("gauge"),
-                    pid: pid.to_string(),
-                };
-                file_infos.push(info);
-            }
+        let json = r#"["family","name",["label_a"],["value_a"]]"#;
+        let bytes = TestEntry::new(json, 1.0).as_bstring();
+        let entry = RawEntry::from_slice(&bytes).unwrap();
 
-            let file_entries: Vec<FileEntry> = input_bytes
-                .iter()
-                .map(|s| RawEntry::from_slice(s).unwrap())
-                .zip(file_infos)
-                .map(|(entry, info)| {
-                    let meta = EntryMetadata::new(&entry, &info).unwrap();
-                    let borrowed =
-                        BorrowedData::new(&entry, &info, meta.is_pid_significant()).unwrap();
-                    let data = EntryData::try_from(borrowed).unwrap();
-                    FileEntry { data, meta }
-                })
-                .collect();
+        let meta = |metric_type: &str, multiprocess_mode: &str| {
+            let test_file = TestFile::new(b"foobar");
+            let info = FileInfo {
+                file: test_file.file,
+                path: test_file.path,
+                len: json.len(),
+                multiprocess_mode: Symbol::new(multiprocess_mode),
+                type_: Symbol::new(metric_type),
+                pid: "worker-1".to_string(),
+            };
+            EntryMetadata::new(&entry, &info)
+        };
 
-            let output = FileEntry::entries_to_string(file_entries);
+        for mode in ["all", "liveall", "livesum", "max", "min"] {
+            assert!(meta("gauge", mode).is_ok(), "gauge {mode}");
+        }
 
-            if let Some(expected_out) = case.expected_out {
-                assert_eq!(
-                    expected_out,
-                    output.as_ref().unwrap(),
-                    "test case: {name} - output"
-                );
-            }
+        // Only gauges have a multiprocess mode.
+        assert!(meta("counter", "mx").is_ok());
 
-            if let Some(expected_err) = case.expected_err {
-                assert_eq!(
-                    expected_err,
-                    output.unwrap_err(),
-                    "test case: {name} - error"
-                );
-            }
-        }
+        let err = meta("gauge", "mx").unwrap_err();
+        assert!(
+            matches!(&err, MmapError::UnknownMode { mode, .. } if mode == "mx"),
+            "{err}"
+        );
+        assert_eq!(RubyError::PromParsing, err.ruby_err());
     }
 
     #[test]
@@ -1249,4 +3663,52 @@ mod test {
             );
         }
     }
+
+    #[test]
+    fn test_merge_exemplars() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let exemplar = |value, timestamp| Exemplar {
+            label_name: "trace_id".to_string(),
+            label_value: format!("trace-{timestamp}"),
+            value,
+            timestamp,
+        };
+
+        let meta = |ex: Option<Exemplar>| EntryMetadata {
+            multiprocess_mode: Symbol::new(""),
+            type_: Symbol::new("counter"),
+            value: Some(1.0),
+            ex,
+        };
+
+        let older = meta(Some(exemplar(5.0, 100)));
+        let newer = meta(Some(exemplar(1.0, 200)));
+
+        // The newer exemplar survives regardless of merge order.
+        let mut a = older.clone();
+        a.merge(&newer);
+        assert_eq!(200, a.ex.as_ref().unwrap().timestamp);
+        assert_eq!(Some(2.0), a.value, "values still summed");
+
+        let mut b = newer.clone();
+        b.merge(&older);
+        assert_eq!(200, b.ex.as_ref().unwrap().timestamp);
+
+        // Ties on timestamp keep the larger value.
+        let mut c = meta(Some(exemplar(1.0, 200)));
+        c.merge(&meta(Some(exemplar(3.0, 200))));
+        assert_eq!(3.0, c.ex.as_ref().unwrap().value);
+
+        // An entry without an exemplar takes the other's, and never clears one.
+        let mut d = meta(None);
+        d.merge(&older);
+        assert_eq!(100, d.ex.as_ref().unwrap().timestamp);
+
+        let mut e = newer.clone();
+        e.merge(&meta(None));
+        assert_eq!(200, e.ex.as_ref().unwrap().timestamp);
+    }
 }
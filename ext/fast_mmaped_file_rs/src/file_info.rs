@@ -1,15 +1,21 @@
 use magnus::exception::*;
-use magnus::{Error, RString, Symbol, Value};
+use magnus::prelude::*;
+use magnus::{Error, RArray, RString, Symbol, TryConvert, Value};
+use memmap2::{Advice, MmapOptions};
 use std::ffi::OsString;
 use std::fs::File;
 use std::io::{self, Read, Seek};
-use std::os::unix::ffi::OsStringExt;
-use std::path::PathBuf;
+use std::mem::size_of;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
 
 use crate::err;
 use crate::error::{MmapError, RubyError};
 use crate::util;
 use crate::Result;
+use crate::HEADER_SIZE;
 
 /// The details of a `*.db` file.
 #[derive(Debug)]
@@ -41,7 +47,120 @@ impl FileInfo {
         let path_bytes: Vec<_> = unsafe { filepath.as_slice().to_owned() };
         let path = PathBuf::from(OsString::from_vec(path_bytes));
 
-        let mut file = File::open(&path).map_err(|_| {
+        let (file, length) = Self::open(&path)?;
+
+        let (multiprocess_mode, type_, pid) = Self::metadata_from_params(params)?;
+
+        Ok(Self {
+            file,
+            path,
+            len: length,
+            multiprocess_mode,
+            type_,
+            pid,
+        })
+    }
+
+    /// Receive the details of a file from Ruby as in `open_from_params`, with
+    /// an open file descriptor in place of the path. The descriptor is
+    /// duplicated rather than taken, so it remains owned and closed by the
+    /// caller. The duplicate shares its file offset, which is moved to read
+    /// the file from the start.
+    pub fn open_from_fd_params(params: &[Value; 4]) -> magnus::error::Result<Self> {
+        let fd = RawFd::try_convert(params[0])?;
+        let path = PathBuf::from(format!("<fd {fd}>"));
+
+        let dup = nix::unistd::dup(fd)
+            .map_err(|e| err!(arg_error(), "Can't duplicate file descriptor {fd}: {e}"))?;
+
+        // SAFETY: `dup` returned a new descriptor that nothing else owns, it is
+        // closed when the `File` is dropped.
+        let file = unsafe { File::from_raw_fd(dup) };
+        let (file, length) = Self::prepare(file, &path)?;
+
+        let (multiprocess_mode, type_, pid) = Self::metadata_from_params(params)?;
+
+        Ok(Self {
+            file,
+            path,
+            len: length,
+            multiprocess_mode,
+            type_,
+            pid,
+        })
+    }
+
+    /// Open a `*.db` file, deriving its metadata from the file name using the
+    /// naming scheme of the Ruby client: `<type>_<multiprocess_mode>_<pid>-<n>.db`.
+    pub fn open_from_path(path: PathBuf) -> magnus::error::Result<Self> {
+        let (type_, multiprocess_mode, pid) = Self::metadata_from_file_name(&path)
+            .ok_or_else(|| err!(arg_error(), "Can't parse file name {}", path.display()))?;
+
+        let (file, length) = Self::open(&path)?;
+
+        Ok(Self {
+            file,
+            path,
+            len: length,
+            multiprocess_mode: Symbol::new(multiprocess_mode),
+            type_: Symbol::new(type_),
+            pid,
+        })
+    }
+
+    /// Open a `*.db` file, deriving its metadata from the
+    /// `[multiprocess_mode, type, pid]` Array returned by calling `resolver`
+    /// with the file's path, for files not named by the Ruby client's scheme.
+    pub fn open_from_resolver(path: PathBuf, resolver: Value) -> magnus::error::Result<Self> {
+        let rpath = RString::from_slice(path.as_os_str().as_bytes());
+        let metadata: RArray = resolver.funcall("call", (rpath,))?;
+        if metadata.len() != 3 {
+            return Err(err!(
+                arg_error(),
+                "resolver returned {} values for {} instead of 3",
+                metadata.len(),
+                path.display()
+            ));
+        }
+
+        let params = [
+            rpath.as_value(),
+            metadata.entry(0)?,
+            metadata.entry(1)?,
+            metadata.entry(2)?,
+        ];
+
+        Self::open_from_params(&params)
+    }
+
+    /// Split a file name into its type, multiprocess mode, and pid. This matches
+    /// `EntryParser#parts` in the Ruby client, including its handling of
+    /// non-gauge files, where the pid lands in the multiprocess mode position.
+    pub fn metadata_from_file_name(path: &Path) -> Option<(String, String, String)> {
+        let stem = path.file_stem()?.to_str()?;
+
+        let parts: Vec<&str> = stem.split('_').map(Self::strip_file_number).collect();
+
+        let type_ = parts.first()?.to_string();
+        let multiprocess_mode = parts.get(1).copied().unwrap_or_default().to_string();
+        let pid = parts.get(2..).unwrap_or_default().join("_");
+
+        Some((type_, multiprocess_mode, pid))
+    }
+
+    /// Remove a trailing `-<digits>` file number suffix, if present.
+    fn strip_file_number(part: &str) -> &str {
+        match part.rfind('-') {
+            Some(i) if i + 1 < part.len() && part[i + 1..].bytes().all(|b| b.is_ascii_digit()) => {
+                &part[..i]
+            }
+            _ => part,
+        }
+    }
+
+    /// Open the file at `path` for reading, returning it with its length.
+    fn open(path: &Path) -> magnus::error::Result<(File, usize)> {
+        let file = Self::open_noatime(path).map_err(|_| {
             err!(
                 arg_error(),
                 "Can't open {}, errno: {}",
@@ -50,12 +169,61 @@ impl FileInfo {
             )
         })?;
 
+        Self::prepare(file, path)
+    }
+
+    /// Check the length of an open file and rewind it to be read, returning
+    /// it with its length.
+    fn prepare(mut file: File, path: &Path) -> magnus::error::Result<(File, usize)> {
         let stat = file
             .metadata()
             .map_err(|_| err!(io_error(), "Can't stat file, errno: {}", util::errno()))?;
 
         let length = util::cast_chk::<_, usize>(stat.len(), "file size")?;
+        util::check_file_size(path, length)?;
+
+        file.rewind()
+            .map_err(|_| err!(io_error(), "Can't fseek 0, errno: {}", util::errno()))?;
+
+        Ok((file, length))
+    }
+
+    /// Open the file at `path` for reading with `O_NOATIME`, so that scrapers
+    /// repeatedly reading many files don't update their access times. Only
+    /// the file's owner may set the flag, otherwise fall back to a plain open.
+    #[cfg(target_os = "linux")]
+    fn open_noatime(path: &Path) -> io::Result<File> {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        Self::open_noatime_with(path, |path, flags| {
+            File::options().read(true).custom_flags(flags).open(path)
+        })
+    }
+
+    /// Open `path` by calling `open` with `O_NOATIME` in its flags, retrying
+    /// with no flags if that is refused with `EPERM`.
+    #[cfg(target_os = "linux")]
+    fn open_noatime_with<F>(path: &Path, mut open: F) -> io::Result<File>
+    where
+        F: FnMut(&Path, libc::c_int) -> io::Result<File>,
+    {
+        match open(path, libc::O_NOATIME) {
+            Err(e) if e.raw_os_error() == Some(libc::EPERM) => open(path, 0),
+            result => result,
+        }
+    }
+
+    // `O_NOATIME` is Linux-specific.
+    #[cfg(not(target_os = "linux"))]
+    fn open_noatime(path: &Path) -> io::Result<File> {
+        File::open(path)
+    }
 
+    /// Extract the multiprocess mode, metric type, and pid from the Ruby params,
+    /// ignoring the leading file element.
+    pub fn metadata_from_params(
+        params: &[Value; 4],
+    ) -> magnus::error::Result<(Symbol, Symbol, String)> {
         let multiprocess_mode = Symbol::from_value(params[1])
             .ok_or_else(|| err!(arg_error(), "expected multiprocess_mode to be a symbol"))?;
 
@@ -65,17 +233,74 @@ impl FileInfo {
         let pid = RString::from_value(params[3])
             .ok_or_else(|| err!(arg_error(), "expected pid to be a String"))?;
 
-        file.rewind()
-            .map_err(|_| err!(io_error(), "Can't fseek 0, errno: {}", util::errno()))?;
+        Ok((multiprocess_mode, type_, pid.to_string()?))
+    }
 
-        Ok(Self {
-            file,
-            path,
-            len: length,
-            multiprocess_mode,
-            type_,
-            pid: pid.to_string()?,
-        })
+    /// Read the `used` header, then the `[0, used)` region of the file into the
+    /// buffer provided with a single `pread(2)`. Entries appended after the
+    /// header was loaded are excluded, so the buffer is consistent with its
+    /// header even if a worker writes to the file concurrently.
+    pub fn read_used_from_file(&mut self, buf: &mut Vec<u8>) -> Result<()> {
+        buf.clear();
+
+        let mut header = [0u8; size_of::<u32>()];
+        match self.file.read_exact_at(&mut header, 0) {
+            Ok(()) => {}
+            // Smaller than the header, nothing to read.
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(MmapError::io("read", &self.path, e)),
+        }
+
+        // Re-stat as the file may have grown since it was opened.
+        let stat = self
+            .file
+            .metadata()
+            .map_err(|e| MmapError::io("stat", &self.path, e))?;
+        let file_len = util::cast_chk::<_, usize>(stat.len(), "file size")?;
+
+        // CAST: no-op on 32-bit, widening on 64-bit.
+        let used = u32::from_ne_bytes(header) as usize;
+
+        // A `used` past the end of the file is reported as corruption when
+        // the buffer is parsed.
+        let len = used.max(HEADER_SIZE).min(file_len);
+
+        buf.try_reserve(len).map_err(|_| {
+            MmapError::legacy(
+                format!("Can't malloc {len}, errno: {}", util::errno()),
+                RubyError::Io,
+            )
+        })?;
+        buf.resize(len, 0);
+
+        self.file
+            .read_exact_at(buf, 0)
+            .map_err(|e| MmapError::io("read", &self.path, e))?;
+
+        // The header may have been updated between reads, restore the value
+        // the region was sized from.
+        buf[..size_of::<u32>()].copy_from_slice(&header);
+        self.len = len;
+
+        Ok(())
+    }
+
+    /// Ask the kernel to read the file into the page cache ahead of use with
+    /// `madvise(MADV_WILLNEED)`, returning the number of bytes advised. The
+    /// read happens in the background, this does not wait for it.
+    pub fn prewarm(&self) -> Result<usize> {
+        if self.len == 0 {
+            return Ok(0);
+        }
+
+        // SAFETY: The mapping is never read, it only exists to be advised.
+        let map = unsafe { MmapOptions::new().len(self.len).map(&self.file) }
+            .map_err(|e| MmapError::io("mmap", &self.path, e))?;
+
+        map.advise(Advice::WillNeed)
+            .map_err(|e| MmapError::io("madvise", &self.path, e))?;
+
+        Ok(self.len)
     }
 
     /// Read the contents of the associated file into the buffer provided by
@@ -117,6 +342,76 @@ mod test {
     use super::*;
     use crate::testhelper::TestFile;
 
+    #[test]
+    fn test_open_noatime() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let file = TestFile::new(b"foobar");
+        let (mut opened, len) = FileInfo::open(&file.path).unwrap();
+        assert_eq!(6, len);
+
+        let mut buf = Vec::new();
+        opened.read_to_end(&mut buf).unwrap();
+        assert_eq!(b"foobar", buf.as_slice());
+
+        assert!(FileInfo::open(&file.dir.path().join("missing.db")).is_err());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_open_noatime_fallback() {
+        let file = TestFile::new(b"foobar");
+
+        // `O_NOATIME` is refused with `EPERM` for a file owned by another
+        // user, unless running as root. The plain open is used instead.
+        let mut calls = Vec::new();
+        let mut opened = FileInfo::open_noatime_with(&file.path, |path, flags| {
+            calls.push(flags);
+            if flags & libc::O_NOATIME != 0 {
+                return Err(io::Error::from_raw_os_error(libc::EPERM));
+            }
+            File::open(path)
+        })
+        .unwrap();
+        assert_eq!(vec![libc::O_NOATIME, 0], calls, "retried without flag");
+
+        let mut buf = Vec::new();
+        opened.read_to_end(&mut buf).unwrap();
+        assert_eq!(b"foobar", buf.as_slice());
+
+        // Other errors are returned without retrying.
+        let mut calls = Vec::new();
+        let result = FileInfo::open_noatime_with(&file.path, |_, flags| {
+            calls.push(flags);
+            Err(io::Error::from_raw_os_error(libc::EACCES))
+        });
+        assert_eq!(Some(libc::EACCES), result.unwrap_err().raw_os_error());
+        assert_eq!(vec![libc::O_NOATIME], calls, "not retried");
+    }
+
+    #[test]
+    fn test_prewarm() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        for len in [0, 8, 4096 + 1] {
+            let file = TestFile::new(&vec![1u8; len]);
+            let info = FileInfo {
+                file: file.file,
+                path: file.path,
+                len,
+                multiprocess_mode: Symbol::new("all"),
+                type_: Symbol::new("gauge"),
+                pid: "worker-1".to_string(),
+            };
+
+            assert_eq!(len, info.prewarm().unwrap(), "len {len}");
+        }
+    }
+
     #[test]
     fn test_open_from_params() {
         let _cleanup = unsafe { magnus::embed::init() };
@@ -152,6 +447,76 @@ mod test {
         assert_eq!(out.pid, pid);
     }
 
+    #[test]
+    fn test_open_file_too_large() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let TestFile {
+            file,
+            path,
+            dir: _dir,
+        } = TestFile::new(b"foobar");
+
+        // Creates a sparse file, no disk space is consumed.
+        file.set_len(crate::MAX_FILE_SIZE as u64 + 1).unwrap();
+
+        let err = FileInfo::open(&path).unwrap_err();
+        assert!(
+            err.to_string().contains("exceeds limit"),
+            "unexpected error: {err}"
+        );
+
+        file.set_len(crate::MAX_FILE_SIZE as u64).unwrap();
+        assert!(FileInfo::open(&path).is_ok(), "file at limit is accepted");
+    }
+
+    #[test]
+    fn test_metadata_from_file_name() {
+        struct TestCase {
+            name: &'static str,
+            path: &'static str,
+            expected: Option<(&'static str, &'static str, &'static str)>,
+        }
+
+        let tc = vec![
+            TestCase {
+                name: "gauge",
+                path: "/tmp/gauge_max_worker-1-0.db",
+                expected: Some(("gauge", "max", "worker-1")),
+            },
+            TestCase {
+                name: "pid with underscore",
+                path: "/tmp/gauge_all_puma_master-12.db",
+                expected: Some(("gauge", "all", "puma_master")),
+            },
+            TestCase {
+                name: "counter",
+                path: "/tmp/counter_worker-1-0.db",
+                expected: Some(("counter", "worker-1", "")),
+            },
+            TestCase {
+                name: "no file name",
+                path: "/",
+                expected: None,
+            },
+        ];
+
+        for case in tc {
+            let name = case.name;
+            let expected = case
+                .expected
+                .map(|(t, m, p)| (t.to_string(), m.to_string(), p.to_string()));
+
+            assert_eq!(
+                expected,
+                FileInfo::metadata_from_file_name(Path::new(case.path)),
+                "test case: {name}"
+            );
+        }
+    }
+
     #[test]
     fn test_read_from_file() {
         let _cleanup = unsafe { magnus::embed::init() };
@@ -237,4 +602,51 @@ mod test {
 
         assert_eq!(BUF_LEN + 1024, info.len, "resized file updated len");
     }
+
+    #[test]
+    fn test_read_used_from_file() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let json = r#"["family","name",["label_a"],["value_a"]]"#;
+        let mut data = crate::testhelper::entries_to_db(&[json], &[1.0], None);
+        let used = data.len();
+
+        // Bytes past `used`, as left by a worker mid-write.
+        data.extend([0xffu8; 64]);
+
+        let TestFile {
+            file,
+            path,
+            dir: _dir,
+        } = TestFile::new(&data);
+
+        let mut info = FileInfo {
+            file,
+            path,
+            len: data.len(),
+            multiprocess_mode: Symbol::new("max"),
+            type_: Symbol::new("gauge"),
+            pid: "worker-0_0".to_string(),
+        };
+
+        let mut out_buf = Vec::new();
+        info.read_used_from_file(&mut out_buf).unwrap();
+
+        assert_eq!(&data[..used], out_buf.as_slice(), "only used region read");
+        assert_eq!(used, info.len, "len set to used");
+
+        // Files smaller than the header are empty.
+        let TestFile {
+            file,
+            path,
+            dir: _dir,
+        } = TestFile::new(b"foo");
+        info.file = file;
+        info.path = path;
+
+        info.read_used_from_file(&mut out_buf).unwrap();
+        assert!(out_buf.is_empty());
+    }
 }
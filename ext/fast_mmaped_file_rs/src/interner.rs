@@ -0,0 +1,61 @@
+use hashbrown::HashSet;
+use std::rc::Rc;
+
+/// A set of shared strings. Family names and common label names/values repeat
+/// heavily across series, interning them means each distinct string is
+/// allocated once rather than once per series.
+///
+/// An `Interner` is intended to be scoped to a single aggregation call, so
+/// its memory is released as soon as the output has been rendered.
+#[derive(Default, Debug)]
+pub struct Interner(HashSet<Rc<str>>);
+
+impl Interner {
+    /// Construct a new, empty `Interner`.
+    pub fn new() -> Self {
+        Self(HashSet::new())
+    }
+
+    /// Return a shared copy of `s`, allocating only if an equal string has
+    /// not already been interned.
+    pub fn intern(&mut self, s: &str) -> Rc<str> {
+        if let Some(existing) = self.0.get(s) {
+            return Rc::clone(existing);
+        }
+
+        let interned: Rc<str> = Rc::from(s);
+        self.0.insert(Rc::clone(&interned));
+        interned
+    }
+
+    /// The number of distinct strings interned.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Whether no strings have been interned.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_intern() {
+        let mut interner = Interner::new();
+        assert!(interner.is_empty());
+
+        let a = interner.intern("label_a");
+        let b = interner.intern("label_b");
+        let a_again = interner.intern("label_a");
+
+        assert_eq!("label_a", &*a);
+        assert_eq!("label_b", &*b);
+        assert!(Rc::ptr_eq(&a, &a_again), "repeated string shares storage");
+        assert!(!Rc::ptr_eq(&a, &b), "distinct strings are not shared");
+        assert_eq!(2, interner.len());
+    }
+}
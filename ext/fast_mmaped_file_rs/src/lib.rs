@@ -9,8 +9,10 @@ use crate::mmap::MmapedFile;
 pub mod error;
 pub mod file_entry;
 pub mod file_info;
+pub mod interner;
 mod macros;
 pub mod map;
+pub mod matcher;
 pub mod mmap;
 pub mod raw_entry;
 pub mod util;
@@ -24,6 +26,11 @@ pub mod io {
     }
 }
 
+/// Remote-write protocol messages.
+pub mod prometheus {
+    include!(concat!(env!("OUT_DIR"), "/prometheus.rs"));
+}
+
 #[cfg(test)]
 mod testhelper;
 
@@ -31,11 +38,18 @@ type Result<T> = std::result::Result<T, crate::error::MmapError>;
 
 const MAP_SHARED: i64 = libc::MAP_SHARED as i64;
 const HEADER_SIZE: usize = 2 * size_of::<u32>();
+/// The largest `.db` file that will be mapped or read. Metrics files are
+/// expected to be far smaller, anything larger is treated as corrupt.
+const MAX_FILE_SIZE: usize = 1 << 30; // 1GiB
 
+static SYM_COUNTER: LazyId = LazyId::new("counter");
 static SYM_GAUGE: LazyId = LazyId::new("gauge");
+static SYM_EXEMPLAR: LazyId = LazyId::new("exemplar");
 static SYM_MIN: LazyId = LazyId::new("min");
 static SYM_MAX: LazyId = LazyId::new("max");
 static SYM_LIVESUM: LazyId = LazyId::new("livesum");
+static SYM_ALL: LazyId = LazyId::new("all");
+static SYM_LIVEALL: LazyId = LazyId::new("liveall");
 static SYM_PID: LazyId = LazyId::new("pid");
 static SYM_SAMPLES: LazyId = LazyId::new("samples");
 
@@ -49,41 +63,138 @@ static PROM_EPARSING_ERROR: Lazy<ExceptionClass> = Lazy::new(|_| {
         .expect("failed to create exception class from `PrometheusParsingError`")
 });
 
+/// Raised when an allocation sized from file contents is implausibly large,
+/// e.g. due to a corrupt `.db` file. Unlike `NoMemoryError`, which is kept for
+/// genuine allocator failures, this can be rescued safely so a scrape can fail
+/// without taking down the worker.
+static PROM_EALLOCATION_ERROR: Lazy<ExceptionClass> = Lazy::new(|_| {
+    let prom_err = define_class(
+        "PrometheusAllocationError",
+        exception::runtime_error().as_r_class(),
+    )
+    .expect("failed to create class `PrometheusAllocationError`");
+    ExceptionClass::from_value(prom_err.as_value())
+        .expect("failed to create exception class from `PrometheusAllocationError`")
+});
+
 #[magnus::init]
 fn init(ruby: &Ruby) -> magnus::error::Result<()> {
     // Initialize the static symbols
+    LazyId::force(&SYM_COUNTER, ruby);
     LazyId::force(&SYM_GAUGE, ruby);
+    LazyId::force(&SYM_EXEMPLAR, ruby);
     LazyId::force(&SYM_MIN, ruby);
     LazyId::force(&SYM_MAX, ruby);
     LazyId::force(&SYM_LIVESUM, ruby);
+    LazyId::force(&SYM_ALL, ruby);
+    LazyId::force(&SYM_LIVEALL, ruby);
     LazyId::force(&SYM_PID, ruby);
     LazyId::force(&SYM_SAMPLES, ruby);
 
     // Initialize `PrometheusParsingError` class.
     Lazy::force(&PROM_EPARSING_ERROR, ruby);
 
+    // Initialize `PrometheusAllocationError` class.
+    Lazy::force(&PROM_EALLOCATION_ERROR, ruby);
+
     let klass = define_class("FastMmapedFileRs", class::object())?;
     klass.undef_default_alloc_func();
 
     // UNWRAP: We know `MAP_SHARED` fits in a `Fixnum`.
     klass.const_set("MAP_SHARED", Fixnum::from_i64(MAP_SHARED).unwrap())?;
 
-    klass.define_singleton_method("to_metrics", function!(MmapedFile::to_metrics, 1))?;
-    klass.define_singleton_method("to_protobuf", function!(MmapedFile::to_protobuf, 1))?;
+    klass.define_singleton_method("to_metrics", function!(MmapedFile::to_metrics, -1))?;
+    klass.define_singleton_method("to_protobuf", function!(MmapedFile::to_protobuf, -1))?;
+    klass.define_singleton_method("to_protobuf_io", function!(MmapedFile::to_protobuf_io, -1))?;
+    klass.define_singleton_method("render", function!(MmapedFile::render, -1))?;
+    klass.define_singleton_method("active_pids", function!(MmapedFile::active_pids, 1))?;
+    klass.define_singleton_method(
+        "to_metrics_by_pid",
+        function!(MmapedFile::to_metrics_by_pid, 1),
+    )?;
+    klass.define_singleton_method(
+        "to_metrics_with_extra",
+        function!(MmapedFile::to_metrics_with_extra, 2),
+    )?;
+    klass.define_singleton_method(
+        "to_metrics_with_ranges",
+        function!(MmapedFile::to_metrics_with_ranges, 1),
+    )?;
+    klass.define_singleton_method(
+        "series_breakdown",
+        function!(MmapedFile::series_breakdown, 4),
+    )?;
+    klass.define_singleton_method("metrics_digest", function!(MmapedFile::metrics_digest, 1))?;
+    klass.define_singleton_method(
+        "to_metrics_if_modified",
+        function!(MmapedFile::to_metrics_if_modified, 2),
+    )?;
+    klass.define_singleton_method("estimate_size", function!(MmapedFile::estimate_size, 1))?;
+    klass.define_singleton_method("stats", function!(MmapedFile::stats, 1))?;
+    klass.define_singleton_method("self_stats", function!(MmapedFile::self_stats, 1))?;
+    klass.define_singleton_method(
+        "histogram_quantile",
+        function!(MmapedFile::histogram_quantile, 3),
+    )?;
+    klass.define_singleton_method("prewarm", function!(MmapedFile::prewarm, 1))?;
+    klass.define_singleton_method("repair", function!(MmapedFile::repair, 1))?;
+    klass.define_singleton_method("debug_dump", function!(MmapedFile::debug_dump, 1))?;
+    klass.define_singleton_method("to_remote_write", function!(MmapedFile::to_remote_write, 1))?;
+    klass.define_singleton_method("zero_value_keys", function!(MmapedFile::zero_value_keys, 1))?;
+    klass.define_singleton_method("to_family_map", function!(MmapedFile::to_family_map, 1))?;
+    klass.define_singleton_method("top_series", function!(MmapedFile::top_series, 2))?;
+    klass.define_singleton_method("cardinality", function!(MmapedFile::cardinality, 1))?;
+    klass.define_singleton_method("delta", function!(MmapedFile::delta, 2))?;
+    klass.define_singleton_method("select", function!(MmapedFile::select, 2))?;
+    klass.define_singleton_method("to_metrics_dir", function!(MmapedFile::to_metrics_dir, -1))?;
+    klass.define_singleton_method(
+        "to_metrics_from_mmaps",
+        function!(MmapedFile::to_metrics_from_mmaps, 1),
+    )?;
+    klass.define_singleton_method(
+        "to_metrics_from_fds",
+        function!(MmapedFile::to_metrics_from_fds, 1),
+    )?;
 
     // Required for subclassing to work
     klass.define_alloc_func::<MmapedFile>();
     klass.define_singleton_method("new", method!(MmapedFile::new, -1))?;
-    klass.define_method("initialize", method!(MmapedFile::initialize, 1))?;
+    klass.define_singleton_method("open_read_only", method!(MmapedFile::open_read_only, 1))?;
+    klass.define_method("initialize", method!(MmapedFile::initialize, -1))?;
     klass.define_method("slice", method!(MmapedFile::slice, -1))?;
     klass.define_method("sync", method!(MmapedFile::sync, -1))?;
     klass.define_method("munmap", method!(MmapedFile::munmap, 0))?;
+    klass.define_method("mapped?", method!(MmapedFile::is_mapped, 0))?;
+    klass.define_method("read_only?", method!(MmapedFile::is_read_only, 0))?;
+    klass.define_method("refresh", method!(MmapedFile::refresh, 0))?;
 
     klass.define_method("used", method!(MmapedFile::load_used, 0))?;
     klass.define_method("used=", method!(MmapedFile::save_used, 1))?;
+    klass.define_method("snapshot_bytes", method!(MmapedFile::snapshot_bytes, 0))?;
+    klass.define_method("remap_count", method!(MmapedFile::remap_count, 0))?;
+    klass.define_method("atomic_values=", method!(MmapedFile::save_atomic_values, 1))?;
+    klass.define_method("atomic_values?", method!(MmapedFile::atomic_values, 0))?;
+    klass.define_method(
+        "durable_writes=",
+        method!(MmapedFile::save_durable_writes, 1),
+    )?;
+    klass.define_method("durable_writes?", method!(MmapedFile::durable_writes, 0))?;
+    klass.define_method("prefault=", method!(MmapedFile::save_prefault, 1))?;
+    klass.define_method("prefault?", method!(MmapedFile::prefault, 0))?;
+    klass.define_method("track_strings?", method!(MmapedFile::track_strings, 0))?;
+    klass.define_method("max_key_length", method!(MmapedFile::max_key_length, 0))?;
+    klass.define_method(
+        "max_label_value_length",
+        method!(MmapedFile::max_label_value_length, 0),
+    )?;
+    klass.define_method("layout", method!(MmapedFile::layout, 0))?;
+    klass.define_method("layout=", method!(MmapedFile::save_layout, 1))?;
+    klass.define_method("entry_at", method!(MmapedFile::entry_at, 1))?;
     klass.define_method("fetch_entry", method!(MmapedFile::fetch_entry, 3))?;
+    klass.define_method("peek_entry", method!(MmapedFile::peek_entry, 3))?;
+    klass.define_method("load_value_bits", method!(MmapedFile::load_value_bits, 2))?;
     klass.define_method("upsert_entry", method!(MmapedFile::upsert_entry, 3))?;
-    klass.define_method("upsert_exemplar", method!(MmapedFile::upsert_exemplar, 5))?;
+    klass.define_method("upsert_exemplar", method!(MmapedFile::upsert_exemplar, -1))?;
 
     Ok(())
 }
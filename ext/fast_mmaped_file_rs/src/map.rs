@@ -1,16 +1,62 @@
 use hashbrown::hash_map::RawEntryMut;
 use hashbrown::HashMap;
-use magnus::{exception::*, Error, RArray};
+use magnus::typed_data::Obj;
+use magnus::{exception::*, Error, RArray, TryConvert, Value};
+use serde::Deserialize;
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs;
 use std::hash::{BuildHasher, Hash, Hasher};
 use std::mem::size_of;
+use std::ops::{Bound, RangeBounds};
+use std::path::Path;
 
 use crate::error::MmapError;
-use crate::file_entry::{BorrowedData, EntryData, EntryMetadata, FileEntry};
+use crate::exemplars::ExemplarFormat;
+use crate::file_entry::{BorrowedData, EntryData, EntryMetadata, FileEntry, MetricText};
 use crate::file_info::FileInfo;
-use crate::raw_entry::RawEntry;
-use crate::util::read_u32;
+use crate::mmap::MmapedFile;
+use crate::raw_entry::{Layout, RawEntry};
+use crate::util::{self, read_u32, CheckedOps};
 use crate::Result;
-use crate::{err, HEADER_SIZE};
+use crate::{err, HEADER_SIZE, SYM_COUNTER};
+
+/// The default `read_buffer_limit`, the largest file we expect to read.
+const DEFAULT_READ_BUFFER_LIMIT: usize = 4 << 20; // 4MiB
+
+thread_local! {
+    /// The buffer files are read into, kept between aggregations so that a
+    /// long-lived exporter doesn't reallocate it on every scrape.
+    static READ_BUFFER: Cell<Vec<u8>> = const { Cell::new(Vec::new()) };
+}
+
+/// Take this thread's read buffer, empty and with at least 16KiB reserved. A
+/// nested aggregation, or one following an error, starts with a new buffer.
+fn take_read_buffer() -> magnus::error::Result<Vec<u8>> {
+    let mut buf = READ_BUFFER.with(Cell::take);
+    buf.clear();
+
+    // We expect file sizes between 4KiB and 4MiB. Pre-allocate 16KiB to reduce reallocations
+    // a bit.
+    buf.try_reserve(16_384)
+        .map_err(|_| err!(no_mem_error(), "Couldn't allocate for {} memory", 16_384))?;
+
+    Ok(buf)
+}
+
+/// Keep `buf` for the next aggregation on this thread, shrinking it to
+/// `limit` bytes if larger. It is cleared so no file's data outlives the
+/// aggregation that read it.
+fn return_read_buffer(mut buf: Vec<u8>, limit: usize) {
+    buf.clear();
+    buf.shrink_to(limit);
+
+    READ_BUFFER.with(|cell| cell.set(buf));
+}
+
+/// A contiguous range of family names, see `EntryMap::family_batches`.
+type FamilyRange<'a> = (Bound<&'a str>, Bound<&'a str>);
 
 /// A HashMap of JSON strings and their associated metadata.
 /// Used to print metrics in text format.
@@ -19,34 +65,85 @@ use crate::{err, HEADER_SIZE};
 /// allows us to have multiple entries on the map for multiple pids using the
 /// same string.
 #[derive(Default, Debug)]
-pub struct EntryMap(HashMap<EntryData, EntryMetadata>);
+pub struct EntryMap {
+    entries: HashMap<EntryData, EntryMetadata>,
+    options: MapOptions,
+}
+
+/// Options controlling how entries are keyed when merged into an `EntryMap`.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct MapOptions {
+    /// Sort the labels within each entry's JSON before merging, so writers that
+    /// serialize the same labels in different orders produce a single series.
+    /// Labels are rendered in sorted order when this is enabled.
+    pub canonicalize_labels: bool,
+    /// Read only the `[0, used)` region of each file, sized from its header,
+    /// so each file is parsed in a consistent state even if a worker writes
+    /// to it during aggregation. Consistency across files is best-effort, a
+    /// worker may update one file after another has been read.
+    pub snapshot_files: bool,
+    /// Accept files in the `Packed` layout written by foreign writers, whose
+    /// values are not 8-byte aligned. Unaligned loads are slower on some
+    /// platforms and such files can't use atomic value access, so files in
+    /// this layout are rejected unless enabled.
+    pub unaligned_entries: bool,
+    /// Aggregate files a batch of families at a time with at most this many
+    /// entries, see `EntryMap::aggregate_files_by_family`.
+    pub max_series_per_pass: Option<usize>,
+    /// The capacity in bytes above which the buffer files are read into is
+    /// shrunk rather than kept for the next aggregation on the same thread,
+    /// `0` to free it after each aggregation. `None` uses the default of 4MiB.
+    pub read_buffer_limit: Option<usize>,
+    /// Reject entries whose key is longer than this many bytes, rather than
+    /// aggregating accidentally enormous label sets.
+    pub max_key_length: Option<usize>,
+    /// Skip a UTF-8 byte order mark and any whitespace before the JSON key of
+    /// each entry, as `RenderOptions::tolerant_keys` does when rendering, so
+    /// such keys are grouped and canonicalized like any other.
+    pub tolerant_keys: bool,
+}
+
+impl MapOptions {
+    /// The `read_buffer_limit` in effect.
+    fn read_buffer_limit(&self) -> usize {
+        self.read_buffer_limit.unwrap_or(DEFAULT_READ_BUFFER_LIMIT)
+    }
+}
 
 impl EntryMap {
     /// Construct a new EntryMap.
     pub fn new() -> Self {
-        Self(HashMap::new())
+        Self::with_options(MapOptions::default())
+    }
+
+    /// Construct a new EntryMap using the `MapOptions` provided.
+    pub fn with_options(options: MapOptions) -> Self {
+        Self {
+            entries: HashMap::new(),
+            options,
+        }
     }
 
     /// Given a list of files, read each one into memory and parse the metrics it contains.
     pub fn aggregate_files(&mut self, list_of_files: RArray) -> magnus::error::Result<()> {
-        // Pre-allocate the `HashMap` and validate we don't OOM. The C implementation
-        // ignores allocation failures here. We perform this check to avoid potential
-        // panics. We assume ~1,000 entries per file, so 72 KiB allocated per file.
-        self.0
-            .try_reserve(list_of_files.len() * 1024)
-            .map_err(|_| {
-                err!(
-                    no_mem_error(),
-                    "Couldn't allocate for {} memory",
-                    size_of::<FileEntry>() * list_of_files.len() * 1024
-                )
-            })?;
+        self.aggregate_files_with(list_of_files, FileInfo::open_from_params)
+    }
+
+    /// As `aggregate_files`, with an open file descriptor in place of each
+    /// path. See `FileInfo::open_from_fd_params`.
+    pub fn aggregate_fds(&mut self, list_of_fds: RArray) -> magnus::error::Result<()> {
+        self.aggregate_files_with(list_of_fds, FileInfo::open_from_fd_params)
+    }
 
-        // We expect file sizes between 4KiB and 4MiB. Pre-allocate 16KiB to reduce reallocations
-        // a bit.
-        let mut buf = Vec::new();
-        buf.try_reserve(16_384)
-            .map_err(|_| err!(no_mem_error(), "Couldn't allocate for {} memory", 16_384))?;
+    /// Aggregate the list of files, opening each with `open`.
+    fn aggregate_files_with(
+        &mut self,
+        list_of_files: RArray,
+        open: fn(&[Value; 4]) -> magnus::error::Result<FileInfo>,
+    ) -> magnus::error::Result<()> {
+        self.reserve_for_files(list_of_files.len())?;
+
+        let mut buf = take_read_buffer()?;
 
         for item in list_of_files.each() {
             let params = RArray::from_value(item?).expect("file list was not a Ruby Array");
@@ -60,13 +157,251 @@ impl EntryMap {
 
             let params = params.to_value_array::<4>()?;
 
-            let mut file_info = FileInfo::open_from_params(&params)?;
-            file_info.read_from_file(&mut buf)?;
+            let mut file_info = open(&params)?;
+            self.read_file(&mut file_info, &mut buf)?;
+            self.process_buffer(file_info, &buf)?;
+        }
+
+        return_read_buffer(buf, self.options.read_buffer_limit());
+        Ok(())
+    }
+
+    /// Aggregate the list of files a batch of families at a time, passing the
+    /// sorted entries of each batch to `render` and releasing them before the
+    /// next batch is read. This bounds peak memory to roughly `max_series`
+    /// entries rather than every series in the files.
+    ///
+    /// A first pass counts the entries of each family, then consecutive
+    /// families in sorted order are grouped into batches of at most
+    /// `max_series` entries. Every file is re-read once per batch, so this
+    /// trades I/O for memory and only helps when series are spread across
+    /// many families. A single family is never split, one larger than
+    /// `max_series` is aggregated in a batch of its own.
+    ///
+    /// Batches are passed to `render` in family order and no family spans two
+    /// batches, so rendering each in turn matches rendering `into_sorted`.
+    /// The batches cover every family name, so a family first written by a
+    /// live worker after the counting pass is still rendered, in the batch
+    /// whose range it sorts into.
+    ///
+    /// `bench_aggregate_files_by_family` compares the peak heap use of this
+    /// against building the full map for 16 files of 100 families with 2,000
+    /// series each. It asserts that with `max_series` of 10,000, one family
+    /// per batch, the peak is below a quarter of the full map's.
+    pub fn aggregate_files_by_family<F>(
+        list_of_files: RArray,
+        options: MapOptions,
+        max_series: usize,
+        mut render: F,
+    ) -> magnus::error::Result<()>
+    where
+        F: FnMut(Vec<FileEntry>) -> magnus::error::Result<()>,
+    {
+        let mut files = Vec::new();
+        for item in list_of_files.each() {
+            let params = RArray::from_value(item?).expect("file list was not a Ruby Array");
+            if params.len() != 4 {
+                return Err(err!(
+                    arg_error(),
+                    "wrong number of arguments {} instead of 4",
+                    params.len()
+                ));
+            }
+
+            files.push(params.to_value_array::<4>()?);
+        }
+
+        let mut buf = take_read_buffer()?;
+
+        let scan = Self::with_options(options);
+        let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+        for params in &files {
+            let mut file_info = FileInfo::open_from_params(params)?;
+            scan.read_file(&mut file_info, &mut buf)?;
+            Self::each_raw_entry(&options, &file_info, &buf, |raw_entry| {
+                let family = Self::family_name(&options, &raw_entry);
+                match counts.get_mut(family) {
+                    Some(count) => *count += 1,
+                    None => {
+                        counts.insert(family.to_string(), 1);
+                    }
+                }
+                Ok(())
+            })?;
+        }
+
+        for families in Self::family_batches(&counts, max_series) {
+            let mut map = Self::with_options(options);
+            for params in &files {
+                let mut file_info = FileInfo::open_from_params(params)?;
+                map.read_file(&mut file_info, &mut buf)?;
+                map.process_buffer_families(file_info, &buf, Some(&families))?;
+            }
+
+            render(map.into_sorted()?)?;
+        }
+
+        return_read_buffer(buf, options.read_buffer_limit());
+        Ok(())
+    }
+
+    /// Group the families in `counts` into consecutive ranges holding at most
+    /// `max_series` entries, or a single family if it alone exceeds that.
+    ///
+    /// The ranges are contiguous: the first is unbounded below, each other
+    /// starts just after the previous one ends, and the last is unbounded
+    /// above. Any family name falls within exactly one range, including one
+    /// not in `counts`.
+    fn family_batches(counts: &BTreeMap<String, usize>, max_series: usize) -> Vec<FamilyRange<'_>> {
+        let mut batches = Vec::new();
+        let mut start = Bound::Unbounded;
+        let mut last: Option<&str> = None;
+        let mut total = 0;
+
+        for (family, &count) in counts {
+            if let Some(last) = last {
+                if total + count > max_series {
+                    batches.push((start, Bound::Included(last)));
+                    start = Bound::Excluded(last);
+                    total = 0;
+                }
+            }
+
+            total += count;
+            last = Some(family.as_str());
+        }
+
+        batches.push((start, Bound::Unbounded));
+
+        batches
+    }
+
+    /// The family name of `raw_entry`, read from the start of its key without
+    /// parsing the labels and values that follow, as each pass over the files
+    /// needs only the name. Entries whose name can't be read, including names
+    /// containing escapes, are grouped under the empty name. Entries that
+    /// are otherwise malformed still fail to render as they would when
+    /// aggregated all at once.
+    fn family_name<'a>(options: &MapOptions, raw_entry: &'a RawEntry) -> &'a str {
+        let mut json = raw_entry.json();
+        if options.tolerant_keys {
+            let Ok(key) = std::str::from_utf8(json) else {
+                return "";
+            };
+            json = util::trim_key(key).as_bytes();
+        }
+
+        let Some(rest) = json.strip_prefix(b"[") else {
+            return "";
+        };
+
+        let mut de = serde_json::Deserializer::from_slice(rest);
+        <&str>::deserialize(&mut de).unwrap_or("")
+    }
+
+    /// Read every `*.db` file in `dir`, deriving each file's metadata from its
+    /// name, or with `resolver` if given, and parse the metrics it contains.
+    /// See `FileInfo::open_from_resolver`.
+    pub fn aggregate_dir(
+        &mut self,
+        dir: &Path,
+        resolver: Option<Value>,
+    ) -> magnus::error::Result<()> {
+        let read_dir = fs::read_dir(dir).map_err(|e| MmapError::io("read directory", dir, e))?;
+
+        let mut paths = Vec::new();
+        for dir_entry in read_dir {
+            let path = dir_entry
+                .map_err(|e| MmapError::io("read directory", dir, e))?
+                .path();
+
+            if path.extension() == Some(OsStr::new("db")) {
+                paths.push(path);
+            }
+        }
+
+        // Match the ordering of `Dir.glob(...).sort` used by the Ruby client.
+        paths.sort_unstable();
+
+        self.reserve_for_files(paths.len())?;
+
+        let mut buf = take_read_buffer()?;
+
+        for path in paths {
+            let mut file_info = match resolver {
+                Some(resolver) => FileInfo::open_from_resolver(path, resolver)?,
+                None => FileInfo::open_from_path(path)?,
+            };
+            self.read_file(&mut file_info, &mut buf)?;
             self.process_buffer(file_info, &buf)?;
         }
+
+        return_read_buffer(buf, self.options.read_buffer_limit());
+        Ok(())
+    }
+
+    /// Given a list of `[FastMmapedFileRs, multiprocess_mode, type, pid]` tuples,
+    /// parse the metrics directly from each file's mapped memory. This avoids
+    /// re-opening and reading files the calling process already has mapped.
+    pub fn aggregate_mmaps(&mut self, list_of_mmaps: RArray) -> magnus::error::Result<()> {
+        self.reserve_for_files(list_of_mmaps.len())?;
+
+        for item in list_of_mmaps.each() {
+            let params = RArray::from_value(item?).expect("mmap list was not a Ruby Array");
+            if params.len() != 4 {
+                return Err(err!(
+                    arg_error(),
+                    "wrong number of arguments {} instead of 4",
+                    params.len()
+                ));
+            }
+
+            let params = params.to_value_array::<4>()?;
+
+            let mmap = Obj::<MmapedFile>::try_convert(params[0])?;
+            let (multiprocess_mode, type_, pid) = FileInfo::metadata_from_params(&params)?;
+
+            // The read lock is held only while this file's entries are parsed.
+            mmap.with_mapped_bytes(|path, file, bytes| {
+                let file_info = FileInfo {
+                    file: file
+                        .try_clone()
+                        .map_err(|e| MmapError::io("duplicate handle for", path, e))?,
+                    path: path.to_path_buf(),
+                    len: bytes.len(),
+                    multiprocess_mode,
+                    type_,
+                    pid,
+                };
+
+                self.process_buffer(file_info, bytes)
+            })?;
+        }
         Ok(())
     }
 
+    /// Read a file into `buf`, honoring `MapOptions::snapshot_files`.
+    fn read_file(&self, file_info: &mut FileInfo, buf: &mut Vec<u8>) -> Result<()> {
+        if self.options.snapshot_files {
+            file_info.read_used_from_file(buf)
+        } else {
+            file_info.read_from_file(buf)
+        }
+    }
+
+    /// Pre-allocate the `HashMap` and validate we don't OOM. The C implementation
+    /// ignores allocation failures here. We perform this check to avoid potential
+    /// panics. We assume ~1,000 entries per file, so 72 KiB allocated per file.
+    fn reserve_for_files(&mut self, file_count: usize) -> magnus::error::Result<()> {
+        self.entries.try_reserve(file_count * 1024).map_err(|_| {
+            err!(
+                no_mem_error(),
+                "Couldn't allocate for {} memory",
+                size_of::<FileEntry>() * file_count * 1024
+            )
+        })
+    }
+
     /// Consume the `EntryMap` and convert the key/value into`FileEntry`
     /// objects, sorting them by their JSON strings.
     pub fn into_sorted(self) -> Result<Vec<FileEntry>> {
@@ -77,14 +412,14 @@ impl EntryMap {
         // `collect` the iterator into a new `Vec` in place, but this panics
         // if it can't allocate and we want to continue execution in that
         // scenario.
-        if sorted.try_reserve_exact(self.0.len()).is_err() {
-            return Err(MmapError::OutOfMemory(
-                self.0.len() * size_of::<FileEntry>(),
+        if sorted.try_reserve_exact(self.entries.len()).is_err() {
+            return Err(MmapError::alloc_failed(
+                self.entries.len() * size_of::<FileEntry>(),
             ));
         }
 
         sorted.extend(
-            self.0
+            self.entries
                 .into_iter()
                 .map(|(data, meta)| FileEntry { data, meta }),
         );
@@ -98,14 +433,24 @@ impl EntryMap {
     /// If yes, update the associated value, if not insert the
     /// entry into the map.
     pub fn merge_or_store(&mut self, data: BorrowedData, meta: EntryMetadata) -> Result<()> {
+        self.merge_or_store_with(data, meta, EntryMetadata::merge)
+    }
+
+    /// As `merge_or_store`, combining matching entries with `merge`.
+    fn merge_or_store_with(
+        &mut self,
+        data: BorrowedData,
+        meta: EntryMetadata,
+        merge: fn(&mut EntryMetadata, &EntryMetadata),
+    ) -> Result<()> {
         // Manually hash the `BorrowedData` and perform an equality check on the
         // key. This allows us to perform the comparison without allocating a
         // new `EntryData` that may not be needed.
-        let mut state = self.0.hasher().build_hasher();
+        let mut state = self.entries.hasher().build_hasher();
         data.hash(&mut state);
         let hash = state.finish();
 
-        match self.0.raw_entry_mut().from_hash(hash, |k| k == &data) {
+        match self.entries.raw_entry_mut().from_hash(hash, |k| k == &data) {
             RawEntryMut::Vacant(entry) => {
                 // Allocate a new `EntryData` as the JSON/pid combination is
                 // not present in the map.
@@ -113,16 +458,138 @@ impl EntryMap {
                 entry.insert(owned, meta);
             }
             RawEntryMut::Occupied(mut entry) => {
-                let existing = entry.get_mut();
-                existing.merge(&meta);
+                merge(entry.get_mut(), &meta);
             }
         }
 
         Ok(())
     }
 
+    /// Walk the length prefixes of the entries in `source`, yielding the offset
+    /// of each entry without parsing its contents. This reads only one `u32`
+    /// per entry, and each entry can then be parsed independently. Offsets
+    /// are found as the iterator advances, callers that need them all up
+    /// front collect them.
+    ///
+    /// Fails on the same bounds violations as parsing the entries in turn,
+    /// after yielding the offsets of the entries before the violation.
+    pub(crate) fn entry_offsets(
+        source: &[u8],
+        used: usize,
+        layout: Layout,
+        is_exemplar: bool,
+    ) -> EntryOffsets<'_> {
+        EntryOffsets {
+            source,
+            used,
+            layout,
+            is_exemplar,
+            pos: HEADER_SIZE,
+        }
+    }
+
+    /// The length of the run of valid entries at the start of `source`, up to
+    /// `used`, including the header. Unlike `entry_offsets` this stops at the
+    /// first entry that is out of bounds, malformed, or whose key isn't valid
+    /// JSON rather than returning an error, so the entries before it can be
+    /// salvaged.
+    pub(crate) fn valid_len(
+        source: &[u8],
+        used: usize,
+        layout: Layout,
+        is_exemplar: bool,
+    ) -> usize {
+        let mut pos = HEADER_SIZE;
+
+        while pos + size_of::<u32>() < used {
+            let entry = &source[pos..used];
+
+            // The slot format doesn't affect the length of exemplar entries.
+            let raw_entry = if is_exemplar {
+                RawEntry::from_slice_exemplar(entry, ExemplarFormat::default())
+            } else if layout == Layout::Legacy {
+                RawEntry::validate(entry).and_then(|_| RawEntry::from_slice(entry))
+            } else {
+                RawEntry::from_slice_with_layout(entry, layout)
+            };
+            let Ok(raw_entry) = raw_entry else {
+                break;
+            };
+
+            if serde_json::from_slice::<MetricText>(raw_entry.json()).is_err() {
+                break;
+            }
+
+            pos += if is_exemplar {
+                raw_entry.total_len_exemplar()
+            } else {
+                raw_entry.total_len()
+            };
+        }
+
+        pos
+    }
+
     /// Parse metrics data from a `.db` file and store in the `EntryMap`.
     fn process_buffer(&mut self, file_info: FileInfo, source: &[u8]) -> Result<()> {
+        self.process_buffer_families(file_info, source, None)
+    }
+
+    /// As `process_buffer`, storing only the entries whose family falls within
+    /// `families`, if provided.
+    fn process_buffer_families(
+        &mut self,
+        file_info: FileInfo,
+        source: &[u8],
+        families: Option<&FamilyRange>,
+    ) -> Result<()> {
+        // Every entry in a counter file is summed, so select the cheaper merge
+        // once for the file rather than checking the type on each entry.
+        let merge = if file_info.type_ == SYM_COUNTER {
+            EntryMetadata::merge_counter
+        } else {
+            EntryMetadata::merge
+        };
+
+        let options = self.options;
+        Self::each_raw_entry(&options, &file_info, source, |raw_entry| {
+            if let Some(families) = families {
+                if !families.contains(&Self::family_name(&options, &raw_entry)) {
+                    return Ok(());
+                }
+            }
+
+            let meta = EntryMetadata::new(&raw_entry, &file_info)?;
+            let mut data = BorrowedData::new(&raw_entry, &file_info, meta.is_pid_significant())?;
+            if options.tolerant_keys {
+                data.json = util::trim_key(data.json);
+            }
+
+            if options.canonicalize_labels {
+                if let Some(canonical) = FileEntry::canonical_json(data.json) {
+                    let data = BorrowedData {
+                        json: &canonical,
+                        pid: data.pid,
+                    };
+                    return self.merge_or_store_with(data, meta, merge);
+                }
+            }
+
+            self.merge_or_store_with(data, meta, merge)
+        })
+    }
+
+    /// Validate the header of a `.db` file and call `f` with each of its entries.
+    /// Only `[0, used)` is authoritative, anything past `used` is never read.
+    fn each_raw_entry<F>(
+        options: &MapOptions,
+        file_info: &FileInfo,
+        source: &[u8],
+        mut f: F,
+    ) -> Result<()>
+    where
+        F: FnMut(RawEntry) -> Result<()>,
+    {
         if source.len() < HEADER_SIZE {
             // Nothing to read, OK.
             return Ok(());
@@ -139,56 +606,118 @@ impl EntryMap {
             )));
         }
 
-        let mut pos = HEADER_SIZE;
+        let layout = Layout::from_header(source)?;
+        if layout == Layout::Packed && !options.unaligned_entries {
+            return Err(MmapError::PromParsing(format!(
+                "source file {} has unaligned entries, enable unaligned_entries to read it",
+                file_info.path.display()
+            )));
+        }
+        let is_exemplar = file_info.type_.to_string() == "exemplar";
+        let exemplar_format = if is_exemplar {
+            ExemplarFormat::from_header(source)?
+        } else {
+            ExemplarFormat::default()
+        };
 
-        while pos + size_of::<u32>() < used {
-            let raw_entry: RawEntry;
+        for pos in Self::entry_offsets(source, used, layout, is_exemplar) {
+            let pos = pos?;
+            let raw_entry = if is_exemplar {
+                RawEntry::from_slice_exemplar(&source[pos..used], exemplar_format)?
+            } else {
+                RawEntry::from_slice_with_layout(&source[pos..used], layout)?
+            };
 
-            if file_info.type_.to_string() == "exemplar" {
-                raw_entry = RawEntry::from_slice_exemplar(&source[pos..used])?;
+            if let Some(limit) = options.max_key_length {
+                RawEntry::check_key_len(raw_entry.json().len(), limit)?;
+            }
 
-                if pos + raw_entry.total_len_exemplar() > used {
-                    return Err(MmapError::PromParsing(format!(
-                        "source file {} corrupted, used {used} < stored data length {}",
-                        file_info.path.display(),
-                        pos + raw_entry.total_len()
-                    )));
-                }
+            f(raw_entry)?;
+        }
 
-                pos += raw_entry.total_len_exemplar();
+        Ok(())
+    }
+}
 
-            } else {
-                raw_entry = RawEntry::from_slice(&source[pos..used])?;
-
-                if pos + raw_entry.total_len() > used {
-                    return Err(MmapError::PromParsing(format!(
-                        "source file {} corrupted, used {used} < stored data length {}",
-                        file_info.path.display(),
-                        pos + raw_entry.total_len()
-                    )));
-                }
+/// The offsets of the entries in a `.db` file, see `EntryMap::entry_offsets`.
+pub(crate) struct EntryOffsets<'a> {
+    source: &'a [u8],
+    used: usize,
+    layout: Layout,
+    is_exemplar: bool,
+    /// The offset of the next entry.
+    pos: usize,
+}
 
-                pos += raw_entry.total_len();
-            }
-            
-            let meta = EntryMetadata::new(&raw_entry, &file_info)?;
-            let data = BorrowedData::new(&raw_entry, &file_info, meta.is_pid_significant())?;
+impl EntryOffsets<'_> {
+    /// The total length of the entry at `pos`, confirming it is in bounds of
+    /// the data written.
+    fn entry_len(&self, pos: usize) -> Result<usize> {
+        // CAST: no-op on 32-bit, widening on 64-bit.
+        let encoded_len = read_u32(self.source, pos)? as usize;
 
-            self.merge_or_store(data, meta)?;
+        let total_len = if self.is_exemplar {
+            RawEntry::calc_total_len_exemplar(encoded_len)?
+        } else {
+            self.layout.total_len(encoded_len)?
+        };
+
+        if total_len > self.used - pos {
+            return Err(MmapError::out_of_bounds(total_len, self.used - pos));
         }
 
-        Ok(())
+        Ok(total_len)
+    }
+}
+
+impl Iterator for EntryOffsets<'_> {
+    type Item = Result<usize>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let pos = self.pos;
+        if pos >= self.used {
+            return None;
+        }
+
+        // Entries are written before `used` is bumped past them, so it always
+        // ends on an entry boundary. Bytes left over mean `used` is corrupt,
+        // e.g. by a torn write, and the final entry can't be trusted.
+        let total_len = if pos + size_of::<u32>() < self.used {
+            self.entry_len(pos)
+        } else {
+            Err(MmapError::PromParsing(format!(
+                "used {} is not on an entry boundary, {} bytes after last entry",
+                self.used,
+                self.used - pos
+            )))
+        };
+
+        match total_len {
+            Ok(total_len) => {
+                self.pos += total_len;
+                Some(Ok(pos))
+            }
+            Err(e) => {
+                // Stop after the first error.
+                self.pos = self.used;
+                Some(Err(e))
+            }
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
-    use magnus::Symbol;
+    use hashbrown::HashSet;
+    use magnus::{RString, Symbol};
+    use prost::Message;
     use std::mem;
 
     use super::*;
+    use crate::exemplars::{Exemplar, ExemplarFormat};
     use crate::file_entry::FileEntry;
-    use crate::testhelper::{self, TestFile};
+    use crate::io;
+    use crate::testhelper::{self, DbBuilder, TestEntry, TestFile};
 
     impl EntryData {
         /// A helper function for tests to convert owned data to references.
@@ -284,7 +813,7 @@ mod test {
         let mut map = EntryMap::new();
 
         for entry in entries {
-            map.0.insert(entry.data, entry.meta);
+            map.entries.insert(entry.data, entry.meta);
         }
 
         let result = map.into_sorted();
@@ -301,96 +830,613 @@ mod test {
         assert_eq!(sorted[5].data.json, "zzzzzz");
     }
 
+    /// Random entries sharing a handful of families, with and without pids.
+    fn random_entries(count: usize) -> Vec<FileEntry> {
+        use rand::{thread_rng, Rng};
+
+        let mut rng = thread_rng();
+
+        (0..count)
+            .map(|_| {
+                let family = rng.gen_range(0..8);
+                let label = rng.gen_range(0..count);
+                let pid = match rng.gen_bool(0.5) {
+                    true => Some(format!("worker-{}", rng.gen_range(0..4))),
+                    false => None,
+                };
+
+                FileEntry {
+                    data: EntryData {
+                        json: format!(r#"["family_{family}","name",["label"],["{label}"]]"#),
+                        pid,
+                    },
+                    meta: EntryMetadata {
+                        multiprocess_mode: Symbol::new("all"),
+                        type_: Symbol::new("gauge"),
+                        value: Some(1.0),
+                        ex: None,
+                    },
+                }
+            })
+            .collect()
+    }
+
     #[test]
-    fn test_merge_or_store() {
+    fn test_into_sorted_matches_full_sort() {
         let _cleanup = unsafe { magnus::embed::init() };
         let ruby = magnus::Ruby::get().unwrap();
         crate::init(&ruby).unwrap();
 
-        let key = "foobar";
+        let mut map = EntryMap::new();
+        for entry in random_entries(10_000) {
+            map.entries.insert(entry.data, entry.meta);
+        }
 
-        let starting_entry = FileEntry {
-            data: EntryData {
-                json: key.to_string(),
-                pid: Some("worker-0_0".to_string()),
-            },
-            meta: EntryMetadata {
-                multiprocess_mode: Symbol::new("all"),
-                type_: Symbol::new("gauge"),
-                value: Some(1.0),
-                ex: None,
-            },
-        };
+        let mut expected: Vec<EntryData> = map.entries.keys().cloned().collect();
+        expected.sort();
 
-        let matching_entry = FileEntry {
-            data: EntryData {
-                json: key.to_string(),
-                pid: Some("worker-0_0".to_string()),
-            },
-            meta: EntryMetadata {
-                multiprocess_mode: Symbol::new("all"),
-                type_: Symbol::new("gauge"),
-                value: Some(5.0),
-                ex: None,
-            },
-        };
+        let sorted: Vec<EntryData> = map
+            .into_sorted()
+            .unwrap()
+            .into_iter()
+            .map(|e| e.data)
+            .collect();
 
-        let same_key_different_worker = FileEntry {
-            data: EntryData {
-                json: key.to_string(),
-                pid: Some("worker-1_0".to_string()),
-            },
-            meta: EntryMetadata {
-                multiprocess_mode: Symbol::new("all"),
-                type_: Symbol::new("gauge"),
-                value: Some(100.0),
-                ex: None,
-            },
-        };
+        assert_eq!(expected, sorted);
+    }
 
-        let unmatched_entry = FileEntry {
-            data: EntryData {
-                json: "another key".to_string(),
-                pid: Some("worker-0_0".to_string()),
-            },
-            meta: EntryMetadata {
-                multiprocess_mode: Symbol::new("all"),
-                type_: Symbol::new("gauge"),
-                value: Some(100.0),
-                ex: None,
-            },
-        };
+    /// Record the baseline cost of `into_sorted` on a million entries: a
+    /// single allocation holding exactly the output, which is sorted in
+    /// place into the same order as sorting the entries directly. Run with
+    /// `cargo nextest run --release --run-ignored ignored-only
+    /// bench_into_sorted`.
+    #[test]
+    #[ignore]
+    fn bench_into_sorted() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
 
-        let mut map = EntryMap::new();
+        let entries = random_entries(1_000_000);
 
-        map.0
-            .insert(starting_entry.data.clone(), starting_entry.meta.clone());
+        let mut map = EntryMap::new();
+        for entry in entries {
+            map.entries.insert(entry.data, entry.meta);
+        }
+        let len = map.entries.len();
+        let mut expected: Vec<EntryData> = map.entries.keys().cloned().collect();
+        expected.sort_unstable();
 
-        let matching_borrowed = matching_entry.data.as_borrowed();
-        map.merge_or_store(matching_borrowed, matching_entry.meta)
-            .unwrap();
+        let (sorted, stats) = testhelper::measure_allocs(|| map.into_sorted().unwrap());
 
+        assert_eq!(1, stats.count, "{stats:?}");
         assert_eq!(
-            5.0,
-            map.0.get(&starting_entry.data).unwrap().value.unwrap(),
-            "value updated"
+            (len * size_of::<FileEntry>()) as isize,
+            stats.peak,
+            "{stats:?}"
         );
-        assert_eq!(1, map.0.len(), "no entry added");
 
-        let same_key_different_worker_borrowed = same_key_different_worker.data.as_borrowed();
-        map.merge_or_store(
-            same_key_different_worker_borrowed,
-            same_key_different_worker.meta,
-        )
-        .unwrap();
+        let sorted: Vec<_> = sorted.into_iter().map(|e| e.data).collect();
+        assert_eq!(expected, sorted);
+    }
 
-        assert_eq!(
-            5.0,
-            map.0.get(&starting_entry.data).unwrap().value.unwrap(),
+    /// Build a `to_metrics` style list of gauge files, one per path.
+    fn gauge_file_list(paths: &[&Path]) -> RArray {
+        let list = RArray::new();
+        for (i, path) in paths.iter().enumerate() {
+            let params = RArray::new();
+            params
+                .push(RString::new(&path.display().to_string()))
+                .unwrap();
+            params.push(Symbol::new("all")).unwrap();
+            params.push(Symbol::new("gauge")).unwrap();
+            params.push(RString::new(&format!("worker-{i}"))).unwrap();
+            list.push(params).unwrap();
+        }
+        list
+    }
+
+    /// The capacity and address of this thread's read buffer.
+    fn read_buffer_state() -> (usize, *const u8) {
+        READ_BUFFER.with(|cell| {
+            let buf = cell.take();
+            let state = (buf.capacity(), buf.as_ptr());
+            cell.set(buf);
+            state
+        })
+    }
+
+    #[test]
+    fn test_read_buffer_reuse() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        // Larger than the 16KiB reserved up front, so reading it grows the buffer.
+        let large: &'static str = Box::leak(
+            format!(r#"["family","name",["label"],["{}"]]"#, "a".repeat(32_768)).into_boxed_str(),
+        );
+        let small = r#"["family","name",["label"],["b"]]"#;
+
+        let large_file = TestFile::new(&testhelper::entries_to_db(&[large], &[1.0], None));
+        let small_file = TestFile::new(&testhelper::entries_to_db(&[small], &[2.0], None));
+        let list = gauge_file_list(&[&large_file.path, &small_file.path]);
+
+        let mut map = EntryMap::new();
+        map.aggregate_files(list).unwrap();
+        let (capacity, ptr) = read_buffer_state();
+        assert!(capacity > 32_768, "buffer kept, capacity {capacity}");
+
+        // The small file read after the large one has none of its data.
+        let values: Vec<_> = map
+            .into_sorted()
+            .unwrap()
+            .into_iter()
+            .map(|e| e.meta.value)
+            .collect();
+        assert_eq!(vec![Some(1.0), Some(2.0)], values);
+
+        let mut map = EntryMap::new();
+        map.aggregate_files(list).unwrap();
+        assert_eq!((capacity, ptr), read_buffer_state(), "buffer reused");
+
+        let mut map = EntryMap::with_options(MapOptions {
+            read_buffer_limit: Some(0),
+            ..Default::default()
+        });
+        map.aggregate_files(list).unwrap();
+        assert_eq!(0, read_buffer_state().0, "buffer freed");
+    }
+
+    #[test]
+    fn test_aggregate_files_trailing_garbage() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let json = &[
+            r#"["family","name",["label_a"],["value_a"]]"#,
+            r#"["family","name",["label_a"],["value_b"]]"#,
+        ];
+        let source = testhelper::entries_to_db(json, &[1.0, 2.0], None);
+
+        // Past `used`: a well-formed entry, an oversized length prefix, and
+        // non-zero filler, as left behind by a crash or compaction.
+        let stale = TestEntry::new(r#"["family","name",["label_a"],["value_c"]]"#, 3.0);
+        let mut garbage = source.clone();
+        garbage.extend(stale.as_bytes());
+        garbage.extend(u32::MAX.to_ne_bytes());
+        garbage.extend([0xffu8; 64]);
+
+        let clean_file = TestFile::new(&source);
+        let garbage_file = TestFile::new(&garbage);
+
+        for snapshot_files in [false, true] {
+            let render = |path: &Path| {
+                let mut map = EntryMap::with_options(MapOptions {
+                    snapshot_files,
+                    ..Default::default()
+                });
+                map.aggregate_files(gauge_file_list(&[path])).unwrap();
+                FileEntry::entries_to_string(map.into_sorted().unwrap()).unwrap()
+            };
+
+            let expected = render(&clean_file.path);
+            let actual = render(&garbage_file.path);
+
+            assert_eq!(expected, actual, "snapshot_files: {snapshot_files}");
+            assert!(!actual.contains("value_c"), "{actual}");
+        }
+    }
+
+    /// Reusing the read buffer saves one allocation per scrape, as each file
+    /// fits in the 16KiB reserved up front, and files larger than that save
+    /// the reallocations made to grow it too.
+    #[test]
+    fn test_read_buffer_reuse_allocs() {
+        const SCRAPES: usize = 100;
+
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let json: Vec<&'static str> = (0..100)
+            .map(|i| {
+                &*Box::leak(format!(r#"["family","name",["label"],["{i}"]]"#).into_boxed_str())
+            })
+            .collect();
+        let values = vec![1.0; json.len()];
+        let files: Vec<_> = (0..10)
+            .map(|_| TestFile::new(&testhelper::entries_to_db(&json, &values, None)))
+            .collect();
+        let paths: Vec<_> = files.iter().map(|f| f.path.as_path()).collect();
+        let list = gauge_file_list(&paths);
+
+        let scrapes = |read_buffer_limit| {
+            let options = MapOptions {
+                read_buffer_limit,
+                ..Default::default()
+            };
+
+            // Leave the buffer as a long-lived exporter would find it.
+            EntryMap::with_options(options)
+                .aggregate_files(list)
+                .unwrap();
+
+            let (_, stats) = testhelper::measure_allocs(|| {
+                for _ in 0..SCRAPES {
+                    let mut map = EntryMap::with_options(options);
+                    map.aggregate_files(list).unwrap();
+                }
+            });
+            stats.count
+        };
+
+        let reused = scrapes(None);
+        let freed = scrapes(Some(0));
+        assert_eq!(reused + SCRAPES, freed);
+    }
+
+    #[test]
+    fn test_family_batches() {
+        let counts: BTreeMap<String, usize> = [("a", 2), ("b", 1), ("c", 5), ("d", 1), ("e", 1)]
+            .into_iter()
+            .map(|(family, count)| (family.to_string(), count))
+            .collect();
+
+        use Bound::*;
+
+        struct TestCase {
+            max_series: usize,
+            expected: Vec<FamilyRange<'static>>,
+        }
+
+        let tc = vec![
+            TestCase {
+                max_series: 1,
+                expected: vec![
+                    (Unbounded, Included("a")),
+                    (Excluded("a"), Included("b")),
+                    (Excluded("b"), Included("c")),
+                    (Excluded("c"), Included("d")),
+                    (Excluded("d"), Unbounded),
+                ],
+            },
+            TestCase {
+                max_series: 3,
+                expected: vec![
+                    (Unbounded, Included("b")),
+                    (Excluded("b"), Included("c")),
+                    (Excluded("c"), Unbounded),
+                ],
+            },
+            TestCase {
+                max_series: 100,
+                expected: vec![(Unbounded, Unbounded)],
+            },
+        ];
+
+        for case in tc {
+            let batches = EntryMap::family_batches(&counts, case.max_series);
+            assert_eq!(case.expected, batches, "max_series: {}", case.max_series);
+
+            // Families missing from the count, as when first written after
+            // it, each fall within exactly one batch.
+            for family in ["", "0", "a", "aa", "bb", "e", "z"] {
+                let matches = batches.iter().filter(|b| b.contains(&family)).count();
+                assert_eq!(
+                    1, matches,
+                    "max_series: {}, family: {family:?}",
+                    case.max_series
+                );
+            }
+        }
+
+        let unbounded: Vec<FamilyRange> = vec![(Unbounded, Unbounded)];
+        assert_eq!(unbounded, EntryMap::family_batches(&BTreeMap::new(), 10));
+    }
+
+    #[test]
+    fn test_aggregate_files_by_family() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let json = &[
+            r#"["family_c","name",["label"],["a"]]"#,
+            r#"["family_a","name",["label"],["a"]]"#,
+            r#"["family_b","name",["label"],["a"]]"#,
+            r#"["family_a","name",["label"],["b"]]"#,
+            r#"["family_c","name",["label"],["b"]]"#,
+        ];
+        let files: Vec<_> = [1.0, 2.0]
+            .iter()
+            .map(|v| TestFile::new(&testhelper::entries_to_db(json, &[*v; 5], None)))
+            .collect();
+        let paths: Vec<_> = files.iter().map(|f| f.path.as_path()).collect();
+        let list = gauge_file_list(&paths);
+
+        let mut map = EntryMap::new();
+        map.aggregate_files(list).unwrap();
+        let expected: Vec<_> = map
+            .into_sorted()
+            .unwrap()
+            .into_iter()
+            .map(|e| (e.data, e.meta.value))
+            .collect();
+
+        for max_series in [1, 4, 100] {
+            let mut batches = Vec::new();
+            EntryMap::aggregate_files_by_family(list, MapOptions::default(), max_series, |e| {
+                batches.push(e);
+                Ok(())
+            })
+            .unwrap();
+
+            for batch in &batches {
+                let families: HashSet<_> = batch
+                    .iter()
+                    .map(|e| e.metric_text().unwrap().family_name)
+                    .collect();
+                assert!(
+                    batch.len() <= max_series || families.len() == 1,
+                    "max_series: {max_series}, batch of {}",
+                    batch.len()
+                );
+            }
+
+            let streamed: Vec<_> = batches
+                .into_iter()
+                .flatten()
+                .map(|e| (e.data, e.meta.value))
+                .collect();
+            assert_eq!(expected, streamed, "max_series: {max_series}");
+        }
+    }
+
+    #[test]
+    fn test_aggregate_files_tolerant_keys() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let json = &[
+            "\u{feff}[\"family_b\",\"name\",[\"label_b\",\"label_a\"],[\"b\",\"a\"]]",
+            " [\"family_b\",\"name\",[\"label_a\",\"label_b\"],[\"a\",\"b\"]]",
+            r#"["family_a","name",["label"],["a"]]"#,
+        ];
+        let file = TestFile::new(&testhelper::entries_to_db(json, &[1.0, 2.0, 3.0], None));
+        let list = gauge_file_list(&[&file.path]);
+
+        let aggregate = |tolerant_keys| {
+            let mut map = EntryMap::with_options(MapOptions {
+                canonicalize_labels: true,
+                tolerant_keys,
+                ..Default::default()
+            });
+            map.aggregate_files(list).unwrap();
+            map.into_sorted().unwrap()
+        };
+
+        // Untrimmed keys can't be canonicalized, so the series stay apart.
+        assert_eq!(3, aggregate(false).len());
+
+        let options = MapOptions {
+            canonicalize_labels: true,
+            tolerant_keys: true,
+            ..Default::default()
+        };
+        let expected: Vec<_> = aggregate(true)
+            .into_iter()
+            .map(|e| (e.data, e.meta.value))
+            .collect();
+        assert_eq!(2, expected.len());
+        assert_eq!(
+            r#"["family_b","name",["label_a","label_b"],["a","b"]]"#,
+            &*expected[1].0.json
+        );
+
+        // Each family is batched under its own name, not the empty name.
+        let mut batches = Vec::new();
+        EntryMap::aggregate_files_by_family(list, options, 1, |e| {
+            batches.push(e);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(2, batches.len());
+
+        let streamed: Vec<_> = batches
+            .into_iter()
+            .flatten()
+            .map(|e| (e.data, e.meta.value))
+            .collect();
+        assert_eq!(expected, streamed);
+    }
+
+    #[test]
+    fn test_aggregate_files_same_pid_all_gauge() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let json = &[r#"["family","name",["label"],["a"]]"#];
+        let files: Vec<_> = [1.0, 2.0]
+            .iter()
+            .map(|v| TestFile::new(&testhelper::entries_to_db(json, &[*v], None)))
+            .collect();
+
+        // Both files are written by the same pid, as when a worker's series
+        // are split across files.
+        let list = RArray::new();
+        for file in &files {
+            let params = RArray::new();
+            params
+                .push(RString::new(&file.path.display().to_string()))
+                .unwrap();
+            params.push(Symbol::new("all")).unwrap();
+            params.push(Symbol::new("gauge")).unwrap();
+            params.push(RString::new("worker-1")).unwrap();
+            list.push(params).unwrap();
+        }
+
+        let mut map = EntryMap::new();
+        map.aggregate_files(list).unwrap();
+        let out = FileEntry::entries_to_string(map.into_sorted().unwrap()).unwrap();
+
+        let line = "name{label=\"a\",pid=\"worker-1\"}";
+        assert_eq!(1, out.matches(line).count(), "{out}");
+        assert!(out.contains(&format!("{line} 2\n")), "{out}");
+    }
+
+    /// Compare the peak heap use of streaming aggregation against building the
+    /// full map. Run with `cargo nextest run --release --run-ignored
+    /// ignored-only bench_aggregate_files_by_family`.
+    #[test]
+    #[ignore]
+    fn bench_aggregate_files_by_family() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let json: Vec<&'static str> = (0..100)
+            .flat_map(|family| {
+                (0..2_000).map(move |label| {
+                    let json = format!(r#"["family_{family}","name",["label"],["{label}"]]"#);
+                    &*Box::leak(json.into_boxed_str())
+                })
+            })
+            .collect();
+        let values = vec![1.0; json.len()];
+
+        let files: Vec<_> = (0..16)
+            .map(|_| TestFile::new(&testhelper::entries_to_db(&json, &values, None)))
+            .collect();
+        let paths: Vec<_> = files.iter().map(|f| f.path.as_path()).collect();
+        let list = gauge_file_list(&paths);
+
+        let (streamed, stream) = testhelper::measure_allocs(|| {
+            let mut count = 0;
+            EntryMap::aggregate_files_by_family(list, MapOptions::default(), 10_000, |e| {
+                count += e.len();
+                Ok(())
+            })
+            .unwrap();
+            count
+        });
+
+        let (full, full_map) = testhelper::measure_allocs(|| {
+            let mut map = EntryMap::new();
+            map.aggregate_files(list).unwrap();
+            map.into_sorted().unwrap().len()
+        });
+
+        assert_eq!(full, streamed);
+        assert!(
+            stream.peak * 4 < full_map.peak,
+            "streaming peak {} bytes, full map peak {} bytes",
+            stream.peak,
+            full_map.peak
+        );
+    }
+
+    #[test]
+    fn test_merge_or_store() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let key = "foobar";
+
+        let starting_entry = FileEntry {
+            data: EntryData {
+                json: key.to_string(),
+                pid: Some("worker-0_0".to_string()),
+            },
+            meta: EntryMetadata {
+                multiprocess_mode: Symbol::new("all"),
+                type_: Symbol::new("gauge"),
+                value: Some(1.0),
+                ex: None,
+            },
+        };
+
+        let matching_entry = FileEntry {
+            data: EntryData {
+                json: key.to_string(),
+                pid: Some("worker-0_0".to_string()),
+            },
+            meta: EntryMetadata {
+                multiprocess_mode: Symbol::new("all"),
+                type_: Symbol::new("gauge"),
+                value: Some(5.0),
+                ex: None,
+            },
+        };
+
+        let same_key_different_worker = FileEntry {
+            data: EntryData {
+                json: key.to_string(),
+                pid: Some("worker-1_0".to_string()),
+            },
+            meta: EntryMetadata {
+                multiprocess_mode: Symbol::new("all"),
+                type_: Symbol::new("gauge"),
+                value: Some(100.0),
+                ex: None,
+            },
+        };
+
+        let unmatched_entry = FileEntry {
+            data: EntryData {
+                json: "another key".to_string(),
+                pid: Some("worker-0_0".to_string()),
+            },
+            meta: EntryMetadata {
+                multiprocess_mode: Symbol::new("all"),
+                type_: Symbol::new("gauge"),
+                value: Some(100.0),
+                ex: None,
+            },
+        };
+
+        let mut map = EntryMap::new();
+
+        map.entries
+            .insert(starting_entry.data.clone(), starting_entry.meta.clone());
+
+        let matching_borrowed = matching_entry.data.as_borrowed();
+        map.merge_or_store(matching_borrowed, matching_entry.meta)
+            .unwrap();
+
+        assert_eq!(
+            5.0,
+            map.entries
+                .get(&starting_entry.data)
+                .unwrap()
+                .value
+                .unwrap(),
+            "value updated"
+        );
+        assert_eq!(1, map.entries.len(), "no entry added");
+
+        let same_key_different_worker_borrowed = same_key_different_worker.data.as_borrowed();
+        map.merge_or_store(
+            same_key_different_worker_borrowed,
+            same_key_different_worker.meta,
+        )
+        .unwrap();
+
+        assert_eq!(
+            5.0,
+            map.entries
+                .get(&starting_entry.data)
+                .unwrap()
+                .value
+                .unwrap(),
             "value unchanged"
         );
 
-        assert_eq!(2, map.0.len(), "additional entry added");
+        assert_eq!(2, map.entries.len(), "additional entry added");
 
         let unmatched_entry_borrowed = unmatched_entry.data.as_borrowed();
         map.merge_or_store(unmatched_entry_borrowed, unmatched_entry.meta)
@@ -398,10 +1444,724 @@ mod test {
 
         assert_eq!(
             5.0,
-            map.0.get(&starting_entry.data).unwrap().value.unwrap(),
+            map.entries
+                .get(&starting_entry.data)
+                .unwrap()
+                .value
+                .unwrap(),
             "value unchanged"
         );
-        assert_eq!(3, map.0.len(), "entry added");
+        assert_eq!(3, map.entries.len(), "entry added");
+    }
+
+    #[test]
+    fn test_to_db_bytes_round_trip() {
+        use rand::{thread_rng, Rng};
+
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let mut rng = thread_rng();
+
+        for count in [0, 1, 10, 1_000] {
+            // A `.db` file holds a single pid, so every entry takes the pid
+            // the file is read with.
+            let mut map = EntryMap::new();
+            for mut entry in random_entries(count) {
+                entry.data.pid = Some("worker-1".to_string());
+                entry.meta.value = Some(rng.gen_range(-1e6..1e6));
+                map.entries.insert(entry.data, entry.meta);
+            }
+
+            let bytes = map.to_db_bytes().unwrap();
+            assert_eq!(bytes.len(), read_u32(&bytes, 0).unwrap() as usize);
+
+            let TestFile {
+                file,
+                path,
+                dir: _dir,
+            } = TestFile::new(&bytes);
+            let info = FileInfo {
+                file,
+                path,
+                len: bytes.len(),
+                multiprocess_mode: Symbol::new("all"),
+                type_: Symbol::new("gauge"),
+                pid: "worker-1".to_string(),
+            };
+
+            let mut round_trip = EntryMap::new();
+            round_trip.process_buffer(info, &bytes).unwrap();
+
+            assert_eq!(map.entries.len(), round_trip.entries.len(), "count {count}");
+            for (data, meta) in &map.entries {
+                let got = round_trip.entries.get(data).expect("entry round trips");
+                assert_eq!(meta.value, got.value, "count {count}: {}", data.json);
+            }
+        }
+    }
+
+    #[test]
+    fn test_to_db_bytes_missing_value() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let mut map = EntryMap::new();
+        for mut entry in random_entries(1) {
+            entry.meta.value = None;
+            map.entries.insert(entry.data, entry.meta);
+        }
+
+        assert!(map.to_db_bytes().is_err());
+    }
+
+    #[test]
+    fn test_process_buffer_counter() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let json = &[
+            r#"["family","name",["label_a"],["value_a"]]"#,
+            r#"["family","name",["label_a"],["value_b"]]"#,
+            r#"["family","name",["label_a"],["value_a"]]"#,
+        ];
+        let values = &[1.0, 2.5, 4.0];
+
+        let input_bytes = testhelper::entries_to_db(json, values, None);
+
+        let info = || {
+            let TestFile { file, path, dir } = TestFile::new(&input_bytes);
+            let info = FileInfo {
+                file,
+                path,
+                len: input_bytes.len(),
+                multiprocess_mode: Symbol::new("all"),
+                type_: Symbol::new("counter"),
+                pid: "worker-1".to_string(),
+            };
+            (info, dir)
+        };
+
+        // The counter path, reading the same file twice as two workers would.
+        let mut fast = EntryMap::new();
+        for _ in 0..2 {
+            let (info, _dir) = info();
+            fast.process_buffer(info, &input_bytes).unwrap();
+        }
+
+        // The general path, merging each entry with `EntryMetadata::merge`.
+        let mut general = EntryMap::new();
+        for _ in 0..2 {
+            let (info, _dir) = info();
+            let offsets =
+                EntryMap::entry_offsets(&input_bytes, input_bytes.len(), Layout::Legacy, false);
+
+            for pos in offsets {
+                let pos = pos.unwrap();
+                let raw_entry = RawEntry::from_slice(&input_bytes[pos..]).unwrap();
+                let meta = EntryMetadata::new(&raw_entry, &info).unwrap();
+                let data = BorrowedData::new(&raw_entry, &info, meta.is_pid_significant()).unwrap();
+                general.merge_or_store(data, meta).unwrap();
+            }
+        }
+
+        let fast = FileEntry::entries_to_string(fast.into_sorted().unwrap()).unwrap();
+        let general = FileEntry::entries_to_string(general.into_sorted().unwrap()).unwrap();
+
+        assert_eq!(general, fast);
+        assert!(fast.contains("name{label_a=\"value_a\"} 10\n"), "{fast}");
+        assert!(fast.contains("name{label_a=\"value_b\"} 5\n"), "{fast}");
+    }
+
+    #[test]
+    fn test_process_buffer_mixed_exemplar() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let json = r#"["family","name",["label_a"],["value_a"]]"#;
+
+        let counter_bytes = testhelper::entries_to_db(&[json], &[2.0], None);
+
+        let entry_len = RawEntry::calc_total_len_exemplar(json.len()).unwrap();
+        let mut exemplar_bytes = vec![0u8; HEADER_SIZE + entry_len];
+        // CAST: the entry is far smaller than u32::MAX.
+        exemplar_bytes[..size_of::<u32>()]
+            .copy_from_slice(&((HEADER_SIZE + entry_len) as u32).to_ne_bytes());
+        RawEntry::save_exemplar(
+            &mut exemplar_bytes[HEADER_SIZE..],
+            json.as_bytes(),
+            Exemplar {
+                label_name: "trace_id".to_string(),
+                label_value: "abc".to_string(),
+                value: 1.0,
+                timestamp: 1_000_000_000,
+            },
+            ExemplarFormat::Legacy,
+        )
+        .unwrap();
+
+        let info = |bytes: &[u8], type_: &str| {
+            let TestFile { file, path, dir } = TestFile::new(bytes);
+            let info = FileInfo {
+                file,
+                path,
+                len: bytes.len(),
+                multiprocess_mode: Symbol::new("all"),
+                type_: Symbol::new(type_),
+                pid: "worker-1".to_string(),
+            };
+            (info, dir)
+        };
+
+        // The series is the same whichever file is read first.
+        for exemplar_first in [true, false] {
+            let mut map = EntryMap::new();
+            let mut files = [(&exemplar_bytes, "exemplar"), (&counter_bytes, "counter")];
+            if !exemplar_first {
+                files.reverse();
+            }
+
+            for (bytes, type_) in files {
+                let (info, _dir) = info(bytes, type_);
+                map.process_buffer(info, bytes).unwrap();
+            }
+
+            let entries = map.into_sorted().unwrap();
+            assert_eq!(1, entries.len(), "exemplar first: {exemplar_first}");
+
+            let meta = &entries[0].meta;
+            assert_eq!(
+                "counter",
+                meta.type_.name().unwrap(),
+                "exemplar first: {exemplar_first}"
+            );
+            assert_eq!(Some(2.0), meta.value, "exemplar first: {exemplar_first}");
+            assert_eq!(
+                "abc",
+                meta.ex.as_ref().unwrap().label_value,
+                "exemplar first: {exemplar_first}"
+            );
+
+            let out = FileEntry::entries_to_protobuf(entries).unwrap();
+            let mut buf = out.as_bytes();
+            let mf =
+                io::prometheus::client::MetricFamily::decode_length_delimited(&mut buf).unwrap();
+            let counter = mf.metric[0].counter.as_ref().unwrap();
+            assert_eq!(Some(2.0), counter.value);
+            assert_eq!(Some(1.0), counter.exemplar.as_ref().unwrap().value);
+        }
+    }
+
+    #[test]
+    fn test_process_buffer_packed() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let json = [
+            r#"["family","name",["label_a"],["value_a"]]"#,
+            r#"["family","name",["label_a"],["value_bb"]]"#,
+        ];
+
+        // A misaligned fixture: `[len][key][value]` with no padding.
+        let mut input_bytes = vec![0u8; HEADER_SIZE];
+        input_bytes[4..8].copy_from_slice(&u32::from(Layout::Packed.flag()).to_ne_bytes());
+        for (i, key) in json.iter().enumerate() {
+            input_bytes.extend((key.len() as u32).to_ne_bytes());
+            input_bytes.extend(key.as_bytes());
+            input_bytes.extend((i as f64 + 1.0).to_ne_bytes());
+        }
+        let used = input_bytes.len() as u32;
+        input_bytes[..4].copy_from_slice(&used.to_ne_bytes());
+
+        let info = || {
+            let TestFile { file, path, dir } = TestFile::new(&input_bytes);
+            let info = FileInfo {
+                file,
+                path,
+                len: input_bytes.len(),
+                multiprocess_mode: Symbol::new("all"),
+                type_: Symbol::new("counter"),
+                pid: "worker-1".to_string(),
+            };
+            (info, dir)
+        };
+
+        let (file_info, _dir) = info();
+        let mut map = EntryMap::new();
+        assert!(
+            map.process_buffer(file_info, &input_bytes).is_err(),
+            "rejected by default"
+        );
+
+        let (file_info, _dir) = info();
+        let mut map = EntryMap::with_options(MapOptions {
+            unaligned_entries: true,
+            ..Default::default()
+        });
+        map.process_buffer(file_info, &input_bytes).unwrap();
+
+        let sorted = map.into_sorted().unwrap();
+        assert_eq!(2, sorted.len());
+        assert_eq!(json[0], sorted[0].data.json);
+        assert_eq!(Some(1.0), sorted[0].meta.value);
+        assert_eq!(json[1], sorted[1].data.json);
+        assert_eq!(Some(2.0), sorted[1].meta.value);
+    }
+
+    #[test]
+    fn test_process_buffer_adversarial() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        struct TestCase {
+            name: &'static str,
+            input: DbBuilder,
+            expected: Option<usize>,
+        }
+
+        let json = [
+            r#"["family","name",["label_a"],["value_a"]]"#,
+            r#"["family","name",["label_a"],["value_bb"]]"#,
+        ];
+        let valid = || DbBuilder::new().entry(json[0], 1.0).entry(json[1], 2.0);
+        let first_len = RawEntry::calc_total_len(json[0].len()).unwrap();
+
+        let tc = vec![
+            TestCase {
+                name: "valid",
+                input: valid(),
+                expected: Some(2),
+            },
+            TestCase {
+                name: "packed",
+                input: DbBuilder::new()
+                    .entry(json[0], 1.0)
+                    .padding(0)
+                    .entry(json[1], 2.0)
+                    .padding(0)
+                    .layout(Layout::Packed),
+                expected: Some(2),
+            },
+            TestCase {
+                name: "used covers header only",
+                input: valid().used(HEADER_SIZE as u32),
+                expected: Some(0),
+            },
+            TestCase {
+                name: "used ends mid length prefix",
+                input: valid().used((HEADER_SIZE + first_len + 2) as u32),
+                expected: None,
+            },
+            TestCase {
+                name: "used past end of file",
+                input: valid().used(1000),
+                expected: None,
+            },
+            TestCase {
+                name: "encoded length past used",
+                input: valid().encoded_len(1000),
+                expected: None,
+            },
+            TestCase {
+                name: "short padding misaligns next entry",
+                input: DbBuilder::new()
+                    .entry(json[0], 1.0)
+                    .padding(RawEntry::padding_len(json[0].len()) - 1)
+                    .entry(json[1], 2.0),
+                expected: None,
+            },
+            TestCase {
+                name: "truncated value",
+                input: valid().truncate_value(4),
+                expected: None,
+            },
+        ];
+
+        for case in tc {
+            let name = case.name;
+            let input_bytes = case.input.build();
+
+            let TestFile {
+                file,
+                path,
+                dir: _dir,
+            } = TestFile::new(&input_bytes);
+            let info = FileInfo {
+                file,
+                path,
+                len: input_bytes.len(),
+                multiprocess_mode: Symbol::new("all"),
+                type_: Symbol::new("counter"),
+                pid: "worker-1".to_string(),
+            };
+
+            let mut map = EntryMap::with_options(MapOptions {
+                unaligned_entries: true,
+                ..Default::default()
+            });
+            let result = map.process_buffer(info, &input_bytes);
+
+            match case.expected {
+                Some(count) => {
+                    assert!(result.is_ok(), "test case: {name} - {result:?}");
+                    assert_eq!(count, map.entries.len(), "test case: {name}");
+                }
+                None => assert!(result.is_err(), "test case: {name}"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_aggregate_dir() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let json = r#"["family","name",["label_a"],["value_a"]]"#;
+        let dir = tempfile::tempdir().unwrap();
+
+        for (file_name, value) in [
+            ("gauge_all_worker-1-0.db", 1.0),
+            ("gauge_all_worker-2-0.db", 2.0),
+        ] {
+            let data = testhelper::entries_to_db(&[json], &[value], None);
+            fs::write(dir.path().join(file_name), data).unwrap();
+        }
+        // Files without the `.db` extension are skipped.
+        fs::write(dir.path().join("notes.txt"), b"foobar").unwrap();
+
+        let mut map = EntryMap::new();
+        map.aggregate_dir(dir.path(), None).unwrap();
+
+        let sorted = map.into_sorted().unwrap();
+        assert_eq!(2, sorted.len());
+        assert_eq!(Some("worker-1"), sorted[0].data.pid.as_deref());
+        assert_eq!(Some("worker-2"), sorted[1].data.pid.as_deref());
+    }
+
+    #[test]
+    fn test_aggregate_dir_resolver() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let json = r#"["family","name",["label_a"],["value_a"]]"#;
+        let dir = tempfile::tempdir().unwrap();
+
+        // Not the Ruby client's naming scheme.
+        for (file_name, value) in [("worker-1.db", 1.0), ("worker-2.db", 2.0)] {
+            let data = testhelper::entries_to_db(&[json], &[value], None);
+            fs::write(dir.path().join(file_name), data).unwrap();
+        }
+
+        let resolver: Value =
+            magnus::eval("->(path) { [:max, :gauge, File.basename(path, '.db')] }").unwrap();
+        let mut map = EntryMap::new();
+        map.aggregate_dir(dir.path(), Some(resolver)).unwrap();
+
+        let sorted = map.into_sorted().unwrap();
+        assert_eq!(1, sorted.len(), "max merges across pids");
+        assert_eq!(None, sorted[0].data.pid);
+        assert_eq!(Some(2.0), sorted[0].meta.value);
+
+        let resolver: Value = magnus::eval("->(path) { [:max, :gauge] }").unwrap();
+        let mut map = EntryMap::new();
+        assert!(map.aggregate_dir(dir.path(), Some(resolver)).is_err());
+    }
+
+    #[test]
+    fn test_aggregate_dir_snapshot_concurrent_writes() {
+        use std::os::unix::fs::FileExt;
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        const ENTRY_CT: usize = 500;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("gauge_all_worker-1-0.db");
+        fs::write(&path, testhelper::entries_to_db(&[], &[], None)).unwrap();
+
+        let done = AtomicBool::new(false);
+
+        std::thread::scope(|s| {
+            // Append entries as a worker would, writing each entry before
+            // bumping `used` in the header.
+            s.spawn(|| {
+                let file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+                let mut used = HEADER_SIZE;
+
+                for i in 0..ENTRY_CT {
+                    let json = format!(r#"["family","name",["label_a"],["value_{i}"]]"#);
+                    let entry = [
+                        &(json.len() as u32).to_ne_bytes()[..],
+                        json.as_bytes(),
+                        &vec![b' '; RawEntry::padding_len(json.len())],
+                        &(i as f64).to_ne_bytes(),
+                    ]
+                    .concat();
+
+                    file.write_all_at(&entry, used as u64).unwrap();
+                    used += entry.len();
+                    file.write_all_at(&(used as u32).to_ne_bytes(), 0).unwrap();
+                }
+
+                done.store(true, Ordering::SeqCst);
+            });
+
+            let mut last_ct = 0;
+            loop {
+                let finished = done.load(Ordering::SeqCst);
+
+                let mut map = EntryMap::with_options(MapOptions {
+                    snapshot_files: true,
+                    ..Default::default()
+                });
+                map.aggregate_dir(dir.path(), None).unwrap();
+
+                let ct = map.entries.len();
+                assert!(ct >= last_ct, "entries are never lost");
+                last_ct = ct;
+
+                if finished {
+                    break;
+                }
+            }
+
+            assert_eq!(ENTRY_CT, last_ct, "all entries read once writes finish");
+        });
+    }
+
+    #[test]
+    fn test_process_buffer_canonicalize_labels() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let inputs = [
+            (
+                r#"["family","name",["label_a","label_b"],["value_a","value_b"]]"#,
+                1.0,
+                "worker-1",
+            ),
+            (
+                r#"["family","name",["label_b","label_a"],["value_b","value_a"]]"#,
+                5.0,
+                "worker-2",
+            ),
+        ];
+
+        for (canonicalize_labels, expected_ct) in [(false, 2), (true, 1)] {
+            let mut map = EntryMap::with_options(MapOptions {
+                canonicalize_labels,
+                ..Default::default()
+            });
+
+            for (json, value, pid) in inputs {
+                let input_bytes = testhelper::entries_to_db(&[json], &[value], None);
+                let TestFile {
+                    file,
+                    path,
+                    dir: _dir,
+                } = TestFile::new(&input_bytes);
+
+                let info = FileInfo {
+                    file,
+                    path,
+                    len: input_bytes.len(),
+                    multiprocess_mode: Symbol::new("max"),
+                    type_: Symbol::new("gauge"),
+                    pid: pid.to_string(),
+                };
+
+                map.process_buffer(info, &input_bytes).unwrap();
+            }
+
+            assert_eq!(
+                expected_ct,
+                map.entries.len(),
+                "canonicalize_labels: {canonicalize_labels} - count"
+            );
+
+            if canonicalize_labels {
+                let (data, meta) = map.entries.iter().next().unwrap();
+                assert_eq!(
+                    r#"["family","name",["label_a","label_b"],["value_a","value_b"]]"#,
+                    data.json
+                );
+                assert_eq!(Some(5.0), meta.value, "values merged");
+            }
+        }
+    }
+
+    #[test]
+    fn test_entry_offsets() {
+        let json = &[
+            r#"["first_family","first_name",["label_a"],["value_a"]]"#,
+            r#"["second_family","second_name",["label_a","label_b"],["value_a","value_b"]]"#,
+            r#"["third_family","third_name",[],[]]"#,
+        ];
+        let source = testhelper::entries_to_db(json, &[1.0, 2.0, 3.0], None);
+
+        let first_len = Layout::Legacy.total_len(json[0].len()).unwrap();
+        let second_len = Layout::Legacy.total_len(json[1].len()).unwrap();
+        let expected = vec![
+            HEADER_SIZE,
+            HEADER_SIZE + first_len,
+            HEADER_SIZE + first_len + second_len,
+        ];
+
+        let offsets: Vec<_> = EntryMap::entry_offsets(&source, source.len(), Layout::Legacy, false)
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(expected, offsets);
+
+        for pos in offsets {
+            assert!(
+                RawEntry::from_slice(&source[pos..]).is_ok(),
+                "entry at {pos} parses"
+            );
+        }
+
+        // Walking the offsets doesn't allocate.
+        let (count, stats) = testhelper::measure_allocs(|| {
+            EntryMap::entry_offsets(&source, source.len(), Layout::Legacy, false).count()
+        });
+        assert_eq!(3, count);
+        assert_eq!(0, stats.count, "no allocations");
+
+        // `used` cuts the final entry short, the entries before it are still
+        // found.
+        let truncated = source.len() - 8;
+        let offsets: Vec<_> =
+            EntryMap::entry_offsets(&source, truncated, Layout::Legacy, false).collect();
+        assert_eq!(
+            vec![
+                Ok(expected[0]),
+                Ok(expected[1]),
+                Err(MmapError::out_of_bounds(
+                    source.len() - expected[2],
+                    truncated - expected[2]
+                )),
+            ],
+            offsets
+        );
+
+        // `used` ends a few bytes past the final entry.
+        let mut misaligned = source.clone();
+        misaligned.extend([0u8; 3]);
+        assert!(matches!(
+            EntryMap::entry_offsets(&misaligned, misaligned.len(), Layout::Legacy, false)
+                .collect::<Result<Vec<_>>>(),
+            Err(MmapError::PromParsing(_))
+        ));
+    }
+
+    /// Store the entries of `source` at the offsets in `shard`, a run of
+    /// those returned by `EntryMap::entry_offsets` as split by
+    /// `shard_offsets`, so that each shard of a file can be parsed
+    /// independently.
+    fn process_shard(
+        map: &mut EntryMap,
+        info: &FileInfo,
+        source: &[u8],
+        shard: &[usize],
+    ) -> Result<()> {
+        for &pos in shard {
+            if pos >= source.len() {
+                return Err(MmapError::out_of_bounds(pos, source.len()));
+            }
+
+            let raw_entry = RawEntry::from_slice(&source[pos..])?;
+            let meta = EntryMetadata::new(&raw_entry, info)?;
+            let data = BorrowedData::new(&raw_entry, info, meta.is_pid_significant())?;
+            map.merge_or_store(data, meta)?;
+        }
+
+        Ok(())
+    }
+
+    /// Split the entry offsets of a file into at most `shards` contiguous runs
+    /// of similar length. Runs start and end on entry boundaries, never within
+    /// an entry.
+    fn shard_offsets(offsets: &[usize], shards: usize) -> Vec<&[usize]> {
+        if offsets.is_empty() || shards == 0 {
+            return Vec::new();
+        }
+
+        offsets.chunks(offsets.len().div_ceil(shards)).collect()
+    }
+
+    #[test]
+    fn test_process_buffer_shard() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let json = &[
+            r#"["family_a","name_a",["label_a"],["value_a"]]"#,
+            r#"["family_a","name_a",["label_a"],["value_b"]]"#,
+            r#"["family_b","name_b",["label_a","label_b"],["value_a","value_b"]]"#,
+            r#"["family_c","name_c",[],[]]"#,
+            r#"["family_d","name_d",["label_a"],["a_much_longer_value_a"]]"#,
+        ];
+        let source = testhelper::entries_to_db(json, &[1.0, 2.0, 3.0, 4.0, 5.0], None);
+        let offsets: Vec<_> = EntryMap::entry_offsets(&source, source.len(), Layout::Legacy, false)
+            .collect::<Result<_>>()
+            .unwrap();
+
+        let TestFile {
+            file,
+            path,
+            dir: _dir,
+        } = TestFile::new(&source);
+        let info = FileInfo {
+            file,
+            path,
+            len: source.len(),
+            multiprocess_mode: Symbol::new("max"),
+            type_: Symbol::new("gauge"),
+            pid: "worker-1".to_string(),
+        };
+
+        let mut whole = EntryMap::new();
+        process_shard(&mut whole, &info, &source, &offsets).unwrap();
+        let expected = FileEntry::entries_to_string(whole.into_sorted().unwrap()).unwrap();
+
+        for shards in 1..=offsets.len() + 1 {
+            let split = shard_offsets(&offsets, shards);
+            assert!(split.len() <= shards, "{shards} shards");
+            assert_eq!(
+                offsets,
+                split.concat(),
+                "{shards} shards cover every entry once"
+            );
+
+            let mut map = EntryMap::new();
+            for shard in split {
+                // Each shard starts on an entry boundary.
+                assert!(offsets.contains(&shard[0]));
+                process_shard(&mut map, &info, &source, shard).unwrap();
+            }
+
+            let out = FileEntry::entries_to_string(map.into_sorted().unwrap()).unwrap();
+            assert_eq!(expected, out, "{shards} shards");
+        }
+
+        assert!(shard_offsets(&offsets, 0).is_empty());
+        assert!(shard_offsets(&[], 4).is_empty());
+
+        // An offset past the data written is rejected.
+        let mut map = EntryMap::new();
+        assert!(process_shard(&mut map, &info, &source, &[source.len()]).is_err());
     }
 
     #[test]
@@ -494,7 +2254,11 @@ mod test {
             let mut map = EntryMap::new();
             let result = map.process_buffer(info, &input_bytes);
 
-            assert_eq!(case.expected_ct, map.0.len(), "test case: {name} - count");
+            assert_eq!(
+                case.expected_ct,
+                map.entries.len(),
+                "test case: {name} - count"
+            );
 
             if let Some(expected_err) = case.expected_err {
                 // Validate we have the right enum type for the error. Error
@@ -510,7 +2274,7 @@ mod test {
 
                 assert_eq!(
                     case.json.len(),
-                    map.0.len(),
+                    map.entries.len(),
                     "test case: {name} - all entries captured"
                 );
             }
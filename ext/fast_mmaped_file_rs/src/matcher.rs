@@ -0,0 +1,182 @@
+use regex::Regex;
+use std::borrow::Cow;
+
+use crate::error::MmapError;
+use crate::file_entry::{FileEntry, MetricText};
+use crate::Result;
+
+/// The label name matching a series' metric name, as in PromQL.
+const NAME_LABEL: &str = "__name__";
+
+/// How a `LabelMatcher` compares a label value.
+#[derive(Clone, Debug)]
+pub enum MatchOp {
+    /// The value equals the string.
+    Eq(String),
+    /// The value does not equal the string.
+    Neq(String),
+    /// The whole value matches the regex.
+    Regex(Regex),
+}
+
+/// A PromQL-style matcher on one label of a series. The metric name may be
+/// matched as the `__name__` label, and a label the series doesn't have
+/// matches as the empty string.
+#[derive(Clone, Debug)]
+pub struct LabelMatcher {
+    pub label: String,
+    pub op: MatchOp,
+}
+
+impl LabelMatcher {
+    /// Construct a matcher from the name of its operation, one of `eq`, `neq`,
+    /// or `regex`. Like PromQL, regexes are anchored at both ends.
+    pub fn new(label: &str, op: &str, value: &str) -> Result<Self> {
+        let op = match op {
+            "eq" => MatchOp::Eq(value.to_string()),
+            "neq" => MatchOp::Neq(value.to_string()),
+            "regex" => {
+                let re = Regex::new(&format!("^(?:{value})$")).map_err(|e| {
+                    MmapError::Other(format!("invalid regex for label {label}: {e}"))
+                })?;
+                MatchOp::Regex(re)
+            }
+            op => {
+                return Err(MmapError::Other(format!(
+                    "unknown match op {op} for label {label}, expected eq, neq, or regex"
+                )))
+            }
+        };
+
+        Ok(Self {
+            label: label.to_string(),
+            op,
+        })
+    }
+
+    /// Whether the series parsed into `metric` satisfies the matcher.
+    pub fn matches(&self, metric: &MetricText) -> Result<bool> {
+        let value = if self.label == NAME_LABEL {
+            Cow::Borrowed(metric.metric_name)
+        } else {
+            match metric.labels.iter().position(|&l| l == self.label) {
+                Some(i) => Cow::Owned(FileEntry::label_value(metric.values[i])?),
+                None => Cow::Borrowed(""),
+            }
+        };
+
+        Ok(match &self.op {
+            MatchOp::Eq(expected) => value == expected.as_str(),
+            MatchOp::Neq(expected) => value != expected.as_str(),
+            MatchOp::Regex(re) => re.is_match(&value),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_matches() {
+        struct TestCase {
+            name: &'static str,
+            label: &'static str,
+            op: &'static str,
+            value: &'static str,
+            expected: bool,
+        }
+
+        let json = r#"["requests","requests_total",["code","method","shard"],["200","get",3]]"#;
+        let metric: MetricText = serde_json::from_str(json).unwrap();
+
+        let tc = vec![
+            TestCase {
+                name: "eq",
+                label: "code",
+                op: "eq",
+                value: "200",
+                expected: true,
+            },
+            TestCase {
+                name: "eq mismatch",
+                label: "code",
+                op: "eq",
+                value: "500",
+                expected: false,
+            },
+            TestCase {
+                name: "eq numeric value",
+                label: "shard",
+                op: "eq",
+                value: "3",
+                expected: true,
+            },
+            TestCase {
+                name: "eq missing label",
+                label: "path",
+                op: "eq",
+                value: "",
+                expected: true,
+            },
+            TestCase {
+                name: "neq",
+                label: "method",
+                op: "neq",
+                value: "post",
+                expected: true,
+            },
+            TestCase {
+                name: "neq mismatch",
+                label: "method",
+                op: "neq",
+                value: "get",
+                expected: false,
+            },
+            TestCase {
+                name: "regex",
+                label: "code",
+                op: "regex",
+                value: "2..",
+                expected: true,
+            },
+            TestCase {
+                name: "regex is anchored",
+                label: "code",
+                op: "regex",
+                value: "0",
+                expected: false,
+            },
+            TestCase {
+                name: "regex alternation is anchored",
+                label: "method",
+                op: "regex",
+                value: "ge|post",
+                expected: false,
+            },
+            TestCase {
+                name: "metric name",
+                label: "__name__",
+                op: "regex",
+                value: ".*_total",
+                expected: true,
+            },
+        ];
+
+        for case in tc {
+            let matcher = LabelMatcher::new(case.label, case.op, case.value).unwrap();
+            assert_eq!(
+                case.expected,
+                matcher.matches(&metric).unwrap(),
+                "test case: {}",
+                case.name
+            );
+        }
+    }
+
+    #[test]
+    fn test_new_invalid() {
+        assert!(LabelMatcher::new("code", "regex", "(").is_err());
+        assert!(LabelMatcher::new("code", "like", "2%").is_err());
+    }
+}
@@ -1,31 +1,48 @@
+use hashbrown::HashSet;
 use magnus::exception::*;
 use magnus::prelude::*;
 use magnus::rb_sys::{AsRawValue, FromRawValue};
 use magnus::typed_data::Obj;
 use magnus::value::Fixnum;
-use magnus::{eval, scan_args, Error, Integer, RArray, RClass, RHash, RString, Value};
+use magnus::{
+    eval, scan_args, Error, Integer, RArray, RClass, RHash, RString, Range, Ruby, Symbol,
+    TryConvert, Value,
+};
 use nix::libc::{c_char, c_long, c_ulong};
 use rb_sys::rb_str_new_static;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
 use std::fs::File;
 use std::io::{prelude::*, SeekFrom};
 use std::mem;
+use std::os::unix::fs::FileExt;
 use std::path::Path;
 use std::ptr::NonNull;
-use std::sync::RwLock;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{RwLock, TryLockError};
 
 use crate::err;
 use crate::error::MmapError;
-use crate::exemplars::Exemplar;
-use crate::file_entry::FileEntry;
-use crate::map::EntryMap;
-use crate::raw_entry::RawEntry;
+use crate::exemplars::{Exemplar, ExemplarFormat};
+use crate::file_entry::{self, FileEntry, RenderOptions, RenderedNames, OPENMETRICS_EOF};
+use crate::file_info::FileInfo;
+use crate::map::{EntryMap, MapOptions};
+use crate::matcher::LabelMatcher;
+use crate::raw_entry::{Layout, RawEntry};
 use crate::util::{self, CheckedOps};
 use crate::Result;
 use crate::HEADER_SIZE;
+use crate::SYM_COUNTER;
 use inner::InnerMmap;
 
 mod inner;
 
+/// The number of bytes that must remain free past the written data. The
+/// region up to `used` is handed to Ruby with `rb_str_new_static`, which
+/// expects a NUL-terminated C string, so the mmap must always extend at least
+/// one byte beyond it. An entry is never allowed to fill the mmap exactly.
+const TRAILING_SLACK: usize = 1;
+
 /// The Ruby `STR_NOEMBED` flag, aka `FL_USER1`.
 const STR_NOEMBED: c_ulong = 1 << (13);
 /// The Ruby `STR_SHARED` flag, aka `FL_USER2`.
@@ -73,15 +90,130 @@ const STR_SHARED: c_ulong = 1 << (14);
 // `Default` trait, so a `File` cannot directly help by the object being
 // constructed. Having the `RwLock` hold an `Option` of the interior object
 // resolves this.
-#[derive(Debug, Default)]
+//
+// The remaining fields live outside of the `RwLock` as the `InnerMmap` is
+// replaced on each remap.
+#[derive(Debug)]
 #[magnus::wrap(class = "FastMmapedFileRs", free_immediately, size)]
-pub struct MmapedFile(RwLock<Option<InnerMmap>>);
+pub struct MmapedFile {
+    inner: RwLock<Option<InnerMmap>>,
+    /// How many times the file has been re-mapped over the lifetime of the
+    /// object.
+    remap_count: AtomicUsize,
+    /// How much the file grows on each expansion.
+    growth_factor: GrowthFactor,
+    /// Read and write values with atomic loads and stores.
+    atomic_values: AtomicBool,
+    /// Flush each new entry before the header includes it.
+    durable_writes: AtomicBool,
+    /// Prefault the pages added when the file expands.
+    prefault: AtomicBool,
+    /// Track the strings returned by `slice`, disabled for write-only files.
+    /// Set only at construction.
+    track_strings: bool,
+    /// The longest key that may be written, in bytes. Set only at
+    /// construction.
+    max_key_length: Option<usize>,
+    /// The longest label value that may be written, in bytes. Set only at
+    /// construction.
+    max_label_value_length: Option<usize>,
+}
+
+impl Default for MmapedFile {
+    fn default() -> Self {
+        Self {
+            inner: RwLock::new(None),
+            remap_count: AtomicUsize::new(0),
+            growth_factor: GrowthFactor::default(),
+            atomic_values: AtomicBool::new(false),
+            durable_writes: AtomicBool::new(false),
+            prefault: AtomicBool::new(false),
+            track_strings: true,
+            max_key_length: None,
+            max_label_value_length: None,
+        }
+    }
+}
+
+/// The output formats accepted by `MmapedFile::render`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum OutputFormat {
+    Text,
+    OpenMetrics,
+    Protobuf,
+}
+
+impl OutputFormat {
+    fn from_symbol(format: Symbol) -> magnus::error::Result<Self> {
+        match format.name()?.as_ref() {
+            "text" => Ok(OutputFormat::Text),
+            "openmetrics" => Ok(OutputFormat::OpenMetrics),
+            "protobuf" => Ok(OutputFormat::Protobuf),
+            other => Err(err!(arg_error(), "unknown format {}", other)),
+        }
+    }
+}
+
+/// The factor by which the file's capacity is multiplied when it must be
+/// expanded. Stored as the bit pattern of an `f64` so it can be set through
+/// the shared borrow available in `initialize`.
+#[derive(Debug)]
+struct GrowthFactor(AtomicU64);
+
+impl Default for GrowthFactor {
+    fn default() -> Self {
+        Self(AtomicU64::new(Self::DEFAULT.to_bits()))
+    }
+}
+
+impl GrowthFactor {
+    /// Double the file on each expansion, matching the C implementation.
+    const DEFAULT: f64 = 2.0;
+
+    fn get(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    /// Set the growth factor, which must be finite and greater than 1.
+    fn set(&self, factor: f64) -> magnus::error::Result<()> {
+        if !(factor.is_finite() && factor > 1.0) {
+            return Err(err!(
+                arg_error(),
+                "growth factor must be greater than 1, got {}",
+                factor
+            ));
+        }
+
+        self.0.store(factor.to_bits(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Calculate the capacity following `cap`, which is always at least one
+    /// byte larger.
+    fn grow(&self, cap: usize) -> Result<usize> {
+        let factor = self.get();
+
+        // Keep the exact integer path for the default.
+        if factor == Self::DEFAULT {
+            return cap.mul_chk(2);
+        }
+
+        // CAST: widening, any precision lost is made up by rounding up.
+        let next = (cap as f64 * factor).ceil();
+        if next >= usize::MAX as f64 {
+            return Err(MmapError::overflowed(cap as f64, factor, "multiplying"));
+        }
+
+        // CAST: confirmed above that `next` is in range.
+        Ok((next as usize).max(cap.add_chk(1)?))
+    }
+}
 
 use std::time::{SystemTime, UNIX_EPOCH};
 
 impl MmapedFile {
     /// call-seq:
-    ///   new(file)
+    ///   new(file, growth_factor = 2.0, track_strings: true, max_key_length: nil, max_label_value_length: nil)
     ///
     /// create a new Mmap object
     ///
@@ -90,21 +222,72 @@ impl MmapedFile {
     ///
     ///     Creates a mapping that's shared with all other processes
     ///     mapping the same area of the file.
+    ///
+    /// * <em>growth_factor</em>
+    ///
+    ///     The factor by which the file grows when it runs out of space. Must
+    ///     be greater than 1.
+    ///
+    /// * <em>track_strings</em>
+    ///
+    ///     Track the strings returned by `slice` so they can be moved when the
+    ///     file is re-mapped. Producers that only write to the file can
+    ///     disable this to skip the tracking on each expansion, `slice` then
+    ///     raises an `IOError`.
+    ///
+    /// * <em>max_key_length</em>
+    ///
+    ///     Raise an error for keys longer than this many bytes rather than
+    ///     writing them, to catch accidentally enormous label sets before they
+    ///     bloat the file. Defaults to the most the format allows, `2**31 - 1`.
+    ///
+    /// * <em>max_label_value_length</em>
+    ///
+    ///     Raise an error naming the label for new entries with a label value
+    ///     longer than this many bytes. Entries already written are
+    ///     unaffected. Unlimited by default.
     pub fn new(klass: RClass, args: &[Value]) -> magnus::error::Result<Obj<Self>> {
-        let args = scan_args::scan_args::<(RString,), (), (), (), (), ()>(args)?;
+        let args = scan_args::scan_args::<(RString,), (Option<f64>,), (), (), RHash, ()>(args)?;
         let path = args.required.0;
 
-        let lock = MmapedFile(RwLock::new(None));
+        type Kwargs = (Option<bool>, Option<usize>, Option<usize>);
+        let kwargs = scan_args::get_kwargs::<_, (), Kwargs, ()>(
+            args.keywords,
+            &[],
+            &["track_strings", "max_key_length", "max_label_value_length"],
+        )?;
+        let (track_strings, max_key_length, max_label_value_length) = kwargs.optional;
+
+        if let Some(limit) = max_key_length {
+            RawEntry::check_key_len(limit, i32::MAX as usize)?;
+        }
+
+        let lock = MmapedFile {
+            track_strings: track_strings.unwrap_or(true),
+            max_key_length,
+            max_label_value_length,
+            ..Default::default()
+        };
         let obj = Obj::wrap_as(lock, klass);
 
-        let _: Value = obj.funcall("initialize", (path,))?;
+        let _: Value = match args.optional.0 {
+            Some(growth_factor) => obj.funcall("initialize", (path, growth_factor))?,
+            None => obj.funcall("initialize", (path,))?,
+        };
 
         Ok(obj)
     }
 
     /// Initialize a new `FastMmapedFileRs` object. This must be defined in
     /// order for inheritance to work.
-    pub fn initialize(rb_self: Obj<Self>, fname: String) -> magnus::error::Result<()> {
+    pub fn initialize(rb_self: Obj<Self>, args: &[Value]) -> magnus::error::Result<()> {
+        let args = scan_args::scan_args::<(String,), (Option<f64>,), (), (), (), ()>(args)?;
+        let fname = args.required.0;
+
+        if let Some(growth_factor) = args.optional.0 {
+            rb_self.growth_factor.set(growth_factor)?;
+        }
+
         let file = File::options()
             .read(true)
             .write(true)
@@ -114,6 +297,65 @@ impl MmapedFile {
         let inner = InnerMmap::new(fname.into(), file)?;
         rb_self.insert_inner(inner)?;
 
+        if !rb_self.track_strings() {
+            return Ok(());
+        }
+
+        Self::init_weak_obj_tracker(rb_self)
+    }
+
+    /// call-seq:
+    ///   open_read_only(file)
+    ///
+    /// Map an existing file read-only, for reading files owned by another
+    /// process from a read-only mount. The file is neither extended nor
+    /// written to, and any method that would write to it raises an `IOError`.
+    pub fn open_read_only(klass: RClass, path: RString) -> magnus::error::Result<Obj<Self>> {
+        let fname = path.to_string()?;
+
+        let file = File::open(&fname).map_err(|_| err!(arg_error(), "Can't open {}", fname))?;
+
+        let obj = Obj::wrap_as(Self::default(), klass);
+
+        let inner = InnerMmap::new_read_only(fname.into(), file)?;
+        obj.insert_inner(inner)?;
+
+        Self::init_weak_obj_tracker(obj)?;
+
+        Ok(obj)
+    }
+
+    /// call-seq: read_only?
+    ///
+    /// Whether the file was opened with `open_read_only`.
+    pub fn is_read_only(&self) -> magnus::error::Result<bool> {
+        self.inner(|inner| Ok(inner.is_read_only()))
+    }
+
+    /// call-seq: track_strings?
+    ///
+    /// Whether the strings returned by `slice` are tracked, see `new`.
+    pub fn track_strings(&self) -> bool {
+        self.track_strings
+    }
+
+    /// call-seq: max_key_length
+    ///
+    /// The longest key that may be written, in bytes, or `nil` if limited
+    /// only by the format, see `new`.
+    pub fn max_key_length(&self) -> Option<usize> {
+        self.max_key_length
+    }
+
+    /// call-seq: max_label_value_length
+    ///
+    /// The longest label value that may be written, in bytes, or `nil` if
+    /// there is no limit, see `new`.
+    pub fn max_label_value_length(&self) -> Option<usize> {
+        self.max_label_value_length
+    }
+
+    fn init_weak_obj_tracker(rb_self: Obj<Self>) -> magnus::error::Result<()> {
         let weak_klass = RClass::from_value(eval("ObjectSpace::WeakMap")?)
             .ok_or_else(|| err!(no_method_error(), "unable to create WeakMap"))?;
         let weak_obj_tracker = weak_klass.new_instance(())?;
@@ -129,819 +371,4768 @@ impl MmapedFile {
         Ok(())
     }
 
+    /// call-seq:
+    ///   to_metrics(file_list, strict_finite: false, canonicalize_labels: false, snapshot: false, timestamps: false, sanitize_names: false, openmetrics: false, unaligned_entries: false, max_series_per_pass: nil, read_buffer_limit: nil, max_key_length: nil, name_prefixes: nil, scrape_timestamp: nil, tolerant_keys: false, target_info: nil)
+    ///
     /// Read the list of files provided from Ruby and convert them to a Prometheus
     /// metrics String.
-    pub fn to_metrics(file_list: RArray) -> magnus::error::Result<String> {
-        let mut map = EntryMap::new();
-        map.aggregate_files(file_list)?;
+    ///
+    /// * <em>strict_finite</em>
+    ///
+    ///     Raise an error naming the series if any merged value is `NaN` or
+    ///     infinite, rather than emitting it.
+    ///
+    /// * <em>canonicalize_labels</em>
+    ///
+    ///     Merge series whose labels were written in a different order. Labels
+    ///     are rendered in sorted order.
+    ///
+    /// * <em>snapshot</em>
+    ///
+    ///     Read only the region of each file marked as used by its header, so
+    ///     each file is internally consistent even if a worker writes to it
+    ///     mid-scrape. Consistency across files remains best-effort.
+    ///
+    /// * <em>timestamps</em>
+    ///
+    ///     Append the time of a series' most recent exemplar to its sample, in
+    ///     milliseconds in the Prometheus text format and in seconds in the
+    ///     OpenMetrics format.
+    ///
+    /// * <em>sanitize_names</em>
+    ///
+    ///     Replace characters that are invalid in metric and label names with
+    ///     `_`. A warning is emitted when any name is changed.
+    ///
+    /// * <em>openmetrics</em>
+    ///
+    ///     Render in the OpenMetrics text format. Counter names take the
+    ///     `_total` suffix if they don't already have it.
+    ///
+    /// * <em>unaligned_entries</em>
+    ///
+    ///     Read files written by foreign writers in the `:packed` layout, whose
+    ///     values are not 8-byte aligned. Such files raise an error otherwise.
+    ///
+    /// * <em>max_series_per_pass</em>
+    ///
+    ///     Aggregate and render a batch of families at a time, holding at most
+    ///     roughly this many series in memory. Every file is re-read once per
+    ///     batch, so this only lowers peak memory when series are spread across
+    ///     many families, a single family is never split.
+    ///
+    /// * <em>read_buffer_limit</em>
+    ///
+    ///     Files are read into a buffer kept between scrapes on the same
+    ///     thread, so a long-lived exporter doesn't reallocate it each time.
+    ///     The buffer is shrunk to this many bytes if larger when the scrape
+    ///     completes, `0` frees it. Defaults to 4MiB.
+    ///
+    /// * <em>max_key_length</em>
+    ///
+    ///     Raise an error for entries whose key is longer than this many bytes,
+    ///     rather than aggregating accidentally enormous label sets.
+    ///
+    /// * <em>name_prefixes</em>
+    ///
+    ///     A Hash of prefixes to rewrite in metric names, e.g.
+    ///     `{ "" => "vendor_" }` to prefix every name. The longest matching
+    ///     prefix is replaced.
+    ///
+    /// * <em>scrape_timestamp</em>
+    ///
+    ///     Append this time, given in milliseconds, e.g. the time of the
+    ///     scrape, to every sample, in place of any <em>timestamps</em>. It is
+    ///     written in milliseconds in the Prometheus text format and protobuf,
+    ///     and in seconds with a millisecond fraction in the OpenMetrics
+    ///     format. Prometheus assigns its own scrape timestamp, so most
+    ///     setups should leave this unset.
+    ///
+    /// * <em>tolerant_keys</em>
+    ///
+    ///     Skip a UTF-8 byte order mark and whitespace written by a foreign
+    ///     writer before the JSON key of an entry. Such entries raise an
+    ///     error otherwise.
+    ///
+    /// * <em>target_info</em>
+    ///
+    ///     A Hash of labels describing the target, e.g. its environment, to
+    ///     emit once as a `target_info` sample in the OpenMetrics format.
+    ///     Ignored in the Prometheus text format.
+    pub fn to_metrics(args: &[Value]) -> magnus::error::Result<String> {
+        let args = scan_args::scan_args::<(RArray,), (), (), (), RHash, ()>(args)?;
+        let file_list = args.required.0;
+        let (map_options, render_options) = Self::scrape_options(args.keywords)?;
+
+        Self::render_files(file_list, OutputFormat::Text, map_options, render_options)
+    }
 
-        let sorted = map.into_sorted()?;
+    /// call-seq:
+    ///   render(file_list, format, **options)
+    ///
+    /// Read the list of files provided from Ruby and render them in `format`,
+    /// one of `:text`, `:openmetrics`, or `:protobuf`. Accepts the same
+    /// options as `to_metrics`, of which only <em>timestamps</em>,
+    /// <em>scrape_timestamp</em>, <em>name_prefixes</em> and the options
+    /// controlling how files are read apply to `:protobuf`.
+    pub fn render(args: &[Value]) -> magnus::error::Result<String> {
+        let args = scan_args::scan_args::<(RArray, Symbol), (), (), (), RHash, ()>(args)?;
+        let (file_list, format) = args.required;
+        let format = OutputFormat::from_symbol(format)?;
+        let (map_options, render_options) = Self::scrape_options(args.keywords)?;
+
+        Self::render_files(file_list, format, map_options, render_options)
+    }
 
-        FileEntry::entries_to_string(sorted).map_err(|e| e.into())
+    /// Aggregate the files in `file_list` and render them in `format`. All
+    /// output methods share this pipeline, so each aggregates identically.
+    fn render_files(
+        file_list: RArray,
+        format: OutputFormat,
+        map_options: MapOptions,
+        mut render_options: RenderOptions,
+    ) -> magnus::error::Result<String> {
+        if format == OutputFormat::OpenMetrics {
+            render_options.openmetrics = true;
+        }
+
+        let render_entries = |entries: Vec<FileEntry>| match format {
+            OutputFormat::Protobuf => {
+                FileEntry::entries_to_protobuf_with_options(entries, &render_options)
+                    .map(|out| (out, 0))
+            }
+            OutputFormat::Text | OutputFormat::OpenMetrics => {
+                FileEntry::render_text(entries, &render_options)
+            }
+        };
+
+        let (out, sanitized) = match map_options.max_series_per_pass {
+            None => {
+                let mut map = EntryMap::with_options(map_options);
+                map.aggregate_files(file_list)?;
+
+                render_entries(map.into_sorted()?)?
+            }
+            Some(max_series) => {
+                // Each batch holds only its families, the text preamble and
+                // the OpenMetrics `# EOF` trailer are written once around
+                // them.
+                let text = format != OutputFormat::Protobuf;
+
+                let mut out = String::new();
+                // Shared by every batch, so families rendered under the same
+                // name in different batches collide and each sanitized name
+                // is counted once.
+                let mut names = RenderedNames::default();
+
+                if text {
+                    FileEntry::append_preamble(&render_options, &mut names, &mut out)?;
+                }
+
+                let append_batch = |entries: Vec<FileEntry>| -> magnus::error::Result<()> {
+                    if text {
+                        FileEntry::append_text(
+                            entries,
+                            &render_options,
+                            &mut names,
+                            &mut out,
+                            None,
+                        )?;
+                    } else {
+                        let batch =
+                            FileEntry::entries_to_protobuf_with_options(entries, &render_options)?;
+                        out.push_str(&batch);
+                    }
+                    Ok(())
+                };
+                EntryMap::aggregate_files_by_family(
+                    file_list,
+                    map_options,
+                    max_series,
+                    append_batch,
+                )?;
+
+                if text && render_options.openmetrics {
+                    out.push_str(OPENMETRICS_EOF);
+                }
+
+                (out, names.sanitized_count())
+            }
+        };
+
+        if sanitized > 0 {
+            // UNWRAP: this will panic if called outside of a Ruby thread.
+            let ruby = Ruby::get().unwrap();
+            let msg = format!(
+                "prometheus-client-mmap: sanitized {sanitized} invalid metric or label names"
+            );
+            let _: Value = ruby.module_kernel().funcall("warn", (msg,))?;
+        }
+
+        Ok(out)
     }
 
-    /// Read the list of files provided from Ruby and convert them to a Prometheus
-    /// metrics String.
-    pub fn to_protobuf(file_list: RArray) -> magnus::error::Result<String> {
-        let mut map = EntryMap::new();
-        map.aggregate_files(file_list)?;
+    /// Parse the keyword arguments accepted by the text rendering methods.
+    fn scrape_options(keywords: RHash) -> magnus::error::Result<(MapOptions, RenderOptions)> {
+        // The options controlling how files are read, the rest are left in
+        // the splat for rendering.
+        type MapKwargs = (
+            Option<bool>,
+            Option<bool>,
+            Option<bool>,
+            Option<usize>,
+            Option<usize>,
+            Option<usize>,
+        );
+        let kwargs = scan_args::get_kwargs::<_, (), MapKwargs, RHash>(
+            keywords,
+            &[],
+            &[
+                "canonicalize_labels",
+                "snapshot",
+                "unaligned_entries",
+                "max_series_per_pass",
+                "read_buffer_limit",
+                "max_key_length",
+            ],
+        )?;
+        let (
+            canonicalize_labels,
+            snapshot,
+            unaligned_entries,
+            max_series_per_pass,
+            read_buffer_limit,
+            max_key_length,
+        ) = kwargs.optional;
+
+        let mut map_options = MapOptions {
+            canonicalize_labels: canonicalize_labels.unwrap_or(false),
+            snapshot_files: snapshot.unwrap_or(false),
+            unaligned_entries: unaligned_entries.unwrap_or(false),
+            max_series_per_pass,
+            read_buffer_limit,
+            max_key_length,
+            ..Default::default()
+        };
 
-        let sorted = map.into_sorted()?;
+        type Kwargs = (
+            Option<bool>,
+            Option<bool>,
+            Option<bool>,
+            Option<bool>,
+            Option<HashMap<String, String>>,
+            Option<i64>,
+            Option<bool>,
+            Option<HashMap<String, String>>,
+        );
+        let kwargs = scan_args::get_kwargs::<_, (), Kwargs, ()>(
+            kwargs.splat,
+            &[],
+            &[
+                "strict_finite",
+                "timestamps",
+                "sanitize_names",
+                "openmetrics",
+                "name_prefixes",
+                "scrape_timestamp",
+                "tolerant_keys",
+                "target_info",
+            ],
+        )?;
+        let (
+            strict_finite,
+            timestamps,
+            sanitize_names,
+            openmetrics,
+            name_prefixes,
+            scrape_timestamp_ms,
+            tolerant_keys,
+            target_info,
+        ) = kwargs.optional;
+
+        let render_options = RenderOptions {
+            strict_finite: strict_finite.unwrap_or(false),
+            timestamps: timestamps.unwrap_or(false),
+            sanitize_names: sanitize_names.unwrap_or(false),
+            openmetrics: openmetrics.unwrap_or(false),
+            name_prefixes: name_prefixes
+                .map(|prefixes| prefixes.into_iter().collect())
+                .unwrap_or_default(),
+            scrape_timestamp_ms,
+            tolerant_keys: tolerant_keys.unwrap_or(false),
+            target_info: Self::sorted_labels(target_info.unwrap_or_default()),
+            ..Default::default()
+        };
+        // Keys are parsed while aggregating as well as rendering.
+        map_options.tolerant_keys = render_options.tolerant_keys;
 
-        FileEntry::entries_to_protobuf(sorted).map_err(|e| e.into())
+        Ok((map_options, render_options))
     }
 
+    /// Order labels passed as a Hash by name, so the output is stable.
+    fn sorted_labels(labels: HashMap<String, String>) -> Vec<(String, String)> {
+        let mut labels: Vec<_> = labels.into_iter().collect();
+        labels.sort();
+        labels
+    }
 
-    /// Document-method: []
-    /// Document-method: slice
+    /// call-seq:
+    ///   to_protobuf(file_list, timestamps: false, scrape_timestamp: nil, reject_malformed: false, tolerant_keys: false)
     ///
-    /// call-seq: [](args)
+    /// Read the list of files provided from Ruby and convert them to a Prometheus
+    /// metrics String.
     ///
-    /// Element reference - with the following syntax:
+    /// * <em>timestamps</em>
     ///
-    ///   self[nth]
+    ///     Set `timestamp_ms` on each metric to the time of its most recent
+    ///     exemplar.
     ///
-    /// retrieve the <em>nth</em> character
+    /// * <em>scrape_timestamp</em>
     ///
-    ///   self[start..last]
+    ///     Set `timestamp_ms` on every metric to this time in milliseconds,
+    ///     as for `to_metrics`.
     ///
-    /// return a substring from <em>start</em> to <em>last</em>
+    /// * <em>reject_malformed</em>
     ///
-    ///   self[start, length]
+    ///     Raise an error if any histogram bucket or summary quantile lacks a
+    ///     valid `le` or `quantile` label, rather than skipping it.
     ///
-    /// return a substring of <em>lenght</em> characters from <em>start</em>
-    pub fn slice(rb_self: Obj<Self>, args: &[Value]) -> magnus::error::Result<RString> {
-        // The C implementation would trigger a GC cycle via `rb_gc_force_recycle`
-        // if the `MM_PROTECT` flag is set, but in practice this is never used.
-        // We omit this logic, particularly because `rb_gc_force_recycle` is a
-        // no-op as of Ruby 3.1.
-        let rs_self = &*rb_self;
+    /// * <em>tolerant_keys</em>
+    ///
+    ///     As for `to_metrics`.
+    pub fn to_protobuf(args: &[Value]) -> magnus::error::Result<String> {
+        let args = scan_args::scan_args::<(RArray,), (), (), (), RHash, ()>(args)?;
+        let file_list = args.required.0;
+        let (map_options, options) = Self::protobuf_options(args.keywords)?;
 
-        let str = rs_self.str(rb_self)?;
-        rs_self._slice(rb_self, str, args)
+        Self::render_files(file_list, OutputFormat::Protobuf, map_options, options)
     }
 
-    fn _slice(
-        &self,
-        rb_self: Obj<Self>,
-        str: RString,
-        args: &[Value],
-    ) -> magnus::error::Result<RString> {
-        let substr: RString = str.funcall("[]", args)?;
+    /// call-seq:
+    ///   to_protobuf_io(file_list, io, **options) -> Integer
+    ///
+    /// Read the list of files provided from Ruby and write them to `io` in
+    /// the format returned by `to_protobuf`, calling `io.write` with each
+    /// length-delimited `MetricFamily` in turn rather than building the
+    /// whole output as one String. Accepts the same options as
+    /// `to_protobuf`. Returns the number of bytes written.
+    pub fn to_protobuf_io(args: &[Value]) -> magnus::error::Result<usize> {
+        let args = scan_args::scan_args::<(RArray, Value), (), (), (), RHash, ()>(args)?;
+        let (file_list, io) = args.required;
+        let (map_options, options) = Self::protobuf_options(args.keywords)?;
+
+        let mut map = EntryMap::with_options(map_options);
+        map.aggregate_files(file_list)?;
 
-        // Track shared child strings which use the same backing storage.
-        if Self::rb_string_is_shared(substr) {
-            (*rb_self).track_rstring(rb_self, substr)?;
+        let mut written = 0;
+        let mut frame = Vec::new();
+        for mf in FileEntry::metric_families(map.into_sorted()?, &options)? {
+            frame.clear();
+            file_entry::write_length_delimited(&mut frame, &mf.into_proto())?;
+
+            let _: Value = io.funcall("write", (RString::from_slice(&frame),))?;
+            written += frame.len();
         }
 
-        // The C implementation does this, perhaps to validate that the len we
-        // provided is actually being used.
-        (*rb_self).inner_mut(|inner| {
-            inner.set_len(str.len());
-            Ok(())
-        })?;
+        Ok(written)
+    }
 
-        Ok(substr)
+    /// Parse the keyword arguments accepted by the protobuf rendering methods.
+    fn protobuf_options(keywords: RHash) -> magnus::error::Result<(MapOptions, RenderOptions)> {
+        type Kwargs = (Option<bool>, Option<i64>, Option<bool>, Option<bool>);
+        let kwargs = scan_args::get_kwargs::<_, (), Kwargs, ()>(
+            keywords,
+            &[],
+            &[
+                "timestamps",
+                "scrape_timestamp",
+                "reject_malformed",
+                "tolerant_keys",
+            ],
+        )?;
+        let (timestamps, scrape_timestamp_ms, reject_malformed, tolerant_keys) = kwargs.optional;
+
+        let map_options = MapOptions {
+            tolerant_keys: tolerant_keys.unwrap_or(false),
+            ..Default::default()
+        };
+        let render_options = RenderOptions {
+            timestamps: timestamps.unwrap_or(false),
+            scrape_timestamp_ms,
+            reject_malformed: reject_malformed.unwrap_or(false),
+            tolerant_keys: map_options.tolerant_keys,
+            ..Default::default()
+        };
+
+        Ok((map_options, render_options))
     }
 
-    /// Document-method: msync
-    /// Document-method: sync
-    /// Document-method: flush
-    ///
-    /// call-seq: msync
-    ///
-    /// flush the file
-    pub fn sync(&self, args: &[Value]) -> magnus::error::Result<()> {
-        use nix::sys::mman::MsFlags;
+    /// Read the list of files provided from Ruby and encode them as an
+    /// uncompressed Prometheus remote-write `WriteRequest`, with each series
+    /// sampled at the current time. The caller must snappy-compress the
+    /// returned binary String before sending it.
+    pub fn to_remote_write(file_list: RArray) -> magnus::error::Result<RString> {
+        let mut map = EntryMap::new();
+        map.aggregate_files(file_list)?;
 
-        let mut ms_async = false;
-        let args = scan_args::scan_args::<(), (Option<i32>,), (), (), (), ()>(args)?;
+        let sorted = map.into_sorted()?;
 
-        if let Some(flag) = args.optional.0 {
-            let flag = MsFlags::from_bits(flag).unwrap_or(MsFlags::empty());
-            ms_async = flag.contains(MsFlags::MS_ASYNC);
-        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| err!(runtime_error(), "system clock before epoch: {}", e))?;
+        let timestamp_ms = util::cast_chk::<_, i64>(now.as_millis(), "timestamp")?;
 
-        // The `memmap2` crate does not support the `MS_INVALIDATE` flag. We ignore that
-        // flag if passed in, checking only for `MS_ASYNC`. In practice no arguments are ever
-        // passed to this function, but we do this to maintain compatibility with the
-        // C implementation.
-        self.inner_mut(|inner| inner.flush(ms_async))
-            .map_err(|e| e.into())
+        let encoded = FileEntry::entries_to_remote_write(sorted, timestamp_ms)?;
+
+        Ok(RString::from_slice(&encoded))
     }
 
-    /// Document-method: munmap
-    /// Document-method: unmap
-    ///
-    /// call-seq: munmap
+    /// call-seq:
+    ///   to_metrics_dir(dir, mode_and_type_resolver = nil)
     ///
-    /// terminate the association
-    pub fn munmap(rb_self: Obj<Self>) -> magnus::error::Result<()> {
-        let rs_self = &*rb_self;
+    /// Read all `*.db` files in `dir` and convert them to a Prometheus metrics
+    /// String. The type, multiprocess mode, and pid of each file are derived
+    /// from its name, following the Ruby client's naming scheme. For other
+    /// schemes, pass a `mode_and_type_resolver` called with the path of each
+    /// file that returns its `[multiprocess_mode, type, pid]`.
+    pub fn to_metrics_dir(args: &[Value]) -> magnus::error::Result<String> {
+        let args = scan_args::scan_args::<(String,), (Option<Value>,), (), (), (), ()>(args)?;
+        let dir = args.required.0;
+        let resolver = args.optional.0;
 
-        rs_self.inner_mut(|inner| {
-            // We are about to release the backing mmap for Ruby's String
-            // objects. If Ruby attempts to read from them the program will
-            // segfault. We update the length of all Strings to zero so Ruby
-            // does not attempt to access the now invalid address between now
-            // and when GC eventually reaps the objects.
-            //
-            // See the following for more detail:
-            // https://gitlab.com/gitlab-org/ruby/gems/prometheus-client-mmap/-/issues/39
-            // https://gitlab.com/gitlab-org/ruby/gems/prometheus-client-mmap/-/issues/41
-            // https://gitlab.com/gitlab-org/ruby/gems/prometheus-client-mmap/-/merge_requests/80
-            inner.set_len(0);
-            Ok(())
-        })?;
+        let mut map = EntryMap::new();
+        map.aggregate_dir(Path::new(&dir), resolver)?;
 
-        // Update each String object to be zero-length.
-        let cap = util::cast_chk::<_, c_long>(rs_self.capacity(), "capacity")?;
-        rs_self.update_weak_map(rb_self, rs_self.as_mut_ptr(), cap)?;
+        let sorted = map.into_sorted()?;
 
-        // Remove the `InnerMmap` from the `RwLock`. This will drop
-        // end of this function, unmapping and closing the file.
-        let _ = rs_self.take_inner()?;
-        Ok(())
+        FileEntry::entries_to_string(sorted).map_err(|e| e.into())
     }
 
-    /// Fetch the `used` header from the `.db` file, the length
-    /// in bytes of the data written to the file.
-    pub fn load_used(&self) -> magnus::error::Result<Integer> {
-        let used = self.inner(|inner| inner.load_used())?;
+    /// Collect the distinct pids in the list of files provided from Ruby, in the
+    /// order they are first seen. The pid is per-file metadata, so the files
+    /// themselves are not opened or parsed.
+    pub fn active_pids(file_list: RArray) -> magnus::error::Result<RArray> {
+        let mut seen = HashSet::new();
+        let mut pids = Vec::new();
 
-        Ok(Integer::from_u64(used as u64))
+        for item in file_list.each() {
+            let pid = Self::file_pid(item?)?;
+
+            if seen.insert(pid.clone()) {
+                pids.push(pid);
+            }
+        }
+
+        Ok(RArray::from_vec(pids))
     }
 
-    /// Update the `used` header for the `.db` file, the length
-    /// in bytes of the data written to the file.
-    pub fn save_used(rb_self: Obj<Self>, used: Fixnum) -> magnus::error::Result<Fixnum> {
-        let rs_self = &*rb_self;
-        let used_uint = used.to_u32()?;
-
-        // If the underlying mmap is smaller than the header, then resize to fit.
-        // The file has already been expanded to page size when first opened, so
-        // even if the map is less than HEADER_SIZE, we're not at risk of a
-        // SIGBUS.
-        if rs_self.capacity() < HEADER_SIZE {
-            rs_self.expand_to_fit(rb_self, HEADER_SIZE)?;
+    /// call-seq:
+    ///   to_metrics_by_pid(file_list) -> {pid => String}
+    ///
+    /// Render the files of each pid in the list separately, as `to_metrics`
+    /// would with the default options, for inspecting a single worker's
+    /// metrics. Pids are in the order they are first seen.
+    pub fn to_metrics_by_pid(file_list: RArray) -> magnus::error::Result<RHash> {
+        let out = RHash::new();
+        for (pid, files) in Self::files_by_pid(file_list)? {
+            out.aset(pid, Self::to_metrics(&[files.as_value()])?)?;
         }
 
-        rs_self.inner_mut(|inner| inner.save_used(used_uint))?;
-
-        Ok(used)
+        Ok(out)
     }
 
-    /// Fetch the value associated with a key from the mmap.
-    /// If no entry is present, initialize with the default
-    /// value provided.
-    pub fn fetch_entry(
-        rb_self: Obj<Self>,
-        positions: RHash,
-        key: RString,
-        default_value: f64,
-    ) -> magnus::error::Result<f64> {
-        let rs_self = &*rb_self;
-        let position: Option<Fixnum> = positions.lookup(key)?;
-
-        if let Some(pos) = position {
-            let pos = pos.to_usize()?;
-            return rs_self
-                .inner(|inner| inner.load_value(pos))
-                .map_err(|e| e.into());
+    /// Group the list of files provided from Ruby by pid, in the order pids
+    /// are first seen.
+    fn files_by_pid(file_list: RArray) -> magnus::error::Result<Vec<(String, RArray)>> {
+        let by_pid = RHash::new();
+        let mut pids = Vec::new();
+
+        for item in file_list.each() {
+            let item = item?;
+            let pid = Self::file_pid(item)?;
+
+            let files = match by_pid.lookup::<_, Option<RArray>>(pid.as_str())? {
+                Some(files) => files,
+                None => {
+                    let files = RArray::new();
+                    by_pid.aset(pid.as_str(), files)?;
+                    pids.push(pid);
+                    files
+                }
+            };
+            files.push(item)?;
         }
 
-        rs_self.check_expand(rb_self, key.len())?;
-
-        let value_offset: usize = rs_self.inner_mut(|inner| {
-            // SAFETY: We must not call any Ruby code for the lifetime of this borrow.
-            unsafe { inner.initialize_entry(key.as_slice(), default_value) }
-        })?;
+        pids.into_iter()
+            .map(|pid| {
+                let files: RArray = by_pid.fetch(pid.as_str())?;
+                Ok((pid, files))
+            })
+            .collect()
+    }
 
-        // CAST: no-op on 64-bit, widening on 32-bit.
-        positions.aset(key, Integer::from_u64(value_offset as u64))?;
+    /// call-seq:
+    ///   series_breakdown(file_list, family, name, labels) -> {value:, pids: {pid => value}} or nil
+    ///
+    /// Find the series `name` of `family` with exactly the `labels` Hash
+    /// given, and return its merged value along with each pid's contribution,
+    /// to help diagnose an aggregate that looks wrong. The files of each pid
+    /// are aggregated separately, then merged in the order pids are first
+    /// seen. Gauges in `all` mode render a series per pid, for these the
+    /// merged value is that of the last pid. Returns `nil` if no file holds
+    /// the series.
+    pub fn series_breakdown(
+        file_list: RArray,
+        family: String,
+        name: String,
+        labels: HashMap<String, String>,
+    ) -> magnus::error::Result<Option<RHash>> {
+        let mut merged: Option<file_entry::EntryMetadata> = None;
+        let pids = RHash::new();
+
+        for (pid, files) in Self::files_by_pid(file_list)? {
+            let mut map = EntryMap::new();
+            map.aggregate_files(files)?;
+
+            for entry in map.into_sorted()? {
+                let Ok(metric) = entry.metric_text() else {
+                    continue;
+                };
+                if metric.family_name != family
+                    || metric.metric_name != name
+                    || metric.labels.len() != labels.len()
+                {
+                    continue;
+                }
 
-        rs_self.load_value(value_offset)
-    }
+                let mut matched = true;
+                for (&label, value) in metric.labels.iter().zip(metric.values.iter()) {
+                    if labels.get(label) != Some(&FileEntry::label_value(value)?) {
+                        matched = false;
+                        break;
+                    }
+                }
 
-    pub fn upsert_exemplar(
-        rb_self: Obj<Self>,
-        positions: RHash,
-        key: RString,
-        value: f64,
-        exemplar_name: RString,
-        exemplar_value: RString,
-    ) -> magnus::error::Result<f64> {
-        let rs_self = &*rb_self;
-        let position: Option<Fixnum> = positions.lookup(key)?;
+                let Some(value) = entry.meta.value.filter(|_| matched) else {
+                    continue;
+                };
 
-        let start = SystemTime::now();
-        let since_the_epoch = start
-            .duration_since(UNIX_EPOCH)
-            .expect("Time went backwards");
+                pids.aset(pid.as_str(), value)?;
+                match merged.as_mut() {
+                    Some(meta) => meta.merge(&entry.meta),
+                    None => merged = Some(entry.meta),
+                }
+            }
+        }
 
-        let ex: Exemplar = Exemplar {
-            label_name: unsafe { exemplar_name.as_str().unwrap().into() },
-            label_value: unsafe { exemplar_value.as_str().unwrap().into() },
-            value: value,
-            timestamp: since_the_epoch.as_nanos(),
+        let Some(merged) = merged else {
+            return Ok(None);
         };
 
-        if let Some(pos) = position {
-            let pos = pos.to_usize()?;
-            return rs_self
-                .inner_mut(|inner| {
-                    inner.save_exemplar(pos, ex)?;
+        let out = RHash::new();
+        out.aset(Symbol::new("value"), merged.value)?;
+        out.aset(Symbol::new("pids"), pids)?;
 
-                    // TODO just return `value` here instead of loading it?
-                    // This is how the C implementation did it, but I don't
-                    // see what the extra load gains us.
-                    let ex = inner.load_exemplar(pos);
+        Ok(Some(out))
+    }
 
-                    Ok(ex.unwrap().value)
-                })
-                .map_err(|e| e.into());
+    /// call-seq:
+    ///   to_metrics_with_extra(file_list, extra_text) -> String
+    ///
+    /// Render the files in the list as `to_metrics` would with the default
+    /// options, followed by `extra_text`, exposition from another source in
+    /// the text format. Line endings in `extra_text` are normalized to `\n`.
+    /// Raises an `ArgumentError` if `extra_text` declares the `# TYPE` of a
+    /// family more than once, or of a family already rendered.
+    pub fn to_metrics_with_extra(
+        file_list: RArray,
+        extra_text: String,
+    ) -> magnus::error::Result<String> {
+        let mut out = Self::to_metrics(&[file_list.as_value()])?;
+        let extra_text = util::normalize_newlines(&extra_text);
+
+        let mut families: HashSet<&str> = Self::type_families(&out).collect();
+        for family in Self::type_families(&extra_text) {
+            if !families.insert(family) {
+                return Err(err!(
+                    arg_error(),
+                    "duplicate # TYPE for family '{family}' in extra text"
+                ));
+            }
         }
 
+        if !extra_text.is_empty() {
+            if !out.is_empty() && !out.ends_with('\n') {
+                out.push('\n');
+            }
+            out.push_str(&extra_text);
+        }
 
-        rs_self.check_expand_exemplar(rb_self, key.len())?;
+        Ok(out)
+    }
 
-        let value_offset: usize = rs_self.inner_mut(|inner| {
-            // SAFETY: We must not call any Ruby code for the lifetime of this borrow.
-            unsafe { inner.initialize_entry_exemplar(key.as_slice(), ex) }
-        })?;
+    /// call-seq:
+    ///   to_metrics_with_ranges(file_list) -> [String, {family => [start, length]}]
+    ///
+    /// Render the files in the list as `to_metrics` would with the default
+    /// options, along with the byte offset and length of each family's region
+    /// in the output, so a caller can serve a single family without parsing
+    /// the text again.
+    pub fn to_metrics_with_ranges(file_list: RArray) -> magnus::error::Result<RArray> {
+        let mut map = EntryMap::new();
+        map.aggregate_files(file_list)?;
 
-        // CAST: no-op on 64-bit, widening on 32-bit.
-        positions.aset(key, Integer::from_u64(value_offset as u64))?;
+        let sorted = map.into_sorted()?;
+        let (out, ranges) =
+            FileEntry::entries_to_string_with_ranges(sorted, &RenderOptions::default())?;
 
-        let ex = rs_self.load_exemplar(value_offset);
+        let families = RHash::new();
+        for (family, range) in ranges {
+            families.aset(family, RArray::from_vec(vec![range.start, range.len()]))?;
+        }
 
-        Ok(ex.unwrap().value)
+        Ok(RArray::from_vec(vec![
+            RString::new(&out).as_value(),
+            families.as_value(),
+        ]))
     }
 
-    /// Update the value of an existing entry, if present. Otherwise create a new entry
-    /// for the key.
-    pub fn upsert_entry(
-        rb_self: Obj<Self>,
-        positions: RHash,
-        key: RString,
-        value: f64,
-    ) -> magnus::error::Result<f64> {
-        let rs_self = &*rb_self;
-        let position: Option<Fixnum> = positions.lookup(key)?;
+    /// call-seq:
+    ///   prewarm(file_list) -> Integer
+    ///
+    /// Ask the kernel to load each file in the list into the page cache, so a
+    /// scrape that follows doesn't start cold. This is a maintenance operation,
+    /// the reads happen in the background. Returns the number of bytes advised.
+    pub fn prewarm(file_list: RArray) -> magnus::error::Result<usize> {
+        let mut total = 0usize;
 
-        if let Some(pos) = position {
-            let pos = pos.to_usize()?;
-            return rs_self
-                .inner_mut(|inner| {
-                    inner.save_value(pos, value)?;
+        for item in file_list.each() {
+            let params = Self::file_params(item?)?;
+            let file_info = FileInfo::open_from_params(&params)?;
 
-                    // TODO just return `value` here instead of loading it?
-                    // This is how the C implementation did it, but I don't
-                    // see what the extra load gains us.
-                    inner.load_value(pos)
-                })
-                .map_err(|e| e.into());
+            total = total.add_chk(file_info.prewarm()?)?;
         }
 
-        rs_self.check_expand(rb_self, key.len())?;
-
-        let value_offset: usize = rs_self.inner_mut(|inner| {
-            // SAFETY: We must not call any Ruby code for the lifetime of this borrow.
-            unsafe { inner.initialize_entry(key.as_slice(), value) }
-        })?;
+        Ok(total)
+    }
 
-        // CAST: no-op on 64-bit, widening on 32-bit.
-        positions.aset(key, Integer::from_u64(value_offset as u64))?;
+    /// The pid of a file in a file list, from its `[path, multiprocess_mode,
+    /// type, pid]` params.
+    fn file_pid(item: Value) -> magnus::error::Result<String> {
+        let params = Self::file_params(item)?;
+        let (_, _, pid) = FileInfo::metadata_from_params(&params)?;
 
-        rs_self.load_value(value_offset)
+        Ok(pid)
     }
 
-    /// Creates a Ruby String containing the section of the mmapped file that
-    /// has been written to.
-    fn str(&self, rb_self: Obj<Self>) -> magnus::error::Result<RString> {
-        let val_id = (*rb_self).inner(|inner| {
-            let ptr = inner.as_ptr();
-            let len = inner.len();
-
-            // SAFETY: This is safe so long as the data provided to Ruby meets its
-            // requirements. When unmapping the file this will no longer be the
-            // case, see the comment on `munmap` for how we handle this.
-            Ok(unsafe { rb_str_new_static(ptr as _, len as _) })
-        })?;
+    /// The `[path, multiprocess_mode, type, pid]` params of a file in a file
+    /// list.
+    fn file_params(item: Value) -> magnus::error::Result<[Value; 4]> {
+        let params = RArray::from_value(item)
+            .ok_or_else(|| err!(arg_error(), "file list was not a Ruby Array"))?;
+        if params.len() != 4 {
+            return Err(err!(
+                arg_error(),
+                "wrong number of arguments {} instead of 4",
+                params.len()
+            ));
+        }
 
-        // SAFETY: We know that rb_str_new_static returns a VALUE.
-        let val = unsafe { Value::from_raw(val_id) };
+        params.to_value_array::<4>()
+    }
 
-        // UNWRAP: We created this value as a string above.
-        let str = RString::from_value(val).unwrap();
+    /// The family names declared by the `# TYPE` lines of text format output.
+    fn type_families(text: &str) -> impl Iterator<Item = &str> {
+        text.lines().filter_map(|line| {
+            line.strip_prefix("# TYPE ")
+                .and_then(|rest| rest.split_whitespace().next())
+        })
+    }
 
-        // Freeze the root string so it can't be mutated out from under any
-        // substrings created. This object is never exposed to callers.
-        str.freeze();
+    /// Read the list of files provided from Ruby and return a digest of the
+    /// aggregated metrics. Identical metric state yields an identical digest,
+    /// regardless of the order of `file_list`, so callers can detect whether
+    /// the output has changed without rendering or diffing it.
+    pub fn metrics_digest(file_list: RArray) -> magnus::error::Result<u64> {
+        let mut map = EntryMap::new();
+        map.aggregate_files(file_list)?;
 
-        // Track the RString in our `WeakMap` so we can update its address if
-        // we re-mmap the backing file.
-        (*rb_self).track_rstring(rb_self, str)?;
+        let sorted = map.into_sorted()?;
 
-        Ok(str)
+        Ok(FileEntry::digest(&sorted))
     }
 
-    /// If we reallocate, any live Ruby strings provided by the `str()` method
-    /// will be invalidated. We need to iterate over them using and update their
-    /// heap pointers to the newly allocated memory region.
-    fn update_weak_map(
-        &self,
-        rb_self: Obj<Self>,
-        old_ptr: *const c_char,
-        old_cap: c_long,
-    ) -> magnus::error::Result<()> {
-        let tracker: Value = rb_self.ivar_get("@weak_obj_tracker")?;
+    /// call-seq:
+    ///   to_metrics_if_modified(file_list, digests) -> [{family => String or :not_modified}, {family => Integer}]
+    ///
+    /// Render the files in the list as `to_metrics` would with the default
+    /// options, one family at a time, skipping those whose digest matches the
+    /// one in `digests` from a previous call. Unchanged families map to
+    /// `:not_modified` so a caching caller can reuse its text for them, and
+    /// families no longer present are omitted. The digests of the current
+    /// state are returned alongside, to pass to the next call.
+    pub fn to_metrics_if_modified(
+        file_list: RArray,
+        digests: HashMap<String, u64>,
+    ) -> magnus::error::Result<RArray> {
+        let mut map = EntryMap::new();
+        map.aggregate_files(file_list)?;
 
-        let new_len = self.inner(|inner| util::cast_chk::<_, c_long>(inner.len(), "mmap len"))?;
+        let families = RHash::new();
+        let current = RHash::new();
 
-        // Iterate over the values of the `WeakMap`.
-        for val in tracker.enumeratorize("each_value", ()) {
-            let rb_string = val?;
-            let str = RString::from_value(rb_string)
-                .ok_or_else(|| err!(arg_error(), "weakmap value was not a string"))?;
+        for (family, entries) in FileEntry::split_families(map.into_sorted()?) {
+            let digest = FileEntry::digest(&entries);
 
-            // SAFETY: We're messing with Ruby's internals here, YOLO.
-            unsafe {
-                // Convert the magnus wrapper type to a raw string exposed by `rb_sys`,
-                // which provides access to its internals.
-                let mut raw_str = Self::rb_string_internal(str);
+            if digests.get(&family) == Some(&digest) {
+                families.aset(family.as_str(), Symbol::new("not_modified"))?;
+            } else {
+                families.aset(family.as_str(), FileEntry::entries_to_string(entries)?)?;
+            }
+            current.aset(family, digest)?;
+        }
 
-                // Shared string have their own `ptr` and `len` values, but `aux`
-                // is the id of the parent string so the GC can track this
-                // dependency. The `ptr` will always be an offset from the base
-                // address of the mmap, and `len` will be the length of the mmap
-                // less the offset from the base.
-                if Self::rb_string_is_shared(str) && new_len > 0 {
-                    // Calculate how far into the original mmap the shared string
-                    // started and update to the equivalent address in the new
-                    // one.
-                    let substr_ptr = raw_str.as_ref().as_.heap.ptr;
-                    let offset = substr_ptr.offset_from(old_ptr);
+        Ok(RArray::from_vec(vec![
+            families.as_value(),
+            current.as_value(),
+        ]))
+    }
 
-                    raw_str.as_mut().as_.heap.ptr = self.as_mut_ptr().offset(offset);
+    /// Read the list of files provided from Ruby and estimate the length in
+    /// bytes of the text `to_metrics` would render for them, without building
+    /// the output. This is exact with the default options, but options that
+    /// rewrite names or append timestamps change the rendered length.
+    pub fn estimate_size(file_list: RArray) -> magnus::error::Result<usize> {
+        let mut map = EntryMap::new();
+        map.aggregate_files(file_list)?;
 
-                    let current_len = str.len() as c_long;
-                    let new_shared_len = old_cap + current_len;
+        let sorted = map.into_sorted()?;
 
-                    self.update_rstring_len(raw_str, new_shared_len);
-                    continue;
-                }
+        Ok(FileEntry::estimate_text_len(&sorted))
+    }
 
-                // Update the string to point to the new mmapped file.
-                // We're matching the behavior of Ruby's `str_new_static` function.
-                // See https://github.com/ruby/ruby/blob/e51014f9c05aa65cbf203442d37fef7c12390015/string.c#L1030-L1053
-                //
-                // We deliberately do _NOT_ increment the `capa` field of the
-                // string to match the new `len`. We were initially doing this,
-                // but consistently triggered GCs in the middle of updating the
-                // string pointers, causing a segfault.
-                //
-                // See https://gitlab.com/gitlab-org/ruby/gems/prometheus-client-mmap/-/issues/45
-                raw_str.as_mut().as_.heap.ptr = self.as_mut_ptr();
-                self.update_rstring_len(raw_str, new_len);
-            }
+    /// call-seq:
+    ///   stats(file_list) -> {type => count}
+    ///
+    /// Read the list of files provided from Ruby and count the series they
+    /// aggregate to by metric type, e.g. `{counter: 10, gauge: 5}`. Each
+    /// histogram bucket and summary sample counts as a series.
+    pub fn stats(file_list: RArray) -> magnus::error::Result<RHash> {
+        let mut map = EntryMap::new();
+        map.aggregate_files(file_list)?;
+
+        let counts = RHash::new();
+        for entry in map.into_sorted()? {
+            let type_ = entry.meta.type_;
+            let count = counts.lookup::<_, Option<usize>>(type_)?.unwrap_or(0);
+            counts.aset(type_, count + 1)?;
         }
 
-        Ok(())
+        Ok(counts)
     }
 
-    /// Check that the mmap is large enough to contain the value to be added,
-    /// and expand it to fit if necessary.
-    fn check_expand(&self, rb_self: Obj<Self>, key_len: usize) -> magnus::error::Result<()> {
-        // CAST: no-op on 32-bit, widening on 64-bit.
-        let used = self.inner(|inner| inner.load_used())? as usize;
-        let entry_len = RawEntry::calc_total_len(key_len)?;
+    /// call-seq:
+    ///   self_stats(mmaps) -> {mapped_bytes:, tracked_strings:, remaps:}
+    ///
+    /// Describe the memory footprint of the `FastMmapedFileRs` objects
+    /// provided: the total bytes they have mapped, the number of strings
+    /// backed by their mmaps held in their `WeakMap`s, and the total number
+    /// of times they were re-mapped. Unmapped objects map no bytes.
+    pub fn self_stats(mmaps: RArray) -> magnus::error::Result<RHash> {
+        let mut mapped_bytes = 0;
+        let mut tracked_strings = 0;
+        let mut remaps = 0;
+
+        for item in mmaps.each() {
+            let rb_self = Obj::<Self>::try_convert(item?)?;
+
+            if rb_self.is_mapped() {
+                mapped_bytes += rb_self.inner(|inner| Ok(inner.capacity()))?;
+            }
+
+            if rb_self.track_strings() {
+                let tracker: Value = rb_self.ivar_get("@weak_obj_tracker")?;
+                tracked_strings += tracker.funcall::<_, _, usize>("size", ())?;
+            }
 
-        // We need the mmapped region to contain at least one byte beyond the
-        // written data to create a NUL- terminated C string. Validate that
-        // new length does not exactly match or exceed the length of the mmap.
-        while self.capacity() <= used.add_chk(entry_len)? {
-            self.expand_to_fit(rb_self, self.capacity().mul_chk(2)?)?;
+            remaps += rb_self.remap_count();
         }
 
-        Ok(())
+        let stats = RHash::new();
+        stats.aset(Symbol::new("mapped_bytes"), mapped_bytes)?;
+        stats.aset(Symbol::new("tracked_strings"), tracked_strings)?;
+        stats.aset(Symbol::new("remaps"), remaps)?;
+
+        Ok(stats)
     }
 
-    /// Check that the mmap is large enough to contain the value to be added,
-    /// and expand it to fit if necessary.
-    fn check_expand_exemplar(&self, rb_self: Obj<Self>, key_len: usize) -> magnus::error::Result<()> {
-        // CAST: no-op on 32-bit, widening on 64-bit.
-        let used = self.inner(|inner| inner.load_used())? as usize;
-        let entry_len = RawEntry::calc_total_len_exemplar(key_len)?;
+    /// call-seq:
+    ///   histogram_quantile(file_list, family, q) -> Float or nil
+    ///
+    /// Read the list of files provided from Ruby and estimate the `q` quantile
+    /// of the histogram `family`, interpolating within its aggregated buckets
+    /// as PromQL's `histogram_quantile` does. Returns `nil` if `family` is not
+    /// a histogram.
+    pub fn histogram_quantile(
+        file_list: RArray,
+        family: String,
+        q: f64,
+    ) -> magnus::error::Result<Option<f64>> {
+        let mut map = EntryMap::new();
+        map.aggregate_files(file_list)?;
 
-        // We need the mmapped region to contain at least one byte beyond the
-        // written data to create a NUL- terminated C string. Validate that
-        // new length does not exactly match or exceed the length of the mmap.
-        while self.capacity() <= used.add_chk(entry_len)? {
-            self.expand_to_fit(rb_self, self.capacity().mul_chk(2)?)?;
-        }
+        let sorted = map.into_sorted()?;
 
-        Ok(())
+        FileEntry::histogram_quantile(sorted, &family, q).map_err(|e| e.into())
     }
 
-    /// Expand the underlying file until it is long enough to fit `target_cap`.
-    /// This will remove the existing mmap, expand the file, then update any
-    /// strings held by the `WeakMap` to point to the newly mmapped address.
-    fn expand_to_fit(&self, rb_self: Obj<Self>, target_cap: usize) -> magnus::error::Result<()> {
-        if target_cap < self.capacity() {
-            return Err(err!(arg_error(), "Can't reduce the size of mmap"));
+    /// call-seq:
+    ///   repair(path) -> Integer
+    ///
+    /// Salvage a corrupt `.db` file by keeping the run of valid entries at its
+    /// start: `used` is set to the end of the last valid entry and the file
+    /// is truncated there, discarding the first malformed entry and all that
+    /// follow it. Returns the number of bytes of entries discarded, those
+    /// between the last valid entry and `used`. A file with no corruption is
+    /// left untouched, including any unused space past `used`, and returns
+    /// zero. This is an operator recovery tool, the file must not be mapped
+    /// by any process while it is repaired.
+    pub fn repair(path: String) -> magnus::error::Result<usize> {
+        let path = Path::new(&path);
+        let source = std::fs::read(path).map_err(|e| MmapError::io("read", path, e))?;
+
+        if source.len() < HEADER_SIZE {
+            return Ok(0);
         }
 
-        let mut new_cap = self.capacity();
-        while new_cap < target_cap {
-            new_cap = new_cap.mul_chk(2)?;
+        let is_exemplar = FileInfo::metadata_from_file_name(path)
+            .is_some_and(|(type_, _, _)| type_ == "exemplar");
+
+        // CAST: no-op on 32-bit, widening on 64-bit.
+        let used = util::read_u32(&source, 0)? as usize;
+        let layout = Layout::from_header(&source)?;
+
+        // A `used` past the end of the file is itself corrupt, check the
+        // entries that were actually written.
+        let valid = EntryMap::valid_len(&source, used.min(source.len()), layout, is_exemplar);
+        if valid == used {
+            return Ok(0);
         }
+        let valid_u32 = util::cast_chk::<_, u32>(valid, "used")?;
 
-        if new_cap != self.capacity() {
-            let old_ptr = self.as_mut_ptr();
-            let old_cap = util::cast_chk::<_, c_long>(self.capacity(), "capacity")?;
+        let file = File::options()
+            .write(true)
+            .open(path)
+            .map_err(|e| MmapError::io("open", path, e))?;
 
-            // Drop the old mmap.
-            let (mut file, path) = self.take_inner()?.munmap();
+        file.write_all_at(&valid_u32.to_ne_bytes(), 0)
+            .map_err(|e| MmapError::io("write", path, e))?;
+        file.set_len(valid as u64)
+            .map_err(|e| MmapError::io("truncate", path, e))?;
+        file.sync_all()
+            .map_err(|e| MmapError::io("sync", path, e))?;
 
-            self.expand_file(&mut file, &path, target_cap)?;
+        Ok(used.min(source.len()).saturating_sub(valid))
+    }
 
-            // Re-mmap the expanded file.
-            let new_inner = InnerMmap::reestablish(path, file, target_cap)?;
+    /// call-seq:
+    ///   debug_dump(path) -> {used:, capacity:, entries: [{offset:, key:, value:, exemplar:}]}
+    ///
+    /// Describe the raw contents of a single `.db` file for debugging. Entries
+    /// are listed in file order with the offset of their length prefix. The
+    /// file is treated as an exemplar file if its name has the `exemplar`
+    /// type, in which case `exemplar` holds the stored exemplar, otherwise it
+    /// is `nil`. This reads the whole file and is not meant for scraping.
+    pub fn debug_dump(path: String) -> magnus::error::Result<RHash> {
+        let path = Path::new(&path);
+        let source = std::fs::read(path).map_err(|e| MmapError::io("read", path, e))?;
+
+        let is_exemplar = FileInfo::metadata_from_file_name(path)
+            .is_some_and(|(type_, _, _)| type_ == "exemplar");
+
+        let dump = RHash::new();
+        dump.aset(Symbol::new("capacity"), source.len())?;
+
+        let entries = RArray::new();
+        if source.len() < HEADER_SIZE {
+            dump.aset(Symbol::new("used"), 0)?;
+            dump.aset(Symbol::new("entries"), entries)?;
+            return Ok(dump);
+        }
 
-            self.insert_inner(new_inner)?;
+        // CAST: no-op on 32-bit, widening on 64-bit.
+        let used = util::read_u32(&source, 0)? as usize;
+        dump.aset(Symbol::new("used"), used)?;
+
+        if used > source.len() {
+            return Err(MmapError::PromParsing(format!(
+                "source file {} corrupted, used {used} > file size {}",
+                path.display(),
+                source.len()
+            ))
+            .into());
+        }
 
-            return self.update_weak_map(rb_self, old_ptr, old_cap);
+        let layout = Layout::from_header(&source)?;
+        let exemplar_format = if is_exemplar {
+            ExemplarFormat::from_header(&source)?
+        } else {
+            ExemplarFormat::default()
+        };
+        for offset in EntryMap::entry_offsets(&source, used, layout, is_exemplar) {
+            let offset = offset?;
+            let entry = RHash::new();
+            entry.aset(Symbol::new("offset"), offset)?;
+
+            if is_exemplar {
+                let raw_entry =
+                    RawEntry::from_slice_exemplar(&source[offset..used], exemplar_format)?;
+                let ex = raw_entry.exemplar()?;
+
+                let exemplar = RHash::new();
+                exemplar.aset(Symbol::new("label_name"), ex.label_name.as_str())?;
+                exemplar.aset(Symbol::new("label_value"), ex.label_value.as_str())?;
+                exemplar.aset(Symbol::new("value"), ex.value)?;
+                exemplar.aset(
+                    Symbol::new("timestamp"),
+                    util::cast_chk::<_, u64>(ex.timestamp, "timestamp")?,
+                )?;
+
+                entry.aset(Symbol::new("key"), RString::from_slice(raw_entry.json()))?;
+                entry.aset(Symbol::new("value"), ex.value)?;
+                entry.aset(Symbol::new("exemplar"), exemplar)?;
+            } else {
+                let raw_entry = RawEntry::from_slice_with_layout(&source[offset..used], layout)?;
+
+                entry.aset(Symbol::new("key"), RString::from_slice(raw_entry.json()))?;
+                entry.aset(Symbol::new("value"), raw_entry.value())?;
+                entry.aset(Symbol::new("exemplar"), ())?;
+            }
+
+            entries.push(entry)?;
         }
+        dump.aset(Symbol::new("entries"), entries)?;
 
-        Ok(())
+        Ok(dump)
     }
 
-    /// Use lseek(2) to seek past the end of the file and write a NUL byte. This
-    /// creates a file hole that expands the size of the file without consuming
-    /// disk space until it is actually written to.
-    fn expand_file(&self, file: &mut File, path: &Path, len: usize) -> Result<()> {
-        if len == 0 {
-            return Err(MmapError::overflowed(0, -1, "adding"));
+    /// call-seq:
+    ///   to_family_map(file_list) -> {family_name => [{name:, labels:, value:, pid:}]}
+    ///
+    /// Read the list of files provided from Ruby and return the merged series
+    /// grouped by family, for callers doing their own serialization. Label
+    /// values are Strings, and `pid` is `nil` unless the series is per-pid.
+    pub fn to_family_map(file_list: RArray) -> magnus::error::Result<RHash> {
+        let mut map = EntryMap::new();
+        map.aggregate_files(file_list)?;
+
+        let sorted = map.into_sorted()?;
+
+        let families = RHash::new();
+        let mut current: Option<(String, RArray)> = None;
+
+        for entry in &sorted {
+            let metric = entry.metric_text()?;
+            let series = Self::series_hash(entry)?;
+
+            // Entries are sorted by JSON, which starts with the family name,
+            // so each family's series are adjacent.
+            match current.as_ref() {
+                Some((family, list)) if family == metric.family_name => list.push(series)?,
+                _ => {
+                    let list = RArray::from_vec(vec![series]);
+                    families.aset(metric.family_name, list)?;
+                    current = Some((metric.family_name.to_string(), list));
+                }
+            }
         }
 
-        // CAST: no-op on 64-bit, widening on 32-bit.
-        let len = len as u64;
+        Ok(families)
+    }
 
-        match file.seek(SeekFrom::Start(len - 1)) {
-            Ok(_) => {}
-            Err(_) => {
-                return Err(MmapError::with_errno(format!("Can't lseek {}", len - 1)));
+    /// call-seq:
+    ///   top_series(file_list, n) -> [{name:, labels:, value:, pid:}]
+    ///
+    /// Read the list of files provided from Ruby and return the `n` merged
+    /// series with the highest values, highest first, to find runaway
+    /// counters. Series are in the form returned by `to_family_map`. `NaN`
+    /// values are skipped.
+    pub fn top_series(file_list: RArray, n: usize) -> magnus::error::Result<RArray> {
+        let mut map = EntryMap::new();
+        map.aggregate_files(file_list)?;
+
+        let sorted = map.into_sorted()?;
+
+        // A min-heap of the highest `n` values seen so far. Equal values keep
+        // their sorted order.
+        let mut top = BinaryHeap::with_capacity(n.min(sorted.len()) + 1);
+        for (i, entry) in sorted.iter().enumerate() {
+            let Some(value) = entry.meta.value.filter(|v| !v.is_nan()) else {
+                continue;
+            };
+
+            top.push(Reverse((TotalOrd(value), Reverse(i))));
+            if top.len() > n {
+                top.pop();
             }
         }
 
-        match file.write(&[0x0]) {
-            Ok(1) => {}
-            _ => {
-                return Err(MmapError::with_errno(format!(
-                    "Can't extend {}",
-                    path.display()
-                )));
+        let out = RArray::with_capacity(top.len());
+        for Reverse((_, Reverse(i))) in top.into_sorted_vec() {
+            out.push(Self::series_hash(&sorted[i])?)?;
+        }
+
+        Ok(out)
+    }
+
+    /// call-seq:
+    ///   cardinality(file_list) -> {family_name => series_count}
+    ///
+    /// Read the list of files provided from Ruby and count the merged series
+    /// of each family, to spot label explosions. Series kept per-pid, such as
+    /// gauges in the `all` mode, count once per pid.
+    pub fn cardinality(file_list: RArray) -> magnus::error::Result<RHash> {
+        let mut map = EntryMap::new();
+        map.aggregate_files(file_list)?;
+
+        let sorted = map.into_sorted()?;
+
+        // Entries are sorted by JSON, which starts with the family name, so
+        // each family's series are adjacent.
+        let mut counts: Vec<(&str, usize)> = Vec::new();
+        for entry in &sorted {
+            let family = entry.metric_text()?.family_name;
+
+            match counts.last_mut() {
+                Some((name, count)) if *name == family => *count += 1,
+                _ => counts.push((family, 1)),
             }
         }
 
-        Ok(())
+        let out = RHash::new();
+        for (family, count) in counts {
+            out.aset(family, count)?;
+        }
+
+        Ok(out)
     }
 
-    fn track_rstring(&self, rb_self: Obj<Self>, str: RString) -> magnus::error::Result<()> {
-        let tracker: Value = rb_self.ivar_get("@weak_obj_tracker")?;
+    /// call-seq:
+    ///   delta(old_list, new_list) -> [{name:, labels:, value:, pid:, old:, delta:, reset:}]
+    ///
+    /// Aggregate two snapshots of the same files, e.g. copies taken a minute
+    /// apart, and return how much each merged counter series increased
+    /// between them, to analyze counter behavior offline. Series are in the
+    /// form returned by `to_family_map`, with `old` set to the value in
+    /// `old_list`. A value lower than `old` is a reset, flagged by `reset`,
+    /// and as in PromQL's `increase` the new value is taken as the delta. A
+    /// series only in `new_list` has a nil `old` and its value as the delta.
+    /// Series only in `old_list` are omitted, and series of other types are
+    /// ignored.
+    pub fn delta(old_list: RArray, new_list: RArray) -> magnus::error::Result<RArray> {
+        let mut old_map = EntryMap::new();
+        old_map.aggregate_files(old_list)?;
+
+        let old: HashMap<_, _> = old_map
+            .into_sorted()?
+            .into_iter()
+            .filter(|entry| entry.meta.type_ == SYM_COUNTER)
+            .filter_map(|entry| Some((entry.data, entry.meta.value?)))
+            .collect();
+
+        let mut new_map = EntryMap::new();
+        new_map.aggregate_files(new_list)?;
+
+        let out = RArray::new();
+        for entry in new_map.into_sorted()? {
+            if entry.meta.type_ != SYM_COUNTER {
+                continue;
+            }
+            let Some(value) = entry.meta.value else {
+                continue;
+            };
+
+            let prev = old.get(&entry.data).copied();
+            let (delta, reset) = match prev {
+                Some(prev) if value < prev => (value, true),
+                Some(prev) => (value - prev, false),
+                None => (value, false),
+            };
+
+            let series = Self::series_hash(&entry)?;
+            series.aset(Symbol::new("old"), prev)?;
+            series.aset(Symbol::new("delta"), delta)?;
+            series.aset(Symbol::new("reset"), reset)?;
+            out.push(series)?;
+        }
 
-        // Use the string's Id as the key in the `WeakMap`.
-        let key = str.as_raw();
-        let _: Value = tracker.funcall("[]=", (key, str))?;
-        Ok(())
+        Ok(out)
     }
 
-    /// The total capacity of the underlying mmap.
-    #[inline]
-    fn capacity(&self) -> usize {
-        // UNWRAP: This is actually infallible, but we need to
-        // wrap it in a `Result` for use with `inner()`.
-        self.inner(|inner| Ok(inner.capacity())).unwrap()
+    /// call-seq:
+    ///   select(file_list, matchers) -> [{name:, labels:, value:, pid:}]
+    ///
+    /// Read the list of files provided from Ruby and return the merged series
+    /// matching every one of `matchers`, in the form returned by
+    /// `to_family_map`. Each matcher is a Hash of `label`, `op`, and `value`,
+    /// where `op` is `:eq`, `:neq`, or `:regex`. As in PromQL, regexes must
+    /// match the whole value, `__name__` matches the metric name, and a
+    /// missing label matches as the empty string.
+    pub fn select(file_list: RArray, matchers: RArray) -> magnus::error::Result<RArray> {
+        let matchers = matchers
+            .each()
+            .map(|matcher| Self::label_matcher(matcher?))
+            .collect::<magnus::error::Result<Vec<_>>>()?;
+
+        let mut map = EntryMap::new();
+        map.aggregate_files(file_list)?;
+
+        let out = RArray::new();
+        for entry in &map.into_sorted()? {
+            let metric = entry.metric_text()?;
+
+            let mut selected = true;
+            for matcher in &matchers {
+                if !matcher.matches(&metric)? {
+                    selected = false;
+                    break;
+                }
+            }
+
+            if selected {
+                out.push(Self::series_hash(entry)?)?;
+            }
+        }
+
+        Ok(out)
     }
 
-    fn load_value(&self, position: usize) -> magnus::error::Result<f64> {
-        self.inner(|inner| inner.load_value(position))
-            .map_err(|e| e.into())
+    /// Parse a `{label:, op:, value:}` Hash into a `LabelMatcher`.
+    fn label_matcher(matcher: Value) -> magnus::error::Result<LabelMatcher> {
+        let matcher = RHash::from_value(matcher)
+            .ok_or_else(|| err!(arg_error(), "matcher was not a Ruby Hash"))?;
+
+        let label: String = matcher.fetch(Symbol::new("label"))?;
+        let value: String = matcher.fetch(Symbol::new("value"))?;
+        let op: Value = matcher.fetch(Symbol::new("op"))?;
+        let op = match Symbol::from_value(op) {
+            Some(op) => op.name()?.into_owned(),
+            None => RString::from_value(op)
+                .ok_or_else(|| err!(arg_error(), "expected matcher op to be a Symbol or String"))?
+                .to_string()?,
+        };
+
+        Ok(LabelMatcher::new(&label, &op, &value)?)
     }
 
-    fn load_exemplar<'a, 'b>(&'a self, position: usize) -> magnus::error::Result<Exemplar> {
-        self.inner_mut(|inner| inner.load_exemplar(position))
-            .map_err(|e| e.into())
+    /// A merged series as a Hash of its `name`, `labels`, `value`, and `pid`.
+    fn series_hash(entry: &FileEntry) -> magnus::error::Result<RHash> {
+        let metric = entry.metric_text()?;
+
+        let labels = RHash::new();
+        for (&name, value) in metric.labels.iter().zip(metric.values.iter()) {
+            labels.aset(name, FileEntry::label_value(value)?)?;
+        }
+
+        let series = RHash::new();
+        series.aset(Symbol::new("name"), metric.metric_name)?;
+        series.aset(Symbol::new("labels"), labels)?;
+        series.aset(Symbol::new("value"), entry.meta.value)?;
+        series.aset(Symbol::new("pid"), entry.data.pid.as_deref())?;
+
+        Ok(series)
     }
 
-    fn as_mut_ptr(&self) -> *mut c_char {
-        // UNWRAP: This is actually infallible, but we need to
-        // wrap it in a `Result` for use with `inner()`.
-        self.inner(|inner| Ok(inner.as_mut_ptr() as *mut c_char))
-            .unwrap()
+    /// Read the list of files provided from Ruby and return the JSON keys of the
+    /// merged series whose value is exactly `0.0`, e.g. counters that were
+    /// never incremented. These are candidates for pruning, but note that a
+    /// counter which is legitimately zero is indistinguishable from an unused
+    /// one. A key is returned once even if several pids hold a zero series for
+    /// it.
+    pub fn zero_value_keys(file_list: RArray) -> magnus::error::Result<RArray> {
+        let mut map = EntryMap::new();
+        map.aggregate_files(file_list)?;
+
+        let sorted = map.into_sorted()?;
+
+        let mut keys: Vec<String> = Vec::new();
+        for entry in sorted {
+            if entry.meta.value != Some(0.0) {
+                continue;
+            }
+
+            // Entries are sorted by JSON, so duplicates are adjacent.
+            if keys.last() != Some(&entry.data.json) {
+                keys.push(entry.data.json);
+            }
+        }
+
+        Ok(RArray::from_vec(keys))
     }
 
-    /// Takes a closure with immutable access to InnerMmap. Will fail if the inner
-    /// object has a mutable borrow or has been dropped.
-    fn inner<F, T>(&self, func: F) -> Result<T>
-    where
-        F: FnOnce(&InnerMmap) -> Result<T>,
-    {
-        let inner_opt = self.0.try_read().map_err(|_| MmapError::ConcurrentAccess)?;
+    /// Read the list of `FastMmapedFileRs` objects provided from Ruby and convert
+    /// them to a Prometheus metrics String. Entries are read from the existing
+    /// mappings rather than by re-opening each file.
+    pub fn to_metrics_from_mmaps(mmap_list: RArray) -> magnus::error::Result<String> {
+        let mut map = EntryMap::new();
+        map.aggregate_mmaps(mmap_list)?;
 
-        let inner = inner_opt.as_ref().ok_or(MmapError::UnmappedFile)?;
+        let sorted = map.into_sorted()?;
 
-        func(inner)
+        FileEntry::entries_to_string(sorted).map_err(|e| e.into())
     }
 
-    /// Takes a closure with mutable access to InnerMmap. Will fail if the inner
-    /// object has an existing mutable borrow, or has been dropped.
-    fn inner_mut<F, T>(&self, func: F) -> Result<T>
-    where
-        F: FnOnce(&mut InnerMmap) -> Result<T>,
-    {
-        let mut inner_opt = self
-            .0
-            .try_write()
-            .map_err(|_| MmapError::ConcurrentAccess)?;
+    /// call-seq:
+    ///   to_metrics_from_fds(fd_list) -> String
+    ///
+    /// As `to_metrics`, with an open file descriptor in place of the path of
+    /// each file, e.g. `[[fd, :all, :counter, "worker-1"]]`, for processes
+    /// handed descriptors rather than paths. The descriptors are duplicated
+    /// and remain owned by the caller, but their offsets are moved.
+    pub fn to_metrics_from_fds(fd_list: RArray) -> magnus::error::Result<String> {
+        let mut map = EntryMap::new();
+        map.aggregate_fds(fd_list)?;
 
-        let inner = inner_opt.as_mut().ok_or(MmapError::UnmappedFile)?;
+        let sorted = map.into_sorted()?;
 
-        func(inner)
+        FileEntry::entries_to_string(sorted).map_err(|e| e.into())
     }
 
-    /// Take ownership of the `InnerMmap` from the `RwLock`.
-    /// Will fail if a mutable borrow is already held or the inner
-    /// object has been dropped.
-    fn take_inner(&self) -> Result<InnerMmap> {
-        let mut inner_opt = self
-            .0
-            .try_write()
-            .map_err(|_| MmapError::ConcurrentAccess)?;
-        match (*inner_opt).take() {
-            Some(i) => Ok(i),
-            None => Err(MmapError::UnmappedFile),
-        }
+    /// Document-method: []
+    /// Document-method: slice
+    ///
+    /// call-seq: [](args)
+    ///
+    /// Element reference - with the following syntax:
+    ///
+    ///   self[nth]
+    ///
+    /// retrieve the <em>nth</em> character
+    ///
+    ///   self[start..last]
+    ///
+    /// return a substring from <em>start</em> to <em>last</em>
+    ///
+    ///   self[start, length]
+    ///
+    /// return a substring of <em>lenght</em> characters from <em>start</em>
+    pub fn slice(rb_self: Obj<Self>, args: &[Value]) -> magnus::error::Result<RString> {
+        // The C implementation would trigger a GC cycle via `rb_gc_force_recycle`
+        // if the `MM_PROTECT` flag is set, but in practice this is never used.
+        // We omit this logic, particularly because `rb_gc_force_recycle` is a
+        // no-op as of Ruby 3.1.
+        let rs_self = &*rb_self;
+
+        let str = rs_self.str(rb_self)?;
+        rs_self.check_slice_range(args, str.len())?;
+
+        rs_self._slice(rb_self, str, args)
     }
 
-    /// Move `new_inner` into the `RwLock`.
-    /// Will return an error if a mutable borrow is already held.
-    fn insert_inner(&self, new_inner: InnerMmap) -> Result<()> {
-        let mut inner_opt = self
-            .0
-            .try_write()
-            .map_err(|_| MmapError::ConcurrentAccess)?;
-        (*inner_opt).replace(new_inner);
+    /// Validate that the range requested by `slice` does not extend beyond the
+    /// data written to the file, which would return padding or uninitialized
+    /// bytes rather than metrics entries.
+    fn check_slice_range(&self, args: &[Value], str_len: usize) -> magnus::error::Result<()> {
+        // CAST: no-op on 32-bit, widening on 64-bit.
+        let used = self.inner(|inner| inner.load_used())? as usize;
+
+        if let Some(range) = Self::slice_range(args, str_len)? {
+            if range.start > used || range.end > used {
+                return Err(err!(
+                    index_error(),
+                    "range {}..{} beyond used length {}",
+                    range.start,
+                    range.end,
+                    used
+                ));
+            }
+        }
 
         Ok(())
     }
 
-    /// Check if an RString is shared. Shared string use the same underlying
-    /// storage as their parent, taking an offset from the start. By default
-    /// they must run to the end of the parent string.
-    fn rb_string_is_shared(rb_str: RString) -> bool {
-        // SAFETY: We only hold a reference to the raw object for the duration
-        // of this function, and no Ruby code is called.
-        let flags = unsafe {
-            let raw_str = Self::rb_string_internal(rb_str);
-            raw_str.as_ref().basic.flags
+    /// Resolve the byte range of the `slice` arguments, following the
+    /// semantics of `String#[]`. Returns `None` for arguments that don't
+    /// specify an offset, or for negative offsets or lengths, which Ruby will
+    /// reject itself.
+    fn slice_range(
+        args: &[Value],
+        str_len: usize,
+    ) -> magnus::error::Result<Option<std::ops::Range<usize>>> {
+        let Some(&first) = args.first() else {
+            return Ok(None);
         };
-        let shared_flags = STR_SHARED | STR_NOEMBED;
 
-        flags & shared_flags == shared_flags
-    }
+        // Negative offsets count back from the end of the string.
+        // CAST: mmap lengths are bounded by `used`, a `u32`.
+        let resolve = |pos: i64| if pos < 0 { str_len as i64 + pos } else { pos };
+
+        let (start, end) = if let Some(range) = Range::from_value(first) {
+            let start = resolve(range.beg::<Option<i64>>()?.unwrap_or(0));
+            let end = match range.end::<Option<i64>>()? {
+                Some(end) if range.excl() => resolve(end),
+                Some(end) => resolve(end) + 1,
+                // CAST: see above.
+                None => str_len as i64,
+            };
+            (start, end)
+        } else if let Some(int) = Integer::from_value(first) {
+            let start = resolve(int.to_i64()?);
+            let count = match args.get(1).and_then(|&v| Integer::from_value(v)) {
+                Some(count) => count.to_i64()?,
+                None => 1,
+            };
+            (start, start + count)
+        } else {
+            return Ok(None);
+        };
 
-    /// Convert `magnus::RString` into the raw binding used by `rb_sys::RString`.
-    /// We need this to manually change the pointer and length values for strings
-    /// when moving the mmap to a new file.
-    ///
-    /// SAFETY: Calling Ruby code while the returned object is held may result
-    /// in it being mutated or dropped.
-    unsafe fn rb_string_internal(rb_str: RString) -> NonNull<rb_sys::RString> {
-        mem::transmute::<RString, NonNull<rb_sys::RString>>(rb_str)
+        match (usize::try_from(start), usize::try_from(end)) {
+            (Ok(start), Ok(end)) => Ok(Some(start..end)),
+            _ => Ok(None),
+        }
     }
 
-    #[cfg(ruby_lte_3_2)]
-    unsafe fn update_rstring_len(&self, mut raw_str: NonNull<rb_sys::RString>, new_len: c_long) {
-        raw_str.as_mut().as_.heap.len = new_len;
-    }
+    fn _slice(
+        &self,
+        rb_self: Obj<Self>,
+        str: RString,
+        args: &[Value],
+    ) -> magnus::error::Result<RString> {
+        let substr: RString = str.funcall("[]", args)?;
 
-    #[cfg(ruby_gte_3_3)]
-    unsafe fn update_rstring_len(&self, mut raw_str: NonNull<rb_sys::RString>, new_len: c_long) {
-        raw_str.as_mut().len = new_len;
+        // Track shared child strings which use the same backing storage.
+        if Self::rb_string_is_shared(substr) {
+            (*rb_self).track_rstring(rb_self, substr)?;
+        }
+
+        // The C implementation does this, perhaps to validate that the len we
+        // provided is actually being used.
+        (*rb_self).inner_mut(|inner| {
+            inner.set_len(str.len());
+            Ok(())
+        })?;
+
+        Ok(substr)
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use core::panic;
-    use magnus::error::Error;
-    use magnus::eval;
-    use magnus::Range;
-    use nix::unistd::{sysconf, SysconfVar};
-    use std::mem::size_of;
+    /// Document-method: msync
+    /// Document-method: sync
+    /// Document-method: flush
+    ///
+    /// call-seq: msync(flags = nil, chunk_size: nil)
+    ///
+    /// flush the file
+    ///
+    /// * <em>chunk_size</em>
+    ///
+    ///     Flush the mmap in page-aligned chunks of at least this many bytes,
+    ///     letting other threads run between chunks, to spread out the I/O of
+    ///     flushing a large mmap.
+    pub fn sync(&self, args: &[Value]) -> magnus::error::Result<()> {
+        use nix::sys::mman::MsFlags;
+
+        let mut ms_async = false;
+        let args = scan_args::scan_args::<(), (Option<i32>,), (), (), RHash, ()>(args)?;
+        let kwargs = scan_args::get_kwargs::<_, (), (Option<usize>,), ()>(
+            args.keywords,
+            &[],
+            &["chunk_size"],
+        )?;
+        let (chunk_size,) = kwargs.optional;
+
+        if let Some(flag) = args.optional.0 {
+            let flag = MsFlags::from_bits(flag).unwrap_or(MsFlags::empty());
+            ms_async = flag.contains(MsFlags::MS_ASYNC);
+        }
+
+        // The `memmap2` crate does not support the `MS_INVALIDATE` flag. We ignore that
+        // flag if passed in, checking only for `MS_ASYNC`. In practice no arguments are ever
+        // passed to this function, but we do this to maintain compatibility with the
+        // C implementation.
+        match chunk_size {
+            None => self.inner_mut(|inner| inner.flush(ms_async))?,
+            Some(chunk_size) => self.sync_chunks(chunk_size, ms_async)?,
+        }
+
+        Ok(())
+    }
+
+    /// Flush the mmap in page-aligned chunks of at least `chunk_size` bytes.
+    /// The lock is released between chunks and other Ruby threads are given
+    /// a chance to run.
+    fn sync_chunks(&self, chunk_size: usize, ms_async: bool) -> Result<()> {
+        let chunk_size = InnerMmap::next_page_boundary(chunk_size)?;
+        let chunk_size = util::cast_chk::<_, usize>(chunk_size, "chunk size")?;
+
+        let capacity = self.inner(|inner| Ok(inner.capacity()))?;
+
+        let mut offset = 0;
+        while offset < capacity {
+            let len = chunk_size.min(capacity - offset);
+            self.inner_mut(|inner| inner.flush_chunk(offset, len, ms_async))?;
+            offset += len;
+
+            if offset < capacity {
+                // SAFETY: We hold no locks or Ruby objects across the call.
+                unsafe { rb_sys::rb_thread_schedule() };
+            }
+        }
+
+        Ok(())
+    }
+
+    /// call-seq: mapped?
+    ///
+    /// Whether the file is currently mapped, i.e. `munmap` has not been
+    /// called. Returns `true` if another thread is writing to the mmap, as it
+    /// can only be unmapped while holding the lock, and `false` if the lock
+    /// was poisoned, as the mmap can no longer be used.
+    pub fn is_mapped(&self) -> bool {
+        match self.inner.try_read() {
+            Ok(inner_opt) => inner_opt.is_some(),
+            Err(TryLockError::WouldBlock) => true,
+            Err(TryLockError::Poisoned(_)) => false,
+        }
+    }
+
+    /// call-seq: refresh
+    ///
+    /// Re-stat the file and, if another process has grown it past the current
+    /// mapping, re-map it so the new data can be read. Returns `true` if the
+    /// file was re-mapped.
+    pub fn refresh(rb_self: Obj<Self>) -> magnus::error::Result<bool> {
+        let rs_self = &*rb_self;
+
+        let file_size = rs_self.inner(|inner| {
+            let stat = inner
+                .file()
+                .metadata()
+                .map_err(|e| MmapError::io("stat", inner.path(), e))?;
+
+            let file_size = util::cast_chk::<_, usize>(stat.len(), "file length")?;
+            util::check_file_size(inner.path(), file_size)?;
+
+            Ok(file_size)
+        })?;
+
+        if file_size <= rs_self.capacity() {
+            return Ok(false);
+        }
+
+        let old_ptr = rs_self.as_mut_ptr();
+        let old_cap = util::cast_chk::<_, c_long>(rs_self.capacity(), "capacity")?;
+
+        let read_only = rs_self.is_read_only()?;
+        let (file, path) = rs_self.take_inner()?.munmap();
+        let new_inner = if read_only {
+            InnerMmap::reestablish_read_only(path, file, file_size)?
+        } else {
+            InnerMmap::reestablish(path, file, file_size)?
+        };
+
+        rs_self.insert_inner(new_inner)?;
+        rs_self.remap_count.fetch_add(1, Ordering::Relaxed);
+
+        rs_self.update_weak_map(rb_self, old_ptr, old_cap)?;
+
+        Ok(true)
+    }
+
+    /// Document-method: munmap
+    /// Document-method: unmap
+    ///
+    /// call-seq: munmap
+    ///
+    /// terminate the association
+    pub fn munmap(rb_self: Obj<Self>) -> magnus::error::Result<()> {
+        let rs_self = &*rb_self;
+
+        rs_self.inner_mut(|inner| {
+            // We are about to release the backing mmap for Ruby's String
+            // objects. If Ruby attempts to read from them the program will
+            // segfault. We update the length of all Strings to zero so Ruby
+            // does not attempt to access the now invalid address between now
+            // and when GC eventually reaps the objects.
+            //
+            // See the following for more detail:
+            // https://gitlab.com/gitlab-org/ruby/gems/prometheus-client-mmap/-/issues/39
+            // https://gitlab.com/gitlab-org/ruby/gems/prometheus-client-mmap/-/issues/41
+            // https://gitlab.com/gitlab-org/ruby/gems/prometheus-client-mmap/-/merge_requests/80
+            inner.set_len(0);
+            Ok(())
+        })?;
+
+        // Update each String object to be zero-length.
+        let cap = util::cast_chk::<_, c_long>(rs_self.capacity(), "capacity")?;
+        rs_self.update_weak_map(rb_self, rs_self.as_mut_ptr(), cap)?;
+
+        // Remove the `InnerMmap` from the `RwLock`. This will drop
+        // end of this function, unmapping and closing the file.
+        let _ = rs_self.take_inner()?;
+        Ok(())
+    }
+
+    /// Fetch the `used` header from the `.db` file, the length
+    /// in bytes of the data written to the file.
+    pub fn load_used(&self) -> magnus::error::Result<Integer> {
+        let used = self.inner(|inner| inner.load_used())?;
+
+        Ok(Integer::from_u64(used as u64))
+    }
+
+    /// Update the `used` header for the `.db` file, the length
+    /// in bytes of the data written to the file.
+    pub fn save_used(rb_self: Obj<Self>, used: Fixnum) -> magnus::error::Result<Fixnum> {
+        let rs_self = &*rb_self;
+        let used_uint = used.to_u32()?;
+
+        // If the underlying mmap is smaller than the header, then resize to fit.
+        // The file has already been expanded to page size when first opened, so
+        // even if the map is less than HEADER_SIZE, we're not at risk of a
+        // SIGBUS.
+        if rs_self.capacity() < HEADER_SIZE {
+            rs_self.expand_to_fit(rb_self, HEADER_SIZE)?;
+        }
+
+        rs_self.inner_mut(|inner| inner.save_used(used_uint))?;
+
+        Ok(used)
+    }
+
+    /// Copy the data written to the file, `[0, used)`, into a new frozen String.
+    /// The copy is taken under a read lock so it can't race with writes. Unlike
+    /// `slice` this does not alias the mmap, so the result remains valid
+    /// across subsequent writes and remaps.
+    pub fn snapshot_bytes(&self) -> magnus::error::Result<RString> {
+        let snapshot = self.inner(|inner| {
+            // CAST: no-op on 32-bit, widening on 64-bit.
+            let used = inner.load_used()? as usize;
+
+            let bytes = inner
+                .as_slice()
+                .get(..used)
+                .ok_or_else(|| MmapError::out_of_bounds(used, inner.capacity()))?;
+
+            Ok(RString::from_slice(bytes))
+        })?;
+
+        snapshot.freeze();
+
+        Ok(snapshot)
+    }
+
+    /// The number of times the file has been re-mapped to grow it. A file that
+    /// remaps frequently should be created with a larger initial size.
+    pub fn remap_count(&self) -> usize {
+        self.remap_count.load(Ordering::Relaxed)
+    }
+
+    /// call-seq: atomic_values = bool
+    ///
+    /// Read and write values with single 8-byte atomic operations, so a value
+    /// being updated by another process is never observed half written.
+    /// Every process sharing the file should enable this.
+    pub fn save_atomic_values(&self, enabled: bool) {
+        self.atomic_values.store(enabled, Ordering::Relaxed);
+    }
+
+    /// call-seq: atomic_values?
+    ///
+    /// Whether values are read and written with atomic operations.
+    pub fn atomic_values(&self) -> bool {
+        self.atomic_values.load(Ordering::Relaxed)
+    }
+
+    /// call-seq: durable_writes = bool
+    ///
+    /// Flush each new entry to disk before advancing the `used` header to
+    /// include it, and the header after. If the process crashes mid-write the
+    /// header never covers an entry that did not reach disk, and a partial
+    /// entry past `used` is ignored by readers and overwritten by the next
+    /// write. This costs two msync(2) calls per new entry; updates to existing
+    /// entries are unaffected.
+    pub fn save_durable_writes(&self, enabled: bool) {
+        self.durable_writes.store(enabled, Ordering::Relaxed);
+    }
+
+    /// call-seq: durable_writes?
+    ///
+    /// Whether new entries are flushed before the header is advanced.
+    pub fn durable_writes(&self) -> bool {
+        self.durable_writes.load(Ordering::Relaxed)
+    }
+
+    /// call-seq: prefault = bool
+    ///
+    /// Touch each page added when the file expands, so later writes to new
+    /// entries don't incur page faults on the request path. Expansion takes
+    /// longer and the added pages count towards RSS and are allocated on disk
+    /// immediately, rather than as entries are written.
+    pub fn save_prefault(&self, enabled: bool) {
+        self.prefault.store(enabled, Ordering::Relaxed);
+    }
+
+    /// call-seq: prefault?
+    ///
+    /// Whether pages are prefaulted when the file expands.
+    pub fn prefault(&self) -> bool {
+        self.prefault.load(Ordering::Relaxed)
+    }
+
+    /// Fetch the entry layout of the file, `:legacy`, `:value_first`, or
+    /// `:packed`.
+    pub fn layout(&self) -> magnus::error::Result<Symbol> {
+        let layout = self.inner(|inner| inner.layout())?;
+
+        Ok(match layout {
+            Layout::Legacy => Symbol::new("legacy"),
+            Layout::ValueFirst => Symbol::new("value_first"),
+            Layout::Packed => Symbol::new("packed"),
+        })
+    }
+
+    /// Set the entry layout of the file, `:legacy` or `:value_first`. This is
+    /// only permitted before any entries have been written. The Ruby
+    /// `Helper::EntryParser` only reads the legacy layout and raises a
+    /// `ParsingError` for other files, so `MmapedDict` can't reopen them.
+    pub fn save_layout(&self, layout: Symbol) -> magnus::error::Result<Symbol> {
+        let new_layout = match layout.name()?.as_ref() {
+            "legacy" => Layout::Legacy,
+            "value_first" => Layout::ValueFirst,
+            other => return Err(err!(arg_error(), "unknown layout {}", other)),
+        };
+
+        self.inner_mut(|inner| inner.save_layout(new_layout))?;
+
+        Ok(layout)
+    }
+
+    /// call-seq:
+    ///   entry_at(offset) -> {key:, value:}
+    ///
+    /// Read the key and value of the entry whose value is stored at `offset`,
+    /// as recorded in `positions`. Raises an error if `offset` is not the value
+    /// offset of an entry.
+    pub fn entry_at(&self, offset: usize) -> magnus::error::Result<RHash> {
+        let (key, value) = self.inner(|inner| {
+            let (key, value) = inner.entry_at(offset)?;
+            Ok((key.to_vec(), value))
+        })?;
+
+        let entry = RHash::new();
+        entry.aset(Symbol::new("key"), RString::from_slice(&key))?;
+        entry.aset(Symbol::new("value"), value)?;
+
+        Ok(entry)
+    }
+
+    /// Fetch the value associated with a key from the mmap.
+    /// If no entry is present, return the default value provided
+    /// without writing anything to the file. Only a read lock is
+    /// taken.
+    pub fn peek_entry(
+        rb_self: Obj<Self>,
+        positions: RHash,
+        key: RString,
+        default_value: f64,
+    ) -> magnus::error::Result<f64> {
+        let position: Option<Value> = positions.lookup(key)?;
+
+        match position {
+            Some(pos) => rb_self.load_value(rb_self.check_position(key, pos)?),
+            None => Ok(default_value),
+        }
+    }
+
+    /// Fetch the value associated with a key from the mmap.
+    /// If no entry is present, initialize with the default
+    /// value provided.
+    pub fn fetch_entry(
+        rb_self: Obj<Self>,
+        positions: RHash,
+        key: RString,
+        default_value: f64,
+    ) -> magnus::error::Result<f64> {
+        let rs_self = &*rb_self;
+        let position: Option<Value> = positions.lookup(key)?;
+
+        if let Some(pos) = position {
+            let pos = rs_self.check_position(key, pos)?;
+            return rs_self.load_value(pos);
+        }
+
+        rs_self.check_key(key)?;
+        rs_self.check_expand(rb_self, key.len())?;
+
+        let durable = rs_self.durable_writes();
+        let value_offset: usize = rs_self.inner_mut(|inner| {
+            // SAFETY: We must not call any Ruby code for the lifetime of this borrow.
+            unsafe { inner.initialize_entry(key.as_slice(), default_value, durable) }
+        })?;
+
+        // CAST: no-op on 64-bit, widening on 32-bit.
+        positions.aset(key, Integer::from_u64(value_offset as u64))?;
+
+        rs_self.load_value(value_offset)
+    }
+
+    /// Fetch the bit pattern of the value associated with a key, as given by
+    /// `f64::to_bits`, to compare stored values exactly. Returns `nil` if no
+    /// entry is present, nothing is written to the file.
+    pub fn load_value_bits(
+        rb_self: Obj<Self>,
+        positions: RHash,
+        key: RString,
+    ) -> magnus::error::Result<Option<u64>> {
+        let position: Option<Value> = positions.lookup(key)?;
+
+        match position {
+            Some(pos) => {
+                let value = rb_self.load_value(rb_self.check_position(key, pos)?)?;
+                Ok(Some(value.to_bits()))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// call-seq:
+    ///   upsert_exemplar(positions, key, value, exemplar_name, exemplar_value, timestamp = nil)
+    ///
+    /// Update the exemplar of an existing entry, if present. Otherwise create a
+    /// new entry for the key. The exemplar is labelled
+    /// `exemplar_name=exemplar_value` and stamped with `timestamp`, in
+    /// nanoseconds since the epoch, or the current time if `nil`.
+    pub fn upsert_exemplar(rb_self: Obj<Self>, args: &[Value]) -> magnus::error::Result<f64> {
+        let args = scan_args::scan_args::<
+            (RHash, RString, f64, RString, RString),
+            (Option<Option<u64>>,),
+            (),
+            (),
+            (),
+            (),
+        >(args)?;
+
+        let (positions, key, value, exemplar_name, exemplar_value) = args.required;
+        let timestamp = match args.optional.0.flatten() {
+            Some(timestamp) => u128::from(timestamp),
+            None => Self::now_nanos(),
+        };
+
+        Self::upsert_exemplar_at(
+            rb_self,
+            positions,
+            key,
+            value,
+            exemplar_name,
+            exemplar_value,
+            timestamp,
+        )
+    }
+
+    /// The current time in nanoseconds since the epoch, the default timestamp
+    /// of an exemplar.
+    fn now_nanos() -> u128 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_nanos()
+    }
+
+    /// As `upsert_exemplar`, stamping the exemplar with `timestamp`.
+    fn upsert_exemplar_at(
+        rb_self: Obj<Self>,
+        positions: RHash,
+        key: RString,
+        value: f64,
+        exemplar_name: RString,
+        exemplar_value: RString,
+        timestamp: u128,
+    ) -> magnus::error::Result<f64> {
+        let rs_self = &*rb_self;
+        let position: Option<Value> = positions.lookup(key)?;
+
+        let ex: Exemplar = Exemplar {
+            label_name: unsafe { exemplar_name.as_str().unwrap().into() },
+            label_value: unsafe { exemplar_value.as_str().unwrap().into() },
+            value: value,
+            timestamp,
+        };
+
+        if let Some(pos) = position {
+            let pos = rs_self.check_position(key, pos)?;
+            return rs_self
+                .inner_mut(|inner| {
+                    inner.save_exemplar(pos, ex)?;
+
+                    // TODO just return `value` here instead of loading it?
+                    // This is how the C implementation did it, but I don't
+                    // see what the extra load gains us.
+                    let ex = inner.load_exemplar(pos)?;
+
+                    Ok(ex.value)
+                })
+                .map_err(|e| e.into());
+        }
+
+
+        rs_self.check_key(key)?;
+        rs_self.check_expand_exemplar(rb_self, key.len())?;
+
+        let durable = rs_self.durable_writes();
+        let value_offset: usize = rs_self.inner_mut(|inner| {
+            // SAFETY: We must not call any Ruby code for the lifetime of this borrow.
+            unsafe { inner.initialize_entry_exemplar(key.as_slice(), ex, durable) }
+        })?;
+
+        // CAST: no-op on 64-bit, widening on 32-bit.
+        positions.aset(key, Integer::from_u64(value_offset as u64))?;
+
+        let ex = rs_self.load_exemplar(value_offset)?;
+
+        Ok(ex.value)
+    }
+
+    /// Update the value of an existing entry, if present. Otherwise create a new entry
+    /// for the key.
+    pub fn upsert_entry(
+        rb_self: Obj<Self>,
+        positions: RHash,
+        key: RString,
+        value: f64,
+    ) -> magnus::error::Result<f64> {
+        let rs_self = &*rb_self;
+        let position: Option<Value> = positions.lookup(key)?;
+
+        if let Some(pos) = position {
+            let pos = rs_self.check_position(key, pos)?;
+            return rs_self
+                .inner_mut(|inner| {
+                    if rs_self.atomic_values() {
+                        inner.save_value_atomic(pos, value)?;
+                        return inner.load_value_atomic(pos);
+                    }
+
+                    inner.save_value(pos, value)?;
+
+                    // TODO just return `value` here instead of loading it?
+                    // This is how the C implementation did it, but I don't
+                    // see what the extra load gains us.
+                    inner.load_value(pos)
+                })
+                .map_err(|e| e.into());
+        }
+
+        rs_self.check_key(key)?;
+        rs_self.check_expand(rb_self, key.len())?;
+
+        let durable = rs_self.durable_writes();
+        let value_offset: usize = rs_self.inner_mut(|inner| {
+            // SAFETY: We must not call any Ruby code for the lifetime of this borrow.
+            unsafe { inner.initialize_entry(key.as_slice(), value, durable) }
+        })?;
+
+        // CAST: no-op on 64-bit, widening on 32-bit.
+        positions.aset(key, Integer::from_u64(value_offset as u64))?;
+
+        rs_self.load_value(value_offset)
+    }
+
+    /// Creates a Ruby String containing the section of the mmapped file that
+    /// has been written to.
+    fn str(&self, rb_self: Obj<Self>) -> magnus::error::Result<RString> {
+        let val_id = (*rb_self).inner(|inner| {
+            let ptr = inner.as_ptr();
+            let len = inner.len();
+
+            // SAFETY: This is safe so long as the data provided to Ruby meets its
+            // requirements. When unmapping the file this will no longer be the
+            // case, see the comment on `munmap` for how we handle this.
+            Ok(unsafe { rb_str_new_static(ptr as _, len as _) })
+        })?;
+
+        // SAFETY: We know that rb_str_new_static returns a VALUE.
+        let val = unsafe { Value::from_raw(val_id) };
+
+        // UNWRAP: We created this value as a string above.
+        let str = RString::from_value(val).unwrap();
+
+        // Freeze the root string so it can't be mutated out from under any
+        // substrings created. This object is never exposed to callers.
+        str.freeze();
+
+        // Track the RString in our `WeakMap` so we can update its address if
+        // we re-mmap the backing file.
+        (*rb_self).track_rstring(rb_self, str)?;
+
+        Ok(str)
+    }
+
+    /// If we reallocate, any live Ruby strings provided by the `str()` method
+    /// will be invalidated. We need to iterate over them using and update their
+    /// heap pointers to the newly allocated memory region.
+    fn update_weak_map(
+        &self,
+        rb_self: Obj<Self>,
+        old_ptr: *const c_char,
+        old_cap: c_long,
+    ) -> magnus::error::Result<()> {
+        if !self.track_strings() {
+            return Ok(());
+        }
+
+        let tracker: Value = rb_self.ivar_get("@weak_obj_tracker")?;
+
+        let new_len = self.inner(|inner| util::cast_chk::<_, c_long>(inner.len(), "mmap len"))?;
+
+        // Iterate over the values of the `WeakMap`.
+        for val in tracker.enumeratorize("each_value", ()) {
+            let rb_string = val?;
+            let str = RString::from_value(rb_string)
+                .ok_or_else(|| err!(arg_error(), "weakmap value was not a string"))?;
+
+            // SAFETY: We're messing with Ruby's internals here, YOLO.
+            unsafe {
+                // Convert the magnus wrapper type to a raw string exposed by `rb_sys`,
+                // which provides access to its internals.
+                let mut raw_str = Self::rb_string_internal(str);
+
+                // Shared string have their own `ptr` and `len` values, but `aux`
+                // is the id of the parent string so the GC can track this
+                // dependency. The `ptr` will always be an offset from the base
+                // address of the mmap, and `len` will be the length of the mmap
+                // less the offset from the base.
+                if Self::rb_string_is_shared(str) && new_len > 0 {
+                    // Calculate how far into the original mmap the shared string
+                    // started and update to the equivalent address in the new
+                    // one.
+                    let substr_ptr = raw_str.as_ref().as_.heap.ptr;
+                    let offset = substr_ptr.offset_from(old_ptr);
+
+                    raw_str.as_mut().as_.heap.ptr = self.as_mut_ptr().offset(offset);
+
+                    let current_len = str.len() as c_long;
+                    let new_shared_len = old_cap + current_len;
+
+                    self.update_rstring_len(raw_str, new_shared_len);
+                    continue;
+                }
+
+                // Update the string to point to the new mmapped file.
+                // We're matching the behavior of Ruby's `str_new_static` function.
+                // See https://github.com/ruby/ruby/blob/e51014f9c05aa65cbf203442d37fef7c12390015/string.c#L1030-L1053
+                //
+                // We deliberately do _NOT_ increment the `capa` field of the
+                // string to match the new `len`. We were initially doing this,
+                // but consistently triggered GCs in the middle of updating the
+                // string pointers, causing a segfault.
+                //
+                // See https://gitlab.com/gitlab-org/ruby/gems/prometheus-client-mmap/-/issues/45
+                raw_str.as_mut().as_.heap.ptr = self.as_mut_ptr();
+                self.update_rstring_len(raw_str, new_len);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reject an empty key before creating an entry for it. The storage layer
+    /// can hold a zero-length key, but it isn't valid JSON and could never be
+    /// rendered as a series. The key length is checked against the
+    /// `max_key_length` of the file, and label values against its
+    /// `max_label_value_length`.
+    fn check_key(&self, key: RString) -> magnus::error::Result<()> {
+        if key.is_empty() {
+            return Err(err!(arg_error(), "metric key must not be empty"));
+        }
+
+        if let Some(limit) = self.max_key_length {
+            RawEntry::check_key_len(key.len(), limit)?;
+        }
+
+        // SAFETY: We must not call any Ruby code for the lifetime of this borrow.
+        FileEntry::check_label_values(unsafe { key.as_slice() }, self.max_label_value_length)?;
+
+        Ok(())
+    }
+
+    /// Validate an offset read from the Ruby `positions` cache for `key`. It
+    /// must be an `Integer` falling within the written region of the file, a
+    /// corrupt cache is reported rather than read from or written through.
+    fn check_position(&self, key: RString, position: Value) -> magnus::error::Result<usize> {
+        let Some(offset) = Fixnum::from_value(position) else {
+            return Err(err!(
+                arg_error(),
+                "position for key {} is not an integer: {}",
+                key.inspect(),
+                position.inspect()
+            ));
+        };
+
+        // CAST: no-op on 32-bit, widening on 64-bit.
+        let used = self.inner(|inner| inner.load_used())? as usize;
+
+        let in_range = |offset: usize| {
+            offset >= HEADER_SIZE
+                && offset
+                    .checked_add(mem::size_of::<f64>())
+                    .is_some_and(|end| end <= used)
+        };
+
+        match usize::try_from(offset.to_i64()) {
+            Ok(offset) if in_range(offset) => Ok(offset),
+            _ => Err(err!(
+                arg_error(),
+                "position {} for key {} is outside the used range {}..{}",
+                offset.to_i64(),
+                key.inspect(),
+                HEADER_SIZE,
+                used
+            )),
+        }
+    }
+
+    /// Check that the mmap is large enough to contain the value to be added,
+    /// and expand it to fit if necessary.
+    fn check_expand(&self, rb_self: Obj<Self>, key_len: usize) -> magnus::error::Result<()> {
+        let entry_len = self.inner(|inner| inner.layout()?.total_len(key_len))?;
+
+        self.expand_for_entry(rb_self, entry_len)
+    }
+
+    /// Check that the mmap is large enough to contain the value to be added,
+    /// and expand it to fit if necessary.
+    fn check_expand_exemplar(&self, rb_self: Obj<Self>, key_len: usize) -> magnus::error::Result<()> {
+        let entry_len = RawEntry::calc_total_len_exemplar(key_len)?;
+
+        self.expand_for_entry(rb_self, entry_len)
+    }
+
+    /// Expand the mmap until an entry of `entry_len` bytes fits past `used`
+    /// while leaving `TRAILING_SLACK` bytes free.
+    fn expand_for_entry(&self, rb_self: Obj<Self>, entry_len: usize) -> magnus::error::Result<()> {
+        // CAST: no-op on 32-bit, widening on 64-bit.
+        let used = self.inner(|inner| inner.load_used())? as usize;
+
+        while !Self::has_room(self.capacity(), used, entry_len, TRAILING_SLACK)? {
+            self.expand_to_fit(rb_self, self.growth_factor.grow(self.capacity())?)?;
+        }
+
+        Ok(())
+    }
+
+    /// Whether a mmap of `capacity` bytes holds an entry of `entry_len` bytes
+    /// written at `used`, with at least `slack` bytes to spare.
+    fn has_room(capacity: usize, used: usize, entry_len: usize, slack: usize) -> Result<bool> {
+        Ok(used.add_chk(entry_len)?.add_chk(slack)? <= capacity)
+    }
+
+    /// Expand the underlying file until it is long enough to fit `target_cap`.
+    /// This will remove the existing mmap, expand the file, then update any
+    /// strings held by the `WeakMap` to point to the newly mmapped address.
+    fn expand_to_fit(&self, rb_self: Obj<Self>, target_cap: usize) -> magnus::error::Result<()> {
+        self.inner(|inner| inner.check_writable())?;
+
+        if target_cap < self.capacity() {
+            return Err(err!(arg_error(), "Can't reduce the size of mmap"));
+        }
+
+        let mut new_cap = self.capacity();
+        while new_cap < target_cap {
+            new_cap = self.growth_factor.grow(new_cap)?;
+        }
+
+        if new_cap != self.capacity() {
+            let old_ptr = self.as_mut_ptr();
+            let old_len = self.capacity();
+            let old_cap = util::cast_chk::<_, c_long>(old_len, "capacity")?;
+
+            // Drop the old mmap.
+            let (mut file, path) = self.take_inner()?.munmap();
+
+            self.expand_file(&mut file, &path, target_cap)?;
+
+            // Re-mmap the expanded file.
+            let new_inner = InnerMmap::reestablish(path, file, target_cap)?;
+
+            self.insert_inner(new_inner)?;
+            self.remap_count.fetch_add(1, Ordering::Relaxed);
+
+            if self.prefault() {
+                self.inner_mut(|inner| {
+                    let len = inner.capacity() - old_len;
+                    inner.prefault(old_len, len)
+                })?;
+            }
+
+            return self.update_weak_map(rb_self, old_ptr, old_cap);
+        }
+
+        Ok(())
+    }
+
+    /// Use lseek(2) to seek past the end of the file and write a NUL byte. This
+    /// creates a file hole that expands the size of the file without consuming
+    /// disk space until it is actually written to.
+    fn expand_file(&self, file: &mut File, path: &Path, len: usize) -> Result<()> {
+        if len == 0 {
+            return Err(MmapError::overflowed(0, -1, "adding"));
+        }
+
+        // CAST: no-op on 64-bit, widening on 32-bit.
+        let len = len as u64;
+
+        match file.seek(SeekFrom::Start(len - 1)) {
+            Ok(_) => {}
+            Err(_) => {
+                return Err(MmapError::with_errno(format!("Can't lseek {}", len - 1)));
+            }
+        }
+
+        match file.write(&[0x0]) {
+            Ok(1) => {}
+            _ => {
+                return Err(MmapError::with_errno(format!(
+                    "Can't extend {}",
+                    path.display()
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn track_rstring(&self, rb_self: Obj<Self>, str: RString) -> magnus::error::Result<()> {
+        // An untracked string would dangle after the next remap.
+        if !self.track_strings() {
+            return Err(err!(
+                io_error(),
+                "string tracking is disabled, the file can't be read with slice"
+            ));
+        }
+
+        let tracker: Value = rb_self.ivar_get("@weak_obj_tracker")?;
+
+        // Use the string's Id as the key in the `WeakMap`.
+        let key = str.as_raw();
+        let _: Value = tracker.funcall("[]=", (key, str))?;
+        Ok(())
+    }
+
+    /// The total capacity of the underlying mmap.
+    #[inline]
+    fn capacity(&self) -> usize {
+        // UNWRAP: This is actually infallible, but we need to
+        // wrap it in a `Result` for use with `inner()`.
+        self.inner(|inner| Ok(inner.capacity())).unwrap()
+    }
+
+    fn load_value(&self, position: usize) -> magnus::error::Result<f64> {
+        if self.atomic_values() {
+            return self
+                .inner(|inner| inner.load_value_atomic(position))
+                .map_err(|e| e.into());
+        }
+
+        self.inner(|inner| inner.load_value(position))
+            .map_err(|e| e.into())
+    }
+
+    fn load_exemplar<'a, 'b>(&'a self, position: usize) -> magnus::error::Result<Exemplar> {
+        self.inner_mut(|inner| inner.load_exemplar(position))
+            .map_err(|e| e.into())
+    }
+
+    fn as_mut_ptr(&self) -> *mut c_char {
+        // UNWRAP: This is actually infallible, but we need to
+        // wrap it in a `Result` for use with `inner()`.
+        self.inner(|inner| Ok(inner.as_mut_ptr() as *mut c_char))
+            .unwrap()
+    }
+
+    /// Provide `func` with the path, handle, and mapped bytes of the file. The
+    /// read lock is released as soon as `func` returns.
+    pub(crate) fn with_mapped_bytes<F, T>(&self, func: F) -> Result<T>
+    where
+        F: FnOnce(&Path, &File, &[u8]) -> Result<T>,
+    {
+        self.inner(|inner| func(inner.path(), inner.file(), inner.as_slice()))
+    }
+
+    /// Takes a closure with immutable access to InnerMmap. Will fail if the inner
+    /// object has a mutable borrow or has been dropped.
+    fn inner<F, T>(&self, func: F) -> Result<T>
+    where
+        F: FnOnce(&InnerMmap) -> Result<T>,
+    {
+        let inner_opt = self.inner.try_read().map_err(Self::lock_error)?;
+
+        let inner = inner_opt.as_ref().ok_or(MmapError::UnmappedFile)?;
+
+        func(inner)
+    }
+
+    /// Takes a closure with mutable access to InnerMmap. Will fail if the inner
+    /// object has an existing mutable borrow, or has been dropped.
+    fn inner_mut<F, T>(&self, func: F) -> Result<T>
+    where
+        F: FnOnce(&mut InnerMmap) -> Result<T>,
+    {
+        let mut inner_opt = self.inner.try_write().map_err(Self::lock_error)?;
+
+        let inner = inner_opt.as_mut().ok_or(MmapError::UnmappedFile)?;
+
+        func(inner)
+    }
+
+    /// Take ownership of the `InnerMmap` from the `RwLock`.
+    /// Will fail if a mutable borrow is already held or the inner
+    /// object has been dropped.
+    fn take_inner(&self) -> Result<InnerMmap> {
+        let mut inner_opt = self.inner.try_write().map_err(Self::lock_error)?;
+        match (*inner_opt).take() {
+            Some(i) => Ok(i),
+            None => Err(MmapError::UnmappedFile),
+        }
+    }
+
+    /// Move `new_inner` into the `RwLock`.
+    /// Will return an error if a mutable borrow is already held.
+    fn insert_inner(&self, new_inner: InnerMmap) -> Result<()> {
+        let mut inner_opt = self.inner.try_write().map_err(Self::lock_error)?;
+        (*inner_opt).replace(new_inner);
+
+        Ok(())
+    }
+
+    /// Map a failure to acquire the `RwLock` to an error. Contention is
+    /// transient, but a lock poisoned by a panic while it was held is not
+    /// cleared, as the mmap may have been left partially written.
+    fn lock_error<G>(err: TryLockError<G>) -> MmapError {
+        match err {
+            TryLockError::WouldBlock => MmapError::ConcurrentAccess,
+            TryLockError::Poisoned(_) => MmapError::Poisoned,
+        }
+    }
+
+    /// Check if an RString is shared. Shared string use the same underlying
+    /// storage as their parent, taking an offset from the start. By default
+    /// they must run to the end of the parent string.
+    fn rb_string_is_shared(rb_str: RString) -> bool {
+        // SAFETY: We only hold a reference to the raw object for the duration
+        // of this function, and no Ruby code is called.
+        let flags = unsafe {
+            let raw_str = Self::rb_string_internal(rb_str);
+            raw_str.as_ref().basic.flags
+        };
+        let shared_flags = STR_SHARED | STR_NOEMBED;
+
+        flags & shared_flags == shared_flags
+    }
+
+    /// Convert `magnus::RString` into the raw binding used by `rb_sys::RString`.
+    /// We need this to manually change the pointer and length values for strings
+    /// when moving the mmap to a new file.
+    ///
+    /// SAFETY: Calling Ruby code while the returned object is held may result
+    /// in it being mutated or dropped.
+    unsafe fn rb_string_internal(rb_str: RString) -> NonNull<rb_sys::RString> {
+        mem::transmute::<RString, NonNull<rb_sys::RString>>(rb_str)
+    }
+
+    #[cfg(ruby_lte_3_2)]
+    unsafe fn update_rstring_len(&self, mut raw_str: NonNull<rb_sys::RString>, new_len: c_long) {
+        raw_str.as_mut().as_.heap.len = new_len;
+    }
+
+    #[cfg(ruby_gte_3_3)]
+    unsafe fn update_rstring_len(&self, mut raw_str: NonNull<rb_sys::RString>, new_len: c_long) {
+        raw_str.as_mut().len = new_len;
+    }
+}
+
+/// An `f64` ordered by `f64::total_cmp`, so it can be kept in a `BinaryHeap`.
+#[derive(Clone, Copy, Debug)]
+struct TotalOrd(f64);
+
+impl PartialEq for TotalOrd {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == std::cmp::Ordering::Equal
+    }
+}
+
+impl Eq for TotalOrd {}
+
+impl PartialOrd for TotalOrd {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for TotalOrd {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::panic;
+    use indoc::indoc;
+    use magnus::error::Error;
+    use magnus::eval;
+    use magnus::Range;
+    use nix::unistd::{sysconf, SysconfVar};
+    use prost::Message;
+    use std::mem::size_of;
+
+    use crate::io;
+    use crate::raw_entry::RawEntry;
+    use crate::testhelper::{self, TestEntry, TestFile};
+
+    /// Create a wrapped MmapedFile object.
+    /// Map a new file. The `TestFile` must be kept in scope while the mmap is
+    /// used, or its directory is deleted out from under it.
+    fn create_obj() -> (Obj<MmapedFile>, TestFile) {
+        let test_file = TestFile::new(&[0u8; 8]);
+
+        let path_str = test_file.path.display().to_string();
+        let rpath = RString::new(&path_str);
+
+        test_file.assert_exists();
+        let obj = eval!("FastMmapedFileRs.new(path)", path = rpath).unwrap();
+
+        (obj, test_file)
+    }
+
+    /// Add three entries to the mmap. Expected length is 56, 3x 16-byte
+    /// entries with 8-byte header.
+    fn populate_entries(rb_self: &Obj<MmapedFile>) -> RHash {
+        let positions = RHash::from_value(eval("{}").unwrap()).unwrap();
+
+        MmapedFile::upsert_entry(*rb_self, positions, RString::new("a"), 0.0).unwrap();
+        MmapedFile::upsert_entry(*rb_self, positions, RString::new("b"), 1.0).unwrap();
+        MmapedFile::upsert_entry(*rb_self, positions, RString::new("c"), 2.0).unwrap();
+
+        positions
+    }
+
+    #[test]
+    fn test_new() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let TestFile {
+            file,
+            path,
+            dir: _dir,
+        } = TestFile::new(&[0u8; 8]);
+
+        let path_str = path.display().to_string();
+        let rpath = RString::new(&path_str);
+
+        // Object created successfully
+        let result: std::result::Result<Obj<MmapedFile>, Error> =
+            eval!("FastMmapedFileRs.new(path)", path = rpath);
+        assert!(result.is_ok());
+
+        // Weak map added
+        let obj = result.unwrap();
+        let weak_tracker: Value = obj.ivar_get("@weak_obj_tracker").unwrap();
+        assert_eq!("ObjectSpace::WeakMap", weak_tracker.class().inspect());
+
+        // File expanded to page size
+        let page_size = sysconf(SysconfVar::PAGE_SIZE).unwrap().unwrap() as u64;
+        let stat = file.metadata().unwrap();
+        assert_eq!(page_size, stat.len());
+
+        // Used set to header size
+        assert_eq!(
+            HEADER_SIZE as u64,
+            obj.load_used().unwrap().to_u64().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_slice() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let (obj, _file) = create_obj();
+        let _ = populate_entries(&obj);
+
+        // Validate header updated with new length
+        let header_range = Range::new(0, HEADER_SIZE, true).unwrap().as_value();
+        let header_slice = MmapedFile::slice(obj, &[header_range]).unwrap();
+        assert_eq!([56, 0, 0, 0, 0, 0, 0, 0], unsafe {
+            header_slice.as_slice()
+        });
+
+        let value_range = Range::new(HEADER_SIZE, 24, true).unwrap().as_value();
+        let value_slice = MmapedFile::slice(obj, &[value_range]).unwrap();
+
+        // Validate string length
+        assert_eq!(1u32.to_ne_bytes(), unsafe { &value_slice.as_slice()[0..4] });
+
+        // Validate string and padding
+        assert_eq!("a   ", unsafe {
+            String::from_utf8_lossy(&value_slice.as_slice()[4..8])
+        });
+
+        // Validate value
+        assert_eq!(0.0f64.to_ne_bytes(), unsafe {
+            &value_slice.as_slice()[8..16]
+        });
+    }
+
+    #[test]
+    fn test_self_stats() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let TestFile {
+            file,
+            path,
+            dir: _dir,
+        } = TestFile::new(&[0u8; 8]);
+        let rpath = RString::new(&path.display().to_string());
+        let obj: Obj<MmapedFile> = eval!("FastMmapedFileRs.new(path)", path = rpath).unwrap();
+
+        // Grow the file past the page it is reserved with, so the mapping
+        // spans the whole file.
+        let positions = RHash::new();
+        for i in 0..500 {
+            let key = RString::new(&format!("key_{i:04}"));
+            MmapedFile::upsert_entry(obj, positions, key, i as f64).unwrap();
+        }
+
+        let header_range = Range::new(0, HEADER_SIZE, true).unwrap().as_value();
+        let _header = MmapedFile::slice(obj, &[header_range]).unwrap();
+
+        let (unmapped, _file) = create_obj();
+        MmapedFile::munmap(unmapped).unwrap();
+
+        let stats: RHash = eval!(
+            "FastMmapedFileRs.self_stats([obj, unmapped])",
+            obj = obj,
+            unmapped = unmapped
+        )
+        .unwrap();
+        let stat = |name: &str| -> usize { stats.fetch(Symbol::new(name)).unwrap() };
+
+        let file_len = file.metadata().unwrap().len() as usize;
+        assert_eq!(file_len, stat("mapped_bytes"));
+        assert_eq!(1, stat("tracked_strings"));
+        assert_eq!(obj.remap_count(), stat("remaps"));
+        assert!(stat("remaps") > 0);
+    }
+
+    #[test]
+    fn test_snapshot_bytes() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let TestFile {
+            file: _file,
+            path,
+            dir: _dir,
+        } = TestFile::new(&[0u8; 8]);
+
+        let rpath = RString::new(&path.display().to_string());
+        let obj: Obj<MmapedFile> = eval!("FastMmapedFileRs.new(path)", path = rpath).unwrap();
+        let positions = populate_entries(&obj);
+
+        let snapshot = obj.snapshot_bytes().unwrap();
+        assert!(snapshot.is_frozen());
+
+        let used = obj.load_used().unwrap().to_u64().unwrap() as usize;
+        let on_disk = std::fs::read(&path).unwrap();
+        assert_eq!(&on_disk[..used], unsafe { snapshot.as_slice() });
+
+        // Later writes are not reflected in the snapshot.
+        MmapedFile::upsert_entry(obj, positions, RString::new("a"), 100.0).unwrap();
+        assert_eq!(&on_disk[..used], unsafe { snapshot.as_slice() });
+    }
+
+    #[test]
+    fn test_entry_at() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let (obj, _file) = create_obj();
+        let positions = populate_entries(&obj);
+
+        for (key, value) in [("a", 0.0), ("b", 1.0), ("c", 2.0)] {
+            let offset: usize = positions.fetch(key).unwrap();
+
+            let entry = obj.entry_at(offset).unwrap();
+            let entry_key: RString = entry.fetch(Symbol::new("key")).unwrap();
+            let entry_value: f64 = entry.fetch(Symbol::new("value")).unwrap();
+
+            assert_eq!(key, entry_key.to_string().unwrap());
+            assert_eq!(value, entry_value);
+        }
+
+        let offset: usize = positions.fetch("b").unwrap();
+        assert!(obj.entry_at(offset + 1).is_err(), "not an entry boundary");
+        assert!(obj.entry_at(0).is_err(), "header");
+        assert!(obj.entry_at(1 << 20).is_err(), "past used");
+    }
+
+    #[test]
+    fn test_slice_past_used() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let (obj, _file) = create_obj();
+        let _ = populate_entries(&obj);
+        let used = obj.load_used().unwrap().to_u64().unwrap() as usize;
+
+        // Reading up to `used` is fine.
+        let in_bounds = Range::new(HEADER_SIZE, used, true).unwrap().as_value();
+        assert!(MmapedFile::slice(obj, &[in_bounds]).is_ok());
+
+        // Starting beyond `used` reads padding and is rejected.
+        let past_used = Range::new(used + 8, used + 16, true).unwrap().as_value();
+        let err = MmapedFile::slice(obj, &[past_used]).unwrap_err();
+        assert!(err.is_kind_of(index_error()));
+
+        let past_used_int = Integer::from_u64(used as u64 + 8).as_value();
+        assert!(MmapedFile::slice(obj, &[past_used_int]).is_err());
+
+        // Starting within `used` but ending past it is rejected as well.
+        let straddle = Range::new(used - 8, used + 8, true).unwrap().as_value();
+        let err = MmapedFile::slice(obj, &[straddle]).unwrap_err();
+        assert!(err.is_kind_of(index_error()));
+
+        let start = Integer::from_u64(used as u64 - 8).as_value();
+        let len = Integer::from_u64(16).as_value();
+        let err = MmapedFile::slice(obj, &[start, len]).unwrap_err();
+        assert!(err.is_kind_of(index_error()));
+
+        let open_ended = Range::new(HEADER_SIZE, -1, false).unwrap().as_value();
+        assert!(MmapedFile::slice(obj, &[open_ended]).is_err());
+
+        let exact = Integer::from_u64(used as u64 - 8).as_value();
+        let len = Integer::from_u64(8).as_value();
+        assert!(MmapedFile::slice(obj, &[exact, len]).is_ok());
+    }
+
+    #[test]
+    fn test_slice_resize() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        fn assert_internals(
+            obj: Obj<MmapedFile>,
+            parent_id: c_ulong,
+            child_id: c_ulong,
+            unshared_id: c_ulong,
+        ) {
+            let rs_self = &*obj;
+            let tracker: Value = obj.ivar_get("@weak_obj_tracker").unwrap();
+
+            let mmap_ptr = rs_self.as_mut_ptr();
+            let mmap_len = rs_self.capacity();
+
+            let mut parent_checked = false;
+            let mut child_checked = false;
+
+            for val in tracker.enumeratorize("each_value", ()) {
+                let rb_string = val.unwrap();
+                let str = RString::from_value(rb_string).unwrap();
+
+                unsafe {
+                    let raw_str = MmapedFile::rb_string_internal(str);
+                    if str.as_raw() == child_id {
+                        assert_eq!(parent_id, raw_str.as_ref().as_.heap.aux.shared);
+
+                        let child_offset = mmap_len as isize - str.len() as isize;
+                        assert_eq!(mmap_ptr.offset(child_offset), raw_str.as_ref().as_.heap.ptr);
+
+                        child_checked = true;
+                    } else if str.as_raw() == parent_id {
+                        assert_eq!(parent_id, str.as_raw());
+
+                        assert_eq!(mmap_ptr, raw_str.as_ref().as_.heap.ptr);
+                        assert_eq!(mmap_len as c_long, str.len() as c_long);
+                        assert!(raw_str.as_ref().basic.flags & (STR_SHARED | STR_NOEMBED) > 0);
+                        assert!(str.is_frozen());
+
+                        parent_checked = true;
+                    } else if str.as_raw() == unshared_id {
+                        panic!("tracking unshared string");
+                    } else {
+                        panic!("unknown string");
+                    }
+                }
+            }
+            assert!(parent_checked && child_checked);
+        }
+
+        let (obj, _file) = create_obj();
+        let _ = populate_entries(&obj);
+
+        let rs_self = &*obj;
+
+        // Create a string containing the full mmap.
+        let parent_str = rs_self.str(obj).unwrap();
+        let parent_id = parent_str.as_raw();
+
+        // Ruby's shared strings are only created when they go to the end of
+        // original string.
+        let len = rs_self.inner(|inner| Ok(inner.len())).unwrap();
+        let shareable_range = Range::new(1, len - 1, false).unwrap().as_value();
+
+        // This string should re-use the parent's buffer with an offset and have
+        // the parent's id in `as.heap.aux.shared`
+        let child_str = rs_self._slice(obj, parent_str, &[shareable_range]).unwrap();
+        let child_id = child_str.as_raw();
+
+        // A range that does not reach the end of the parent will not be shared.
+        assert!(len > 4);
+        let unshareable_range = Range::new(0, 4, false).unwrap().as_value();
+
+        // This string should NOT be tracked, it should own its own buffer.
+        let unshared_str = rs_self
+            ._slice(obj, parent_str, &[unshareable_range])
+            .unwrap();
+        let unshared_id = unshared_str.as_raw();
+        assert!(!MmapedFile::rb_string_is_shared(unshared_str));
+
+        assert_internals(obj, parent_id, child_id, unshared_id);
+
+        let orig_ptr = rs_self.as_mut_ptr();
+        assert_eq!(0, rs_self.remap_count());
+
+        // Expand a bunch to ensure we remap
+        for _ in 0..16 {
+            rs_self.expand_to_fit(obj, rs_self.capacity() * 2).unwrap();
+        }
+        let new_ptr = rs_self.as_mut_ptr();
+        assert!(orig_ptr != new_ptr);
+        assert_eq!(16, rs_self.remap_count(), "each expansion remapped");
+
+        // Fitting within the current capacity does not remap.
+        rs_self.expand_to_fit(obj, rs_self.capacity()).unwrap();
+        assert_eq!(16, rs_self.remap_count(), "no remap within capacity");
+
+        // If we haven't updated the pointer to the newly remapped file this will segfault.
+        let _: Value = eval!("puts parent", parent = parent_str).unwrap();
+        let _: Value = eval!("puts child", child = child_str).unwrap();
+        let _: Value = eval!("puts unshared", unshared = unshared_str).unwrap();
+
+        // Confirm that tracked strings are still valid.
+        assert_internals(obj, parent_id, child_id, unshared_id);
+    }
+
+    #[test]
+    fn test_is_mapped() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let (obj, _file) = create_obj();
+        assert!(obj.is_mapped());
+
+        let mapped: bool = eval!("obj.mapped?", obj = obj).unwrap();
+        assert!(mapped);
+
+        // A writer holding the lock can't have unmapped the file.
+        {
+            let _guard = obj.inner.write().unwrap();
+            assert!(obj.is_mapped());
+        }
+
+        MmapedFile::munmap(obj).unwrap();
+        assert!(!obj.is_mapped());
+
+        let mapped: bool = eval!("obj.mapped?", obj = obj).unwrap();
+        assert!(!mapped);
+    }
+
+    #[test]
+    fn test_poisoned_lock() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let (obj, _file) = create_obj();
+
+        // Contention is reported as concurrent access.
+        {
+            let _guard = obj.inner.read().unwrap();
+            assert_eq!(Err(MmapError::ConcurrentAccess), obj.inner_mut(|_| Ok(())));
+        }
+        assert!(obj.inner_mut(|_| Ok(())).is_ok());
+
+        // Panic while holding the lock.
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _guard = obj.inner.write().unwrap();
+            panic!("panic while locked");
+        }));
+        assert!(result.is_err());
+        assert!(obj.inner.is_poisoned());
+
+        assert_eq!(Err(MmapError::Poisoned), obj.inner(|_| Ok(())));
+        assert_eq!(Err(MmapError::Poisoned), obj.inner_mut(|_| Ok(())));
+        assert_eq!(Some(MmapError::Poisoned), obj.take_inner().err());
+        assert!(!obj.is_mapped());
+
+        let err = MmapedFile::munmap(obj).unwrap_err();
+        assert!(err.is_kind_of(exception::runtime_error()));
+    }
+
+    #[test]
+    fn test_atomic_values() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let (obj, _file) = create_obj();
+        let enabled: bool = eval!("obj.atomic_values?", obj = obj).unwrap();
+        assert!(!enabled);
+
+        let _: Value = eval!("obj.atomic_values = true", obj = obj).unwrap();
+        assert!(obj.atomic_values());
+
+        let positions = RHash::new();
+        let key = RString::new("key");
+
+        let value = MmapedFile::upsert_entry(obj, positions, key, 1.5).unwrap();
+        assert_eq!(1.5, value);
+
+        let value = MmapedFile::upsert_entry(obj, positions, key, 2.5).unwrap();
+        assert_eq!(2.5, value);
+
+        let value = MmapedFile::fetch_entry(obj, positions, key, 0.0).unwrap();
+        assert_eq!(2.5, value);
+
+        // Values written atomically are readable with plain loads.
+        obj.save_atomic_values(false);
+        let value = MmapedFile::fetch_entry(obj, positions, key, 0.0).unwrap();
+        assert_eq!(2.5, value);
+    }
+
+    #[test]
+    fn test_bogus_position() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        struct TestCase {
+            name: &'static str,
+            position: &'static str,
+            expected_err: &'static str,
+        }
+
+        let tc = vec![
+            TestCase {
+                name: "float",
+                position: "8.5",
+                expected_err: "is not an integer: 8.5",
+            },
+            TestCase {
+                name: "nan",
+                position: "Float::NAN",
+                expected_err: "is not an integer: NaN",
+            },
+            TestCase {
+                name: "string",
+                position: "'8'",
+                expected_err: "is not an integer: \"8\"",
+            },
+            TestCase {
+                name: "negative",
+                position: "-8",
+                expected_err: "position -8 for key \"a\" is outside the used range",
+            },
+            TestCase {
+                name: "inside header",
+                position: "0",
+                expected_err: "position 0 for key \"a\" is outside the used range",
+            },
+            TestCase {
+                name: "beyond used",
+                position: "1_000_000",
+                expected_err: "position 1000000 for key \"a\" is outside the used range",
+            },
+        ];
+
+        for case in tc {
+            let name = case.name;
+
+            let (obj, _file) = create_obj();
+            let positions = populate_entries(&obj);
+            let used = obj.inner(|inner| inner.load_used()).unwrap();
+            let key = RString::new("a");
+
+            let position: Value = eval(case.position).unwrap();
+            positions.aset(key, position).unwrap();
+
+            let results = [
+                MmapedFile::fetch_entry(obj, positions, key, 1.0),
+                MmapedFile::peek_entry(obj, positions, key, 1.0),
+                MmapedFile::upsert_entry(obj, positions, key, 1.0),
+            ];
+
+            for result in results {
+                let err = result.expect_err(name);
+                assert!(
+                    err.to_string().contains(case.expected_err),
+                    "test case: {name} - {err}"
+                );
+            }
+
+            assert_eq!(
+                used,
+                obj.inner(|inner| inner.load_used()).unwrap(),
+                "test case: {name} - used unchanged"
+            );
+        }
+    }
+
+    #[test]
+    fn test_peek_entry() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let (obj, _file) = create_obj();
+        let positions = RHash::new();
+        let key = RString::new(r#"["family","name",["label_a"],["value_a"]]"#);
+
+        let used = obj.inner(|inner| inner.load_used()).unwrap();
+
+        let value = MmapedFile::peek_entry(obj, positions, key, 5.0).unwrap();
+        assert_eq!(5.0, value, "default for missing key");
+        assert_eq!(
+            used,
+            obj.inner(|inner| inner.load_used()).unwrap(),
+            "used unchanged"
+        );
+        assert!(positions.is_empty(), "no position recorded");
+
+        MmapedFile::upsert_entry(obj, positions, key, 2.0).unwrap();
+
+        let value: f64 = eval!(
+            "obj.peek_entry(positions, key, 5.0)",
+            obj = obj,
+            positions = positions,
+            key = key
+        )
+        .unwrap();
+        assert_eq!(2.0, value, "existing value");
+    }
+
+    #[test]
+    fn test_load_value_bits() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let (obj, _file) = create_obj();
+        let positions = RHash::new();
+        let key = RString::new(r#"["family","name",["label_a"],["value_a"]]"#);
+
+        assert_eq!(
+            None,
+            MmapedFile::load_value_bits(obj, positions, key).unwrap()
+        );
+
+        // Values that formatting or `==` can't tell apart from others.
+        for value in [-0.0, 0.1 + 0.2, f64::from_bits(0x7ff8_0000_dead_beef)] {
+            MmapedFile::upsert_entry(obj, positions, key, value).unwrap();
+
+            let bits = MmapedFile::load_value_bits(obj, positions, key).unwrap();
+            assert_eq!(Some(value.to_bits()), bits, "value: {value}");
+        }
+
+        let bits: u64 = eval!(
+            "obj.load_value_bits(positions, key)",
+            obj = obj,
+            positions = positions,
+            key = key
+        )
+        .unwrap();
+        assert_eq!(0x7ff8_0000_dead_beef, bits);
+    }
+
+    #[test]
+    fn test_empty_key() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let (obj, _file) = create_obj();
+        let positions = RHash::new();
+        let used = obj.inner(|inner| inner.load_used()).unwrap();
+
+        assert!(MmapedFile::upsert_entry(obj, positions, RString::new(""), 1.0).is_err());
+        assert!(MmapedFile::fetch_entry(obj, positions, RString::new(""), 1.0).is_err());
+        assert!(MmapedFile::upsert_exemplar_at(
+            obj,
+            positions,
+            RString::new(""),
+            1.0,
+            RString::new("trace_id"),
+            RString::new("abc"),
+            0,
+        )
+        .is_err());
+
+        assert_eq!(
+            used,
+            obj.inner(|inner| inner.load_used()).unwrap(),
+            "nothing written"
+        );
+        assert!(positions.is_empty());
+    }
+
+    #[test]
+    fn test_exemplar_timestamp() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let (obj, _file) = create_obj();
+        let positions = RHash::new();
+        let key = RString::new("key");
+
+        // An explicit timestamp is stored as given, in nanoseconds.
+        let timestamp: u64 = 1_700_000_000_123_456_789;
+        let value: f64 = eval!(
+            "obj.upsert_exemplar(positions, key, 1.5, 'trace_id', 'abc', ts)",
+            obj = obj,
+            positions = positions,
+            key = key,
+            ts = timestamp
+        )
+        .unwrap();
+        assert_eq!(1.5, value);
+
+        let pos: usize = positions.fetch(key).unwrap();
+        let ex = obj.load_exemplar(pos).unwrap();
+        assert_eq!(u128::from(timestamp), ex.timestamp);
+        assert_eq!("trace_id", ex.label_name);
+        assert_eq!("abc", ex.label_value);
+
+        // Updating an existing entry replaces the timestamp.
+        MmapedFile::upsert_exemplar_at(
+            obj,
+            positions,
+            key,
+            2.5,
+            RString::new("trace_id"),
+            RString::new("def"),
+            42,
+        )
+        .unwrap();
+        let ex = obj.load_exemplar(pos).unwrap();
+        assert_eq!(42, ex.timestamp);
+        assert_eq!("def", ex.label_value);
+
+        // Without a timestamp the current time is used.
+        let before = MmapedFile::now_nanos();
+        let _: f64 = eval!(
+            "obj.upsert_exemplar(positions, key, 3.5, 'trace_id', 'ghi')",
+            obj = obj,
+            positions = positions,
+            key = key
+        )
+        .unwrap();
+        let after = MmapedFile::now_nanos();
+
+        let ex = obj.load_exemplar(pos).unwrap();
+        assert!((before..=after).contains(&ex.timestamp));
+    }
+
+    #[test]
+    fn test_durable_writes() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let (obj, _file) = create_obj();
+        let enabled: bool = eval!("obj.durable_writes?", obj = obj).unwrap();
+        assert!(!enabled);
+
+        let _: Value = eval!("obj.durable_writes = true", obj = obj).unwrap();
+        assert!(obj.durable_writes());
+
+        let positions = RHash::new();
+        let value = MmapedFile::upsert_entry(obj, positions, RString::new("key"), 1.5).unwrap();
+        assert_eq!(1.5, value);
+
+        let used = obj.inner(|inner| inner.load_used()).unwrap();
+        assert!(used as usize > HEADER_SIZE, "header advanced");
+    }
+
+    #[test]
+    fn test_sync_chunks() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let (obj, _file) = create_obj();
+        let _ = populate_entries(&obj);
+        let path = obj.inner(|inner| Ok(inner.path().to_path_buf())).unwrap();
+
+        let _: Value = eval!("obj.sync(nil, chunk_size: 1)", obj = obj).unwrap();
+        let _: Value = eval!("obj.sync(4, chunk_size: 1 << 20)", obj = obj).unwrap();
+
+        let contents = std::fs::read(&path).unwrap();
+        let mapped = obj.inner(|inner| Ok(inner.as_slice().to_vec())).unwrap();
+        assert_eq!(&mapped[..56], &contents[..56]);
+    }
+
+    #[test]
+    fn test_open_read_only() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let json = r#"["family","name",["label_a"],["value_a"]]"#;
+        let data = testhelper::entries_to_db(&[json], &[1.5], None);
+        let TestFile {
+            file: _file,
+            path,
+            dir: _dir,
+        } = TestFile::new(&data);
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o444)).unwrap();
+        let rpath = RString::new(&path.display().to_string());
+
+        let obj: Obj<MmapedFile> =
+            eval!("FastMmapedFileRs.open_read_only(path)", path = rpath).unwrap();
+        assert!(obj.is_read_only().unwrap());
+        assert_eq!(data.len(), obj.capacity(), "file not extended");
+
+        let used: usize = eval!("obj.used", obj = obj).unwrap();
+        assert_eq!(data.len(), used);
+
+        let entry = obj.entry_at(used - size_of::<f64>()).unwrap();
+        let value: f64 = entry.fetch(Symbol::new("value")).unwrap();
+        assert_eq!(1.5, value);
+
+        let positions = RHash::new();
+        let err = MmapedFile::upsert_entry(obj, positions, RString::new("key"), 1.0).unwrap_err();
+        assert!(err.is_kind_of(exception::io_error()), "{err}");
+        assert!(err.to_string().contains("not opened for writing"), "{err}");
+
+        let result: std::result::Result<Value, Error> = eval!("obj.used = 16", obj = obj);
+        assert!(result.is_err(), "header can't be written");
+
+        // Syncing has nothing to flush.
+        let _: Value = eval!("obj.sync", obj = obj).unwrap();
+
+        assert_eq!(data, std::fs::read(&path).unwrap(), "file unchanged");
+    }
+
+    #[test]
+    fn test_prefault() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let (obj, _file) = create_obj();
+        let enabled: bool = eval!("obj.prefault?", obj = obj).unwrap();
+        assert!(!enabled);
+
+        let _: Value = eval!("obj.prefault = true", obj = obj).unwrap();
+        assert!(obj.prefault());
+
+        // Write entries until the file has expanded, the added pages are
+        // zeroed and entries written to them read back as usual.
+        let positions = RHash::new();
+        let page_size = obj.capacity();
+        let mut i = 0;
+        while obj.remap_count() < 2 {
+            let key = RString::new(&format!("key_{i:04}"));
+            MmapedFile::upsert_entry(obj, positions, key, i as f64).unwrap();
+            i += 1;
+        }
+        assert!(obj.capacity() > page_size);
+
+        for j in 0..i {
+            let key = RString::new(&format!("key_{j:04}"));
+            let value = MmapedFile::fetch_entry(obj, positions, key, -1.0).unwrap();
+            assert_eq!(j as f64, value);
+        }
+
+        obj.inner(|inner| {
+            let used = inner.load_used()? as usize;
+            assert!(inner.as_slice()[used..].iter().all(|b| *b == 0));
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    /// The minor page faults taken by the process so far.
+    fn minor_faults() -> c_long {
+        // SAFETY: `rusage` is plain data, valid when zeroed, and `getrusage`
+        // only writes to it.
+        let mut usage: nix::libc::rusage = unsafe { std::mem::zeroed() };
+        let ret = unsafe { nix::libc::getrusage(nix::libc::RUSAGE_SELF, &mut usage) };
+        assert_eq!(0, ret, "getrusage failed");
+        usage.ru_minflt
+    }
+
+    /// Check that prefaulting moves the page faults of writing new entries
+    /// into the writes that expand the file. Run with `cargo nextest run
+    /// --release --run-ignored ignored-only bench_prefault`.
+    #[test]
+    #[ignore]
+    fn bench_prefault() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        // The faults taken by writes that expanded the file, and by the rest.
+        let faults = |prefault| {
+            let (obj, _file) = create_obj();
+            obj.save_prefault(prefault);
+
+            let positions = RHash::new();
+            let keys: Vec<_> = (0..100_000)
+                .map(|i| RString::new(&format!("key_{i:06}")))
+                .collect();
+
+            let (mut expanding, mut steady) = (0, 0);
+            for key in keys {
+                let remaps = obj.remap_count();
+                let start = minor_faults();
+                MmapedFile::upsert_entry(obj, positions, key, 1.0).unwrap();
+                let taken = minor_faults() - start;
+
+                if obj.remap_count() > remaps {
+                    expanding += taken;
+                } else {
+                    steady += taken;
+                }
+            }
+            (expanding, steady)
+        };
+
+        let (plain_expanding, plain_steady) = faults(false);
+        let (prefault_expanding, prefault_steady) = faults(true);
+
+        assert!(
+            prefault_expanding > plain_expanding,
+            "expanding writes took {prefault_expanding} faults prefaulted, {plain_expanding} not"
+        );
+        assert!(
+            prefault_steady < plain_steady,
+            "other writes took {prefault_steady} faults prefaulted, {plain_steady} not"
+        );
+    }
+
+    #[test]
+    fn test_growth_factor() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let TestFile {
+            file: _file,
+            path,
+            dir: _dir,
+        } = TestFile::new(&[0u8; 8]);
+        let rpath = RString::new(&path.display().to_string());
+
+        for factor in [1.0, 0.5, -2.0, f64::NAN] {
+            let result: std::result::Result<Obj<MmapedFile>, Error> = eval!(
+                "FastMmapedFileRs.new(path, factor)",
+                path = rpath,
+                factor = factor
+            );
+            assert!(result.is_err(), "factor {factor} rejected");
+        }
+
+        let obj: Obj<MmapedFile> = eval!("FastMmapedFileRs.new(path, 1.5)", path = rpath).unwrap();
+        let rs_self = &*obj;
+
+        let page_size = rs_self.capacity();
+        assert_eq!(
+            page_size * 3 / 2,
+            rs_self.growth_factor.grow(page_size).unwrap()
+        );
+        assert_eq!(
+            2,
+            rs_self.growth_factor.grow(1).unwrap(),
+            "always grows by at least a byte"
+        );
+
+        // Write entries until the file has expanded several times.
+        let positions = RHash::new();
+        let mut last_cap = page_size;
+        for i in 0..1000 {
+            let key = RString::new(&format!("key_{i:04}"));
+            MmapedFile::upsert_entry(obj, positions, key, i as f64).unwrap();
+
+            let cap = rs_self.capacity();
+            let used = obj.load_used().unwrap().to_u64().unwrap() as usize;
+            assert!(cap >= last_cap, "capacity never shrinks");
+            assert!(cap > used, "capacity past used");
+            last_cap = cap;
+        }
+
+        assert!(last_cap > page_size);
+        assert!(rs_self.remap_count() > 1);
+        assert!(
+            last_cap < page_size * 2usize.pow(rs_self.remap_count() as u32),
+            "grew by less than doubling"
+        );
+    }
+
+    #[test]
+    fn test_track_strings_disabled() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let TestFile {
+            file: _file,
+            path,
+            dir: _dir,
+        } = TestFile::new(&[0u8; 8]);
+        let rpath = RString::new(&path.display().to_string());
+
+        let obj: Obj<MmapedFile> = eval!(
+            "FastMmapedFileRs.new(path, track_strings: false)",
+            path = rpath
+        )
+        .unwrap();
+        let rs_self = &*obj;
+
+        let tracked: bool = eval!("obj.track_strings?", obj = obj).unwrap();
+        assert!(!tracked);
+
+        let tracker: Value = obj.ivar_get("@weak_obj_tracker").unwrap();
+        assert!(tracker.is_nil(), "no WeakMap is created");
+
+        // Write entries until the file has expanded.
+        let page_size = rs_self.capacity();
+        let positions = RHash::new();
+        for i in 0..1000 {
+            let key = RString::new(&format!("key_{i:04}"));
+            MmapedFile::upsert_entry(obj, positions, key, i as f64).unwrap();
+        }
+
+        assert!(rs_self.capacity() > page_size);
+        assert!(rs_self.remap_count() > 0);
+
+        let key = RString::new("key_0999");
+        let value = MmapedFile::fetch_entry(obj, positions, key, 0.0).unwrap();
+        assert_eq!(999.0, value);
+
+        // Strings backed by the file can't be handed out untracked.
+        let err = eval!("obj.slice(0, 8)", obj = obj)
+            .map(|_: Value| ())
+            .unwrap_err();
+        assert!(err.is_kind_of(io_error()), "{err}");
+    }
+
+    #[test]
+    fn test_refresh() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let (obj, _file) = create_obj();
+        let _ = populate_entries(&obj);
+
+        let rs_self = &*obj;
+        let orig_cap = rs_self.capacity();
+
+        assert!(!MmapedFile::refresh(obj).unwrap(), "no growth, no remap");
+
+        // Grow the file from outside of the mmap.
+        let path = rs_self.inner(|inner| Ok(inner.path().to_owned())).unwrap();
+        let mut file = File::options().write(true).open(path).unwrap();
+        file.set_len(orig_cap as u64 * 2).unwrap();
+        file.seek(SeekFrom::Start(orig_cap as u64)).unwrap();
+        file.write_all(b"appended").unwrap();
+        file.sync_all().unwrap();
+
+        assert!(MmapedFile::refresh(obj).unwrap(), "file remapped");
+        assert_eq!(orig_cap * 2, rs_self.capacity());
+        assert_eq!(1, rs_self.remap_count());
+
+        let appended = rs_self
+            .with_mapped_bytes(|_, _, bytes| Ok(bytes[orig_cap..orig_cap + 8].to_vec()))
+            .unwrap();
+        assert_eq!(b"appended", appended.as_slice());
+
+        // Existing entries are still readable.
+        assert_eq!(56, obj.load_used().unwrap().to_u64().unwrap());
+    }
+
+    #[test]
+    fn test_to_metrics_from_mmaps() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let (obj, _file) = create_obj();
+        let positions = RHash::from_value(eval("{}").unwrap()).unwrap();
+
+        let key = r#"["family","name",["label_a"],["value_a"]]"#;
+        MmapedFile::upsert_entry(obj, positions, RString::new(key), 1.0).unwrap();
+
+        let mmap_list: RArray = eval!("[[obj, :max, :gauge, 'worker-1']]", obj = obj).unwrap();
+        let out = MmapedFile::to_metrics_from_mmaps(mmap_list).unwrap();
+
+        assert_eq!(
+            "# HELP family Multiprocess metric\n# TYPE family gauge\nname{label_a=\"value_a\"} 1\n",
+            out
+        );
+
+        // The read lock has been released, so we can still write.
+        MmapedFile::upsert_entry(obj, positions, RString::new(key), 2.0).unwrap();
+    }
+
+    #[test]
+    fn test_to_metrics_from_fds() {
+        use std::os::unix::io::AsRawFd;
+
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let json = r#"["family","name",["label_a"],["value_a"]]"#;
+        let test_file = TestFile::new(&testhelper::entries_to_db(&[json], &[1.0], None));
+        let fd = test_file.file.as_raw_fd();
+
+        let out: String = eval!(
+            "FastMmapedFileRs.to_metrics_from_fds([[fd, :all, :counter, 'worker-1']])",
+            fd = fd
+        )
+        .unwrap();
+        assert_eq!(
+            "# HELP family Multiprocess metric\n# TYPE family counter\nname{label_a=\"value_a\"} 1\n",
+            out
+        );
+
+        // Our descriptor is still open, and can be aggregated again.
+        assert!(test_file.file.metadata().is_ok());
+        let list: RArray = eval!("[[fd, :all, :counter, 'worker-1']]", fd = fd).unwrap();
+        assert_eq!(out, MmapedFile::to_metrics_from_fds(list).unwrap());
+
+        let list: RArray = eval("[[-1, :all, :counter, 'worker-1']]").unwrap();
+        assert!(MmapedFile::to_metrics_from_fds(list).is_err());
+    }
+
+    #[test]
+    fn test_metrics_digest() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let json = r#"["family","name",["label_a"],["value_a"]]"#;
+
+        let first = TestFile::new(&testhelper::entries_to_db(&[json], &[1.0], None));
+        let second = TestFile::new(&testhelper::entries_to_db(&[json], &[2.0], None));
+        let changed = TestFile::new(&testhelper::entries_to_db(&[json], &[3.0], None));
+
+        let digest = |a: &TestFile, b: &TestFile| {
+            let list: RArray = eval!(
+                "[[a, :all, :counter, 'worker-1'], [b, :all, :counter, 'worker-2']]",
+                a = RString::new(&a.path.display().to_string()),
+                b = RString::new(&b.path.display().to_string())
+            )
+            .unwrap();
+            MmapedFile::metrics_digest(list).unwrap()
+        };
+
+        assert_eq!(
+            digest(&first, &second),
+            digest(&second, &first),
+            "stable across file order"
+        );
+        assert_ne!(
+            digest(&first, &second),
+            digest(&first, &changed),
+            "changes with values"
+        );
+    }
+
+    #[test]
+    fn test_to_metrics_if_modified() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let json = &[
+            r#"["family_a","name_a",["label_a"],["value_a"]]"#,
+            r#"["family_b","name_b",["label_a"],["value_a"]]"#,
+        ];
+
+        let first = TestFile::new(&testhelper::entries_to_db(json, &[1.0, 2.0], None));
+        let changed = TestFile::new(&testhelper::entries_to_db(json, &[1.0, 3.0], None));
+
+        let render = |file: &TestFile, digests: Value| {
+            let result: RArray = eval!(
+                "FastMmapedFileRs.to_metrics_if_modified([[a, :all, :counter, 'worker-1']], digests)",
+                a = RString::new(&file.path.display().to_string()),
+                digests = digests
+            )
+            .unwrap();
+            let families: RHash = result.entry(0).unwrap();
+            let digests: RHash = result.entry(1).unwrap();
+            (families, digests)
+        };
+        let is_modified = |families: RHash, family: &str| {
+            let text: Value = families.fetch(family).unwrap();
+            RString::from_value(text).is_some()
+        };
+
+        // Without prior digests, every family is rendered.
+        let (families, digests) = render(&first, RHash::new().as_value());
+        let family_a: String = families.fetch("family_a").unwrap();
+        assert_eq!(
+            "# HELP family_a Multiprocess metric\n# TYPE family_a counter\nname_a{label_a=\"value_a\"} 1\n",
+            family_a
+        );
+        assert!(is_modified(families, "family_b"));
+
+        // Nothing changed.
+        let (families, same) = render(&first, digests.as_value());
+        let not_modified: Symbol = families.fetch("family_a").unwrap();
+        assert_eq!("not_modified", not_modified.name().unwrap());
+        assert!(!is_modified(families, "family_b"));
+        assert!(digests.funcall::<_, _, bool>("==", (same,)).unwrap());
+
+        // Only the family whose value changed is rendered again.
+        let (families, _) = render(&changed, digests.as_value());
+        assert!(!is_modified(families, "family_a"));
+        let family_b: String = families.fetch("family_b").unwrap();
+        assert_eq!(
+            "# HELP family_b Multiprocess metric\n# TYPE family_b counter\nname_b{label_a=\"value_a\"} 3\n",
+            family_b
+        );
+    }
+
+    #[test]
+    fn test_render() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let json = &[
+            r#"["family","name",["label_a"],["value_a"]]"#,
+            r#"["family","name",["label_a"],["value_b"]]"#,
+        ];
+        let file = TestFile::new(&testhelper::entries_to_db(json, &[1.0, 2.0], None));
+
+        let list: RArray = eval!(
+            "[[a, :all, :counter, 'worker-1']]",
+            a = RString::new(&file.path.display().to_string())
+        )
+        .unwrap();
+
+        let render =
+            |format: &str| MmapedFile::render(&[list.as_value(), Symbol::new(format).as_value()]);
+
+        let text = render("text").unwrap();
+        assert_eq!(MmapedFile::to_metrics(&[list.as_value()]).unwrap(), text);
+        assert!(text.contains("name{label_a=\"value_a\"} 1\n"), "{text}");
+
+        let openmetrics = render("openmetrics").unwrap();
+        assert!(
+            openmetrics.contains("name_total{label_a=\"value_a\"} 1\n"),
+            "{openmetrics}"
+        );
+        assert!(openmetrics.ends_with("# EOF\n"), "{openmetrics}");
+
+        let protobuf = render("protobuf").unwrap();
+        assert_eq!(
+            MmapedFile::to_protobuf(&[list.as_value()]).unwrap(),
+            protobuf
+        );
+        let mut buf = protobuf.as_bytes();
+        let mf = io::prometheus::client::MetricFamily::decode_length_delimited(&mut buf).unwrap();
+        assert_eq!(2, mf.metric.len());
+
+        let err = render("json").unwrap_err();
+        assert!(err.to_string().contains("unknown format json"), "{err}");
+    }
+
+    #[test]
+    fn test_to_protobuf_io() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let json = &[
+            r#"["family_b","name_b",["label_a"],["value_a"]]"#,
+            r#"["family_a","name_a",["label_a"],["value_a"]]"#,
+            r#"["family_a","name_a",["label_a"],["value_b"]]"#,
+        ];
+        let file = TestFile::new(&testhelper::entries_to_db(json, &[1.0, 2.0, 3.0], None));
+
+        let list: RArray = eval!(
+            "[[a, :all, :counter, 'worker-1']]",
+            a = RString::new(&file.path.display().to_string())
+        )
+        .unwrap();
+
+        let out: RArray = eval!(
+            r#"
+            require "stringio"
+
+            io = StringIO.new("".b)
+            written = FastMmapedFileRs.to_protobuf_io(list, io, scrape_timestamp: 1_000)
+            [written, io.string]
+            "#,
+            list = list
+        )
+        .unwrap();
+        let written: usize = out.entry(0).unwrap();
+        let streamed: RString = out.entry(1).unwrap();
+        let streamed = unsafe { streamed.as_slice() };
+
+        let expected: RString = eval!(
+            "FastMmapedFileRs.to_protobuf(list, scrape_timestamp: 1_000)",
+            list = list
+        )
+        .unwrap();
+        assert_eq!(unsafe { expected.as_slice() }, streamed);
+        assert_eq!(streamed.len(), written);
+
+        let mut buf = streamed;
+        let mut families = Vec::new();
+        while !buf.is_empty() {
+            let mf =
+                io::prometheus::client::MetricFamily::decode_length_delimited(&mut buf).unwrap();
+            assert_eq!(Some(1_000), mf.metric[0].timestamp_ms, "options applied");
+            families.push((mf.name.unwrap(), mf.metric.len()));
+        }
+        assert_eq!(
+            vec![("family_a".to_string(), 2), ("family_b".to_string(), 1)],
+            families
+        );
+    }
+
+    #[test]
+    fn test_max_series_per_pass() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let json = &[
+            r#"["family_b","name_b",["label_a"],["value_a"]]"#,
+            r#"["family_a","name_a",["label_a"],["value_a"]]"#,
+            r#"["family_a","name_a",["label_a"],["value_b"]]"#,
+        ];
+        let file = TestFile::new(&testhelper::entries_to_db(json, &[1.0, 2.0, 3.0], None));
+
+        let list: RArray = eval!(
+            "[[a, :all, :counter, 'worker-1']]",
+            a = RString::new(&file.path.display().to_string())
+        )
+        .unwrap();
+
+        for format in ["text", "openmetrics", "protobuf"] {
+            let format = Symbol::new(format).as_value();
+            let expected = MmapedFile::render(&[list.as_value(), format]).unwrap();
+
+            let streamed: RString = eval!(
+                "FastMmapedFileRs.render(list, format, max_series_per_pass: 1)",
+                list = list,
+                format = format
+            )
+            .unwrap();
+
+            assert_eq!(
+                expected.as_bytes(),
+                unsafe { streamed.as_slice() },
+                "format: {}",
+                format.inspect()
+            );
+        }
+    }
+
+    #[test]
+    fn test_max_series_per_pass_target_info() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let json = &[
+            r#"["family_b","name_b",["label_a"],["value_a"]]"#,
+            r#"["family_a","name_a",["label_a"],["value_a"]]"#,
+        ];
+        let file = TestFile::new(&testhelper::entries_to_db(json, &[1.0, 2.0], None));
+
+        let list: RArray = eval!(
+            "[[a, :all, :counter, 'worker-1']]",
+            a = RString::new(&file.path.display().to_string())
+        )
+        .unwrap();
+
+        let render = |max_series_per_pass: Option<usize>| -> String {
+            eval!(
+                "FastMmapedFileRs.to_metrics(list, openmetrics: true, target_info: { 'env' => 'prod' }, max_series_per_pass: max)",
+                list = list,
+                max = max_series_per_pass
+            )
+            .unwrap()
+        };
+
+        let streamed = render(Some(1));
+        assert_eq!(render(None), streamed);
+        assert_eq!(
+            1,
+            streamed.matches("# TYPE target info\n").count(),
+            "{streamed}"
+        );
+        assert!(
+            streamed.starts_with("# HELP target Target metadata\n"),
+            "{streamed}"
+        );
+        assert_eq!(1, streamed.matches(OPENMETRICS_EOF).count(), "{streamed}");
+    }
+
+    #[test]
+    fn test_max_series_per_pass_rendered_names() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let render = |json: &[&str]| {
+            let values = vec![1.0; json.len()];
+            let file = TestFile::new(&testhelper::entries_to_db(json, &values, None));
+            let list: RArray = eval!(
+                "[[a, :all, :counter, 'worker-1']]",
+                a = RString::new(&file.path.display().to_string())
+            )
+            .unwrap();
+
+            eval!(
+                r#"
+                require 'stringio'
+                $stderr = StringIO.new
+                begin
+                  [FastMmapedFileRs.to_metrics(list, sanitize_names: true, max_series_per_pass: 1), $stderr.string]
+                ensure
+                  $stderr = STDERR
+                end
+                "#,
+                list = list
+            )
+        };
+
+        // The same label is sanitized in each batch, but counted once.
+        let (_, warning): (String, String) = render(&[
+            r#"["family_a","family_a",["status-code"],["200"]]"#,
+            r#"["family_b","family_b",["status-code"],["200"]]"#,
+        ])
+        .unwrap();
+        assert!(
+            warning.contains("sanitized 1 invalid metric or label names"),
+            "{warning}"
+        );
+
+        // Families rendered under the same name collide across batches.
+        let err = render(&[
+            r#"["http.requests","http_requests",[],[]]"#,
+            r#"["http_requests","http_requests",[],[]]"#,
+        ])
+        .map(|_: (String, String)| ())
+        .unwrap_err();
+        assert!(err.to_string().contains("both render as"), "{err}");
+    }
+
+    #[test]
+    fn test_name_prefixes() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let json = &[r#"["family","name",["label_a"],["value_a"]]"#];
+        let file = TestFile::new(&testhelper::entries_to_db(json, &[1.0], None));
+
+        let list: RArray = eval!(
+            "[[a, :all, :counter, 'worker-1']]",
+            a = RString::new(&file.path.display().to_string())
+        )
+        .unwrap();
+
+        let out: String = eval!(
+            "FastMmapedFileRs.to_metrics(list, name_prefixes: { '' => 'vendor_' })",
+            list = list
+        )
+        .unwrap();
+        assert_eq!(
+            indoc! {r#"# HELP vendor_family Multiprocess metric
+                # TYPE vendor_family counter
+                vendor_name{label_a="value_a"} 1
+                "#},
+            out
+        );
+    }
+
+    #[test]
+    fn test_scrape_timestamp() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let json = &[r#"["family","name",["label_a"],["value_a"]]"#];
+        let file = TestFile::new(&testhelper::entries_to_db(json, &[1.0], None));
+
+        let list: RArray = eval!(
+            "[[a, :all, :counter, 'worker-1']]",
+            a = RString::new(&file.path.display().to_string())
+        )
+        .unwrap();
+
+        let out: String = eval!(
+            "FastMmapedFileRs.to_metrics(list, scrape_timestamp: 1_700_000_005_000)",
+            list = list
+        )
+        .unwrap();
+        assert!(
+            out.ends_with("name{label_a=\"value_a\"} 1 1700000005000\n"),
+            "{out}"
+        );
+
+        let out: RString = eval!(
+            "FastMmapedFileRs.to_protobuf(list, scrape_timestamp: 1_700_000_005_000)",
+            list = list
+        )
+        .unwrap();
+        let mut buf = unsafe { out.as_slice() };
+        let mf = io::prometheus::client::MetricFamily::decode_length_delimited(&mut buf).unwrap();
+        assert_eq!(Some(1_700_000_005_000), mf.metric[0].timestamp_ms);
+    }
+
+    #[test]
+    fn test_estimate_size() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let json = &[
+            r#"["family_b","name_b",["label_a"],["value_a"]]"#,
+            r#"["family_a","name_a",["label_a","label_b"],["value_a",1]]"#,
+            r#"["family_a","name_a",[],[]]"#,
+        ];
+        let file = TestFile::new(&testhelper::entries_to_db(json, &[1.0, 2.5, 0.125], None));
+
+        let list: RArray = eval!(
+            "[[a, :all, :gauge, 'worker-1']]",
+            a = RString::new(&file.path.display().to_string())
+        )
+        .unwrap();
+
+        let estimate: usize = eval!("FastMmapedFileRs.estimate_size(list)", list = list).unwrap();
+        let actual = MmapedFile::to_metrics(&[list.as_value()]).unwrap().len();
+
+        // The estimate is exact for the default options, allow a small margin
+        // in case rendering changes.
+        assert!(
+            estimate.abs_diff(actual) <= actual / 20,
+            "estimate {estimate}, actual {actual}"
+        );
+    }
+
+    #[test]
+    fn test_histogram_quantile() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let json = &[
+            r#"["hist","hist_bucket",["le"],["+Inf"]]"#,
+            r#"["hist","hist_bucket",["le"],["1"]]"#,
+            r#"["hist","hist_bucket",["le"],["2"]]"#,
+            r#"["hist","hist_count",[],[]]"#,
+        ];
+        let data = testhelper::entries_to_db(json, &[4.0, 2.0, 4.0, 4.0], None);
+        let file = TestFile::new(&data);
+
+        let list: RArray = eval!(
+            "[[a, :all, :histogram, 'worker-1'], [a, :all, :histogram, 'worker-2']]",
+            a = RString::new(&file.path.display().to_string())
+        )
+        .unwrap();
+
+        // Both files contribute, 4 of 8 observations are up to 1.
+        let median: Option<f64> = eval!(
+            "FastMmapedFileRs.histogram_quantile(list, 'hist', 0.5)",
+            list = list
+        )
+        .unwrap();
+        assert_eq!(Some(1.0), median);
+
+        let missing: Option<f64> = eval!(
+            "FastMmapedFileRs.histogram_quantile(list, 'other', 0.5)",
+            list = list
+        )
+        .unwrap();
+        assert_eq!(None, missing);
+    }
+
+    #[test]
+    fn test_max_key_length() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let short = r#"["family","name",["label_a"],["a"]]"#;
+        let long = r#"["family","name",["label_a"],["a_much_longer_value"]]"#;
+        let limit = short.len();
+
+        let TestFile {
+            file: _file,
+            path,
+            dir: _dir,
+        } = TestFile::new(&[0u8; 8]);
+        let rpath = RString::new(&path.display().to_string());
+
+        // Unlimited by default.
+        let obj: Obj<MmapedFile> = eval!("FastMmapedFileRs.new(path)", path = rpath).unwrap();
+        assert_eq!(None, obj.max_key_length());
+
+        let positions = RHash::new();
+        MmapedFile::upsert_entry(obj, positions, RString::new(short), 1.0).unwrap();
+        MmapedFile::upsert_entry(obj, positions, RString::new(long), 2.0).unwrap();
+        MmapedFile::munmap(obj).unwrap();
+
+        // A limit set at construction applies to that file's writes only.
+        let limited: Obj<MmapedFile> = eval!(
+            "FastMmapedFileRs.new(path, max_key_length: limit)",
+            path = rpath,
+            limit = limit
+        )
+        .unwrap();
+        assert_eq!(Some(limit), limited.max_key_length());
+
+        let positions = RHash::new();
+        MmapedFile::upsert_entry(limited, positions, RString::new(short), 1.0).unwrap();
+        let too_long = RString::new(&"x".repeat(limit + 1));
+        let err = MmapedFile::upsert_entry(limited, positions, too_long, 1.0).unwrap_err();
+        assert!(err.is_kind_of(arg_error()), "{err}");
+        MmapedFile::munmap(limited).unwrap();
+
+        let err = eval!(
+            "FastMmapedFileRs.new(path, max_key_length: 2**31)",
+            path = rpath
+        )
+        .map(|_: Value| ())
+        .unwrap_err();
+        assert!(err.is_kind_of(arg_error()), "{err}");
+
+        // On read the limit is a scrape option.
+        let list: RArray = eval!("[[path, :max, :gauge, 'worker-1']]", path = rpath).unwrap();
+        let out: String = eval!("FastMmapedFileRs.to_metrics(list)", list = list).unwrap();
+        assert!(out.contains("a_much_longer_value"), "{out}");
+
+        let err = eval!(
+            "FastMmapedFileRs.to_metrics(list, max_key_length: limit)",
+            list = list,
+            limit = limit
+        )
+        .map(|_: String| ())
+        .unwrap_err();
+        assert!(err.is_kind_of(arg_error()), "{err}");
+    }
+
+    #[test]
+    fn test_max_label_value_length() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let (unlimited, _file) = create_obj();
+        assert_eq!(None, unlimited.max_label_value_length());
+
+        let TestFile {
+            file: _file,
+            path,
+            dir: _dir,
+        } = TestFile::new(&[0u8; 8]);
+        let obj: Obj<MmapedFile> = eval!(
+            "FastMmapedFileRs.new(path, max_label_value_length: 8)",
+            path = RString::new(&path.display().to_string())
+        )
+        .unwrap();
+        assert_eq!(Some(8), obj.max_label_value_length());
+        let positions = RHash::new();
+
+        struct TestCase {
+            name: &'static str,
+            key: &'static str,
+            ok: bool,
+        }
+
+        let tc = vec![
+            TestCase {
+                name: "at limit",
+                key: r#"["family","name",["label_a","label_b"],["a","12345678"]]"#,
+                ok: true,
+            },
+            TestCase {
+                name: "escaped value at limit",
+                key: r#"["family","name",["label_a"],["\u00e9\u00e9\u00e9\u00e9"]]"#,
+                ok: true,
+            },
+            TestCase {
+                name: "numeric value at limit",
+                key: r#"["family","name",["label_a"],[12345678]]"#,
+                ok: true,
+            },
+            TestCase {
+                name: "over limit",
+                key: r#"["family","name",["label_a","label_b"],["a","123456789"]]"#,
+                ok: false,
+            },
+            TestCase {
+                name: "invalid entry",
+                key: "key_0001",
+                ok: false,
+            },
+        ];
+
+        for case in tc {
+            let name = case.name;
+            let result = MmapedFile::upsert_entry(obj, positions, RString::new(case.key), 1.0);
+            assert_eq!(case.ok, result.is_ok(), "test case: {name} - {result:?}");
+        }
+
+        let err = MmapedFile::fetch_entry(
+            obj,
+            positions,
+            RString::new(r#"["family","name",["label_a","label_b"],["a","123456789"]]"#),
+            1.0,
+        )
+        .unwrap_err();
+        assert!(err.is_kind_of(arg_error()), "{err}");
+        assert!(err.to_string().contains("label_b"), "{err}");
+
+        // The limit belongs to the file it was set on.
+        let key = RString::new(r#"["family","name",["label_a","label_b"],["a","123456789"]]"#);
+        assert!(MmapedFile::upsert_entry(unlimited, RHash::new(), key, 1.0).is_ok());
+    }
+
+    #[test]
+    fn test_repair() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let json = &[
+            r#"["family","name",["label_a"],["value_a"]]"#,
+            r#"["family","name",["label_a"],["value_b"]]"#,
+        ];
+        let valid = testhelper::entries_to_db(json, &[1.0, 2.0], None);
+
+        // A garbled key, then a valid entry that can no longer be trusted.
+        let corrupt = [
+            TestEntry::new("garbage!", 3.0).as_bytes(),
+            TestEntry::new(r#"["family","name",["label_a"],["value_c"]]"#, 4.0).as_bytes(),
+        ]
+        .concat();
+
+        let mut source = [valid.clone(), corrupt.clone()].concat();
+        source[..4].copy_from_slice(&(source.len() as u32).to_ne_bytes());
+
+        let file = TestFile::new(&source);
+        let rpath = RString::new(&file.path.display().to_string());
+
+        let discarded: usize = eval!("FastMmapedFileRs.repair(path)", path = rpath).unwrap();
+        assert_eq!(corrupt.len(), discarded);
+
+        let repaired = std::fs::read(&file.path).unwrap();
+        assert_eq!(valid, repaired, "used rewritten and trailing bytes removed");
+
+        let list: RArray = eval!("[[path, :max, :gauge, 'worker-1']]", path = rpath).unwrap();
+        let out = MmapedFile::to_metrics(&[list.as_value()]).unwrap();
+        assert!(out.contains("value_b"), "{out}");
+        assert!(!out.contains("value_c"), "{out}");
+
+        // A repaired file needs no further repair.
+        let discarded: usize = eval!("FastMmapedFileRs.repair(path)", path = rpath).unwrap();
+        assert_eq!(0, discarded);
+
+        // Unused space past `used` isn't counted as discarded, and a file
+        // with no corruption is left as it is.
+        let mut padded = valid.clone();
+        padded.resize(4096, 0);
+        let file = TestFile::new(&padded);
+        let rpath = RString::new(&file.path.display().to_string());
+
+        let discarded: usize = eval!("FastMmapedFileRs.repair(path)", path = rpath).unwrap();
+        assert_eq!(0, discarded);
+        assert_eq!(padded, std::fs::read(&file.path).unwrap(), "file untouched");
+
+        let mut padded = source.clone();
+        padded.resize(4096, 0);
+        let file = TestFile::new(&padded);
+        let rpath = RString::new(&file.path.display().to_string());
+
+        let discarded: usize = eval!("FastMmapedFileRs.repair(path)", path = rpath).unwrap();
+        assert_eq!(corrupt.len(), discarded);
+        assert_eq!(valid, std::fs::read(&file.path).unwrap());
+    }
+
+    #[test]
+    fn test_debug_dump() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let json = &[
+            r#"["family_a","name_a",["label_a"],["value_a"]]"#,
+            r#"["family_b","name_b",[],[]]"#,
+        ];
+        let data = testhelper::entries_to_db(json, &[1.5, 2.0], None);
+        let file = TestFile::new(&data);
+
+        let dump: RHash = eval!(
+            "FastMmapedFileRs.debug_dump(path)",
+            path = RString::new(&file.path.display().to_string())
+        )
+        .unwrap();
+
+        let used: usize = dump.aref(Symbol::new("used")).unwrap();
+        let capacity: usize = dump.aref(Symbol::new("capacity")).unwrap();
+        assert_eq!(data.len(), used);
+        assert_eq!(data.len(), capacity);
+
+        let entries: RArray = dump.aref(Symbol::new("entries")).unwrap();
+        assert_eq!(2, entries.len());
+
+        let first = RHash::from_value(entries.entry(0).unwrap()).unwrap();
+        let offset: usize = first.aref(Symbol::new("offset")).unwrap();
+        let key: String = first.aref(Symbol::new("key")).unwrap();
+        let value: f64 = first.aref(Symbol::new("value")).unwrap();
+        let exemplar: Value = first.aref(Symbol::new("exemplar")).unwrap();
+        assert_eq!(HEADER_SIZE, offset);
+        assert_eq!(json[0], key);
+        assert_eq!(1.5, value);
+        assert!(exemplar.is_nil());
+
+        let second = RHash::from_value(entries.entry(1).unwrap()).unwrap();
+        let offset: usize = second.aref(Symbol::new("offset")).unwrap();
+        let key: String = second.aref(Symbol::new("key")).unwrap();
+        let value: f64 = second.aref(Symbol::new("value")).unwrap();
+        assert_eq!(
+            HEADER_SIZE + RawEntry::calc_total_len(json[0].len()).unwrap(),
+            offset
+        );
+        assert_eq!(json[1], key);
+        assert_eq!(2.0, value);
+
+        // Exemplar files are recognized by name.
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("exemplar_all_0-0.db");
+        std::fs::write(&path, [0u8; HEADER_SIZE]).unwrap();
+        let path_str = RString::new(&path.display().to_string());
+
+        let obj: Obj<MmapedFile> = eval!("FastMmapedFileRs.new(path)", path = path_str).unwrap();
+        let positions = RHash::new();
+        MmapedFile::upsert_exemplar_at(
+            obj,
+            positions,
+            RString::new(json[0]),
+            3.0,
+            RString::new("trace_id"),
+            RString::new("abc"),
+            1_000_000_000,
+        )
+        .unwrap();
+        obj.sync(&[]).unwrap();
+
+        let dump: RHash = eval!("FastMmapedFileRs.debug_dump(path)", path = path_str).unwrap();
+        let entries: RArray = dump.aref(Symbol::new("entries")).unwrap();
+        assert_eq!(1, entries.len());
+
+        let entry = RHash::from_value(entries.entry(0).unwrap()).unwrap();
+        let key: String = entry.aref(Symbol::new("key")).unwrap();
+        let value: f64 = entry.aref(Symbol::new("value")).unwrap();
+        assert_eq!(json[0], key);
+        assert_eq!(3.0, value);
+
+        let exemplar: RHash = entry.aref(Symbol::new("exemplar")).unwrap();
+        let label_name: String = exemplar.aref(Symbol::new("label_name")).unwrap();
+        let label_value: String = exemplar.aref(Symbol::new("label_value")).unwrap();
+        assert_eq!("trace_id", label_name);
+        assert_eq!("abc", label_value);
+    }
+
+    #[test]
+    fn test_to_family_map() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let json = &[
+            r#"["first_family","first_name",["label_a"],["value_a"]]"#,
+            r#"["first_family","first_name",["label_a"],["value_b"]]"#,
+            r#"["second_family","second_name",["label_a","label_b"],["value_c",null]]"#,
+        ];
 
-    use crate::raw_entry::RawEntry;
-    use crate::testhelper::TestFile;
+        let file = TestFile::new(&testhelper::entries_to_db(json, &[1.0, 2.0, 3.0], None));
 
-    /// Create a wrapped MmapedFile object.
-    fn create_obj() -> Obj<MmapedFile> {
-        let TestFile {
-            file: _file,
-            path,
-            dir: _dir,
-        } = TestFile::new(&[0u8; 8]);
+        let list: RArray = eval!(
+            "[[a, :all, :gauge, 'worker-1']]",
+            a = RString::new(&file.path.display().to_string())
+        )
+        .unwrap();
 
-        let path_str = path.display().to_string();
-        let rpath = RString::new(&path_str);
+        let families = MmapedFile::to_family_map(list).unwrap();
+        assert_eq!(2, families.len());
 
-        eval!("FastMmapedFileRs.new(path)", path = rpath).unwrap()
-    }
+        let series = |family: &str, i: isize| -> RHash {
+            let list: RArray = families.lookup(family).unwrap();
+            list.entry(i).unwrap()
+        };
 
-    /// Add three entries to the mmap. Expected length is 56, 3x 16-byte
-    /// entries with 8-byte header.
-    fn populate_entries(rb_self: &Obj<MmapedFile>) -> RHash {
-        let positions = RHash::from_value(eval("{}").unwrap()).unwrap();
+        let first: RArray = families.lookup("first_family").unwrap();
+        assert_eq!(2, first.len());
+
+        let second = series("first_family", 1);
+        let name: String = second.lookup(Symbol::new("name")).unwrap();
+        let value: f64 = second.lookup(Symbol::new("value")).unwrap();
+        let pid: String = second.lookup(Symbol::new("pid")).unwrap();
+        let labels: RHash = second.lookup(Symbol::new("labels")).unwrap();
+        let label_a: String = labels.lookup("label_a").unwrap();
+        assert_eq!("first_name", name);
+        assert_eq!(2.0, value);
+        assert_eq!("worker-1", pid);
+        assert_eq!("value_b", label_a);
+
+        let labels: RHash = series("second_family", 0)
+            .lookup(Symbol::new("labels"))
+            .unwrap();
+        let label_a: String = labels.lookup("label_a").unwrap();
+        let label_b: String = labels.lookup("label_b").unwrap();
+        assert_eq!("value_c", label_a);
+        assert_eq!("", label_b, "null label value");
+    }
 
-        MmapedFile::upsert_entry(*rb_self, positions, RString::new("a"), 0.0).unwrap();
-        MmapedFile::upsert_entry(*rb_self, positions, RString::new("b"), 1.0).unwrap();
-        MmapedFile::upsert_entry(*rb_self, positions, RString::new("c"), 2.0).unwrap();
+    #[test]
+    fn test_delta() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
 
-        positions
+        let json = &[
+            r#"["requests","requests",["code"],["200"]]"#,
+            r#"["requests","requests",["code"],["500"]]"#,
+            r#"["requests","requests",["code"],["404"]]"#,
+            r#"["inflight","inflight",[],[]]"#,
+        ];
+
+        // The old snapshot has no 404s.
+        let old_values = [5.0, 7.0];
+        let new_values = [8.0, 3.0, 1.0];
+
+        let files = [
+            TestFile::new(&testhelper::entries_to_db(&json[..2], &old_values, None)),
+            TestFile::new(&testhelper::entries_to_db(&json[3..], &[2.0], None)),
+            TestFile::new(&testhelper::entries_to_db(&json[..3], &new_values, None)),
+            TestFile::new(&testhelper::entries_to_db(&json[3..], &[4.0], None)),
+        ];
+        let path = |i: usize| RString::new(&files[i].path.display().to_string());
+
+        let lists: RArray = eval!(
+            r#"[
+                [[a, :all, :counter, "worker-1"], [b, :all, :gauge, "worker-1"]],
+                [[c, :all, :counter, "worker-1"], [d, :all, :gauge, "worker-1"]],
+            ]"#,
+            a = path(0),
+            b = path(1),
+            c = path(2),
+            d = path(3)
+        )
+        .unwrap();
+        let old_list: RArray = lists.entry(0).unwrap();
+        let new_list: RArray = lists.entry(1).unwrap();
+
+        let series = MmapedFile::delta(old_list, new_list).unwrap();
+        let actual: Vec<(Option<String>, Option<f64>, f64, bool)> = series
+            .each()
+            .map(|s| {
+                let s = RHash::from_value(s.unwrap()).unwrap();
+                let labels: RHash = s.lookup(Symbol::new("labels")).unwrap();
+                (
+                    labels.lookup("code").unwrap(),
+                    s.lookup(Symbol::new("old")).unwrap(),
+                    s.lookup(Symbol::new("delta")).unwrap(),
+                    s.lookup(Symbol::new("reset")).unwrap(),
+                )
+            })
+            .collect();
+
+        // Gauges are ignored.
+        let expected = vec![
+            // Increased.
+            (Some("200".to_string()), Some(5.0), 3.0, false),
+            // New series.
+            (Some("404".to_string()), None, 1.0, false),
+            // Reset, the new value is the delta.
+            (Some("500".to_string()), Some(7.0), 3.0, true),
+        ];
+        assert_eq!(expected, actual);
+
+        // Series only in the old snapshot are omitted.
+        let series = MmapedFile::delta(new_list, old_list).unwrap();
+        assert_eq!(2, series.len());
     }
 
     #[test]
-    fn test_new() {
+    fn test_top_series() {
         let _cleanup = unsafe { magnus::embed::init() };
         let ruby = magnus::Ruby::get().unwrap();
         crate::init(&ruby).unwrap();
 
-        let TestFile {
-            file,
-            path,
-            dir: _dir,
-        } = TestFile::new(&[0u8; 8]);
+        let json = &[
+            r#"["requests","requests",["code"],["200"]]"#,
+            r#"["requests","requests",["code"],["404"]]"#,
+            r#"["requests","requests",["code"],["500"]]"#,
+            r#"["errors","errors",[],[]]"#,
+        ];
+        let values = [5.0, 1.0, f64::NAN, 3.0];
+
+        let files = [
+            TestFile::new(&testhelper::entries_to_db(json, &values, None)),
+            TestFile::new(&testhelper::entries_to_db(json, &values, None)),
+        ];
+        let path = |i: usize| RString::new(&files[i].path.display().to_string());
+
+        let list: RArray = eval!(
+            "[[a, :all, :counter, 'worker-1'], [b, :all, :counter, 'worker-2']]",
+            a = path(0),
+            b = path(1)
+        )
+        .unwrap();
+
+        let top = |n: usize| -> Vec<(String, Option<String>, f64)> {
+            let series = MmapedFile::top_series(list, n).unwrap();
+            series
+                .each()
+                .map(|s| {
+                    let s = RHash::from_value(s.unwrap()).unwrap();
+                    let labels: RHash = s.lookup(Symbol::new("labels")).unwrap();
+                    (
+                        s.lookup(Symbol::new("name")).unwrap(),
+                        labels.lookup("code").unwrap(),
+                        s.lookup(Symbol::new("value")).unwrap(),
+                    )
+                })
+                .collect()
+        };
 
-        let path_str = path.display().to_string();
-        let rpath = RString::new(&path_str);
+        // Counters are summed across files.
+        let expected = vec![
+            ("requests".to_string(), Some("200".to_string()), 10.0),
+            ("errors".to_string(), None, 6.0),
+            ("requests".to_string(), Some("404".to_string()), 2.0),
+        ];
+        assert_eq!(expected[..1], top(1));
+        assert_eq!(expected[..2], top(2));
+        assert_eq!(expected, top(10), "NaN skipped");
+        assert!(top(0).is_empty());
+    }
 
-        // Object created successfully
-        let result: std::result::Result<Obj<MmapedFile>, Error> =
-            eval!("FastMmapedFileRs.new(path)", path = rpath);
-        assert!(result.is_ok());
+    #[test]
+    fn test_cardinality() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
 
-        // Weak map added
-        let obj = result.unwrap();
-        let weak_tracker: Value = obj.ivar_get("@weak_obj_tracker").unwrap();
-        assert_eq!("ObjectSpace::WeakMap", weak_tracker.class().inspect());
+        let counter = &[
+            r#"["requests","requests",["code","method"],["200","get"]]"#,
+            r#"["requests","requests",["code","method"],["200","post"]]"#,
+            r#"["requests","requests",["code","method"],["500","get"]]"#,
+            r#"["requests","requests",["code","method"],["404","get"]]"#,
+        ];
+        let gauge = &[
+            r#"["memory","memory",[],[]]"#,
+            r#"["connections","connections",["pool"],["primary"]]"#,
+            r#"["connections","connections",["pool"],["replica"]]"#,
+        ];
+
+        let files = [
+            TestFile::new(&testhelper::entries_to_db(counter, &[1.0; 4], None)),
+            TestFile::new(&testhelper::entries_to_db(counter, &[1.0; 4], None)),
+            TestFile::new(&testhelper::entries_to_db(gauge, &[1.0; 3], None)),
+            TestFile::new(&testhelper::entries_to_db(gauge, &[1.0; 3], None)),
+        ];
+        let path = |i: usize| RString::new(&files[i].path.display().to_string());
+
+        let file_list: RArray = eval!(
+            r#"[
+                [a, :all, :counter, "worker-1"],
+                [b, :all, :counter, "worker-2"],
+                [c, :all, :gauge, "worker-1"],
+                [d, :all, :gauge, "worker-2"],
+            ]"#,
+            a = path(0),
+            b = path(1),
+            c = path(2),
+            d = path(3)
+        )
+        .unwrap();
+
+        let cardinality = MmapedFile::cardinality(file_list).unwrap();
+
+        // Counters merge across pids, `all` gauges are kept per-pid.
+        let expected: RHash =
+            eval(r#"{ "connections" => 4, "memory" => 2, "requests" => 4 }"#).unwrap();
+        assert!(
+            cardinality.eql(expected).unwrap(),
+            "{}",
+            cardinality.inspect()
+        );
 
-        // File expanded to page size
-        let page_size = sysconf(SysconfVar::PAGE_SIZE).unwrap().unwrap() as u64;
-        let stat = file.metadata().unwrap();
-        assert_eq!(page_size, stat.len());
+        assert!(MmapedFile::cardinality(RArray::new()).unwrap().is_empty());
+    }
 
-        // Used set to header size
+    #[test]
+    fn test_select() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let json = &[
+            r#"["requests","requests",["code","method"],["200","get"]]"#,
+            r#"["requests","requests",["code","method"],["201","post"]]"#,
+            r#"["requests","requests",["code","method"],["500","get"]]"#,
+            r#"["errors","errors",[],[]]"#,
+        ];
+        let values = [1.0, 2.0, 3.0, 4.0];
+        let file = TestFile::new(&testhelper::entries_to_db(json, &values, None));
+
+        let list: RArray = eval!(
+            "[[a, :all, :counter, 'worker-1']]",
+            a = RString::new(&file.path.display().to_string())
+        )
+        .unwrap();
+
+        let select = |matchers: &str| -> Vec<f64> {
+            let matchers: RArray = eval(matchers).unwrap();
+            let series = MmapedFile::select(list, matchers).unwrap();
+            let mut values: Vec<f64> = series
+                .each()
+                .map(|s| {
+                    let s = RHash::from_value(s.unwrap()).unwrap();
+                    s.lookup(Symbol::new("value")).unwrap()
+                })
+                .collect();
+            values.sort_by(f64::total_cmp);
+            values
+        };
+
+        assert_eq!(values.to_vec(), select("[]"));
         assert_eq!(
-            HEADER_SIZE as u64,
-            obj.load_used().unwrap().to_u64().unwrap()
+            vec![1.0, 3.0],
+            select("[{ label: 'method', op: :eq, value: 'get' }]")
+        );
+        assert_eq!(
+            vec![2.0, 4.0],
+            select("[{ label: 'method', op: :neq, value: 'get' }]")
+        );
+        assert_eq!(
+            vec![1.0, 2.0],
+            select("[{ label: 'code', op: :regex, value: '2..' }]")
+        );
+        assert_eq!(
+            vec![1.0],
+            select(
+                "[{ label: 'code', op: 'regex', value: '2..' }, \
+                  { label: 'method', op: :eq, value: 'get' }]"
+            )
+        );
+        assert_eq!(
+            vec![4.0],
+            select("[{ label: '__name__', op: :eq, value: 'errors' }]")
         );
+
+        let invalid = [
+            "[{ label: 'code', op: :like, value: '2%' }]",
+            "[{ label: 'code', op: :regex, value: '(' }]",
+            "[{ label: 'code', value: '200' }]",
+            "['code=200']",
+        ];
+        for matchers in invalid {
+            let matchers: RArray = eval(matchers).unwrap();
+            assert!(MmapedFile::select(list, matchers).is_err(), "{matchers:?}");
+        }
     }
 
     #[test]
-    fn test_slice() {
+    fn test_zero_value_keys() {
         let _cleanup = unsafe { magnus::embed::init() };
         let ruby = magnus::Ruby::get().unwrap();
         crate::init(&ruby).unwrap();
 
-        let obj = create_obj();
-        let _ = populate_entries(&obj);
-
-        // Validate header updated with new length
-        let header_range = Range::new(0, HEADER_SIZE, true).unwrap().as_value();
-        let header_slice = MmapedFile::slice(obj, &[header_range]).unwrap();
-        assert_eq!([56, 0, 0, 0, 0, 0, 0, 0], unsafe {
-            header_slice.as_slice()
-        });
+        let json = &[
+            r#"["family","name",["label_a"],["value_a"]]"#,
+            r#"["family","name",["label_a"],["value_b"]]"#,
+            r#"["family","name",["label_a"],["value_c"]]"#,
+        ];
 
-        let value_range = Range::new(HEADER_SIZE, 24, true).unwrap().as_value();
-        let value_slice = MmapedFile::slice(obj, &[value_range]).unwrap();
+        let first = TestFile::new(&testhelper::entries_to_db(json, &[0.0, 1.0, 0.0], None));
+        let second = TestFile::new(&testhelper::entries_to_db(json, &[0.0, 0.0, 2.0], None));
 
-        // Validate string length
-        assert_eq!(1u32.to_ne_bytes(), unsafe { &value_slice.as_slice()[0..4] });
+        let list: RArray = eval!(
+            "[[a, :all, :counter, 'worker-1'], [b, :all, :counter, 'worker-2']]",
+            a = RString::new(&first.path.display().to_string()),
+            b = RString::new(&second.path.display().to_string())
+        )
+        .unwrap();
 
-        // Validate string and padding
-        assert_eq!("a   ", unsafe {
-            String::from_utf8_lossy(&value_slice.as_slice()[4..8])
-        });
+        let keys = MmapedFile::zero_value_keys(list).unwrap();
+        let keys: Vec<String> = keys.to_vec().unwrap();
 
-        // Validate value
-        assert_eq!(0.0f64.to_ne_bytes(), unsafe {
-            &value_slice.as_slice()[8..16]
-        });
+        // Only `value_a` is zero once merged across both files.
+        assert_eq!(vec![json[0].to_string()], keys);
     }
 
     #[test]
-    fn test_slice_resize() {
+    fn test_active_pids() {
         let _cleanup = unsafe { magnus::embed::init() };
         let ruby = magnus::Ruby::get().unwrap();
         crate::init(&ruby).unwrap();
 
-        fn assert_internals(
-            obj: Obj<MmapedFile>,
-            parent_id: c_ulong,
-            child_id: c_ulong,
-            unshared_id: c_ulong,
-        ) {
-            let rs_self = &*obj;
-            let tracker: Value = obj.ivar_get("@weak_obj_tracker").unwrap();
+        let file_list: RArray = eval(
+            r#"[
+                ["gauge_all_worker-1-0.db", :all, :gauge, "worker-1"],
+                ["counter_worker-1-0.db", :all, :counter, "worker-1"],
+                ["gauge_all_worker-2-0.db", :all, :gauge, "worker-2"],
+            ]"#,
+        )
+        .unwrap();
 
-            let mmap_ptr = rs_self.as_mut_ptr();
-            let mmap_len = rs_self.capacity();
+        let pids = MmapedFile::active_pids(file_list).unwrap();
+        assert_eq!(
+            vec!["worker-1".to_string(), "worker-2".to_string()],
+            pids.to_vec::<String>().unwrap()
+        );
+    }
 
-            let mut parent_checked = false;
-            let mut child_checked = false;
+    #[test]
+    fn test_to_metrics_by_pid() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
 
-            for val in tracker.enumeratorize("each_value", ()) {
-                let rb_string = val.unwrap();
-                let str = RString::from_value(rb_string).unwrap();
+        let gauge = &[r#"["gauge","gauge",[],[]]"#];
+        let counter = &[r#"["counter","counter",[],[]]"#];
+
+        let files = [
+            TestFile::new(&testhelper::entries_to_db(gauge, &[1.0], None)),
+            TestFile::new(&testhelper::entries_to_db(counter, &[2.0], None)),
+            TestFile::new(&testhelper::entries_to_db(gauge, &[3.0], None)),
+            TestFile::new(&testhelper::entries_to_db(counter, &[4.0], None)),
+        ];
+        let path = |i: usize| RString::new(&files[i].path.display().to_string());
+
+        let file_list: RArray = eval!(
+            r#"[
+                [a, :max, :gauge, "worker-2"],
+                [b, :all, :counter, "worker-2"],
+                [c, :max, :gauge, "worker-1"],
+                [d, :all, :counter, "worker-1"],
+            ]"#,
+            a = path(0),
+            b = path(1),
+            c = path(2),
+            d = path(3)
+        )
+        .unwrap();
+
+        let by_pid: RHash =
+            eval!("FastMmapedFileRs.to_metrics_by_pid(list)", list = file_list).unwrap();
+
+        let pids: Vec<String> = eval!("h.keys", h = by_pid).unwrap();
+        assert_eq!(vec!["worker-2", "worker-1"], pids, "ordered as first seen");
+
+        let worker_1: String = by_pid.fetch("worker-1").unwrap();
+        assert_eq!(
+            indoc! {r##"# HELP counter Multiprocess metric
+                # TYPE counter counter
+                counter 4
+                # HELP gauge Multiprocess metric
+                # TYPE gauge gauge
+                gauge 3
+                "##},
+            worker_1
+        );
 
-                unsafe {
-                    let raw_str = MmapedFile::rb_string_internal(str);
-                    if str.as_raw() == child_id {
-                        assert_eq!(parent_id, raw_str.as_ref().as_.heap.aux.shared);
+        let worker_2: String = by_pid.fetch("worker-2").unwrap();
+        assert_eq!(
+            indoc! {r##"# HELP counter Multiprocess metric
+                # TYPE counter counter
+                counter 2
+                # HELP gauge Multiprocess metric
+                # TYPE gauge gauge
+                gauge 1
+                "##},
+            worker_2
+        );
+    }
 
-                        let child_offset = mmap_len as isize - str.len() as isize;
-                        assert_eq!(mmap_ptr.offset(child_offset), raw_str.as_ref().as_.heap.ptr);
+    #[test]
+    fn test_series_breakdown() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
 
-                        child_checked = true;
-                    } else if str.as_raw() == parent_id {
-                        assert_eq!(parent_id, str.as_raw());
+        let json = &[
+            r#"["gauge","gauge_name",["label_a","label_b"],["value_a","value_b"]]"#,
+            r#"["gauge","gauge_name",["label_a","label_b"],["other","value_b"]]"#,
+        ];
+
+        let files = [
+            TestFile::new(&testhelper::entries_to_db(json, &[1.0, 10.0], None)),
+            TestFile::new(&testhelper::entries_to_db(json, &[2.0, 20.0], None)),
+            TestFile::new(&testhelper::entries_to_db(&json[..1], &[4.0], None)),
+        ];
+        let path = |i: usize| RString::new(&files[i].path.display().to_string());
+
+        let file_list: RArray = eval!(
+            r#"[
+                [a, :all, :gauge, "worker-1"],
+                [b, :all, :gauge, "worker-2"],
+                [c, :all, :gauge, "worker-3"],
+            ]"#,
+            a = path(0),
+            b = path(1),
+            c = path(2)
+        )
+        .unwrap();
+
+        let breakdown: RHash = eval!(
+            r#"FastMmapedFileRs.series_breakdown(
+                list, "gauge", "gauge_name", { "label_b" => "value_b", "label_a" => "value_a" }
+            )"#,
+            list = file_list
+        )
+        .unwrap();
+
+        let pids: HashMap<String, f64> = breakdown.fetch(Symbol::new("pids")).unwrap();
+        let expected = HashMap::from([
+            ("worker-1".to_string(), 1.0),
+            ("worker-2".to_string(), 2.0),
+            ("worker-3".to_string(), 4.0),
+        ]);
+        assert_eq!(expected, pids);
+
+        // Gauges in `all` mode keep the value merged last.
+        let value: f64 = breakdown.fetch(Symbol::new("value")).unwrap();
+        assert_eq!(4.0, value);
+
+        // A wrong label value, a subset of the labels, and a missing family.
+        let missing: Vec<Option<RHash>> = eval!(
+            r#"[
+                ["gauge", { "label_a" => "nope", "label_b" => "value_b" }],
+                ["gauge", { "label_a" => "value_a" }],
+                ["missing", {}],
+            ].map do |family, labels|
+                FastMmapedFileRs.series_breakdown(list, family, "gauge_name", labels)
+            end"#,
+            list = file_list
+        )
+        .unwrap();
+        assert!(missing.iter().all(Option::is_none));
+    }
 
-                        assert_eq!(mmap_ptr, raw_str.as_ref().as_.heap.ptr);
-                        assert_eq!(mmap_len as c_long, str.len() as c_long);
-                        assert!(raw_str.as_ref().basic.flags & (STR_SHARED | STR_NOEMBED) > 0);
-                        assert!(str.is_frozen());
+    #[test]
+    fn test_to_metrics_with_ranges() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
 
-                        parent_checked = true;
-                    } else if str.as_raw() == unshared_id {
-                        panic!("tracking unshared string");
-                    } else {
-                        panic!("unknown string");
-                    }
-                }
-            }
-            assert!(parent_checked && child_checked);
-        }
+        let json = &[
+            r#"["counter","counter",[],[]]"#,
+            r#"["gauge","gauge",["label_a"],["value_a"]]"#,
+            r#"["gauge","gauge",["label_a"],["value_b"]]"#,
+        ];
+        let file = TestFile::new(&testhelper::entries_to_db(json, &[1.0, 2.0, 3.0], None));
+
+        let file_list: RArray = eval!(
+            "[[a, :all, :counter, 'worker-1']]",
+            a = RString::new(&file.path.display().to_string())
+        )
+        .unwrap();
+
+        let result = MmapedFile::to_metrics_with_ranges(file_list).unwrap();
+
+        let gauge: String = eval!(
+            "out, ranges = result; out.byteslice(*ranges.fetch('gauge'))",
+            result = result
+        )
+        .unwrap();
+        assert_eq!(
+            indoc! {r##"# HELP gauge Multiprocess metric
+                # TYPE gauge counter
+                gauge{label_a="value_a"} 2
+                gauge{label_a="value_b"} 3
+                "##},
+            gauge
+        );
 
-        let obj = create_obj();
-        let _ = populate_entries(&obj);
+        let counter: String = eval!(
+            "out, ranges = result; out.byteslice(*ranges.fetch('counter'))",
+            result = result
+        )
+        .unwrap();
+        assert_eq!(
+            "# HELP counter Multiprocess metric\n# TYPE counter counter\ncounter 1\n",
+            counter
+        );
 
-        let rs_self = &*obj;
+        // The text is the same as rendered by `to_metrics`.
+        let out: String = result.entry(0).unwrap();
+        let expected = MmapedFile::to_metrics(&[file_list.as_value()]).unwrap();
+        assert_eq!(expected, out);
+    }
 
-        // Create a string containing the full mmap.
-        let parent_str = rs_self.str(obj).unwrap();
-        let parent_id = parent_str.as_raw();
+    #[test]
+    fn test_to_metrics_with_extra() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
 
-        // Ruby's shared strings are only created when they go to the end of
-        // original string.
-        let len = rs_self.inner(|inner| Ok(inner.len())).unwrap();
-        let shareable_range = Range::new(1, len - 1, false).unwrap().as_value();
+        let json = &[r#"["counter","counter",[],[]]"#];
+        let file = TestFile::new(&testhelper::entries_to_db(json, &[1.0], None));
 
-        // This string should re-use the parent's buffer with an offset and have
-        // the parent's id in `as.heap.aux.shared`
-        let child_str = rs_self._slice(obj, parent_str, &[shareable_range]).unwrap();
-        let child_id = child_str.as_raw();
+        let file_list: RArray = eval!(
+            "[[a, :all, :counter, 'worker-1']]",
+            a = RString::new(&file.path.display().to_string())
+        )
+        .unwrap();
 
-        // A range that does not reach the end of the parent will not be shared.
-        assert!(len > 4);
-        let unshareable_range = Range::new(0, 4, false).unwrap().as_value();
+        // Line endings are normalized, with exactly one trailing newline.
+        let extra =
+            "# HELP queue_depth External gauge\r\n# TYPE queue_depth gauge\r\nqueue_depth 7\r\n\r\n";
+        let out = MmapedFile::to_metrics_with_extra(file_list, extra.to_string()).unwrap();
+        assert_eq!(
+            indoc! {r##"# HELP counter Multiprocess metric
+                # TYPE counter counter
+                counter 1
+                # HELP queue_depth External gauge
+                # TYPE queue_depth gauge
+                queue_depth 7
+                "##},
+            out
+        );
+        assert!(!out.contains('\r'), "{out:?}");
 
-        // This string should NOT be tracked, it should own its own buffer.
-        let unshared_str = rs_self
-            ._slice(obj, parent_str, &[unshareable_range])
-            .unwrap();
-        let unshared_id = unshared_str.as_raw();
-        assert!(!MmapedFile::rb_string_is_shared(unshared_str));
+        // Missing trailing newline.
+        let out =
+            MmapedFile::to_metrics_with_extra(file_list, "queue_depth 7".to_string()).unwrap();
+        assert!(out.ends_with("counter 1\nqueue_depth 7\n"), "{out}");
 
-        assert_internals(obj, parent_id, child_id, unshared_id);
+        // Empty extra text is a no-op.
+        assert_eq!(
+            MmapedFile::to_metrics(&[file_list.as_value()]).unwrap(),
+            MmapedFile::to_metrics_with_extra(file_list, String::new()).unwrap()
+        );
 
-        let orig_ptr = rs_self.as_mut_ptr();
-        // Expand a bunch to ensure we remap
-        for _ in 0..16 {
-            rs_self.expand_to_fit(obj, rs_self.capacity() * 2).unwrap();
+        let conflicts = [
+            "# TYPE counter gauge\ncounter 2\n",
+            "# TYPE other gauge\n# TYPE other gauge\nother 2\n",
+        ];
+        for extra in conflicts {
+            let err = MmapedFile::to_metrics_with_extra(file_list, extra.to_string()).unwrap_err();
+            assert!(err.is_kind_of(arg_error()), "{err}");
+            assert!(err.to_string().contains("duplicate # TYPE"), "{err}");
         }
-        let new_ptr = rs_self.as_mut_ptr();
-        assert!(orig_ptr != new_ptr);
+    }
 
-        // If we haven't updated the pointer to the newly remapped file this will segfault.
-        let _: Value = eval!("puts parent", parent = parent_str).unwrap();
-        let _: Value = eval!("puts child", child = child_str).unwrap();
-        let _: Value = eval!("puts unshared", unshared = unshared_str).unwrap();
+    #[test]
+    fn test_stats() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
 
-        // Confirm that tracked strings are still valid.
-        assert_internals(obj, parent_id, child_id, unshared_id);
+        let counter = &[
+            r#"["requests","requests",["code"],["200"]]"#,
+            r#"["requests","requests",["code"],["500"]]"#,
+        ];
+        let gauge = &[r#"["memory","memory",[],[]]"#];
+        let histogram = &[
+            r#"["latency","latency_bucket",["le"],["0.5"]]"#,
+            r#"["latency","latency_bucket",["le"],["+Inf"]]"#,
+            r#"["latency","latency_count",[],[]]"#,
+            r#"["latency","latency_sum",[],[]]"#,
+        ];
+
+        let files = [
+            TestFile::new(&testhelper::entries_to_db(counter, &[1.0, 2.0], None)),
+            TestFile::new(&testhelper::entries_to_db(counter, &[3.0, 4.0], None)),
+            TestFile::new(&testhelper::entries_to_db(gauge, &[5.0], None)),
+            TestFile::new(&testhelper::entries_to_db(
+                histogram,
+                &[1.0, 2.0, 2.0, 1.5],
+                None,
+            )),
+        ];
+        let path = |i: usize| RString::new(&files[i].path.display().to_string());
+
+        let file_list: RArray = eval!(
+            r#"[
+                [a, :all, :counter, "worker-1"],
+                [b, :all, :counter, "worker-2"],
+                [c, :max, :gauge, "worker-1"],
+                [d, :all, :histogram, "worker-1"],
+            ]"#,
+            a = path(0),
+            b = path(1),
+            c = path(2),
+            d = path(3)
+        )
+        .unwrap();
+
+        let stats = MmapedFile::stats(file_list).unwrap();
+        let expected: RHash = eval("{ counter: 2, gauge: 1, histogram: 4 }").unwrap();
+        assert!(stats.eql(expected).unwrap(), "{}", stats.inspect());
+
+        let empty = MmapedFile::stats(RArray::new()).unwrap();
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn test_prewarm() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let json = &[r#"["family","name",[],[]]"#];
+        let data = testhelper::entries_to_db(json, &[1.0], None);
+        let a = TestFile::new(&data);
+        let b = TestFile::new(&data);
+        let empty = TestFile::new(&[]);
+
+        let file_list: RArray = eval!(
+            "[[a, :all, :gauge, 'worker-1'], [b, :all, :counter, 'worker-1'], [c, :all, :gauge, 'worker-2']]",
+            a = RString::new(&a.path.display().to_string()),
+            b = RString::new(&b.path.display().to_string()),
+            c = RString::new(&empty.path.display().to_string())
+        )
+        .unwrap();
+
+        let touched: usize = eval!("FastMmapedFileRs.prewarm(list)", list = file_list).unwrap();
+        assert_eq!(2 * data.len(), touched);
+
+        let missing: RArray = eval("[['/nonexistent.db', :all, :gauge, 'worker-1']]").unwrap();
+        assert!(MmapedFile::prewarm(missing).is_err());
     }
 
     #[test]
@@ -950,7 +5141,7 @@ mod test {
         let ruby = magnus::Ruby::get().unwrap();
         crate::init(&ruby).unwrap();
 
-        let obj = create_obj();
+        let (obj, _file) = create_obj();
         let positions = populate_entries(&obj);
 
         let rs_self = &*obj;
@@ -974,4 +5165,88 @@ mod test {
         // Validate that we have expanded the mmap, ensuring a trailing NUL.
         assert!(rs_self.capacity() > current_cap);
     }
+
+    #[test]
+    fn test_fill_mmap_to_slack() {
+        let _cleanup = unsafe { magnus::embed::init() };
+        let ruby = magnus::Ruby::get().unwrap();
+        crate::init(&ruby).unwrap();
+
+        let (obj, _file) = create_obj();
+        let positions = populate_entries(&obj);
+
+        let rs_self = &*obj;
+
+        rs_self.expand_to_fit(obj, 1024).unwrap();
+
+        let current_used = rs_self.inner(|inner| inner.load_used()).unwrap() as usize;
+        let current_cap = rs_self.inner(|inner| Ok(inner.len())).unwrap();
+
+        // Create a new entry leaving exactly `TRAILING_SLACK` bytes free. Keys
+        // are padded to 8-byte alignment, so pick the largest key that does.
+        let mut val_len =
+            current_cap - current_used - HEADER_SIZE - size_of::<f64>() - size_of::<u32>();
+        while RawEntry::calc_total_len(val_len).unwrap() + current_used + TRAILING_SLACK
+            > current_cap
+        {
+            val_len -= 1;
+        }
+        let entry_len = RawEntry::calc_total_len(val_len).unwrap();
+        assert!(
+            MmapedFile::has_room(current_cap, current_used, entry_len, TRAILING_SLACK).unwrap()
+        );
+
+        let str = String::from_utf8(vec![b'A'; val_len]).unwrap();
+        MmapedFile::upsert_entry(obj, positions, RString::new(&str), 1.0).unwrap();
+
+        // The entry fit, leaving room for the trailing NUL.
+        assert_eq!(current_cap, rs_self.capacity());
+        let used = rs_self.inner(|inner| inner.load_used()).unwrap() as usize;
+        assert!(used + TRAILING_SLACK <= rs_self.capacity());
+    }
+
+    #[test]
+    fn test_has_room() {
+        struct TestCase {
+            slack: usize,
+            entry_len: usize,
+            expected: bool,
+        }
+
+        // A capacity of 64 with 16 bytes used.
+        let tc = vec![
+            TestCase {
+                slack: 1,
+                entry_len: 47,
+                expected: true,
+            },
+            TestCase {
+                slack: 1,
+                entry_len: 48,
+                expected: false,
+            },
+            TestCase {
+                slack: 8,
+                entry_len: 40,
+                expected: true,
+            },
+            TestCase {
+                slack: 8,
+                entry_len: 41,
+                expected: false,
+            },
+        ];
+
+        for case in tc {
+            assert_eq!(
+                case.expected,
+                MmapedFile::has_room(64, 16, case.entry_len, case.slack).unwrap(),
+                "slack {}, entry_len {}",
+                case.slack,
+                case.entry_len
+            );
+        }
+
+        assert!(MmapedFile::has_room(64, usize::MAX, 1, 1).is_err());
+    }
 }
@@ -1,21 +1,60 @@
 use libc::off_t;
-use memmap2::{MmapMut, MmapOptions};
+use memmap2::{Mmap, MmapMut, MmapOptions};
 use nix::libc::c_long;
 use std::fs::File;
-use std::mem::size_of;
+use std::mem::{align_of, size_of};
 use std::ops::Range;
 use std::os::unix::prelude::{AsRawFd, RawFd};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use crate::error::{MmapError, RubyError};
-use crate::raw_entry::RawEntry;
-use crate::exemplars::{Exemplar, EXEMPLAR_ENTRY_MAX_SIZE_BYTES};
+use crate::map::EntryMap;
+use crate::raw_entry::{HeaderFields, Layout, RawEntry};
+use crate::exemplars::{Exemplar, ExemplarFormat, EXEMPLAR_ENTRY_MAX_SIZE_BYTES};
 
-use crate::util::{read_exemplar, CheckedOps};
+use crate::util::{read_exemplar, write_exemplar, CheckedOps};
 use crate::util::{self, errno, read_f64, read_u32};
 use crate::Result;
 use crate::HEADER_SIZE;
-use std::iter;
+
+/// The distance between the bytes written when prefaulting. No larger than
+/// the page size of any supported platform, so every page is written.
+const PREFAULT_STRIDE: usize = 4096;
+
+/// The mapping of an `InnerMmap`, writable unless the file was opened
+/// read-only.
+#[derive(Debug)]
+enum Map {
+    ReadWrite(MmapMut),
+    ReadOnly(Mmap),
+}
+
+impl Map {
+    fn bytes(&self) -> &[u8] {
+        match self {
+            Map::ReadWrite(map) => map.as_ref(),
+            Map::ReadOnly(map) => map.as_ref(),
+        }
+    }
+
+    /// Borrow the mapping mutably, failing if it is read-only. `path` is
+    /// used only in the error.
+    fn bytes_mut(&mut self, path: &Path) -> Result<&mut [u8]> {
+        match self {
+            Map::ReadWrite(map) => Ok(map.as_mut()),
+            Map::ReadOnly(_) => Err(MmapError::ReadOnly(path.display().to_string())),
+        }
+    }
+
+    fn as_ptr(&self) -> *const u8 {
+        self.bytes().as_ptr()
+    }
+
+    fn len(&self) -> usize {
+        self.bytes().len()
+    }
+}
 
 /// A mmapped file and its metadata. Ruby never directly interfaces
 /// with this struct.
@@ -30,7 +69,7 @@ pub(super) struct InnerMmap {
     path: PathBuf,
     /// The mmap itself. When initializing a new entry the length of
     /// the mmap is used for bounds checking.
-    map: MmapMut,
+    map: Map,
     /// The length of data written to the file, used to validate
     /// whether a `load/save_value` call is in bounds and the length
     /// we truncate the file to when unmapping.
@@ -44,15 +83,20 @@ impl InnerMmap {
     /// Use when mmapping a file for the first time. When re-mapping a file
     /// after expanding it the `reestablish` function should be used.
     pub fn new(path: PathBuf, file: File) -> Result<Self> {
-        let stat = file.metadata().map_err(|e| {
-            MmapError::legacy(
-                format!("Can't stat {}: {e}", path.display()),
-                RubyError::Arg,
-            )
-        })?;
-
-        let file_size = util::cast_chk::<_, usize>(stat.len(), "file length")?;
+        let file_size = Self::file_len(&path, &file)?;
+        Self::with_len(path, file, file_size)
+    }
 
+    /// Constructs a new `InnerMmap` from a `file_size` obtained from an
+    /// earlier `file_len` call.
+    ///
+    /// `file_size` is the single source of truth for the file's length: it
+    /// is used for both the mapping and `len`, and the file isn't stat'd
+    /// again. If a worker expands the file afterwards the mapping covers only
+    /// the first `file_size` bytes, which is safe to access. As with
+    /// `FileInfo::read_from_file`, the extra bytes are picked up on the next
+    /// read rather than treated as an error.
+    pub fn with_len(path: PathBuf, file: File, file_size: usize) -> Result<Self> {
         // We need to ensure the underlying file descriptor is at least a page size.
         // Otherwise, we could get a SIGBUS error if mmap() attempts to read or write
         // past the file.
@@ -83,11 +127,36 @@ impl InnerMmap {
         Ok(Self {
             file,
             path,
-            map,
+            map: Map::ReadWrite(map),
             len,
         })
     }
 
+    /// Constructs a new `InnerMmap`, mmapping `path` read-only. Unlike `new`
+    /// the file is not extended to a page boundary, so `file` need only be
+    /// open for reading and any attempt to write fails with `ReadOnly`.
+    pub fn new_read_only(path: PathBuf, file: File) -> Result<Self> {
+        let file_size = Self::file_len(&path, &file)?;
+
+        // Mapping past the end of the file risks SIGBUS on access, and the
+        // file can't be extended to hold the header.
+        if file_size < HEADER_SIZE {
+            return Err(MmapError::PromParsing(format!(
+                "file {} is too small to hold a header, size {file_size}",
+                path.display()
+            )));
+        }
+
+        let map = Self::map_read_only(&file, file_size)?;
+
+        Ok(Self {
+            file,
+            path,
+            map,
+            len: file_size,
+        })
+    }
+
     /// Re-mmap a file that was previously mapped.
     pub fn reestablish(path: PathBuf, file: File, map_len: usize) -> Result<Self> {
         // SAFETY: There is the possibility of UB if the file is modified outside of
@@ -95,6 +164,7 @@ impl InnerMmap {
         let map = unsafe { MmapOptions::new().len(map_len).map_mut(&file) }.map_err(|e| {
             MmapError::legacy(format!("mmap failed ({}): {e}", errno()), RubyError::Arg)
         })?;
+        let map = Map::ReadWrite(map);
 
         // TODO should we keep this as the old len? We'd want to be able to truncate
         // to the old length at this point if closing the file. Matching C implementation
@@ -109,15 +179,73 @@ impl InnerMmap {
         })
     }
 
+    /// Re-mmap a file that was previously mapped read-only.
+    pub fn reestablish_read_only(path: PathBuf, file: File, map_len: usize) -> Result<Self> {
+        let map = Self::map_read_only(&file, map_len)?;
+
+        Ok(Self {
+            file,
+            path,
+            map,
+            len: map_len,
+        })
+    }
+
+    /// The current length of `file`, rejecting files too large to map.
+    pub fn file_len(path: &Path, file: &File) -> Result<usize> {
+        let stat = file.metadata().map_err(|e| {
+            MmapError::legacy(
+                format!("Can't stat {}: {e}", path.display()),
+                RubyError::Arg,
+            )
+        })?;
+
+        let file_size = util::cast_chk::<_, usize>(stat.len(), "file length")?;
+        util::check_file_size(path, file_size)?;
+
+        Ok(file_size)
+    }
+
+    fn map_read_only(file: &File, map_len: usize) -> Result<Map> {
+        // SAFETY: There is the possibility of UB if the file is modified outside of
+        // this program.
+        let map = unsafe { MmapOptions::new().len(map_len).map(file) }.map_err(|e| {
+            MmapError::legacy(format!("mmap failed ({}): {e}", errno()), RubyError::Arg)
+        })?;
+
+        Ok(Map::ReadOnly(map))
+    }
+
+    /// Whether the file was mapped read-only.
+    pub fn is_read_only(&self) -> bool {
+        matches!(self.map, Map::ReadOnly(_))
+    }
+
+    /// Return an error if the file was mapped read-only.
+    pub fn check_writable(&self) -> Result<()> {
+        match self.map {
+            Map::ReadWrite(_) => Ok(()),
+            Map::ReadOnly(_) => Err(MmapError::ReadOnly(self.path.display().to_string())),
+        }
+    }
+
     /// Add a new metrics entry to the end of the mmap. This will fail if the mmap is at
     /// capacity. Callers must expand the file first.
     ///
     /// SAFETY: Must not call any Ruby code for the lifetime of `key`, otherwise we risk
     /// Ruby mutating the underlying `RString`.
-    pub unsafe fn initialize_entry(&mut self, key: &[u8], value: f64) -> Result<usize> {
+    /// When `durable` is set the entry is flushed to disk before the `used`
+    /// header is advanced to include it, see `advance_used`.
+    pub unsafe fn initialize_entry(
+        &mut self,
+        key: &[u8],
+        value: f64,
+        durable: bool,
+    ) -> Result<usize> {
         // CAST: no-op on 32-bit, widening on 64-bit.
         let current_used = self.load_used()? as usize;
-        let entry_length = RawEntry::calc_total_len(key.len())?;
+        let layout = self.layout()?;
+        let entry_length = layout.total_len(key.len())?;
 
         let new_used = current_used.add_chk(entry_length)?;
 
@@ -131,18 +259,22 @@ impl InnerMmap {
             )));
         }
 
-        let bytes = self.map.as_mut();
-        let value_offset = RawEntry::save(&mut bytes[current_used..new_used], key, value)?;
+        let bytes = self.map.bytes_mut(&self.path)?;
+        let value_offset =
+            RawEntry::save_with_layout(&mut bytes[current_used..new_used], key, value, layout)?;
 
         // Won't overflow as value_offset is less than new_used.
         let position = current_used + value_offset;
-        let new_used32 = util::cast_chk::<_, u32>(new_used, "used")?;
-
-        self.save_used(new_used32)?;
+        self.advance_used(current_used, new_used, durable)?;
         Ok(position)
     }
 
-    pub unsafe fn initialize_entry_exemplar(&mut self, key: &[u8], ex: Exemplar) -> Result<usize> {
+    pub unsafe fn initialize_entry_exemplar(
+        &mut self,
+        key: &[u8],
+        ex: Exemplar,
+        durable: bool,
+    ) -> Result<usize> {
         // CAST: no-op on 32-bit, widening on 64-bit.
         let current_used = self.load_used()? as usize;
         let entry_length = RawEntry::calc_total_len_exemplar(key.len())?;
@@ -159,14 +291,24 @@ impl InnerMmap {
             )));
         }
 
-        let bytes = self.map.as_mut();
-        let value_offset = RawEntry::save_exemplar(&mut bytes[current_used..new_used], key, ex)?;
+        // The first exemplar stamps the file with the current format. Files
+        // that already hold exemplars keep the format they were written in,
+        // so their slots are never mixed.
+        let format = if current_used == HEADER_SIZE {
+            let format = ExemplarFormat::LengthPrefixed;
+            self.update_header_fields(|fields| fields.version = format.version())?;
+            format
+        } else {
+            self.exemplar_format()?
+        };
+
+        let bytes = self.map.bytes_mut(&self.path)?;
+        let value_offset =
+            RawEntry::save_exemplar(&mut bytes[current_used..new_used], key, ex, format)?;
 
         // Won't overflow as value_offset is less than new_used.
         let position = current_used + value_offset;
-        let new_used32 = util::cast_chk::<_, u32>(new_used, "used")?;
-
-        self.save_used(new_used32)?;
+        self.advance_used(current_used, new_used, durable)?;
         Ok(position)
     }
 
@@ -184,22 +326,15 @@ impl InnerMmap {
             )));
         }
 
-        let val = serde_json::to_string(&exemplar).unwrap();
-
-        let value_bytes = val.as_bytes();
-
-        let mut value_bytes = value_bytes.to_vec();
-        value_bytes.extend(iter::repeat(0).take(EXEMPLAR_ENTRY_MAX_SIZE_BYTES - value_bytes.len()));
-
         let value_range = self.item_range(offset, EXEMPLAR_ENTRY_MAX_SIZE_BYTES)?;
+        let format = self.exemplar_format()?;
 
-        let bytes = self.map.as_mut();
-        bytes[value_range].copy_from_slice(&value_bytes);
-
-        Ok(())
+        let bytes = self.map.bytes_mut(&self.path)?;
+        write_exemplar(&mut bytes[value_range], &exemplar, format)
     }
 
-    /// Save a metrics value to an existing entry in the mmap.
+    /// Save a metrics value to an existing entry in the mmap. `offset` must
+    /// be the value offset of an entry, as returned by `initialize_entry`.
     pub fn save_value(&mut self, offset: usize, value: f64) -> Result<()> {
         if self.len.add_chk(size_of::<f64>())? <= offset {
             return Err(MmapError::out_of_bounds(
@@ -214,10 +349,12 @@ impl InnerMmap {
             )));
         }
 
+        self.debug_check_value_offset(offset)?;
+
         let value_bytes = value.to_ne_bytes();
         let value_range = self.item_range(offset, value_bytes.len())?;
 
-        let bytes = self.map.as_mut();
+        let bytes = self.map.bytes_mut(&self.path)?;
         bytes[value_range].copy_from_slice(&value_bytes);
 
         Ok(())
@@ -231,7 +368,61 @@ impl InnerMmap {
                 self.len,
             ));
         }
-        read_f64(self.map.as_ref(), offset)
+        read_f64(self.map.bytes(), offset)
+    }
+
+    /// Save a metrics value with a single atomic store, so that a reader in
+    /// another process can never observe a partially written value.
+    pub fn save_value_atomic(&mut self, offset: usize, value: f64) -> Result<()> {
+        if offset < HEADER_SIZE {
+            return Err(MmapError::Other(format!(
+                "writing to offset {offset} would overwrite file header"
+            )));
+        }
+
+        self.debug_check_value_offset(offset)?;
+        self.check_writable()?;
+        self.atomic_slot(offset)?
+            .store(value.to_bits(), Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    /// Load a metrics value with a single atomic load, so that a concurrent
+    /// write from another process can never be observed partially applied.
+    pub fn load_value_atomic(&self, offset: usize) -> Result<f64> {
+        let bits = self.atomic_slot(offset)?.load(Ordering::Relaxed);
+
+        Ok(f64::from_bits(bits))
+    }
+
+    /// Borrow the 8-byte value slot at `offset` as an `AtomicU64`.
+    fn atomic_slot(&self, offset: usize) -> Result<&AtomicU64> {
+        if self.len.add_chk(size_of::<f64>())? <= offset {
+            return Err(MmapError::out_of_bounds(
+                offset + size_of::<f64>(),
+                self.len,
+            ));
+        }
+
+        let range = self.item_range(offset, size_of::<u64>())?;
+
+        // SAFETY: `range` is within the mmap.
+        let ptr = unsafe { self.as_mut_ptr().add(range.start) };
+
+        // Value slots are 8-byte aligned within the file, and the mmap itself
+        // is page aligned.
+        if ptr.align_offset(align_of::<AtomicU64>()) != 0 {
+            return Err(MmapError::Other(format!(
+                "value offset {offset} is not 8-byte aligned"
+            )));
+        }
+
+        // SAFETY: The pointer is in bounds, aligned, and valid for the lifetime
+        // of `&self` as the mmap can't be dropped while borrowed. Other
+        // processes may write to the slot, but only through the same atomic
+        // operations when atomic values are enabled.
+        Ok(unsafe { AtomicU64::from_ptr(ptr.cast::<u64>()) })
     }
 
     pub fn load_exemplar(&mut self, offset: usize) -> Result<Exemplar> {
@@ -242,7 +433,7 @@ impl InnerMmap {
             ));
         }
 
-        read_exemplar(self.map.as_mut(), offset)
+        read_exemplar(self.map.bytes(), offset, self.exemplar_format()?)
     }
 
     /// The length of data written to the file.
@@ -270,6 +461,21 @@ impl InnerMmap {
         self.len = len;
     }
 
+    /// The path of the mmapped file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The handle of the mmapped file.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// Returns the full mmap as a byte slice.
+    pub fn as_slice(&self) -> &[u8] {
+        self.map.bytes()
+    }
+
     /// Returns a raw pointer to the mmap.
     pub fn as_ptr(&self) -> *const u8 {
         self.map.as_ptr()
@@ -284,20 +490,22 @@ impl InnerMmap {
     /// Perform an msync(2) on the mmap, flushing all changes written
     /// to disk. The sync may optionally be performed asynchronously.
     pub fn flush(&mut self, f_async: bool) -> Result<()> {
-        if f_async {
-            self.map
-                .flush_async()
-                .map_err(|_| MmapError::legacy(format!("msync({})", errno()), RubyError::Arg))
+        // A read-only mapping has no changes to flush.
+        let Map::ReadWrite(map) = &self.map else {
+            return Ok(());
+        };
+
+        let result = if f_async {
+            map.flush_async()
         } else {
-            self.map
-                .flush()
-                .map_err(|_| MmapError::legacy(format!("msync({})", errno()), RubyError::Arg))
-        }
+            map.flush()
+        };
+        result.map_err(|e| MmapError::io("msync", &self.path, e))
     }
 
     /// Load the `used` header containing the size of the metrics data written.
     pub fn load_used(&self) -> Result<u32> {
-        match read_u32(self.map.as_ref(), 0) {
+        match read_u32(self.map.bytes(), 0) {
             // CAST: we know HEADER_SIZE fits in a u32.
             Ok(0) => Ok(HEADER_SIZE as u32),
             u => u,
@@ -307,12 +515,199 @@ impl InnerMmap {
     /// Update the `used` header to the value provided.
     /// value provided.
     pub fn save_used(&mut self, used: u32) -> Result<()> {
-        let bytes = self.map.as_mut();
+        let bytes = self.map.bytes_mut(&self.path)?;
         bytes[..size_of::<u32>()].copy_from_slice(&used.to_ne_bytes());
 
         Ok(())
     }
 
+    /// Advance the `used` header to `new_used` once the entry written at
+    /// `start..new_used` is in place.
+    ///
+    /// A crash before the header is updated leaves the entry past `used`,
+    /// where readers ignore it and the next write overwrites it. When
+    /// `durable` is set the entry is msync'd before the header is updated,
+    /// and the header after, so once `used` reaches disk the entry it covers
+    /// is guaranteed to be there too. Otherwise the kernel may write back the
+    /// pages in any order.
+    fn advance_used(&mut self, start: usize, new_used: usize, durable: bool) -> Result<()> {
+        let new_used32 = util::cast_chk::<_, u32>(new_used, "used")?;
+
+        if durable {
+            self.flush_range(start, new_used - start)?;
+        }
+
+        self.save_used(new_used32)?;
+
+        if durable {
+            self.flush_range(0, size_of::<u32>())?;
+        }
+
+        Ok(())
+    }
+
+    /// Perform an msync(2) on `len` bytes of the mmap from `offset`, so a
+    /// large mmap can be flushed a chunk at a time. Fails if the range is
+    /// outside the mmap.
+    pub fn flush_chunk(&self, offset: usize, len: usize, f_async: bool) -> Result<()> {
+        let end = offset.add_chk(len)?;
+        if end > self.capacity() {
+            return Err(MmapError::out_of_bounds(end, self.capacity()));
+        }
+
+        let Map::ReadWrite(map) = &self.map else {
+            return Ok(());
+        };
+
+        let result = if f_async {
+            map.flush_async_range(offset, len)
+        } else {
+            map.flush_range(offset, len)
+        };
+        result.map_err(|e| MmapError::io("msync", &self.path, e))
+    }
+
+    /// Perform a synchronous msync(2) on `len` bytes of the mmap from `offset`.
+    fn flush_range(&self, offset: usize, len: usize) -> Result<()> {
+        let Map::ReadWrite(map) = &self.map else {
+            return Ok(());
+        };
+
+        map.flush_range(offset, len)
+            .map_err(|e| MmapError::io("msync", &self.path, e))
+    }
+
+    /// Write a zero byte to each page in `offset..offset + len`, so the pages
+    /// are resident and mapped writable and later writes to them don't fault.
+    /// The range must lie past the `used` header, where the file holds no
+    /// entries, and within the mmap.
+    pub fn prefault(&mut self, offset: usize, len: usize) -> Result<()> {
+        // CAST: no-op on 32-bit, widening on 64-bit.
+        let used = self.load_used()? as usize;
+        if offset < used {
+            return Err(MmapError::Other(format!(
+                "can't prefault from {offset}, entries are written up to {used}"
+            )));
+        }
+
+        let end = offset.add_chk(len)?;
+        if end > self.capacity() {
+            return Err(MmapError::out_of_bounds(end, self.capacity()));
+        }
+
+        if len == 0 {
+            return Ok(());
+        }
+
+        let bytes = self.map.bytes_mut(&self.path)?;
+        for pos in (offset..end).step_by(PREFAULT_STRIDE) {
+            bytes[pos] = 0;
+        }
+        // The stride may step over the start of the final page.
+        bytes[end - 1] = 0;
+
+        Ok(())
+    }
+
+    /// Load the entry layout flag from the header.
+    pub fn layout(&self) -> Result<Layout> {
+        Layout::from_header(self.map.bytes())
+    }
+
+    /// Find the entry whose value is stored at `offset`, returning its key and
+    /// value. Fails if `offset` is not the value offset of a written entry.
+    pub fn entry_at(&self, offset: usize) -> Result<(&[u8], f64)> {
+        let bytes = self.as_slice();
+        // CAST: no-op on 32-bit, widening on 64-bit.
+        let used = self.load_used()? as usize;
+
+        if used > bytes.len() {
+            return Err(MmapError::out_of_bounds(used, bytes.len()));
+        }
+
+        let layout = self.layout()?;
+
+        // Walk the length prefixes from the header up to `offset`, only the
+        // entry found there is parsed.
+        if offset < used {
+            for pos in EntryMap::entry_offsets(bytes, used, layout, false) {
+                let pos = pos?;
+
+                // Entries are in offset order, we've passed the target.
+                if pos >= offset {
+                    break;
+                }
+
+                // CAST: no-op on 32-bit, widening on 64-bit.
+                let encoded_len = read_u32(bytes, pos)? as usize;
+                if pos + layout.value_offset(encoded_len)? == offset {
+                    let entry = RawEntry::from_slice_with_layout(&bytes[pos..used], layout)?;
+                    return Ok((entry.json(), entry.value()));
+                }
+            }
+        }
+
+        Err(MmapError::Other(format!(
+            "offset {offset} is not the value of an entry"
+        )))
+    }
+
+    /// Verify that `offset` is the value offset of an entry, rather than
+    /// pointing into a key or padding after `positions` went stale. This walks
+    /// every entry before `offset`, so is only done in debug builds.
+    fn debug_check_value_offset(&self, offset: usize) -> Result<()> {
+        if cfg!(debug_assertions) {
+            self.entry_at(offset)?;
+        }
+
+        Ok(())
+    }
+
+    /// Update the entry layout flag in the header. The layout can only be
+    /// changed before any entries have been written.
+    pub fn save_layout(&mut self, layout: Layout) -> Result<()> {
+        if layout == Layout::Packed {
+            return Err(MmapError::Other(
+                "entries can't be written in the packed layout".to_string(),
+            ));
+        }
+
+        // CAST: no-op on 32-bit, widening on 64-bit.
+        if self.load_used()? as usize != HEADER_SIZE {
+            return Err(MmapError::Other(
+                "can't change the layout of a file containing entries".to_string(),
+            ));
+        }
+
+        self.update_header_fields(|fields| fields.layout = layout.flag())
+    }
+
+    /// The format of the exemplar slots in the file, recorded in its header.
+    pub fn exemplar_format(&self) -> Result<ExemplarFormat> {
+        ExemplarFormat::from_header(self.map.bytes())
+    }
+
+    /// Load the fields packed into the header after `used`.
+    pub fn header_fields(&self) -> Result<HeaderFields> {
+        HeaderFields::from_header(self.map.bytes())
+    }
+
+    /// Update the fields packed into the header after `used` with `f`,
+    /// leaving the fields it doesn't change as they are.
+    pub fn update_header_fields<F>(&mut self, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut HeaderFields),
+    {
+        let mut fields = self.header_fields()?;
+        f(&mut fields);
+
+        let range = HeaderFields::HEADER_OFFSET..HEADER_SIZE;
+        let bytes = self.map.bytes_mut(&self.path)?;
+        bytes[range].copy_from_slice(&fields.to_u32().to_ne_bytes());
+
+        Ok(())
+    }
+
     /// Drop self, which performs an munmap(2) on the mmap,
     /// returning the open `File` and `PathBuf` so the
     /// caller can expand the file and re-mmap it.
@@ -347,7 +742,9 @@ impl InnerMmap {
         Ok(start..offset_end)
     }
 
-    fn next_page_boundary(len: usize) -> Result<c_long> {
+    /// The smallest multiple of the page size, doubled as needed, that is at
+    /// least `len`.
+    pub fn next_page_boundary(len: usize) -> Result<c_long> {
         use nix::unistd::{self, SysconfVar};
 
         let len = c_long::try_from(len)
@@ -464,6 +861,52 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_new_file_too_large() {
+        let TestFile {
+            file,
+            path,
+            dir: _dir,
+        } = TestFile::new(&[]);
+
+        // Creates a sparse file, no disk space is consumed.
+        file.set_len(crate::MAX_FILE_SIZE as u64 + 1).unwrap();
+
+        let result = InnerMmap::new(path, file);
+        assert!(matches!(result, Err(MmapError::FileTooLarge { .. })));
+    }
+
+    #[test]
+    fn test_with_len_file_grew() {
+        let json = r#"["first_family","first_name",["label_a","label_b"],["value_a","value_b"]]"#;
+        let data = testhelper::entries_to_db(&[json], &[1.0], None);
+
+        let TestFile {
+            file,
+            path,
+            dir: _dir,
+        } = TestFile::new(&data);
+
+        let file_size = InnerMmap::file_len(&path, &file).unwrap();
+        assert_eq!(data.len(), file_size);
+
+        // Another worker expands the file after we stat'd it.
+        let grown_size = file_size + 3 * HEADER_SIZE;
+        file.set_len(grown_size as u64).unwrap();
+
+        // `new` takes its own length right before mapping.
+        let grown_file = File::options().read(true).write(true).open(&path).unwrap();
+        let inner = InnerMmap::new(path.clone(), grown_file).unwrap();
+        assert_eq!(grown_size, inner.capacity(), "new mmap covers grown file");
+        assert_eq!(grown_size, inner.len(), "new len matches grown file");
+
+        // `with_len` trusts the length it is given.
+        let inner = InnerMmap::with_len(path, file, file_size).unwrap();
+        assert_eq!(file_size, inner.capacity(), "mmap covers stat'd length");
+        assert_eq!(file_size, inner.len(), "len matches stat'd length");
+        assert_eq!(data, inner.map.bytes(), "stat'd bytes readable");
+    }
+
     #[test]
     fn test_reestablish() {
         struct TestCase {
@@ -577,7 +1020,7 @@ mod test {
             }
             let mut inner = InnerMmap::new(path, file).unwrap();
 
-            let result = unsafe { inner.initialize_entry(json.as_bytes(), value) };
+            let result = unsafe { inner.initialize_entry(json.as_bytes(), value, false) };
 
             if let Some(expected_used) = case.expected_used {
                 assert_eq!(
@@ -605,6 +1048,190 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_initialize_entry_value_first() {
+        let json = r#"["first_family","first_name",["label_a","label_b"],["value_a","value_b"]]"#;
+        let value = 1.0;
+
+        let TestFile {
+            file,
+            path,
+            dir: _dir,
+        } = TestFile::new(&[0u8; 8]);
+        file.set_len(4096).unwrap();
+
+        let mut inner = InnerMmap::new(path, file).unwrap();
+        inner.save_layout(Layout::ValueFirst).unwrap();
+        assert_eq!(Layout::ValueFirst, inner.layout().unwrap());
+
+        let offset = unsafe { inner.initialize_entry(json.as_bytes(), value, false) }.unwrap();
+        assert_eq!(HEADER_SIZE + 8, offset, "value directly follows length");
+
+        inner.set_len(inner.capacity());
+        assert_eq!(value, inner.load_value(offset).unwrap());
+        assert_eq!(
+            HEADER_SIZE + Layout::ValueFirst.total_len(json.len()).unwrap(),
+            inner.load_used().unwrap() as usize
+        );
+
+        // Layout is fixed once entries are written.
+        assert!(inner.save_layout(Layout::Legacy).is_err());
+    }
+
+    #[test]
+    fn test_header_fields() {
+        let json = r#"["first_family","first_name",["label_a","label_b"],["value_a","value_b"]]"#;
+
+        // Legacy files read as defaults.
+        let data = testhelper::entries_to_db(&[json], &[1.0], None);
+        let TestFile {
+            file,
+            path,
+            dir: _dir,
+        } = TestFile::new(&data);
+
+        let mut inner = InnerMmap::new(path, file).unwrap();
+        assert_eq!(HeaderFields::default(), inner.header_fields().unwrap());
+        assert_eq!(Layout::Legacy, inner.layout().unwrap());
+
+        // Each field is updated without disturbing the others or `used`.
+        let used = inner.load_used().unwrap();
+        inner.update_header_fields(|f| f.version = 1).unwrap();
+        inner.update_header_fields(|f| f.value_width = 8).unwrap();
+        inner.update_header_fields(|f| f.flags = 0x80).unwrap();
+        assert_eq!(
+            HeaderFields {
+                layout: 0,
+                version: 1,
+                value_width: 8,
+                flags: 0x80,
+            },
+            inner.header_fields().unwrap()
+        );
+        assert_eq!(used, inner.load_used().unwrap());
+
+        // Entries still parse.
+        inner.set_len(inner.capacity());
+        let offset = data.len() - size_of::<f64>();
+        assert_eq!((json.as_bytes(), 1.0), inner.entry_at(offset).unwrap());
+
+        // Setting the layout preserves the other fields.
+        let TestFile {
+            file,
+            path,
+            dir: _dir,
+        } = TestFile::new(&[0u8; 8]);
+        file.set_len(4096).unwrap();
+
+        let mut inner = InnerMmap::new(path, file).unwrap();
+        inner.update_header_fields(|f| f.version = 2).unwrap();
+        inner.save_layout(Layout::ValueFirst).unwrap();
+        assert_eq!(Layout::ValueFirst, inner.layout().unwrap());
+        assert_eq!(2, inner.header_fields().unwrap().version);
+    }
+
+    #[test]
+    fn test_exemplar_format() {
+        let ex = |label_value: &str| Exemplar {
+            label_name: "trace_id".to_string(),
+            label_value: label_value.to_string(),
+            value: 1.0,
+            timestamp: 100,
+        };
+
+        // The first exemplar in a file stamps it with the current format.
+        let TestFile {
+            file,
+            path,
+            dir: _dir,
+        } = TestFile::new(&[0u8; 8]);
+        file.set_len(4096).unwrap();
+
+        let mut inner = InnerMmap::new(path, file).unwrap();
+        assert_eq!(ExemplarFormat::Legacy, inner.exemplar_format().unwrap());
+
+        let pos = unsafe { inner.initialize_entry_exemplar(b"a", ex("abc"), false) }.unwrap();
+        assert_eq!(
+            ExemplarFormat::LengthPrefixed,
+            inner.exemplar_format().unwrap()
+        );
+        assert_eq!("abc", inner.load_exemplar(pos).unwrap().label_value);
+
+        // Files holding legacy exemplars keep their format.
+        let entry_len = RawEntry::calc_total_len_exemplar(1).unwrap();
+        let mut data = vec![0u8; 4096];
+        // CAST: the entry is far smaller than u32::MAX.
+        data[..size_of::<u32>()].copy_from_slice(&((HEADER_SIZE + entry_len) as u32).to_ne_bytes());
+        RawEntry::save_exemplar(
+            &mut data[HEADER_SIZE..],
+            b"a",
+            ex("abc"),
+            ExemplarFormat::Legacy,
+        )
+        .unwrap();
+
+        let TestFile {
+            file,
+            path,
+            dir: _dir,
+        } = TestFile::new(&data);
+
+        let mut inner = InnerMmap::new(path, file).unwrap();
+        let first = HEADER_SIZE + RawEntry::calc_value_offset(1).unwrap();
+        let second = unsafe { inner.initialize_entry_exemplar(b"b", ex("def"), false) }.unwrap();
+        inner.save_exemplar(first, ex("ghi")).unwrap();
+
+        assert_eq!(ExemplarFormat::Legacy, inner.exemplar_format().unwrap());
+        assert_eq!("ghi", inner.load_exemplar(first).unwrap().label_value);
+        assert_eq!("def", inner.load_exemplar(second).unwrap().label_value);
+
+        // An unknown version is an error rather than a misread.
+        inner.update_header_fields(|f| f.version = 9).unwrap();
+        assert!(inner.load_exemplar(first).is_err());
+    }
+
+    #[test]
+    fn test_initialize_entry_crash_before_used() {
+        let first = r#"["first_family","first_name",["label_a"],["value_a"]]"#;
+        let second = r#"["second_family","second_name",["label_a"],["value_b"]]"#;
+
+        let TestFile {
+            file,
+            path,
+            dir: _dir,
+        } = TestFile::new(&[0u8; 8]);
+        file.set_len(4096).unwrap();
+
+        let mut inner = InnerMmap::new(path.clone(), file).unwrap();
+        unsafe { inner.initialize_entry(first.as_bytes(), 1.0, true) }.unwrap();
+        let used = inner.load_used().unwrap();
+
+        // Simulate a crash after the second entry was written but before the
+        // header was updated.
+        let entry = TestEntry::new(second, 2.0).as_bytes();
+        let start = used as usize;
+        inner.map.bytes_mut(&path).unwrap()[start..start + entry.len()].copy_from_slice(&entry);
+        drop(inner);
+
+        // The half-written entry past `used` is ignored on the next open.
+        let file = File::options().read(true).write(true).open(&path).unwrap();
+        let mut inner = InnerMmap::new(path, file).unwrap();
+        assert_eq!(used, inner.load_used().unwrap());
+
+        let offsets: Vec<_> =
+            EntryMap::entry_offsets(inner.as_slice(), used as usize, Layout::Legacy, false)
+                .collect::<Result<_>>()
+                .unwrap();
+        assert_eq!(vec![HEADER_SIZE], offsets, "only the complete entry");
+
+        // And is overwritten by the next write.
+        let offset = unsafe { inner.initialize_entry(second.as_bytes(), 3.0, true) }.unwrap();
+        assert_eq!(start + entry.len() - size_of::<f64>(), offset);
+
+        inner.set_len(inner.capacity());
+        assert_eq!(3.0, inner.load_value(offset).unwrap());
+    }
+
     #[test]
     fn test_save_value() {
         let json = r#"["first_family","first_name",["label_a","label_b"],["value_a","value_b"]]"#;
@@ -625,7 +1252,7 @@ mod test {
                 name: "existing file, in bounds",
                 empty: false,
                 len: None,
-                offset: upper_bound - size_of::<f64>() - 1,
+                offset: value_offset,
                 expected_err: None,
             },
             TestCase {
@@ -664,6 +1291,18 @@ mod test {
                     "writing to offset 7 would overwrite file header".to_string(),
                 )),
             },
+            TestCase {
+                name: "mid-key offset",
+                empty: false,
+                len: None,
+                offset: HEADER_SIZE + 8,
+                expected_err: cfg!(debug_assertions).then(|| {
+                    MmapError::Other(format!(
+                        "offset {} is not the value of an entry",
+                        HEADER_SIZE + 8
+                    ))
+                }),
+            },
         ];
 
         for case in tc {
@@ -700,13 +1339,82 @@ mod test {
 
                 assert_eq!(
                     value,
-                    util::read_f64(&inner.map, case.offset).unwrap(),
+                    util::read_f64(inner.map.bytes(), case.offset).unwrap(),
                     "test case: {name} - value saved"
                 );
             }
         }
     }
 
+    #[test]
+    fn test_atomic_value_stress() {
+        const ITERATIONS: usize = 100_000;
+
+        let json = r#"["first_family","first_name",["label_a","label_b"],["value_a","value_b"]]"#;
+        let mut data = testhelper::entries_to_db(&[json], &[1.0], None);
+        let value_offset = data.len() - size_of::<f64>();
+
+        // Leave room past the value, as a live file has, so the last value
+        // slot isn't at the very end of the mapping.
+        data.resize(4096, 0);
+
+        let TestFile {
+            file,
+            path,
+            dir: _dir,
+        } = TestFile::new(&data);
+
+        // Two mappings of the same file, as two processes would have.
+        let mut writer = InnerMmap::new(path.clone(), file).unwrap();
+        let file = File::options().read(true).write(true).open(&path).unwrap();
+        let reader = InnerMmap::new(path.clone(), file).unwrap();
+
+        // Values differing in every byte, so a torn read would produce neither.
+        let values = [
+            f64::from_bits(0x0101_0101_0101_0101),
+            f64::from_bits(0x7e7e_7e7e_7e7e_7e7e),
+        ];
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for i in 0..ITERATIONS {
+                    writer
+                        .save_value_atomic(value_offset, values[i % 2])
+                        .unwrap();
+                }
+            });
+
+            for _ in 0..ITERATIONS {
+                let value = reader.load_value_atomic(value_offset).unwrap();
+                assert!(
+                    value == 1.0 || values.contains(&value),
+                    "torn read {:#x}",
+                    value.to_bits()
+                );
+            }
+        });
+
+        assert!(writer.save_value_atomic(0, 1.0).is_err(), "header");
+        assert_eq!(
+            Err(MmapError::Other(format!(
+                "value offset {} is not 8-byte aligned",
+                value_offset + 1
+            ))),
+            reader.load_value_atomic(value_offset + 1),
+            "misaligned"
+        );
+
+        let past_len = data.len() + size_of::<f64>();
+        assert_eq!(
+            Err(MmapError::out_of_bounds(
+                past_len + size_of::<f64>(),
+                data.len()
+            )),
+            reader.load_value_atomic(past_len),
+            "past used"
+        );
+    }
+
     #[test]
     fn test_load_value() {
         let json = r#"["first_family","first_name",["label_a","label_b"],["value_a","value_b"]]"#;
@@ -772,4 +1480,67 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn test_flush_chunk() {
+        let page_size = unistd::sysconf(SysconfVar::PAGE_SIZE).unwrap().unwrap() as usize;
+        let cap = page_size * 3;
+
+        let TestFile {
+            file,
+            path,
+            dir: _dir,
+        } = TestFile::new(&[]);
+        file.set_len(cap as u64).unwrap();
+
+        let mut inner = InnerMmap::reestablish(path.clone(), file, cap).unwrap();
+
+        let bytes = inner.map.bytes_mut(&path).unwrap();
+        bytes[page_size..page_size + 6].copy_from_slice(b"foobar");
+
+        inner.flush_chunk(page_size, page_size, false).unwrap();
+        inner.flush_chunk(0, cap, true).unwrap();
+
+        let contents = std::fs::read(&path).unwrap();
+        assert_eq!(b"foobar", &contents[page_size..page_size + 6]);
+
+        assert_eq!(
+            Err(MmapError::out_of_bounds(cap + 1, cap)),
+            inner.flush_chunk(page_size, cap + 1 - page_size, false)
+        );
+    }
+
+    #[test]
+    fn test_prefault() {
+        let json = r#"["first_family","first_name",["label_a","label_b"],["value_a","value_b"]]"#;
+        let data = testhelper::entries_to_db(&[json], &[1.0], None);
+        let used = data.len();
+
+        let TestFile {
+            file,
+            path,
+            dir: _dir,
+        } = TestFile::new(&data);
+
+        let inner = InnerMmap::new(path, file).unwrap();
+
+        // Re-map the file with space past `used`.
+        let cap = PREFAULT_STRIDE * 3;
+        let (file, path) = inner.munmap();
+        file.set_len(cap as u64).unwrap();
+        let mut inner = InnerMmap::reestablish(path, file, cap).unwrap();
+
+        assert!(
+            matches!(inner.prefault(used - 1, 1), Err(MmapError::Other(_))),
+            "can't prefault entries"
+        );
+        assert_eq!(
+            Err(MmapError::out_of_bounds(cap + 1, cap)),
+            inner.prefault(used, cap + 1 - used)
+        );
+
+        inner.prefault(used, cap - used).unwrap();
+        assert_eq!(&data[..], &inner.as_slice()[..used], "entries unchanged");
+        assert!(inner.as_slice()[used..].iter().all(|b| *b == 0));
+    }
 }
@@ -1,20 +1,155 @@
 use std::mem::size_of;
 
 use crate::error::MmapError;
-use crate::exemplars::{Exemplar, EXEMPLAR_ENTRY_MAX_SIZE_BYTES};
+use crate::exemplars::{Exemplar, ExemplarFormat, EXEMPLAR_ENTRY_MAX_SIZE_BYTES};
 use crate::util;
 use crate::util::CheckedOps;
 use crate::Result;
 
+/// The `u32` following `used` in the file header, which older versions left
+/// as zeroed padding, split into byte-sized fields so that several features
+/// can share it. Zero is the default for every field, so files written
+/// before a field was introduced read as its default.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub struct HeaderFields {
+    /// The `Layout` flag, in the low byte so that flags written as a whole
+    /// `u32` read the same.
+    pub layout: u8,
+    /// The format version, which records the `ExemplarFormat` of exemplar
+    /// files.
+    pub version: u8,
+    /// Reserved for the width of values in bytes, zero meaning 8.
+    pub value_width: u8,
+    /// Reserved for format flags, e.g. a non-native byte order.
+    pub flags: u8,
+}
+
+impl HeaderFields {
+    /// The offset of the fields in the file header.
+    pub const HEADER_OFFSET: usize = size_of::<u32>();
+
+    /// Read the fields from the header of a `.db` file.
+    pub fn from_header(buf: &[u8]) -> Result<Self> {
+        Ok(Self::from_u32(util::read_u32(buf, Self::HEADER_OFFSET)?))
+    }
+
+    /// Unpack the fields from the `u32`, lowest byte first.
+    pub fn from_u32(packed: u32) -> Self {
+        let [layout, version, value_width, flags] = packed.to_le_bytes();
+
+        Self {
+            layout,
+            version,
+            value_width,
+            flags,
+        }
+    }
+
+    /// Pack the fields into a `u32`, lowest byte first.
+    pub fn to_u32(self) -> u32 {
+        u32::from_le_bytes([self.layout, self.version, self.value_width, self.flags])
+    }
+}
+
+/// The on-disk layout of the entries in a `.db` file, stored as a flag in the
+/// `layout` field of the `HeaderFields`. Files written before the flag was
+/// introduced have zeroed padding and are read as `Legacy`.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub enum Layout {
+    /// `[len][key][padding][value]`
+    #[default]
+    Legacy,
+    /// `[len][4 byte padding][value][key][padding]`. The value is at a fixed
+    /// offset from the start of each entry, so value-only scans can skip
+    /// reading the key entirely.
+    ValueFirst,
+    /// `[len][key][value]`, with no padding. Written by foreign writers that
+    /// don't align values, so values are read unaligned and can't be updated
+    /// atomically. This crate reads the layout but never writes it.
+    Packed,
+}
+
+impl Layout {
+    /// Read the layout flag from the header of a `.db` file.
+    pub fn from_header(buf: &[u8]) -> Result<Self> {
+        match HeaderFields::from_header(buf)?.layout {
+            0 => Ok(Layout::Legacy),
+            1 => Ok(Layout::ValueFirst),
+            2 => Ok(Layout::Packed),
+            flag => Err(MmapError::PromParsing(format!(
+                "unknown entry layout flag {flag}"
+            ))),
+        }
+    }
+
+    /// The value of the header flag for this layout.
+    pub fn flag(self) -> u8 {
+        match self {
+            Layout::Legacy => 0,
+            Layout::ValueFirst => 1,
+            Layout::Packed => 2,
+        }
+    }
+
+    /// Calculate the offset of the value from the start of an entry.
+    /// Validates encoding_len is within expected bounds.
+    #[inline]
+    pub fn value_offset(self, encoded_len: usize) -> Result<usize> {
+        match self {
+            Layout::Legacy => RawEntry::calc_value_offset(encoded_len),
+            Layout::ValueFirst => {
+                RawEntry::check_encoded_len(encoded_len)?;
+                Ok(2 * size_of::<u32>())
+            }
+            Layout::Packed => {
+                RawEntry::check_encoded_len(encoded_len)?;
+                Ok(size_of::<u32>() + encoded_len)
+            }
+        }
+    }
+
+    /// Calculate the total length of an entry in this layout, including the
+    /// string length, string, padding, and value.
+    #[inline]
+    pub fn total_len(self, encoded_len: usize) -> Result<usize> {
+        match self {
+            Layout::Legacy => RawEntry::calc_total_len(encoded_len),
+            Layout::ValueFirst => {
+                RawEntry::check_encoded_len(encoded_len)?;
+                Ok(2 * size_of::<u32>()
+                    + size_of::<f64>()
+                    + encoded_len
+                    + Self::value_first_padding_len(encoded_len))
+            }
+            Layout::Packed => self.value_offset(encoded_len)?.add_chk(size_of::<f64>()),
+        }
+    }
+
+    /// The number of padding bytes trailing the key in the `ValueFirst` layout
+    /// to reach 8-byte alignment. Like the legacy layout, this is 1 to 8 bytes.
+    #[inline]
+    fn value_first_padding_len(encoded_len: usize) -> usize {
+        8 - encoded_len % 8
+    }
+}
+
 /// The logic to save a `MetricsEntry`, or parse one from a byte slice.
 #[derive(PartialEq, Eq, Clone, Debug)]
 pub struct RawEntry<'a> {
     bytes: &'a [u8],
     encoded_len: usize,
+    layout: Layout,
+    /// The slot format of entries read from exemplar files.
+    exemplar_format: ExemplarFormat,
 }
 
 impl<'a> RawEntry<'a> {
-    pub fn save_exemplar(bytes: &'a mut [u8], key: &[u8], value: Exemplar) -> Result<usize> {
+    pub fn save_exemplar(
+        bytes: &'a mut [u8],
+        key: &[u8],
+        value: Exemplar,
+        format: ExemplarFormat,
+    ) -> Result<usize> {
         let total_len = Self::calc_total_len_exemplar(key.len())?;
 
         if total_len > bytes.len() {
@@ -24,8 +159,6 @@ impl<'a> RawEntry<'a> {
             )));
         }
 
-        let val = serde_json::to_string(&value).unwrap();
-
         // CAST: `calc_len` runs `check_encoded_len`, we know the key len
         // is less than i32::MAX. No risk of overflows or failed casts.
         let key_len: u32 = key.len() as u32;
@@ -45,11 +178,56 @@ impl<'a> RawEntry<'a> {
         bytes[..pad_len].fill(b' ');
         let bytes = &mut bytes[pad_len..];
 
-        bytes[..val.len()].copy_from_slice(val.as_bytes());
+        util::write_exemplar(&mut bytes[..EXEMPLAR_ENTRY_MAX_SIZE_BYTES], &value, format)?;
 
         Self::calc_value_offset(key.len())
     }
 
+    /// Save an entry to the mmap in the layout provided, returning the value
+    /// offset in the newly created entry.
+    pub fn save_with_layout(
+        bytes: &'a mut [u8],
+        key: &[u8],
+        value: f64,
+        layout: Layout,
+    ) -> Result<usize> {
+        match layout {
+            Layout::Legacy => return Self::save(bytes, key, value),
+            Layout::Packed => {
+                return Err(MmapError::Other(
+                    "entries can't be written in the packed layout".to_string(),
+                ))
+            }
+            Layout::ValueFirst => {}
+        }
+
+        let total_len = layout.total_len(key.len())?;
+
+        if total_len > bytes.len() {
+            return Err(MmapError::Other(format!(
+                "entry length {total_len} larger than slice length {}",
+                bytes.len()
+            )));
+        }
+
+        // CAST: `total_len` runs `check_encoded_len`, we know the key len
+        // is less than i32::MAX. No risk of overflows or failed casts.
+        let key_len: u32 = key.len() as u32;
+        let value_offset = layout.value_offset(key.len())?;
+
+        bytes[..size_of::<u32>()].copy_from_slice(&key_len.to_ne_bytes());
+        bytes[size_of::<u32>()..value_offset].fill(b' ');
+
+        let bytes = &mut bytes[value_offset..total_len];
+        bytes[..size_of::<f64>()].copy_from_slice(&value.to_ne_bytes());
+
+        let bytes = &mut bytes[size_of::<f64>()..];
+        bytes[..key.len()].copy_from_slice(key);
+        bytes[key.len()..].fill(b' ');
+
+        Ok(value_offset)
+    }
+
     /// Save an entry to the mmap, returning the value offset in the newly created entry.
     pub fn save(bytes: &'a mut [u8], key: &[u8], value: f64) -> Result<usize> {
         let total_len = Self::calc_total_len(key.len())?;
@@ -100,10 +278,74 @@ impl<'a> RawEntry<'a> {
         // Advance slice past length int and cut at end of entry.
         let bytes = &bytes[size_of::<u32>()..total_len];
 
-        Ok(Self { bytes, encoded_len })
+        Ok(Self {
+            bytes,
+            encoded_len,
+            layout: Layout::Legacy,
+            exemplar_format: ExemplarFormat::default(),
+        })
+    }
+
+    /// Check that `bytes` starts with a structurally valid entry in the legacy
+    /// layout, without constructing a `RawEntry`: the length prefix is within
+    /// bounds, the key is padded with spaces, and the value slot is present.
+    /// Bytes past the end of the entry are ignored, as in `from_slice`. The
+    /// key itself is not parsed.
+    pub fn validate(bytes: &[u8]) -> Result<()> {
+        // CAST: no-op on 32-bit, widening on 64-bit.
+        let encoded_len = util::read_u32(bytes, 0)? as usize;
+
+        let value_offset = Self::calc_value_offset(encoded_len)?;
+        let total_len = value_offset.add_chk(size_of::<f64>())?;
+
+        if total_len > bytes.len() {
+            return Err(MmapError::out_of_bounds(total_len, bytes.len()));
+        }
+
+        let padding_start = size_of::<u32>() + encoded_len;
+        if let Some(i) = bytes[padding_start..value_offset]
+            .iter()
+            .position(|&b| b != b' ')
+        {
+            return Err(MmapError::PromParsing(format!(
+                "entry padding at offset {} is not a space",
+                padding_start + i
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Parse a byte slice containing an entry in the layout provided.
+    pub fn from_slice_with_layout(bytes: &'a [u8], layout: Layout) -> Result<Self> {
+        if layout == Layout::Legacy {
+            return Self::from_slice(bytes);
+        }
+
+        // CAST: no-op on 32-bit, widening on 64-bit.
+        let encoded_len = util::read_u32(bytes, 0)? as usize;
+
+        let total_len = layout.total_len(encoded_len)?;
+
+        // Confirm the value is in bounds of the slice provided.
+        if total_len > bytes.len() {
+            return Err(MmapError::out_of_bounds(total_len, bytes.len()));
+        }
+
+        // Advance slice past length int and cut at end of entry.
+        let bytes = &bytes[size_of::<u32>()..total_len];
+
+        Ok(Self {
+            bytes,
+            encoded_len,
+            layout,
+            exemplar_format: ExemplarFormat::default(),
+        })
     }
 
-    pub fn from_slice_exemplar(bytes: &'a [u8]) -> Result<Self> {
+    /// Parse a byte slice containing an exemplar entry, whose slot is stored
+    /// in `format`.
+    pub fn from_slice_exemplar(bytes: &'a [u8], format: ExemplarFormat) -> Result<Self> {
         // CAST: no-op on 32-bit, widening on 64-bit.
         let encoded_len = util::read_u32(bytes, 0)? as usize;
 
@@ -117,27 +359,36 @@ impl<'a> RawEntry<'a> {
         // Advance slice past length int and cut at end of entry.
         let bytes = &bytes[size_of::<u32>()..total_len];
 
-        Ok(Self { bytes, encoded_len })
+        Ok(Self {
+            bytes,
+            encoded_len,
+            layout: Layout::Legacy,
+            exemplar_format: format,
+        })
     }
 
-    /// Read the `f64` value of an entry from memory.
+    /// Read the `f64` value of an entry from memory, atomically when it is
+    /// aligned so a concurrent `save_value_atomic` can't tear it.
     #[inline]
     pub fn value(&self) -> f64 {
         // We've stripped off the leading u32, don't include that here.
-        let offset = self.encoded_len + Self::padding_len(self.encoded_len);
+        let offset = match self.layout {
+            Layout::Legacy => self.encoded_len + Self::padding_len(self.encoded_len),
+            Layout::ValueFirst => size_of::<u32>(),
+            Layout::Packed => self.encoded_len,
+        };
 
         // UNWRAP: We confirm in the constructor that the value offset
         // is in-range for the slice.
-        util::read_f64(self.bytes, offset).unwrap()
+        util::load_f64(self.bytes, offset).unwrap()
     }
 
-    pub fn exemplar(&self) -> Exemplar {
+    /// Read the `Exemplar` of an entry from memory.
+    pub fn exemplar(&self) -> Result<Exemplar> {
         // We've stripped off the leading u32, don't include that here.
         let offset = self.encoded_len + Self::padding_len(self.encoded_len);
 
-        // UNWRAP: We confirm in the constructor that the value offset
-        // is in-range for the slice.
-        util::read_exemplar(self.bytes, offset).unwrap()
+        util::read_exemplar(self.bytes, offset, self.exemplar_format)
     }
 
     /// The length of the entry key without padding.
@@ -149,7 +400,14 @@ impl<'a> RawEntry<'a> {
     /// Returns a slice with the JSON string in the entry, excluding padding.
     #[inline]
     pub fn json(&self) -> &[u8] {
-        &self.bytes[..self.encoded_len]
+        match self.layout {
+            Layout::Legacy | Layout::Packed => &self.bytes[..self.encoded_len],
+            Layout::ValueFirst => {
+                // We've stripped off the leading u32, skip the padding and value.
+                let start = size_of::<u32>() + size_of::<f64>();
+                &self.bytes[start..start + self.encoded_len]
+            }
+        }
     }
 
     /// Calculate the total length of an `MmapEntry`, including the string length,
@@ -157,7 +415,7 @@ impl<'a> RawEntry<'a> {
     #[inline]
     pub fn total_len(&self) -> usize {
         // UNWRAP:: We confirmed in the constructor that this doesn't overflow.
-        Self::calc_total_len(self.encoded_len).unwrap()
+        self.layout.total_len(self.encoded_len).unwrap()
     }
 
     #[inline]
@@ -195,10 +453,23 @@ impl<'a> RawEntry<'a> {
     }
 
     #[inline]
-    fn check_encoded_len(encoded_len: usize) -> Result<()> {
+    pub(crate) fn check_encoded_len(encoded_len: usize) -> Result<()> {
+        Self::check_key_len(encoded_len, i32::MAX as usize)
+    }
+
+    /// Check `encoded_len` against `i32::MAX`, then the stricter `limit`, e.g.
+    /// the `max_key_length` of a file or scrape.
+    #[inline]
+    pub(crate) fn check_key_len(encoded_len: usize, limit: usize) -> Result<()> {
         if encoded_len as u64 > i32::MAX as u64 {
             return Err(MmapError::KeyLength);
         }
+        if encoded_len > limit {
+            return Err(MmapError::KeyTooLong {
+                len: encoded_len,
+                limit,
+            });
+        }
         Ok(())
     }
 }
@@ -345,6 +616,96 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_validate() {
+        struct TestCase {
+            name: &'static str,
+            input: Vec<u8>,
+            expected_err: Option<MmapError>,
+        }
+
+        let json = r#"["metric","name",["label_a"],["value_a"]]"#;
+        let valid = || TestEntry::new(json, 1.0).as_bytes();
+        let padding_start = size_of::<u32>() + json.len();
+
+        let tc = vec![
+            TestCase {
+                name: "ok",
+                input: valid(),
+                expected_err: None,
+            },
+            TestCase {
+                name: "zero length key",
+                input: TestEntry::new("", 1.0).as_bytes(),
+                expected_err: None,
+            },
+            TestCase {
+                name: "trailing bytes ignored",
+                input: [valid(), vec![0xff; 8]].concat(),
+                expected_err: None,
+            },
+            TestCase {
+                name: "zeroed padding",
+                input: {
+                    let mut input = valid();
+                    input[padding_start] = 0;
+                    input
+                },
+                expected_err: Some(MmapError::PromParsing(format!(
+                    "entry padding at offset {padding_start} is not a space"
+                ))),
+            },
+            TestCase {
+                name: "last padding byte overwritten",
+                input: {
+                    let mut input = valid();
+                    let last = input.len() - size_of::<f64>() - 1;
+                    input[last] = b'x';
+                    input
+                },
+                expected_err: Some(MmapError::PromParsing(format!(
+                    "entry padding at offset {} is not a space",
+                    valid().len() - size_of::<f64>() - 1
+                ))),
+            },
+            TestCase {
+                name: "value slot cut short",
+                input: valid()[..valid().len() - 1].to_vec(),
+                expected_err: Some(MmapError::out_of_bounds(valid().len(), valid().len() - 1)),
+            },
+            TestCase {
+                name: "length prefix past end",
+                input: TestEntry {
+                    header: 256,
+                    ..TestEntry::new(json, 1.0)
+                }
+                .as_bytes(),
+                expected_err: Some(MmapError::out_of_bounds(272, valid().len())),
+            },
+            TestCase {
+                name: "no length prefix",
+                input: vec![1, 0],
+                expected_err: Some(MmapError::out_of_bounds(0, 2)),
+            },
+        ];
+
+        for case in tc {
+            let name = case.name;
+            let result = RawEntry::validate(&case.input);
+
+            match case.expected_err {
+                Some(expected_err) => {
+                    assert_eq!(Err(expected_err), result, "test case: {name}")
+                }
+                None => {
+                    assert_eq!(Ok(()), result, "test case: {name}");
+                    let entry = RawEntry::from_slice(&case.input);
+                    assert!(entry.is_ok(), "test case: {name}");
+                }
+            }
+        }
+    }
+
     #[test]
     fn test_save() {
         struct TestCase {
@@ -466,6 +827,118 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_value_first_layout() {
+        let key = br#"["metric","name",["label_a","label_b"],["value_a","value_b"]]"#;
+        let value = 256.0;
+
+        let total_len = Layout::ValueFirst.total_len(key.len()).unwrap();
+        assert_eq!(0, total_len % 8, "entry is 8-byte aligned");
+
+        let mut buf = vec![0; total_len];
+        let value_offset =
+            RawEntry::save_with_layout(&mut buf, key, value, Layout::ValueFirst).unwrap();
+        assert_eq!(8, value_offset, "value at fixed offset");
+        assert_eq!(value.to_ne_bytes(), buf[value_offset..value_offset + 8]);
+
+        let entry = RawEntry::from_slice_with_layout(&buf, Layout::ValueFirst).unwrap();
+        assert_eq!(key.as_slice(), entry.json(), "json matches");
+        assert_eq!(value, entry.value(), "value matches");
+        assert_eq!(total_len, entry.total_len(), "total_len matches");
+
+        // Too short a buffer is rejected.
+        assert!(RawEntry::save_with_layout(
+            &mut buf[..total_len - 1],
+            key,
+            value,
+            Layout::ValueFirst
+        )
+        .is_err());
+        assert!(
+            RawEntry::from_slice_with_layout(&buf[..total_len - 1], Layout::ValueFirst).is_err()
+        );
+    }
+
+    #[test]
+    fn test_packed_layout() {
+        let key = br#"["metric","name",["label_a"],["value_a"]]"#;
+        let value = 256.0;
+
+        // A misaligned fixture as a foreign writer would produce it.
+        let mut buf = Vec::new();
+        buf.extend((key.len() as u32).to_ne_bytes());
+        buf.extend(key);
+        buf.extend(value.to_ne_bytes());
+        assert_ne!(0, (size_of::<u32>() + key.len()) % 8, "value is misaligned");
+
+        let total_len = Layout::Packed.total_len(key.len()).unwrap();
+        assert_eq!(buf.len(), total_len);
+        assert_eq!(
+            size_of::<u32>() + key.len(),
+            Layout::Packed.value_offset(key.len()).unwrap()
+        );
+
+        let entry = RawEntry::from_slice_with_layout(&buf, Layout::Packed).unwrap();
+        assert_eq!(key.as_slice(), entry.json(), "json matches");
+        assert_eq!(value, entry.value(), "value matches");
+        assert_eq!(total_len, entry.total_len(), "total_len matches");
+
+        assert!(RawEntry::from_slice_with_layout(&buf[..total_len - 1], Layout::Packed).is_err());
+        assert!(
+            RawEntry::save_with_layout(&mut buf, key, value, Layout::Packed).is_err(),
+            "never written"
+        );
+    }
+
+    #[test]
+    fn test_layout_from_header() {
+        let mut header = [0u8; 8];
+        assert_eq!(Layout::Legacy, Layout::from_header(&header).unwrap());
+
+        // Flags written as a whole `u32` by earlier versions.
+        header[4..].copy_from_slice(&u32::from(Layout::ValueFirst.flag()).to_ne_bytes());
+        assert_eq!(Layout::ValueFirst, Layout::from_header(&header).unwrap());
+
+        header[4..].copy_from_slice(&u32::from(Layout::Packed.flag()).to_ne_bytes());
+        assert_eq!(Layout::Packed, Layout::from_header(&header).unwrap());
+
+        header[4..].copy_from_slice(&7u32.to_ne_bytes());
+        assert!(Layout::from_header(&header).is_err());
+
+        // Other header fields don't affect the layout.
+        let fields = HeaderFields {
+            layout: Layout::ValueFirst.flag(),
+            version: 3,
+            value_width: 4,
+            flags: 0xff,
+        };
+        header[4..].copy_from_slice(&fields.to_u32().to_ne_bytes());
+        assert_eq!(Layout::ValueFirst, Layout::from_header(&header).unwrap());
+    }
+
+    #[test]
+    fn test_header_fields() {
+        let mut header = [0u8; 8];
+        assert_eq!(
+            HeaderFields::default(),
+            HeaderFields::from_header(&header).unwrap(),
+            "legacy header"
+        );
+
+        let fields = HeaderFields {
+            layout: 1,
+            version: 2,
+            value_width: 3,
+            flags: 4,
+        };
+        assert_eq!(fields, HeaderFields::from_u32(fields.to_u32()));
+
+        header[4..].copy_from_slice(&fields.to_u32().to_ne_bytes());
+        assert_eq!(fields, HeaderFields::from_header(&header).unwrap());
+
+        assert!(HeaderFields::from_header(&header[..6]).is_err());
+    }
+
     #[test]
     fn test_calc_value_offset() {
         struct TestCase {
@@ -535,6 +1008,61 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_check_key_len() {
+        struct TestCase {
+            encoded_len: usize,
+            limit: usize,
+            expected_err: Option<MmapError>,
+        }
+
+        let tc = vec![
+            TestCase {
+                encoded_len: 64,
+                limit: 64,
+                expected_err: None,
+            },
+            TestCase {
+                encoded_len: 65,
+                limit: 64,
+                expected_err: Some(MmapError::KeyTooLong { len: 65, limit: 64 }),
+            },
+            TestCase {
+                encoded_len: 4096,
+                limit: 4096,
+                expected_err: None,
+            },
+            TestCase {
+                encoded_len: 4097,
+                limit: 4096,
+                expected_err: Some(MmapError::KeyTooLong {
+                    len: 4097,
+                    limit: 4096,
+                }),
+            },
+            TestCase {
+                encoded_len: i32::MAX as usize,
+                limit: i32::MAX as usize,
+                expected_err: None,
+            },
+            TestCase {
+                encoded_len: i32::MAX as usize + 1,
+                limit: i32::MAX as usize,
+                expected_err: Some(MmapError::KeyLength),
+            },
+        ];
+
+        for case in tc {
+            assert_eq!(
+                case.expected_err,
+                RawEntry::check_key_len(case.encoded_len, case.limit).err(),
+                "encoded_len {}, limit {}",
+                case.encoded_len,
+                case.limit
+            );
+        }
+    }
+
     #[test]
     fn test_padding_len() {
         for encoded_len in 0..64 {
@@ -544,4 +1072,77 @@ mod test {
             assert!((size_of::<u32>() + encoded_len + padding) % 8 == 0)
         }
     }
+
+    /// Count the 64-byte lines a value-only scan, as `read_value` and reset
+    /// make, touches in the `Legacy` and `ValueFirst` layouts. This is the
+    /// memory traffic `ValueFirst` saves by storing the value next to the
+    /// length, and unlike wall-clock time is the same on every run. Run with
+    /// `cargo nextest run --release --run-ignored ignored-only
+    /// bench_value_scan`.
+    #[test]
+    #[ignore]
+    fn bench_value_scan() {
+        const LINE: usize = 64;
+
+        let keys: Vec<String> = (0..200_000)
+            .map(|i| {
+                format!(
+                    r#"["http_request_duration_seconds","http_request_duration_seconds_bucket",["controller","action","status","le"],["controller_{}","action_{}","{}","{}"]]"#,
+                    i % 97,
+                    i % 13,
+                    200 + i % 5,
+                    i % 11
+                )
+            })
+            .collect();
+        let expected_sum: f64 = (0..keys.len()).map(|i| i as f64).sum();
+
+        let lines_touched = |layout: Layout| {
+            let len: usize = keys
+                .iter()
+                .map(|k| layout.total_len(k.len()).unwrap())
+                .sum();
+            let mut buf = vec![0u8; len];
+
+            let mut pos = 0;
+            for (i, key) in keys.iter().enumerate() {
+                RawEntry::save_with_layout(&mut buf[pos..], key.as_bytes(), i as f64, layout)
+                    .unwrap();
+                pos += layout.total_len(key.len()).unwrap();
+            }
+
+            // Lines are visited in increasing order, count each once.
+            let mut lines = 0;
+            let mut last_line = None;
+            let mut touch = |range: std::ops::Range<usize>| {
+                for line in range.start / LINE..=(range.end - 1) / LINE {
+                    if last_line.map_or(true, |last| line > last) {
+                        lines += 1;
+                        last_line = Some(line);
+                    }
+                }
+            };
+
+            let mut pos = 0;
+            let mut sum = 0.0;
+            while pos < buf.len() {
+                let encoded_len = util::read_u32(&buf, pos).unwrap() as usize;
+                let offset = pos + layout.value_offset(encoded_len).unwrap();
+                sum += util::read_f64(&buf, offset).unwrap();
+                touch(pos..pos + size_of::<u32>());
+                touch(offset..offset + size_of::<f64>());
+                pos += layout.total_len(encoded_len).unwrap();
+            }
+            assert_eq!(expected_sum, sum, "{layout:?}");
+
+            lines
+        };
+
+        let legacy = lines_touched(Layout::Legacy);
+        let value_first = lines_touched(Layout::ValueFirst);
+        assert!(
+            value_first < legacy,
+            "ValueFirst scan touched {value_first} lines, Legacy {legacy}"
+        );
+    }
 }
@@ -1,10 +1,13 @@
 use bstr::{BString, B};
+use std::alloc::{GlobalAlloc, Layout as AllocLayout, System};
+use std::cell::Cell;
 use std::fs::File;
 use std::io::{Read, Seek, Write};
+use std::mem::size_of;
 use std::path::PathBuf;
 use tempfile::{tempdir, TempDir};
 
-use crate::raw_entry::RawEntry;
+use crate::raw_entry::{HeaderFields, Layout, RawEntry};
 use crate::HEADER_SIZE;
 
 #[derive(PartialEq, Default, Debug)]
@@ -79,7 +82,100 @@ pub fn entries_to_db(entries: &[&'static str], values: &[f64], header: Option<u3
     out
 }
 
+/// Builds the data for a `.db` file entry by entry. Unlike `entries_to_db`,
+/// each part of an entry can be overridden with a value that doesn't match
+/// its contents, to construct adversarial files precisely. The overrides
+/// apply to the entry added most recently.
+#[derive(Default, Debug)]
+pub struct DbBuilder {
+    entries: Vec<(TestEntry, usize)>,
+    used: Option<u32>,
+    layout: Layout,
+}
+
+impl DbBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add an entry in the `Legacy` layout, with correct length and padding.
+    pub fn entry(mut self, json: &'static str, value: f64) -> Self {
+        self.entries
+            .push((TestEntry::new(json, value), size_of::<f64>()));
+        self
+    }
+
+    /// Set the length prefix of the last entry.
+    pub fn encoded_len(mut self, len: u32) -> Self {
+        self.last().0.header = len;
+        self
+    }
+
+    /// Set the number of padding bytes after the key of the last entry.
+    pub fn padding(mut self, len: usize) -> Self {
+        self.last().0.padding_len = len;
+        self
+    }
+
+    /// Keep only the first `len` bytes of the last entry's value.
+    pub fn truncate_value(mut self, len: usize) -> Self {
+        self.last().1 = len;
+        self
+    }
+
+    /// Set the `used` header, which otherwise covers all entries written.
+    pub fn used(mut self, used: u32) -> Self {
+        self.used = Some(used);
+        self
+    }
+
+    /// Set the layout flag in the header. Entries are not rearranged, so
+    /// `padding` should be used to match the layout.
+    pub fn layout(mut self, layout: Layout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    pub fn build(&self) -> Vec<u8> {
+        let entry_bytes: Vec<u8> = self
+            .entries
+            .iter()
+            .flat_map(|(entry, value_len)| {
+                let mut bytes = entry.as_bytes();
+                bytes.truncate(bytes.len() - size_of::<f64>() + value_len);
+                bytes
+            })
+            .collect();
+
+        let used = match self.used {
+            Some(u) => u,
+            None => (entry_bytes.len() + HEADER_SIZE) as u32,
+        };
+        let fields = HeaderFields {
+            layout: self.layout.flag(),
+            ..Default::default()
+        };
+
+        let mut out = Vec::new();
+        out.extend(used.to_ne_bytes());
+        out.extend(fields.to_u32().to_ne_bytes());
+        out.extend(entry_bytes);
+
+        out
+    }
+
+    fn last(&mut self) -> &mut (TestEntry, usize) {
+        self.entries.last_mut().expect("no entry added")
+    }
+}
+
 /// A temporary file, path, and dir for use with testing.
+///
+/// The file is deleted with `dir` when it is dropped, so `dir` must be kept in
+/// scope for as long as the file is used, including by an mmap or `FileInfo`
+/// built from it. Bind it to a named variable such as `_dir` rather than `_`,
+/// which drops it immediately, and return the `TestFile` from any helper that
+/// returns an object using the file.
 #[derive(Debug)]
 pub struct TestFile {
     pub file: File,
@@ -106,6 +202,103 @@ impl TestFile {
         // contains, which may prevent cleanup.
         TestFile { file, path, dir }
     }
+
+    /// Assert the file hasn't been deleted, i.e. `dir` is still in scope.
+    /// Call before operations that rely on the file's path.
+    pub fn assert_exists(&self) {
+        assert!(
+            self.path.exists(),
+            "{} was deleted, is its TempDir still in scope?",
+            self.path.display()
+        );
+    }
+}
+
+/// The heap allocations made by a thread while running a closure passed to
+/// `measure_allocs`.
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct AllocStats {
+    /// The number of allocations and reallocations.
+    pub count: usize,
+    /// Bytes allocated and not yet freed, which is negative if the closure
+    /// freed memory allocated before it ran.
+    pub current: isize,
+    /// The highest value of `current`.
+    pub peak: isize,
+}
+
+thread_local! {
+    static ALLOC_STATS: Cell<AllocStats> = const {
+        Cell::new(AllocStats {
+            count: 0,
+            current: 0,
+            peak: 0,
+        })
+    };
+}
+
+/// Wraps the system allocator to count the allocations of each thread, so
+/// tests can assert on the allocations and peak heap use of the code they
+/// run. Only the thread's own Rust allocations are counted, memory allocated
+/// by Ruby is not.
+struct CountingAlloc;
+
+impl CountingAlloc {
+    fn record(count: usize, delta: isize) {
+        // Ignore allocations made while the thread's locals are destroyed.
+        let _ = ALLOC_STATS.try_with(|cell| {
+            let mut stats = cell.get();
+            stats.count += count;
+            stats.current += delta;
+            stats.peak = stats.peak.max(stats.current);
+            cell.set(stats);
+        });
+    }
+}
+
+// CAST: allocation sizes never exceed `isize::MAX`.
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: AllocLayout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            Self::record(1, layout.size() as isize);
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: AllocLayout) -> *mut u8 {
+        let ptr = System.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            Self::record(1, layout.size() as isize);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: AllocLayout) {
+        System.dealloc(ptr, layout);
+        Self::record(0, -(layout.size() as isize));
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: AllocLayout, new_size: usize) -> *mut u8 {
+        let new_ptr = System.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            Self::record(1, new_size as isize - layout.size() as isize);
+        }
+        new_ptr
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAlloc = CountingAlloc;
+
+/// Run `f` and return its result along with the heap allocations it made on
+/// this thread.
+pub fn measure_allocs<T>(f: impl FnOnce() -> T) -> (T, AllocStats) {
+    ALLOC_STATS.with(|cell| cell.set(AllocStats::default()));
+    let out = f();
+    let stats = ALLOC_STATS.with(Cell::get);
+
+    (out, stats)
 }
 
 mod test {
@@ -199,6 +392,37 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_db_builder() {
+        let json = &["foobar", "qux"];
+        let values = &[1.0, 2.0];
+
+        let out = DbBuilder::new()
+            .entry(json[0], values[0])
+            .entry(json[1], values[1])
+            .build();
+        assert_eq!(entries_to_db(json, values, None), out, "default");
+
+        let out = DbBuilder::new()
+            .entry(json[0], values[0])
+            .encoded_len(1000)
+            .padding(0)
+            .entry(json[1], values[1])
+            .truncate_value(3)
+            .used(7)
+            .layout(Layout::Packed)
+            .build();
+
+        assert_eq!(7u32.to_ne_bytes(), out[0..4], "used set");
+        assert_eq!(2u32.to_ne_bytes(), out[4..8], "layout set");
+        assert_eq!(1000u32.to_ne_bytes(), out[8..12], "encoded len set");
+        assert_eq!(B(json[0]), &out[12..18], "first key");
+        assert_eq!(values[0].to_ne_bytes(), out[18..26], "no padding");
+
+        let second = TestEntry::new(json[1], values[1]).as_bytes();
+        assert_eq!(&second[..second.len() - 5], &out[26..], "value truncated");
+    }
+
     #[test]
     fn test_file() {
         let mut test_file = TestFile::new(b"foobar");
@@ -219,4 +443,39 @@ mod test {
         assert!(write_result.is_ok());
         assert_eq!(3, write_result.unwrap(), "file is writable");
     }
+
+    #[test]
+    #[should_panic(expected = "is its TempDir still in scope?")]
+    fn test_file_assert_exists() {
+        let test_file = TestFile::new(b"foobar");
+        test_file.assert_exists();
+
+        // Replace the file's dir, deleting the original.
+        let TestFile {
+            file,
+            path,
+            dir: old_dir,
+        } = test_file;
+        drop(old_dir);
+        let dir = tempdir().unwrap();
+
+        TestFile { file, path, dir }.assert_exists();
+    }
+
+    #[test]
+    fn test_measure_allocs() {
+        let (v, stats) = measure_allocs(|| {
+            let mut v: Vec<u8> = Vec::with_capacity(16);
+            v.extend_from_slice(&[0u8; 32]);
+            v
+        });
+        assert_eq!(2, stats.count, "allocation and reallocation");
+        assert_eq!(v.capacity() as isize, stats.current, "live bytes");
+        assert_eq!(stats.current, stats.peak, "peak");
+
+        let (_, stats) = measure_allocs(|| drop(v));
+        assert_eq!(0, stats.count, "free isn't counted");
+        assert_eq!(0, stats.peak, "no growth");
+        assert!(stats.current < 0, "freed bytes");
+    }
 }
@@ -1,12 +1,17 @@
 use nix::errno::Errno;
 use nix::libc::c_long;
+use std::borrow::Cow;
 use std::fmt::Display;
+use std::hash::Hasher;
 use std::io;
-use std::mem::size_of;
+use std::mem::{align_of, size_of};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use crate::error::MmapError;
-use crate::exemplars::{Exemplar, EXEMPLAR_ENTRY_MAX_SIZE_BYTES};
+use crate::exemplars::{Exemplar, ExemplarFormat, EXEMPLAR_ENTRY_MAX_SIZE_BYTES};
 use crate::Result;
+use crate::MAX_FILE_SIZE;
 
 /// Wrapper around `checked_add()` that converts failures
 /// to `MmapError::Overflow`.
@@ -48,6 +53,167 @@ where
     U::try_from(val).map_err(|_| MmapError::failed_cast::<T, U>(val, name))
 }
 
+/// A 64-bit FNV-1a `Hasher`. Unlike `DefaultHasher` the algorithm is fixed, so
+/// digests of the same bytes remain comparable across processes and Rust
+/// releases. Feed it explicit bytes with `write`: the integer methods and
+/// derived `Hash` impls use native byte order and unspecified encodings.
+#[derive(Clone, Copy, Debug)]
+pub struct Fnv64(u64);
+
+impl Default for Fnv64 {
+    fn default() -> Self {
+        // The FNV-1a 64-bit offset basis.
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv64 {
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 ^= u64::from(b);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Reject files larger than `MAX_FILE_SIZE` before they are mapped or read.
+pub fn check_file_size(path: &Path, len: usize) -> Result<()> {
+    if len > MAX_FILE_SIZE {
+        return Err(MmapError::FileTooLarge {
+            path: path.display().to_string(),
+            len,
+            limit: MAX_FILE_SIZE,
+        });
+    }
+
+    Ok(())
+}
+
+/// Replace characters that are invalid in a Prometheus metric name, or in a
+/// label name when `is_label` is set, with `_`. Metric names may contain `:`,
+/// label names may not, and neither may start with a digit. A leading digit
+/// is kept behind a `_` prefix rather than replaced, so names such as `4xx`
+/// and `5xx` stay distinct. The name is borrowed unchanged when it is already
+/// valid.
+pub fn sanitize_name(name: &str, is_label: bool) -> Cow<'_, str> {
+    if name
+        .char_indices()
+        .all(|(i, c)| valid_name_char(i, c, is_label))
+    {
+        return Cow::Borrowed(name);
+    }
+
+    let prefixed = name.starts_with(|c: char| c.is_ascii_digit());
+
+    let mut out = String::with_capacity(name.len() + 1);
+    if prefixed {
+        out.push('_');
+    }
+    for (i, c) in name.char_indices() {
+        // Shift the index past the prefix, so a leading digit is valid.
+        let i = i + usize::from(prefixed);
+        if valid_name_char(i, c, is_label) {
+            out.push(c);
+        } else {
+            out.push('_');
+        }
+    }
+
+    out.into()
+}
+
+/// Whether `name` is a valid, non-empty Prometheus metric name, or label name
+/// when `is_label` is set.
+pub fn is_valid_name(name: &str, is_label: bool) -> bool {
+    !name.is_empty()
+        && name
+            .char_indices()
+            .all(|(i, c)| valid_name_char(i, c, is_label))
+}
+
+/// Whether `c` may appear at character index `i` of a metric or label name.
+#[inline]
+fn valid_name_char(i: usize, c: char, is_label: bool) -> bool {
+    match c {
+        'a'..='z' | 'A'..='Z' | '_' => true,
+        ':' => !is_label,
+        '0'..='9' => i > 0,
+        _ => false,
+    }
+}
+
+/// Trim a UTF-8 byte order mark and any whitespace a foreign writer left
+/// before the JSON key of an entry.
+pub fn trim_key(json: &str) -> &str {
+    json.trim_start_matches(|c: char| c == '\u{feff}' || c.is_whitespace())
+}
+
+/// Normalize the line endings of text format exposition, `\r\n` and lone
+/// `\r` alike, to `\n`, ending with exactly one newline. Empty text is
+/// returned empty.
+pub fn normalize_newlines(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 1);
+
+    let mut chars = text.trim_end_matches(['\r', '\n']).chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\r' {
+            chars.next_if_eq(&'\n');
+            out.push('\n');
+        } else {
+            out.push(c);
+        }
+    }
+
+    if !out.is_empty() {
+        out.push('\n');
+    }
+
+    out
+}
+
+/// Format a histogram `le` or summary `quantile` bound as the reference Go
+/// client does with `strconv.FormatFloat(v, 'g', -1, 64)`: the shortest
+/// representation, in exponent form for exponents below -4 or of 6 and up,
+/// and `+Inf`, `-Inf`, or `NaN` for values that aren't finite.
+pub fn format_bound(v: f64) -> String {
+    if v.is_nan() {
+        return "NaN".to_string();
+    }
+    if v.is_infinite() {
+        return if v > 0.0 { "+Inf" } else { "-Inf" }.to_string();
+    }
+
+    // `{:e}` writes the shortest digits that round trip, e.g. `1.5e-5`.
+    let sci = format!("{v:e}");
+    let Some((mantissa, exp)) = sci.split_once('e') else {
+        return v.to_string();
+    };
+    let Ok(exp) = exp.parse::<i32>() else {
+        return v.to_string();
+    };
+
+    if (-4..6).contains(&exp) {
+        return v.to_string();
+    }
+
+    let sign = if exp < 0 { '-' } else { '+' };
+    format!("{mantissa}e{sign}{:02}", exp.abs())
+}
+
+/// Format a timestamp in milliseconds as seconds with a millisecond fraction,
+/// as the OpenMetrics format requires, e.g. `1700000000123` as
+/// `1700000000.123`.
+pub fn format_millis_as_secs(ms: i64) -> String {
+    let sign = if ms < 0 { "-" } else { "" };
+    let abs = ms.unsigned_abs();
+
+    format!("{sign}{}.{:03}", abs / 1000, abs % 1000)
+}
+
 /// Retrieve errno(3).
 pub fn errno() -> i32 {
     // UNWRAP: This will always return `Some` when called from `last_os_error()`.
@@ -89,28 +255,115 @@ pub fn read_f64(buf: &[u8], offset: usize) -> Result<f64> {
     ))
 }
 
-pub fn read_exemplar(buf: &[u8], offset: usize) -> Result<Exemplar> {
-    if let Some(slice) = buf.get(offset..offset + EXEMPLAR_ENTRY_MAX_SIZE_BYTES) {
-        // UNWRAP: We can safely unwrap the conversion from slice to array as we
-       // can be sure the target array has same length as the source slice.
-       let out: &[u8; EXEMPLAR_ENTRY_MAX_SIZE_BYTES] = slice.try_into().expect("failed to convert slice to array");
+/// Read an `f64` value from a byte slice starting from `offset`, with a
+/// single atomic load when the value is 8-byte aligned. A concurrent atomic
+/// store from another process can then never be observed half-written.
+/// Unaligned values fall back to `read_f64`.
+#[inline]
+pub fn load_f64(buf: &[u8], offset: usize) -> Result<f64> {
+    let Some(slice) = buf.get(offset..offset + size_of::<f64>()) else {
+        return read_f64(buf, offset);
+    };
+
+    let ptr = slice.as_ptr();
+    if ptr.align_offset(align_of::<AtomicU64>()) != 0 {
+        return read_f64(buf, offset);
+    }
+
+    // SAFETY: The pointer is aligned and valid for reads of 8 bytes for the
+    // lifetime of `buf`. We only load through it, and the only concurrent
+    // writers are other processes using atomic stores of the same width.
+    let slot = unsafe { AtomicU64::from_ptr(ptr.cast::<u64>().cast_mut()) };
+
+    Ok(f64::from_bits(slot.load(Ordering::Relaxed)))
+}
+
+/// Read the `Exemplar` stored in `format` in the slot at `offset`. Only the
+/// stored JSON of a `LengthPrefixed` slot is read, so `buf` need not extend
+/// past it to the end of the slot.
+pub fn read_exemplar(buf: &[u8], offset: usize, format: ExemplarFormat) -> Result<Exemplar> {
+    serde_json::from_slice(exemplar_json(buf, offset, format)?)
+        .map_err(|e| MmapError::PromParsing(format!("failed to parse exemplar: {e}")))
+}
 
-       let res: Vec<u8> = out.iter().cloned().filter(|&x| x != 0).collect();
+/// Locate the JSON of the exemplar slot at `offset`, using its length prefix
+/// or by trimming the NUL padding of a `Legacy` slot.
+fn exemplar_json(buf: &[u8], offset: usize, format: ExemplarFormat) -> Result<&[u8]> {
+    let (start, end) = match format {
+        ExemplarFormat::Legacy => (offset, offset.add_chk(EXEMPLAR_ENTRY_MAX_SIZE_BYTES)?),
+        ExemplarFormat::LengthPrefixed => {
+            // CAST: no-op on 32-bit, widening on 64-bit.
+            let len = read_u32(buf, offset)? as usize;
+            if len > format.max_json_len() {
+                return Err(MmapError::PromParsing(format!(
+                    "exemplar length {len} larger than {}",
+                    format.max_json_len()
+                )));
+            }
 
-       let v: Exemplar = serde_json::from_slice(&res).expect("failed to convert string to Exemplar");
-        
-       return Ok(v)
+            let start = offset.add_chk(size_of::<u32>())?;
+            (start, start.add_chk(len)?)
+        }
+    };
+
+    let json = buf
+        .get(start..end)
+        .ok_or_else(|| MmapError::out_of_bounds(end, buf.len()))?;
+
+    match format {
+        ExemplarFormat::Legacy => {
+            let json_end = json.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1);
+            Ok(&json[..json_end])
+        }
+        ExemplarFormat::LengthPrefixed => Ok(json),
     }
-    Err(MmapError::out_of_bounds(
-        offset + EXEMPLAR_ENTRY_MAX_SIZE_BYTES,
-        buf.len(),
-    ))
+}
+
+/// Write `exemplar` to `slot` in `format`, filling the rest of the slot with
+/// NUL padding.
+pub fn write_exemplar(slot: &mut [u8], exemplar: &Exemplar, format: ExemplarFormat) -> Result<()> {
+    if slot.len() != EXEMPLAR_ENTRY_MAX_SIZE_BYTES {
+        return Err(MmapError::out_of_bounds(
+            EXEMPLAR_ENTRY_MAX_SIZE_BYTES,
+            slot.len(),
+        ));
+    }
+
+    exemplar.validate()?;
+
+    let json = serde_json::to_vec(exemplar)
+        .map_err(|e| MmapError::Other(format!("failed to serialize exemplar: {e}")))?;
+
+    if json.len() > format.max_json_len() {
+        return Err(MmapError::Other(format!(
+            "exemplar length {} larger than {}",
+            json.len(),
+            format.max_json_len()
+        )));
+    }
+
+    let rest = match format {
+        ExemplarFormat::Legacy => slot,
+        ExemplarFormat::LengthPrefixed => {
+            let (len_bytes, rest) = slot.split_at_mut(size_of::<u32>());
+            // CAST: `json.len()` is less than `EXEMPLAR_ENTRY_MAX_SIZE_BYTES`.
+            len_bytes.copy_from_slice(&(json.len() as u32).to_ne_bytes());
+            rest
+        }
+    };
+
+    rest[..json.len()].copy_from_slice(&json);
+    rest[json.len()..].fill(0);
+
+    Ok(())
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
 
+    const PREFIXED: ExemplarFormat = ExemplarFormat::LengthPrefixed;
+
     #[test]
     fn test_read_u32() {
         let buf = 1u32.to_ne_bytes();
@@ -137,4 +390,345 @@ mod test {
             "index in range but end out of range"
         );
     }
+
+    #[test]
+    fn test_load_f64() {
+        // Backed by u64s so that offset 0 is 8-byte aligned.
+        let words = [1.5f64.to_bits(), 0];
+        // SAFETY: `words` is 16 initialized bytes that outlive `buf`.
+        let buf: &[u8] = unsafe { std::slice::from_raw_parts(words.as_ptr().cast(), 16) };
+
+        assert_eq!(load_f64(buf, 0).unwrap(), 1.5, "aligned");
+        assert_eq!(
+            load_f64(buf, 1).unwrap(),
+            read_f64(buf, 1).unwrap(),
+            "unaligned"
+        );
+        assert!(load_f64(buf, 9).is_err(), "end out of range");
+    }
+
+    #[test]
+    fn test_fnv64() {
+        let hash = |bytes: &[u8]| {
+            let mut hasher = Fnv64::default();
+            hasher.write(bytes);
+            hasher.finish()
+        };
+
+        // Reference values from the FNV specification.
+        assert_eq!(0xcbf29ce484222325, hash(b""));
+        assert_eq!(0xaf63dc4c8601ec8c, hash(b"a"));
+        assert_eq!(0x85944171f73967e8, hash(b"foobar"));
+    }
+
+    #[test]
+    fn test_normalize_newlines() {
+        struct TestCase {
+            name: &'static str,
+            input: &'static str,
+            expected: &'static str,
+        }
+
+        let tc = vec![
+            TestCase {
+                name: "empty",
+                input: "",
+                expected: "",
+            },
+            TestCase {
+                name: "already normalized",
+                input: "# TYPE a gauge\na 1\n",
+                expected: "# TYPE a gauge\na 1\n",
+            },
+            TestCase {
+                name: "missing trailing newline",
+                input: "a 1",
+                expected: "a 1\n",
+            },
+            TestCase {
+                name: "crlf",
+                input: "# TYPE a gauge\r\na 1\r\n",
+                expected: "# TYPE a gauge\na 1\n",
+            },
+            TestCase {
+                name: "extra trailing newlines",
+                input: "a 1\n\r\n\n",
+                expected: "a 1\n",
+            },
+            TestCase {
+                name: "only newlines",
+                input: "\r\n\n",
+                expected: "",
+            },
+            TestCase {
+                name: "lone cr",
+                input: "# TYPE a gauge\ra 1\r\rb 2\r",
+                expected: "# TYPE a gauge\na 1\n\nb 2\n",
+            },
+            TestCase {
+                name: "openmetrics",
+                input: "a_total 1\r\n# EOF\r\n",
+                expected: "a_total 1\n# EOF\n",
+            },
+        ];
+
+        for case in tc {
+            let out = normalize_newlines(case.input);
+            assert_eq!(case.expected, out, "test case: {}", case.name);
+            assert!(!out.contains('\r'), "test case: {}", case.name);
+        }
+    }
+
+    #[test]
+    fn test_format_bound() {
+        let tc = [
+            (0.005, "0.005"),
+            (0.1, "0.1"),
+            (1.0, "1"),
+            (2.5, "2.5"),
+            (10.0, "10"),
+            (0.0, "0"),
+            (0.0001, "0.0001"),
+            (0.00001, "1e-05"),
+            (100000.0, "100000"),
+            (1000000.0, "1e+06"),
+            (1234567.0, "1.234567e+06"),
+            (-0.25, "-0.25"),
+            (f64::INFINITY, "+Inf"),
+            (f64::NEG_INFINITY, "-Inf"),
+            (f64::NAN, "NaN"),
+        ];
+
+        for (input, expected) in tc {
+            assert_eq!(expected, format_bound(input), "input: {input}");
+        }
+    }
+
+    #[test]
+    fn test_format_millis_as_secs() {
+        let tc = [
+            (1_700_000_000_123, "1700000000.123"),
+            (1_700_000_005_000, "1700000005.000"),
+            (1_700_000_000_010, "1700000000.010"),
+            (0, "0.000"),
+            (999, "0.999"),
+            (-1_500, "-1.500"),
+            (-5, "-0.005"),
+        ];
+
+        for (input, expected) in tc {
+            assert_eq!(expected, format_millis_as_secs(input), "input: {input}");
+        }
+    }
+
+    #[test]
+    fn test_sanitize_name_collisions() {
+        let names = ["4xx", "5xx", "5xx_errors", "5.xx", "_5xx_", "1st", "2nd"];
+
+        let sanitized: std::collections::HashSet<_> =
+            names.iter().map(|n| sanitize_name(n, false)).collect();
+        assert_eq!(names.len(), sanitized.len(), "{sanitized:?}");
+    }
+
+    #[test]
+    fn test_sanitize_name() {
+        struct TestCase {
+            name: &'static str,
+            input: &'static str,
+            is_label: bool,
+            expected: &'static str,
+        }
+
+        let tc = vec![
+            TestCase {
+                name: "valid metric",
+                input: "http_requests:total",
+                is_label: false,
+                expected: "http_requests:total",
+            },
+            TestCase {
+                name: "dots",
+                input: "http.requests.total",
+                is_label: false,
+                expected: "http_requests_total",
+            },
+            TestCase {
+                name: "dashes",
+                input: "http-requests",
+                is_label: false,
+                expected: "http_requests",
+            },
+            TestCase {
+                name: "leading digit",
+                input: "5xx_errors",
+                is_label: false,
+                expected: "_5xx_errors",
+            },
+            TestCase {
+                name: "colon in label",
+                input: "le:bucket",
+                is_label: true,
+                expected: "le_bucket",
+            },
+            TestCase {
+                name: "multi-byte",
+                input: "café",
+                is_label: true,
+                expected: "caf_",
+            },
+        ];
+
+        for case in tc {
+            let out = sanitize_name(case.input, case.is_label);
+            assert_eq!(case.expected, out, "test case: {}", case.name);
+            assert_eq!(
+                case.input == case.expected,
+                matches!(out, Cow::Borrowed(_)),
+                "test case: {} borrowed",
+                case.name
+            );
+        }
+    }
+
+    #[test]
+    fn test_exemplar_round_trip() {
+        let ex = Exemplar {
+            label_name: "trace_id".to_string(),
+            label_value: "abc\0def".to_string(),
+            value: 1.5,
+            timestamp: 100,
+        };
+
+        let mut buf = vec![0xffu8; EXEMPLAR_ENTRY_MAX_SIZE_BYTES + 8];
+        write_exemplar(&mut buf[8..], &ex, PREFIXED).unwrap();
+
+        let out = read_exemplar(&buf, 8, PREFIXED).unwrap();
+        assert_eq!(ex.label_name, out.label_name);
+        assert_eq!("abc\0def", out.label_value, "embedded NUL preserved");
+        assert_eq!(ex.value, out.value);
+        assert_eq!(ex.timestamp, out.timestamp);
+
+        assert!(
+            read_exemplar(&buf, 16, PREFIXED).is_err(),
+            "end out of range"
+        );
+        assert!(
+            write_exemplar(&mut buf[..8], &ex, PREFIXED).is_err(),
+            "slot wrong size"
+        );
+    }
+
+    #[test]
+    fn test_read_exemplar_len() {
+        let ex = |label_value: String| Exemplar {
+            label_name: "trace_id".to_string(),
+            label_value,
+            value: 1.5,
+            timestamp: 100,
+        };
+
+        // A short exemplar is read without the rest of its slot, which would
+        // otherwise pull in the bytes that follow it.
+        let short = ex("abc".to_string());
+        let mut slot = vec![0u8; EXEMPLAR_ENTRY_MAX_SIZE_BYTES];
+        write_exemplar(&mut slot, &short, PREFIXED).unwrap();
+
+        let json_len = read_u32(&slot, 0).unwrap() as usize;
+        let mut buf = vec![0xffu8; 8];
+        buf.extend_from_slice(&slot[..size_of::<u32>() + json_len]);
+
+        let out = read_exemplar(&buf, 8, PREFIXED).unwrap();
+        assert_eq!("abc", out.label_value);
+
+        buf.pop();
+        assert!(read_exemplar(&buf, 8, PREFIXED).is_err(), "JSON truncated");
+
+        // The largest valid exemplar, 8 + 120 code points with most of them
+        // multi-byte.
+        let maximal = ex("é".repeat(120));
+        write_exemplar(&mut slot, &maximal, PREFIXED).unwrap();
+
+        let out = read_exemplar(&slot, 0, PREFIXED).unwrap();
+        assert_eq!(maximal.label_value, out.label_value);
+        assert_eq!(maximal.value, out.value);
+        assert_eq!(maximal.timestamp, out.timestamp);
+
+        assert!(
+            read_exemplar(&slot, slot.len() - 2, PREFIXED).is_err(),
+            "length prefix out of range"
+        );
+    }
+
+    #[test]
+    fn test_write_exemplar_invalid() {
+        let mut buf = vec![0u8; EXEMPLAR_ENTRY_MAX_SIZE_BYTES];
+
+        let ex = |label_name: &str, label_value: String| Exemplar {
+            label_name: label_name.to_string(),
+            label_value,
+            value: 1.0,
+            timestamp: 100,
+        };
+
+        let err =
+            write_exemplar(&mut buf, &ex("trace-id", "abc".to_string()), PREFIXED).unwrap_err();
+        assert!(
+            matches!(err, MmapError::InvalidExemplar(_)),
+            "bad label name: {err}"
+        );
+
+        let err = write_exemplar(&mut buf, &ex("", "abc".to_string()), PREFIXED).unwrap_err();
+        assert!(
+            matches!(err, MmapError::InvalidExemplar(_)),
+            "empty label name: {err}"
+        );
+
+        // 8 + 120 code points is exactly the limit, multi-byte characters
+        // count once each.
+        let at_limit = ex("trace_id", "é".repeat(120));
+        assert!(write_exemplar(&mut buf, &at_limit, PREFIXED).is_ok());
+
+        let err = write_exemplar(&mut buf, &ex("trace_id", "é".repeat(121)), PREFIXED).unwrap_err();
+        assert!(
+            matches!(err, MmapError::InvalidExemplar(_)),
+            "over-length labels: {err}"
+        );
+    }
+
+    #[test]
+    fn test_read_exemplar_legacy() {
+        let json = br#"{"label_name":"a","label_value":"b","value":2.0,"timestamp":5}"#;
+        let mut buf = vec![0u8; EXEMPLAR_ENTRY_MAX_SIZE_BYTES];
+        buf[..json.len()].copy_from_slice(json);
+
+        let out = read_exemplar(&buf, 0, ExemplarFormat::Legacy).unwrap();
+        assert_eq!("a", out.label_name);
+        assert_eq!("b", out.label_value);
+        assert_eq!(2.0, out.value);
+        assert_eq!(5, out.timestamp);
+
+        // Reading a slot in the wrong format is an error, not a panic.
+        let err = read_exemplar(&buf, 0, PREFIXED).unwrap_err();
+        assert!(matches!(err, MmapError::PromParsing(_)), "{err}");
+
+        let ex = |label_value: String| Exemplar {
+            label_name: "trace_id".to_string(),
+            label_value,
+            value: 1.5,
+            timestamp: 100,
+        };
+        write_exemplar(&mut buf, &ex("abc".to_string()), PREFIXED).unwrap();
+        let err = read_exemplar(&buf, 0, ExemplarFormat::Legacy).unwrap_err();
+        assert!(matches!(err, MmapError::PromParsing(_)), "{err}");
+
+        // JSON of 522 bytes fills all but two bytes of a legacy slot, more
+        // than a length prefixed slot holds.
+        let large = ex("🦀".repeat(113));
+        write_exemplar(&mut buf, &large, ExemplarFormat::Legacy).unwrap();
+        let out = read_exemplar(&buf, 0, ExemplarFormat::Legacy).unwrap();
+        assert_eq!(large.label_value, out.label_value);
+
+        let err = write_exemplar(&mut buf, &large, PREFIXED).unwrap_err();
+        assert_eq!("exemplar length 522 larger than 520", err.to_string());
+    }
 }
@@ -11,11 +11,20 @@ module Prometheus
         START_POSITION = 8
         VALUE_BYTES = 8
         ENCODED_LENGTH_BYTES = 4
+        # Entries are parsed as [len][key][padding][value]. FastMmapedFileRs
+        # can switch a file to another layout, flagged in the first byte of
+        # the header word after `used`.
+        LEGACY_LAYOUT = 0
 
         def used
           slice(0..3).unpack('l')[0]
         end
 
+        # Not `layout`, which would override FastMmapedFileRs#layout.
+        def layout_flag
+          slice(4).unpack1('C')
+        end
+
         def parts
           @parts ||= File.basename(filepath, '.db')
                        .split('_')
@@ -41,12 +50,19 @@ module Prometheus
         def entries(ignore_errors = false)
           return Enumerator.new {} if empty?
 
+          # Reading, and later writing, a file in another layout at legacy
+          # positions would corrupt it, so this is raised even when ignoring
+          # errors.
+          unless layout_flag == LEGACY_LAYOUT
+            raise ParsingError, "unsupported entry layout #{layout_flag} in #{filepath}"
+          end
+
           Enumerator.new do |yielder|
             used_ = used # cache used to avoid unnecessary unpack operations
 
             pos = START_POSITION # used + padding offset
             while pos < used_ && pos < size && pos > 0
-              data = slice(pos..-1)
+              data = slice(pos...used_)
               unless data
                 raise ParsingError, "data slice is nil at pos #{pos}" unless ignore_errors
                 pos += 8
@@ -21,6 +21,22 @@ describe Prometheus::Client::MmapedDict do
       end
     end
 
+    describe "mmap'ed file in a non-legacy layout" do
+      before do
+        tmp_mmaped_file.layout = :value_first
+      end
+
+      it 'refuses to read the file' do
+        expect { described_class.new(tmp_mmaped_file) }
+          .to raise_error(Prometheus::Client::ParsingError, /unsupported entry layout 1/)
+      end
+
+      it 'reports the layout set through FastMmapedFileRs' do
+        expect(tmp_mmaped_file.layout).to eq(:value_first)
+        expect(tmp_mmaped_file.layout_flag).to eq(1)
+      end
+    end
+
     describe "mmap'ed file that is above minimum size" do
       let(:above_minimum_size) { Prometheus::Client::Helper::EntryParser::MINIMUM_SIZE + 1 }
       let(:page_size) { Prometheus::Client::PageSize.page_size }